@@ -0,0 +1,132 @@
+use std::time::Duration;
+
+/// The number of sub-buckets in each power of two, which bounds the relative error of the recorded
+/// values to 1/16.
+const SUB_BUCKETS: u64 = 16;
+const SUB_BUCKET_BITS: u32 = SUB_BUCKETS.trailing_zeros();
+const NUM_BUCKETS: usize = (64 - SUB_BUCKET_BITS as usize + 1) * SUB_BUCKETS as usize;
+
+/// A histogram of latencies in nanoseconds with log-linear buckets.
+///
+/// Values are rounded down to their buckets, so percentiles are slightly lower than the exact ones,
+/// while the maximum is exact.
+#[derive(Clone, Debug)]
+pub struct Histogram {
+    buckets: Vec<u64>,
+    count: u64,
+    sum: u64,
+    max: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: vec![0; NUM_BUCKETS],
+            count: 0,
+            sum: 0,
+            max: 0,
+        }
+    }
+}
+
+impl Histogram {
+    pub fn record(&mut self, latency: Duration) {
+        let nanos = latency.as_nanos().min(u64::MAX as u128) as u64;
+        self.buckets[bucket_index(nanos)] += 1;
+        self.count += 1;
+        self.sum = self.sum.saturating_add(nanos);
+        self.max = self.max.max(nanos);
+    }
+
+    /// Adds the values recorded by `other` to this histogram.
+    pub fn merge(&mut self, other: &Histogram) {
+        for (a, b) in self.buckets.iter_mut().zip(&other.buckets) {
+            *a += b;
+        }
+        self.count += other.count;
+        self.sum = self.sum.saturating_add(other.sum);
+        self.max = self.max.max(other.max);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn mean(&self) -> Duration {
+        Duration::from_nanos(self.sum / self.count.max(1))
+    }
+
+    pub fn max(&self) -> Duration {
+        Duration::from_nanos(self.max)
+    }
+
+    /// Returns the latency that `p` percent of the recorded values are at or below.
+    pub fn percentile(&self, p: f64) -> Duration {
+        if self.count == 0 {
+            return Duration::ZERO;
+        }
+        let rank = ((p / 100.0 * self.count as f64).ceil() as u64).clamp(1, self.count);
+        let mut seen = 0;
+        for (i, &n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return Duration::from_nanos(bucket_low(i).min(self.max));
+            }
+        }
+        self.max()
+    }
+}
+
+fn bucket_index(value: u64) -> usize {
+    if value < SUB_BUCKETS {
+        return value as usize;
+    }
+    let exp = 63 - value.leading_zeros();
+    let shift = exp - SUB_BUCKET_BITS;
+    let group = (exp - SUB_BUCKET_BITS + 1) as usize;
+    group * SUB_BUCKETS as usize + ((value >> shift) & (SUB_BUCKETS - 1)) as usize
+}
+
+/// Returns the smallest value in the bucket.
+fn bucket_low(index: usize) -> u64 {
+    let group = index / SUB_BUCKETS as usize;
+    let sub = (index % SUB_BUCKETS as usize) as u64;
+    if group == 0 {
+        return sub;
+    }
+    let shift = group as u32 - 1;
+    (SUB_BUCKETS | sub) << shift
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn percentiles() {
+        for value in [0, 1, 15, 16, 17, 100, 1 << 40, u64::MAX] {
+            let low = bucket_low(bucket_index(value));
+            assert!(
+                low <= value && value - low <= value / SUB_BUCKETS,
+                "{}",
+                value
+            );
+        }
+
+        let mut h = Histogram::default();
+        assert_eq!(h.percentile(50.0), Duration::ZERO);
+        for micros in 1..=100 {
+            h.record(Duration::from_micros(micros));
+        }
+        let mut other = Histogram::default();
+        other.record(Duration::from_secs(1));
+        h.merge(&other);
+        assert_eq!(h.count(), 101);
+        assert_eq!(h.max(), Duration::from_secs(1));
+        let p50 = h.percentile(50.0).as_micros();
+        assert!((48..=51).contains(&p50), "{}", p50);
+        let p99 = h.percentile(99.0).as_micros();
+        assert!((94..=100).contains(&p99), "{}", p99);
+        assert!(h.percentile(100.0) > Duration::from_millis(900));
+    }
+}
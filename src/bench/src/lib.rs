@@ -0,0 +1,348 @@
+//! A benchmark harness for PhotonDB.
+//!
+//! The harness sets up a table of a given [`Shape`] deterministically, so that benchmarks of
+//! different engine versions run on the same data with the same tree layout. It is a library so
+//! that applications can embed it in their own performance tests:
+//!
+//! ```no_run
+//! # async fn bench() -> photondb::Result<()> {
+//! use photondb_bench::{Bench, Shape};
+//!
+//! let shape = Shape {
+//!     num_keys: 10_000,
+//!     cold_ratio: 0.5,
+//!     ..Default::default()
+//! };
+//! let bench = Bench::setup("/tmp/bench", shape).await?;
+//! let table = bench.table();
+//! let report = bench
+//!     .measure("get", 10_000, |i| {
+//!         let key = bench.hot_key(i);
+//!         async move { table.get(&key, u64::MAX).await.map(|_| ()) }
+//!     })
+//!     .await?;
+//! let baseline = report.clone();
+//! report.check_regression(&baseline, 0.1).unwrap();
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The `photondb-bench` binary runs the workloads of `db_bench` instead, like `fillrandom` and
+//! `readrandom`, with multiple threads, and reports their throughput and latency percentiles. See
+//! [`USAGE`] for its options.
+
+use std::{
+    fmt,
+    future::Future,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use photondb::{Options, Result, Table};
+
+mod histogram;
+pub use histogram::Histogram;
+
+mod workload;
+pub use workload::{run_workload, Config, Workload, WorkloadReport, USAGE};
+
+/// The shape of the table to set up.
+#[derive(Clone, Debug)]
+pub struct Shape {
+    /// The number of keys.
+    pub num_keys: u64,
+    /// The size of values in bytes.
+    pub value_size: usize,
+    /// The maximum number of entries in a leaf, which determines the number of leaves.
+    ///
+    /// The tree has a root and a level of leaves, since the root doesn't split yet.
+    pub leaf_entries: usize,
+    /// The maximum number of delta pages on top of each leaf after the setup.
+    ///
+    /// Each key is updated `chain_length` times after it is loaded, and leaves are consolidated
+    /// only when their chains grow longer than this. Cold leaves are consolidated by the
+    /// checkpoint.
+    pub chain_length: u8,
+    /// The ratio of keys that are left on disk after the setup.
+    ///
+    /// Keys are ordered by their indexes, and the upper part of them is cold. The table is
+    /// checkpointed and reopened when this is positive, and then the hot keys are read to swap
+    /// their leaves in.
+    pub cold_ratio: f64,
+    /// The seed of the order in which keys are loaded.
+    pub seed: u64,
+}
+
+impl Default for Shape {
+    fn default() -> Self {
+        Self {
+            num_keys: 1 << 16,
+            value_size: 100,
+            leaf_entries: 64,
+            chain_length: 0,
+            cold_ratio: 0.0,
+            seed: 0,
+        }
+    }
+}
+
+impl Shape {
+    /// Returns the number of hot keys.
+    pub fn num_hot_keys(&self) -> u64 {
+        let cold_keys = (self.num_keys as f64 * self.cold_ratio.clamp(0.0, 1.0)) as u64;
+        self.num_keys - cold_keys
+    }
+
+    fn options(&self) -> Options {
+        Options {
+            data_node_size: usize::MAX,
+            data_node_entries: self.leaf_entries,
+            data_delta_length: self.chain_length.saturating_add(1).max(2),
+            ..Default::default()
+        }
+    }
+}
+
+/// A table set up for benchmarks.
+pub struct Bench {
+    table: Table,
+    shape: Shape,
+    lsn: u64,
+}
+
+impl Bench {
+    /// Sets up a table of `shape` in `path`, which must be empty.
+    pub async fn setup(path: impl AsRef<Path>, shape: Shape) -> Result<Self> {
+        let path = path.as_ref();
+        let opts = shape.options();
+        let mut table = Table::open(path, opts.clone()).await?;
+        let mut lsn = 0;
+        let mut order: Vec<u64> = (0..shape.num_keys).collect();
+        shuffle(&mut order, shape.seed);
+        for &i in &order {
+            lsn += 1;
+            table
+                .put(&key(i), lsn, &value(i, 0, shape.value_size))
+                .await?;
+        }
+        for round in 1..=shape.chain_length as u64 {
+            for &i in &order {
+                lsn += 1;
+                let value = value(i, round, shape.value_size);
+                table.put(&key(i), lsn, &value).await?;
+            }
+        }
+        if shape.num_hot_keys() < shape.num_keys {
+            table.checkpoint().await?;
+            drop(table);
+            table = Table::open(path, opts).await?;
+            for i in 0..shape.num_hot_keys() {
+                table.get(&key(i), lsn).await?;
+            }
+        }
+        Ok(Self { table, shape, lsn })
+    }
+
+    pub fn table(&self) -> &Table {
+        &self.table
+    }
+
+    pub fn shape(&self) -> &Shape {
+        &self.shape
+    }
+
+    /// Returns the largest LSN used by the setup.
+    pub fn lsn(&self) -> u64 {
+        self.lsn
+    }
+
+    /// Returns the key with the given index.
+    pub fn key(&self, index: u64) -> Vec<u8> {
+        key(index)
+    }
+
+    /// Returns the `n`th hot key, wrapping around the hot keys.
+    pub fn hot_key(&self, n: u64) -> Vec<u8> {
+        key(n % self.shape.num_hot_keys().max(1))
+    }
+
+    /// Returns the `n`th cold key, wrapping around the cold keys.
+    pub fn cold_key(&self, n: u64) -> Vec<u8> {
+        let num_hot_keys = self.shape.num_hot_keys();
+        let num_cold_keys = (self.shape.num_keys - num_hot_keys).max(1);
+        key(num_hot_keys + n % num_cold_keys)
+    }
+
+    /// Runs `f` with `0..num_ops` one by one and reports the time taken.
+    pub async fn measure<F, Fut>(&self, name: &str, num_ops: u64, mut f: F) -> Result<Report>
+    where
+        F: FnMut(u64) -> Fut,
+        Fut: Future<Output = Result<()>>,
+    {
+        let start = Instant::now();
+        for i in 0..num_ops {
+            f(i).await?;
+        }
+        Ok(Report {
+            name: name.to_owned(),
+            num_ops,
+            elapsed: start.elapsed(),
+        })
+    }
+}
+
+/// The result of a measurement.
+#[derive(Clone, Debug)]
+pub struct Report {
+    pub name: String,
+    pub num_ops: u64,
+    pub elapsed: Duration,
+}
+
+impl Report {
+    pub fn ops_per_sec(&self) -> f64 {
+        self.num_ops as f64 / self.elapsed.as_secs_f64()
+    }
+
+    /// Returns an error if the throughput is lower than `baseline` by more than `tolerance`, which
+    /// is a ratio of the baseline throughput.
+    pub fn check_regression(
+        &self,
+        baseline: &Report,
+        tolerance: f64,
+    ) -> std::result::Result<(), Regression> {
+        let current = self.ops_per_sec();
+        let expected = baseline.ops_per_sec();
+        if current < expected * (1.0 - tolerance) {
+            return Err(Regression {
+                name: self.name.clone(),
+                baseline_ops_per_sec: expected,
+                current_ops_per_sec: current,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} ops in {:?} ({:.0} ops/s)",
+            self.name,
+            self.num_ops,
+            self.elapsed,
+            self.ops_per_sec()
+        )
+    }
+}
+
+/// A throughput regression detected by [`Report::check_regression`].
+#[derive(Clone, Debug)]
+pub struct Regression {
+    pub name: String,
+    pub baseline_ops_per_sec: f64,
+    pub current_ops_per_sec: f64,
+}
+
+impl fmt::Display for Regression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} regressed from {:.0} ops/s to {:.0} ops/s",
+            self.name, self.baseline_ops_per_sec, self.current_ops_per_sec
+        )
+    }
+}
+
+impl std::error::Error for Regression {}
+
+/// Returns the key with the given index, which sorts in the order of indexes.
+fn key(index: u64) -> Vec<u8> {
+    index.to_be_bytes().to_vec()
+}
+
+/// Returns a value of the key with the given index in the given round of updates.
+fn value(index: u64, round: u64, size: usize) -> Vec<u8> {
+    let mut rng = SplitMix64(index ^ round.rotate_left(32));
+    let mut value = Vec::with_capacity(size + 8);
+    while value.len() < size {
+        value.extend_from_slice(&rng.next().to_le_bytes());
+    }
+    value.truncate(size);
+    value
+}
+
+/// Shuffles `data` deterministically with `seed`.
+fn shuffle<T>(data: &mut [T], seed: u64) {
+    let mut rng = SplitMix64(seed);
+    for i in (1..data.len()).rev() {
+        let j = (rng.next() % (i as u64 + 1)) as usize;
+        data.swap(i, j);
+    }
+}
+
+/// A small and fast pseudo random number generator.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn deterministic_order() {
+        let mut a: Vec<u64> = (0..64).collect();
+        let mut b = a.clone();
+        shuffle(&mut a, 7);
+        shuffle(&mut b, 7);
+        assert_eq!(a, b);
+        assert_ne!(a, (0..64).collect::<Vec<_>>());
+        assert_eq!(value(1, 2, 13), value(1, 2, 13));
+        assert_ne!(value(1, 2, 13), value(1, 3, 13));
+    }
+
+    #[tokio::test]
+    async fn setup_and_measure() {
+        let shape = Shape {
+            num_keys: 256,
+            value_size: 16,
+            leaf_entries: 16,
+            chain_length: 2,
+            cold_ratio: 0.5,
+            seed: 1,
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let bench = Bench::setup(dir.path(), shape).await.unwrap();
+        assert_eq!(bench.lsn(), 256 * 3);
+        let table = bench.table();
+        let stats = table.stats().alloc;
+        assert!(stats.swapin_bytes > 0);
+        let value = table.get(&bench.cold_key(0), bench.lsn()).await.unwrap();
+        assert_eq!(value, Some(super::value(128, 2, 16)));
+
+        let report = bench
+            .measure("get", 64, |i| {
+                let key = bench.hot_key(i);
+                async move { table.get(&key, u64::MAX).await.map(|_| ()) }
+            })
+            .await
+            .unwrap();
+        assert_eq!(report.num_ops, 64);
+        assert!(report.check_regression(&report, 0.0).is_ok());
+        let faster = Report {
+            elapsed: report.elapsed / 2,
+            ..report.clone()
+        };
+        assert!(report.check_regression(&faster, 0.1).is_err());
+    }
+}
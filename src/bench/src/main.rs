@@ -0,0 +1,38 @@
+use std::{fs, process, sync::atomic::AtomicU64};
+
+use photondb::{sync::Table, Options};
+use photondb_bench::{run_workload, Config, USAGE};
+
+fn main() {
+    let config = match Config::parse(std::env::args().skip(1)) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, USAGE);
+            process::exit(2);
+        }
+    };
+    if let Err(err) = run(&config) {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
+
+fn run(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    if !config.use_existing && config.path.exists() {
+        fs::remove_dir_all(&config.path)?;
+    }
+    let table = Table::open(&config.path, Options::default())?;
+    // Continues from the LSNs of the existing table.
+    let manifest = table.manifest();
+    let max_lsn = manifest.files.iter().map(|&(_, lsn)| lsn).max();
+    let lsn = AtomicU64::new(max_lsn.unwrap_or(0));
+    println!(
+        "keys: {} ({} bytes), values: {} bytes, threads: {}",
+        config.num_keys, config.key_size, config.value_size, config.threads
+    );
+    for &workload in &config.workloads {
+        let report = run_workload(&table, config, workload, &lsn)?;
+        println!("{}", report);
+    }
+    Ok(())
+}
@@ -0,0 +1,422 @@
+use std::{
+    fmt,
+    path::PathBuf,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use photondb::{sync::Table, Result};
+
+use crate::{value, Histogram, Report, SplitMix64};
+
+/// The workloads of `photondb-bench`, which are named after the ones of `db_bench`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Workload {
+    /// Writes keys in random order.
+    FillRandom,
+    /// Writes keys in sequential order.
+    FillSeq,
+    /// Reads keys in random order.
+    ReadRandom,
+    /// Reads keys in random order while another thread writes keys in random order, and reports
+    /// the reads only.
+    ReadWhileWriting,
+    /// Scans `Config::scan_length` entries from keys in random order.
+    Scan,
+}
+
+impl Workload {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::FillRandom => "fillrandom",
+            Self::FillSeq => "fillseq",
+            Self::ReadRandom => "readrandom",
+            Self::ReadWhileWriting => "readwhilewriting",
+            Self::Scan => "scan",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        [
+            Self::FillRandom,
+            Self::FillSeq,
+            Self::ReadRandom,
+            Self::ReadWhileWriting,
+            Self::Scan,
+        ]
+        .into_iter()
+        .find(|workload| workload.name() == name)
+    }
+
+    fn is_write(&self) -> bool {
+        matches!(self, Self::FillRandom | Self::FillSeq)
+    }
+}
+
+/// The configuration of `photondb-bench`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    /// The workloads to run one by one on the same table.
+    pub workloads: Vec<Workload>,
+    /// The path of the table, which is removed before the workloads unless `use_existing` is set.
+    pub path: PathBuf,
+    pub use_existing: bool,
+    /// The number of keys, which is also the number of operations of the fill workloads.
+    pub num_keys: u64,
+    /// The number of operations of the other workloads.
+    pub num_reads: u64,
+    /// The size of keys in bytes, which is at least 8.
+    pub key_size: usize,
+    pub value_size: usize,
+    pub scan_length: usize,
+    /// The number of threads that run each workload, which share its operations.
+    pub threads: usize,
+    /// Stops each workload after this long even if its operations are not done.
+    pub duration: Option<Duration>,
+    /// The seed of the random keys.
+    pub seed: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            workloads: vec![Workload::FillRandom, Workload::ReadRandom],
+            path: std::env::temp_dir().join("photondb-bench"),
+            use_existing: false,
+            num_keys: 1_000_000,
+            num_reads: 1_000_000,
+            key_size: 16,
+            value_size: 100,
+            scan_length: 100,
+            threads: 1,
+            duration: None,
+            seed: 0,
+        }
+    }
+}
+
+pub const USAGE: &str = "\
+Usage: photondb-bench [options]
+Options:
+    --benchmarks <names>   Comma separated workloads to run in order, from fillrandom,
+                           fillseq, readrandom, readwhilewriting and scan
+                           [default: fillrandom,readrandom]
+    --path <path>          The path of the table, which is removed first
+                           [default: $TMPDIR/photondb-bench]
+    --use-existing         Runs on the existing table in the path instead
+    --num <n>              The number of keys and of fill operations [default: 1000000]
+    --reads <n>            The number of read and scan operations [default: 1000000]
+    --key-size <n>         The size of keys, which is at least 8 [default: 16]
+    --value-size <n>       The size of values [default: 100]
+    --scan-length <n>      The number of entries per scan [default: 100]
+    --threads <n>          The number of threads per workload [default: 1]
+    --duration <secs>      Stops each workload after this many seconds
+    --seed <n>             The seed of the random keys [default: 0]";
+
+impl Config {
+    /// Parses the configuration from the arguments after the program name.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> std::result::Result<Self, String> {
+        let mut config = Self::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if arg == "--use-existing" {
+                config.use_existing = true;
+                continue;
+            }
+            let value = args
+                .next()
+                .ok_or_else(|| format!("missing value of {}", arg))?;
+            let number = || {
+                value
+                    .parse::<u64>()
+                    .map_err(|_| format!("invalid value of {}: {}", arg, value))
+            };
+            match arg.as_str() {
+                "--benchmarks" => {
+                    config.workloads = value
+                        .split(',')
+                        .map(|name| {
+                            Workload::parse(name)
+                                .ok_or_else(|| format!("unknown workload {}", name))
+                        })
+                        .collect::<std::result::Result<_, _>>()?;
+                }
+                "--path" => config.path = PathBuf::from(&value),
+                "--num" => config.num_keys = number()?,
+                "--reads" => config.num_reads = number()?,
+                "--key-size" => config.key_size = number()? as usize,
+                "--value-size" => config.value_size = number()? as usize,
+                "--scan-length" => config.scan_length = number()? as usize,
+                "--threads" => config.threads = number()? as usize,
+                "--duration" => config.duration = Some(Duration::from_secs(number()?)),
+                "--seed" => config.seed = number()?,
+                _ => return Err(format!("unknown argument {}", arg)),
+            }
+        }
+        if config.key_size < 8 {
+            return Err(format!("key size {} is less than 8", config.key_size));
+        }
+        if config.threads == 0 || config.num_keys == 0 {
+            return Err("threads and keys must be positive".to_owned());
+        }
+        Ok(config)
+    }
+
+    /// Returns the key with the given index, which is padded with zeros to `key_size`.
+    fn key(&self, index: u64) -> Vec<u8> {
+        let mut key = vec![0; self.key_size];
+        key[..8].copy_from_slice(&index.to_be_bytes());
+        key
+    }
+}
+
+/// The result of a workload.
+#[derive(Clone, Debug)]
+pub struct WorkloadReport {
+    pub report: Report,
+    /// The bytes of keys and values written or read.
+    pub bytes: u64,
+    /// The number of reads that found their keys, or entries that scans returned.
+    pub found: u64,
+    pub latency: Histogram,
+}
+
+impl fmt::Display for WorkloadReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mb_per_sec = self.bytes as f64 / (1 << 20) as f64 / self.report.elapsed.as_secs_f64();
+        writeln!(f, "{} ({:.1} MB/s)", self.report, mb_per_sec)?;
+        if self.found > 0 {
+            writeln!(f, "    found: {}", self.found)?;
+        }
+        let micros = |latency: Duration| latency.as_secs_f64() * 1e6;
+        write!(
+            f,
+            "    latency (us): mean {:.1}, p50 {:.1}, p95 {:.1}, p99 {:.1}, p99.9 {:.1}, max {:.1}",
+            micros(self.latency.mean()),
+            micros(self.latency.percentile(50.0)),
+            micros(self.latency.percentile(95.0)),
+            micros(self.latency.percentile(99.0)),
+            micros(self.latency.percentile(99.9)),
+            micros(self.latency.max()),
+        )
+    }
+}
+
+/// Runs a workload on `table` with the threads of `config`.
+///
+/// `lsn` is the last LSN written to the table, which writes advance.
+pub fn run_workload(
+    table: &Table,
+    config: &Config,
+    workload: Workload,
+    lsn: &AtomicU64,
+) -> Result<WorkloadReport> {
+    let num_ops = if workload.is_write() {
+        config.num_keys
+    } else {
+        config.num_reads
+    };
+    let ctx = Context {
+        table,
+        config,
+        lsn,
+        next_op: AtomicU64::new(0),
+        num_ops,
+        deadline: config.duration.map(|duration| Instant::now() + duration),
+        stop: AtomicBool::new(false),
+    };
+    let start = Instant::now();
+    let results: Vec<Result<Stats>> = thread::scope(|s| {
+        let ctx = &ctx;
+        let writer = (workload == Workload::ReadWhileWriting)
+            .then(|| s.spawn(move || ctx.write_until_stopped()));
+        let workers: Vec<_> = (0..config.threads)
+            .map(|i| s.spawn(move || ctx.run(workload, i as u64)))
+            .collect();
+        let mut results: Vec<_> = workers
+            .into_iter()
+            .map(|worker| worker.join().unwrap())
+            .collect();
+        ctx.stop.store(true, Ordering::Relaxed);
+        if let Some(writer) = writer {
+            results.push(writer.join().unwrap().map(|_| Stats::default()));
+        }
+        results
+    });
+    let elapsed = start.elapsed();
+    let mut total = Stats::default();
+    for stats in results {
+        total.merge(stats?);
+    }
+    Ok(WorkloadReport {
+        report: Report {
+            name: workload.name().to_owned(),
+            num_ops: total.latency.count(),
+            elapsed,
+        },
+        bytes: total.bytes,
+        found: total.found,
+        latency: total.latency,
+    })
+}
+
+struct Context<'a> {
+    table: &'a Table,
+    config: &'a Config,
+    lsn: &'a AtomicU64,
+    /// The index of the next operation, which threads take one by one.
+    next_op: AtomicU64,
+    num_ops: u64,
+    deadline: Option<Instant>,
+    /// Stops the writer of `ReadWhileWriting` after the readers finish.
+    stop: AtomicBool,
+}
+
+#[derive(Default)]
+struct Stats {
+    bytes: u64,
+    found: u64,
+    latency: Histogram,
+}
+
+impl Stats {
+    fn merge(&mut self, other: Stats) {
+        self.bytes += other.bytes;
+        self.found += other.found;
+        self.latency.merge(&other.latency);
+    }
+}
+
+impl Context<'_> {
+    /// Takes the next operation, or returns `None` if the workload is done.
+    fn next_op(&self) -> Option<u64> {
+        if matches!(self.deadline, Some(deadline) if Instant::now() >= deadline) {
+            return None;
+        }
+        let op = self.next_op.fetch_add(1, Ordering::Relaxed);
+        if self.deadline.is_none() && op >= self.num_ops {
+            return None;
+        }
+        Some(op)
+    }
+
+    fn run(&self, workload: Workload, thread: u64) -> Result<Stats> {
+        let config = self.config;
+        let mut rng = SplitMix64(config.seed ^ thread.rotate_left(32) ^ workload as u64);
+        let value = value(thread, workload as u64, config.value_size);
+        let mut stats = Stats::default();
+        while let Some(op) = self.next_op() {
+            let index = match workload {
+                Workload::FillSeq => op % config.num_keys,
+                _ => rng.next() % config.num_keys,
+            };
+            let key = config.key(index);
+            let start = Instant::now();
+            match workload {
+                Workload::FillRandom | Workload::FillSeq => {
+                    let lsn = self.lsn.fetch_add(1, Ordering::Relaxed) + 1;
+                    self.table.put(&key, lsn, &value)?;
+                    stats.bytes += (key.len() + value.len()) as u64;
+                }
+                Workload::ReadRandom | Workload::ReadWhileWriting => {
+                    if let Some(value) = self.table.get(&key, u64::MAX)? {
+                        stats.bytes += (key.len() + value.len()) as u64;
+                        stats.found += 1;
+                    }
+                }
+                Workload::Scan => {
+                    let mut remaining = config.scan_length.max(1);
+                    self.table.scan(&key, &[], u64::MAX, |key, value| {
+                        stats.bytes += (key.len() + value.len()) as u64;
+                        stats.found += 1;
+                        remaining -= 1;
+                        remaining > 0
+                    })?;
+                }
+            }
+            stats.latency.record(start.elapsed());
+        }
+        Ok(stats)
+    }
+
+    fn write_until_stopped(&self) -> Result<()> {
+        let config = self.config;
+        let mut rng = SplitMix64(config.seed ^ u64::MAX);
+        let value = value(u64::MAX, 0, config.value_size);
+        while !self.stop.load(Ordering::Relaxed) {
+            let key = config.key(rng.next() % config.num_keys);
+            let lsn = self.lsn.fetch_add(1, Ordering::Relaxed) + 1;
+            self.table.put(&key, lsn, &value)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use photondb::Options;
+
+    use super::*;
+
+    fn parse(args: &str) -> std::result::Result<Config, String> {
+        Config::parse(args.split_whitespace().map(String::from))
+    }
+
+    #[test]
+    fn parse_args() {
+        let config =
+            parse("--benchmarks fillseq,scan --num 10 --threads 4 --use-existing").unwrap();
+        assert_eq!(config.workloads, vec![Workload::FillSeq, Workload::Scan]);
+        assert_eq!(config.num_keys, 10);
+        assert_eq!(config.threads, 4);
+        assert!(config.use_existing);
+        assert_eq!(parse("").unwrap(), Config::default());
+        assert!(parse("--benchmarks fillall").is_err());
+        assert!(parse("--num").is_err());
+        assert!(parse("--num ten").is_err());
+        assert!(parse("--key-size 4").is_err());
+        assert!(parse("--threads 0").is_err());
+    }
+
+    #[test]
+    fn workloads() {
+        let dir = tempfile::tempdir().unwrap();
+        let table = Table::open(dir.path(), Options::default()).unwrap();
+        let config = Config {
+            num_keys: 256,
+            num_reads: 128,
+            scan_length: 10,
+            threads: 2,
+            ..Default::default()
+        };
+        let lsn = AtomicU64::new(0);
+        let run = |workload| run_workload(&table, &config, workload, &lsn).unwrap();
+
+        let report = run(Workload::FillSeq);
+        assert_eq!(report.report.num_ops, 256);
+        assert_eq!(report.bytes, 256 * 116);
+        assert_eq!(lsn.load(Ordering::Relaxed), 256);
+        let report = run(Workload::ReadRandom);
+        assert_eq!(report.report.num_ops, 128);
+        assert_eq!(report.found, 128);
+        let report = run(Workload::Scan);
+        assert!(report.found > 128 && report.found <= 128 * 10);
+        let report = run(Workload::ReadWhileWriting);
+        assert_eq!(report.found, 128);
+        assert!(lsn.load(Ordering::Relaxed) > 256);
+        run(Workload::FillRandom);
+        assert!(report
+            .to_string()
+            .starts_with("readwhilewriting: 128 ops in "));
+
+        // Workloads with a duration run until it elapses.
+        let config = Config {
+            duration: Some(Duration::from_millis(20)),
+            ..config
+        };
+        let report = run_workload(&table, &config, Workload::ReadRandom, &lsn).unwrap();
+        assert!(report.report.elapsed >= Duration::from_millis(20));
+        assert!(report.report.num_ops > 0);
+    }
+}
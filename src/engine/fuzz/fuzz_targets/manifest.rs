@@ -0,0 +1,6 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use photondb_engine::tree::fuzz;
+
+fuzz_target!(|data: &[u8]| fuzz::manifest(data));
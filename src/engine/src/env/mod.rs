@@ -0,0 +1,214 @@
+use std::{
+    fmt,
+    future::Future,
+    io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    time::Duration,
+};
+
+use tokio::sync::oneshot;
+
+mod sim;
+pub(crate) use sim::Rng;
+pub use sim::SimEnv;
+
+mod thread_pool;
+pub use thread_pool::ThreadPoolEnv;
+
+#[cfg(feature = "tokio-env")]
+mod tokio_env;
+#[cfg(feature = "tokio-env")]
+pub use tokio_env::TokioEnv;
+
+#[cfg(feature = "object-store")]
+mod object_store;
+#[cfg(feature = "object-store")]
+pub use object_store::{ObjectStore, ObjectStoreEnv};
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// An interface to the async runtime and the file system.
+///
+/// The engine spawns tasks and accesses files through this interface only, so that it can run on
+/// different runtimes.
+pub trait Env: Send + Sync {
+    /// Spawns a task to run in the background.
+    fn spawn(&self, task: BoxFuture<'static, ()>);
+
+    /// Returns a future that completes after `duration`.
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+
+    /// Opens a file for sequential writes.
+    ///
+    /// The file is created if it does not exist, or truncated if it exists.
+    fn open_sequential_writer<'a>(
+        &'a self,
+        path: &'a Path,
+    ) -> BoxFuture<'a, io::Result<Box<dyn SequentialWriter>>>;
+
+    /// Opens a file for positional reads.
+    fn open_positional_reader<'a>(
+        &'a self,
+        path: &'a Path,
+    ) -> BoxFuture<'a, io::Result<Box<dyn PositionalReader>>>;
+
+    /// Returns the size of a file.
+    fn file_size<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<u64>>;
+
+    /// Creates a directory and all its missing parents.
+    fn create_dir_all<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<()>>;
+
+    /// Returns the paths of the entries in a directory.
+    fn read_dir<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<Vec<PathBuf>>>;
+
+    /// Renames a file, replacing the destination if it exists.
+    fn rename<'a>(&'a self, from: &'a Path, to: &'a Path) -> BoxFuture<'a, io::Result<()>>;
+
+    /// Removes a file.
+    fn remove_file<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<()>>;
+
+    /// Syncs a directory so that the changes of its entries are durable.
+    fn sync_dir<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<()>>;
+
+    /// Locks a file exclusively until the returned lock is dropped, creating the file if it does
+    /// not exist.
+    ///
+    /// Returns an error of `io::ErrorKind::WouldBlock` if the file is locked by others. The
+    /// default implementation returns a lock that excludes nobody, for file systems without
+    /// locks.
+    fn lock_file<'a>(&'a self, _path: &'a Path) -> BoxFuture<'a, io::Result<Box<dyn FileLock>>> {
+        Box::pin(async { Ok(Box::new(NoLock) as _) })
+    }
+}
+
+/// A lock on a file, which is released when it is dropped.
+pub trait FileLock: Send + Sync {}
+
+struct NoLock;
+
+impl FileLock for NoLock {}
+
+impl fmt::Debug for dyn Env {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Env")
+    }
+}
+
+pub trait SequentialWriter: Send {
+    /// Writes all bytes in `buf` to the end of the file.
+    fn write<'a>(&'a mut self, buf: &'a [u8]) -> BoxFuture<'a, io::Result<()>>;
+
+    /// Synchronizes the file data to the disk.
+    fn sync_data(&mut self) -> BoxFuture<'_, io::Result<()>>;
+}
+
+pub trait PositionalReader: Send + Sync {
+    /// Reads exactly `buf.len()` bytes at `offset` of the file.
+    fn read_exact_at<'a>(&'a self, buf: &'a mut [u8], offset: u64)
+        -> BoxFuture<'a, io::Result<()>>;
+}
+
+impl<T: SequentialWriter + ?Sized> SequentialWriter for Box<T> {
+    fn write<'a>(&'a mut self, buf: &'a [u8]) -> BoxFuture<'a, io::Result<()>> {
+        (**self).write(buf)
+    }
+
+    fn sync_data(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        (**self).sync_data()
+    }
+}
+
+impl<T: PositionalReader + ?Sized> PositionalReader for Box<T> {
+    fn read_exact_at<'a>(
+        &'a self,
+        buf: &'a mut [u8],
+        offset: u64,
+    ) -> BoxFuture<'a, io::Result<()>> {
+        (**self).read_exact_at(buf, offset)
+    }
+}
+
+/// Checks that `env` behaves as the engine expects, and panics if it doesn't.
+///
+/// This is a conformance test for custom `Env` implementations. It creates files in `dir`, which
+/// must be empty, and runs in an async context that `env` supports.
+pub async fn check_env(env: &dyn Env, dir: &Path) {
+    let path = dir.join("dir");
+    env.create_dir_all(&path).await.unwrap();
+    let path = path.join("file");
+    let mut writer = env.open_sequential_writer(&path).await.unwrap();
+    writer.write(b"hello ").await.unwrap();
+    writer.write(b"world").await.unwrap();
+    writer.sync_data().await.unwrap();
+    drop(writer);
+    assert_eq!(env.file_size(&path).await.unwrap(), 11);
+    let entries = env.read_dir(path.parent().unwrap()).await.unwrap();
+    assert_eq!(entries, vec![path.clone()]);
+
+    let reader = env.open_positional_reader(&path).await.unwrap();
+    let mut buf = [0u8; 5];
+    reader.read_exact_at(&mut buf, 6).await.unwrap();
+    assert_eq!(&buf, b"world");
+    assert!(reader.read_exact_at(&mut buf, 8).await.is_err());
+    drop(reader);
+
+    let new_path = path.with_file_name("new_file");
+    env.rename(&path, &new_path).await.unwrap();
+    env.sync_dir(new_path.parent().unwrap()).await.unwrap();
+    assert_eq!(env.file_size(&new_path).await.unwrap(), 11);
+
+    // Opening an existing file for writes truncates it, and renaming replaces the destination.
+    let mut writer = env.open_sequential_writer(&path).await.unwrap();
+    writer.write(b"!").await.unwrap();
+    writer.sync_data().await.unwrap();
+    drop(writer);
+    let mut writer = env.open_sequential_writer(&path).await.unwrap();
+    writer.write(b"hi").await.unwrap();
+    writer.sync_data().await.unwrap();
+    drop(writer);
+    assert_eq!(env.file_size(&path).await.unwrap(), 2);
+    env.rename(&path, &new_path).await.unwrap();
+    assert_eq!(env.file_size(&new_path).await.unwrap(), 2);
+    assert!(env.file_size(&path).await.is_err());
+
+    env.remove_file(&new_path).await.unwrap();
+    assert!(env.file_size(&new_path).await.is_err());
+
+    env.sleep(Duration::from_millis(1)).await;
+
+    let (tx, rx) = oneshot::channel();
+    env.spawn(Box::pin(async move {
+        tx.send(1).unwrap();
+    }));
+    assert_eq!(rx.await, Ok(1));
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::mpsc;
+
+    use super::*;
+
+    async fn test_env(env: &dyn Env) {
+        let dir = tempfile::tempdir().unwrap();
+        check_env(env, dir.path()).await;
+    }
+
+    #[tokio::test]
+    async fn tokio_env() {
+        test_env(&TokioEnv::current()).await;
+    }
+
+    #[test]
+    fn thread_pool_env() {
+        let env = ThreadPoolEnv::new(2);
+        let (tx, rx) = mpsc::channel();
+        let task_env = env.clone();
+        env.spawn(Box::pin(async move {
+            test_env(&task_env).await;
+            tx.send(()).unwrap();
+        }));
+        rx.recv_timeout(Duration::from_secs(10)).unwrap();
+    }
+}
@@ -0,0 +1,555 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, Weak},
+    time::Duration,
+};
+
+use super::{BoxFuture, Env, PositionalReader, SequentialWriter};
+
+/// An interface to an object storage service, such as S3 or GCS.
+///
+/// Objects are named by keys, which are the paths of the files that they hold. Objects are always
+/// written as a whole, and a key may be overwritten.
+pub trait ObjectStore: Send + Sync {
+    /// Writes an object, replacing the object with the same key if it exists.
+    fn put<'a>(&'a self, key: &'a str, data: Vec<u8>) -> BoxFuture<'a, io::Result<()>>;
+
+    /// Reads a range of an object.
+    ///
+    /// Returns an `UnexpectedEof` error if the range is beyond the end of the object.
+    fn get_range<'a>(
+        &'a self,
+        key: &'a str,
+        range: Range<u64>,
+    ) -> BoxFuture<'a, io::Result<Vec<u8>>>;
+
+    /// Returns the size of an object, or a `NotFound` error if it doesn't exist.
+    fn size<'a>(&'a self, key: &'a str) -> BoxFuture<'a, io::Result<u64>>;
+
+    /// Returns the keys of the objects that start with `prefix`.
+    fn list<'a>(&'a self, prefix: &'a str) -> BoxFuture<'a, io::Result<Vec<String>>>;
+
+    /// Copies an object, replacing the destination if it exists.
+    fn copy<'a>(&'a self, from: &'a str, to: &'a str) -> BoxFuture<'a, io::Result<()>>;
+
+    /// Deletes an object, or returns a `NotFound` error if it doesn't exist.
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, io::Result<()>>;
+}
+
+/// An `Env` that stores files as objects in an `ObjectStore`.
+///
+/// Tasks run on the underlying env. Writes are buffered in memory and uploaded when the file is
+/// synced, and renames are done by copies, which suits the engine since it writes each page file
+/// once and only renames small files.
+///
+/// Directories are implicit in the keys of objects, so creating or syncing them does nothing. Open
+/// writers follow their files across renames, as they do on local file systems.
+#[derive(Clone)]
+pub struct ObjectStoreEnv {
+    env: Arc<dyn Env>,
+    store: Arc<dyn ObjectStore>,
+    cache: Option<Arc<ReadCache>>,
+    // The keys of the open writers.
+    writers: Arc<Mutex<Vec<Weak<Mutex<String>>>>>,
+}
+
+impl ObjectStoreEnv {
+    /// Creates an env that runs tasks on `env` and stores files in `store`.
+    pub fn new(env: Arc<dyn Env>, store: Arc<dyn ObjectStore>) -> Self {
+        Self {
+            env,
+            store,
+            cache: None,
+            writers: Arc::default(),
+        }
+    }
+
+    /// Caches the recently read ranges of objects in `dir` of the underlying env, up to
+    /// `capacity` bytes, so that hot pages are read from the local disk instead of the store.
+    ///
+    /// `dir` is created if it doesn't exist. The files in it are only valid while the env is
+    /// alive, so it should be empty when the env is created.
+    pub fn with_read_cache(self, dir: impl Into<PathBuf>, capacity: u64) -> Self {
+        let cache = ReadCache {
+            env: self.env.clone(),
+            dir: dir.into(),
+            capacity,
+            state: Mutex::default(),
+        };
+        Self {
+            cache: Some(Arc::new(cache)),
+            ..self
+        }
+    }
+
+    async fn invalidate(&self, key: &str) {
+        if let Some(cache) = &self.cache {
+            cache.invalidate(key).await;
+        }
+    }
+}
+
+fn object_key(path: &Path) -> io::Result<String> {
+    path.to_str().map(|key| key.to_owned()).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("path {:?} is not valid UTF-8", path),
+        )
+    })
+}
+
+impl Env for ObjectStoreEnv {
+    fn spawn(&self, task: BoxFuture<'static, ()>) {
+        self.env.spawn(task)
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        self.env.sleep(duration)
+    }
+
+    fn open_sequential_writer<'a>(
+        &'a self,
+        path: &'a Path,
+    ) -> BoxFuture<'a, io::Result<Box<dyn SequentialWriter>>> {
+        Box::pin(async move {
+            let key = object_key(path)?;
+            // Creates or truncates the object.
+            self.invalidate(&key).await;
+            self.store.put(&key, Vec::new()).await?;
+            let key = Arc::new(Mutex::new(key));
+            let mut writers = self.writers.lock().unwrap();
+            writers.retain(|key| key.strong_count() > 0);
+            writers.push(Arc::downgrade(&key));
+            let writer = ObjectWriter {
+                env: self.clone(),
+                key,
+                buf: Vec::new(),
+            };
+            Ok(Box::new(writer) as Box<dyn SequentialWriter>)
+        })
+    }
+
+    fn open_positional_reader<'a>(
+        &'a self,
+        path: &'a Path,
+    ) -> BoxFuture<'a, io::Result<Box<dyn PositionalReader>>> {
+        Box::pin(async move {
+            let key = object_key(path)?;
+            let size = self.store.size(&key).await?;
+            let reader = ObjectReader {
+                env: self.clone(),
+                key,
+                size,
+            };
+            Ok(Box::new(reader) as Box<dyn PositionalReader>)
+        })
+    }
+
+    fn file_size<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<u64>> {
+        Box::pin(async move { self.store.size(&object_key(path)?).await })
+    }
+
+    fn create_dir_all<'a>(&'a self, _: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn read_dir<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<Vec<PathBuf>>> {
+        Box::pin(async move {
+            let prefix = format!("{}/", object_key(path)?.trim_end_matches('/'));
+            let mut paths: Vec<_> = self
+                .store
+                .list(&prefix)
+                .await?
+                .into_iter()
+                // Skips the objects in subdirectories.
+                .filter(|key| !key[prefix.len()..].contains('/'))
+                .map(PathBuf::from)
+                .collect();
+            paths.sort();
+            Ok(paths)
+        })
+    }
+
+    fn rename<'a>(&'a self, from: &'a Path, to: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let from = object_key(from)?;
+            let to = object_key(to)?;
+            self.invalidate(&to).await;
+            self.store.copy(&from, &to).await?;
+            self.invalidate(&from).await;
+            self.store.delete(&from).await?;
+            for key in self.writers.lock().unwrap().iter() {
+                if let Some(key) = key.upgrade() {
+                    let mut key = key.lock().unwrap();
+                    if *key == from {
+                        *key = to.clone();
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let key = object_key(path)?;
+            self.invalidate(&key).await;
+            self.store.delete(&key).await
+        })
+    }
+
+    fn sync_dir<'a>(&'a self, _: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// A writer that uploads the whole object every time it is synced.
+struct ObjectWriter {
+    env: ObjectStoreEnv,
+    key: Arc<Mutex<String>>,
+    buf: Vec<u8>,
+}
+
+impl SequentialWriter for ObjectWriter {
+    fn write<'a>(&'a mut self, buf: &'a [u8]) -> BoxFuture<'a, io::Result<()>> {
+        self.buf.extend_from_slice(buf);
+        Box::pin(async { Ok(()) })
+    }
+
+    fn sync_data(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async move {
+            let key = self.key.lock().unwrap().clone();
+            self.env.invalidate(&key).await;
+            self.env.store.put(&key, self.buf.clone()).await
+        })
+    }
+}
+
+struct ObjectReader {
+    env: ObjectStoreEnv,
+    key: String,
+    size: u64,
+}
+
+impl PositionalReader for ObjectReader {
+    fn read_exact_at<'a>(
+        &'a self,
+        buf: &'a mut [u8],
+        offset: u64,
+    ) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let end = offset + buf.len() as u64;
+            if end > self.size {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            let cache = match &self.env.cache {
+                Some(cache) => cache,
+                None => {
+                    let data = self.env.store.get_range(&self.key, offset..end).await?;
+                    buf.copy_from_slice(&data);
+                    return Ok(());
+                }
+            };
+            let range = (self.key.clone(), offset, buf.len());
+            if cache.read(&range, buf).await {
+                return Ok(());
+            }
+            let generation = cache.generation(&self.key);
+            let data = self.env.store.get_range(&self.key, offset..end).await?;
+            buf.copy_from_slice(&data);
+            // The cache is best effort, so failures to fill it are ignored.
+            let _ = cache.insert(range, &data, generation).await;
+            Ok(())
+        })
+    }
+}
+
+/// A range of an object, which is the key, the offset, and the length.
+type ObjectRange = (String, u64, usize);
+
+/// A cache of object ranges on the local disk, which evicts the least recently read ranges.
+///
+/// Each range is cached in its own file, which is named by a sequence number.
+struct ReadCache {
+    env: Arc<dyn Env>,
+    dir: PathBuf,
+    capacity: u64,
+    state: Mutex<CacheState>,
+}
+
+#[derive(Default)]
+struct CacheState {
+    dir_created: bool,
+    next_file: u64,
+    next_tick: u64,
+    size: u64,
+    // The file number and the last read of each cached range.
+    ranges: HashMap<ObjectRange, (u64, u64)>,
+    // The cached ranges by their last reads.
+    lru: BTreeMap<u64, ObjectRange>,
+    // Bumped when an object is overwritten, so that stale reads are not cached.
+    generations: HashMap<String, u64>,
+}
+
+impl CacheState {
+    fn tick(&mut self) -> u64 {
+        self.next_tick += 1;
+        self.next_tick
+    }
+
+    fn remove(&mut self, range: &ObjectRange) -> Option<u64> {
+        let (file, tick) = self.ranges.remove(range)?;
+        self.lru.remove(&tick);
+        self.size -= range.2 as u64;
+        Some(file)
+    }
+}
+
+impl ReadCache {
+    fn file_path(&self, file: u64) -> PathBuf {
+        self.dir.join(file.to_string())
+    }
+
+    fn generation(&self, key: &str) -> u64 {
+        let state = self.state.lock().unwrap();
+        state.generations.get(key).copied().unwrap_or(0)
+    }
+
+    /// Reads a cached range into `buf`, and returns false if it is not cached.
+    async fn read(&self, range: &ObjectRange, buf: &mut [u8]) -> bool {
+        let file = {
+            let mut state = self.state.lock().unwrap();
+            let tick = state.tick();
+            let (file, last_tick) = match state.ranges.get_mut(range) {
+                Some(entry) => (entry.0, std::mem::replace(&mut entry.1, tick)),
+                None => return false,
+            };
+            let range = state.lru.remove(&last_tick).unwrap();
+            state.lru.insert(tick, range);
+            file
+        };
+        // The file may be evicted in between, in which case the range is read from the store.
+        match self.env.open_positional_reader(&self.file_path(file)).await {
+            Ok(reader) => reader.read_exact_at(buf, 0).await.is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    /// Caches a range that is read when the object is at `generation`.
+    async fn insert(&self, range: ObjectRange, data: &[u8], generation: u64) -> io::Result<()> {
+        if data.len() as u64 > self.capacity {
+            return Ok(());
+        }
+        let (file, create_dir) = {
+            let mut state = self.state.lock().unwrap();
+            state.next_file += 1;
+            (state.next_file, !state.dir_created)
+        };
+        if create_dir {
+            self.env.create_dir_all(&self.dir).await?;
+            self.state.lock().unwrap().dir_created = true;
+        }
+        let path = self.file_path(file);
+        let mut writer = self.env.open_sequential_writer(&path).await?;
+        writer.write(data).await?;
+        drop(writer);
+
+        let mut evicted = Vec::new();
+        {
+            let mut state = self.state.lock().unwrap();
+            let current = state.generations.get(&range.0).copied().unwrap_or(0);
+            if current != generation || state.ranges.contains_key(&range) {
+                evicted.push(file);
+            } else {
+                let tick = state.tick();
+                state.size += data.len() as u64;
+                state.ranges.insert(range.clone(), (file, tick));
+                state.lru.insert(tick, range);
+                while state.size > self.capacity {
+                    let (_, victim) = state.lru.iter().next().unwrap();
+                    let victim = victim.clone();
+                    evicted.extend(state.remove(&victim));
+                }
+            }
+        }
+        for file in evicted {
+            self.env.remove_file(&self.file_path(file)).await?;
+        }
+        Ok(())
+    }
+
+    /// Drops the cached ranges of an object that is about to change.
+    async fn invalidate(&self, key: &str) {
+        let evicted: Vec<_> = {
+            let mut state = self.state.lock().unwrap();
+            *state.generations.entry(key.to_owned()).or_default() += 1;
+            let ranges: Vec<_> = state
+                .ranges
+                .keys()
+                .filter(|range| range.0 == key)
+                .cloned()
+                .collect();
+            ranges
+                .iter()
+                .filter_map(|range| state.remove(range))
+                .collect()
+        };
+        for file in evicted {
+            let _ = self.env.remove_file(&self.file_path(file)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::{super::TokioEnv, *};
+    use crate::tree::{Options, Table};
+
+    /// An in-memory object store that counts the reads.
+    #[derive(Default)]
+    struct MemoryStore {
+        objects: Mutex<BTreeMap<String, Vec<u8>>>,
+        num_reads: AtomicUsize,
+    }
+
+    fn not_found(key: &str) -> io::Error {
+        io::Error::new(io::ErrorKind::NotFound, key.to_owned())
+    }
+
+    impl ObjectStore for MemoryStore {
+        fn put<'a>(&'a self, key: &'a str, data: Vec<u8>) -> BoxFuture<'a, io::Result<()>> {
+            self.objects.lock().unwrap().insert(key.to_owned(), data);
+            Box::pin(async { Ok(()) })
+        }
+
+        fn get_range<'a>(
+            &'a self,
+            key: &'a str,
+            range: Range<u64>,
+        ) -> BoxFuture<'a, io::Result<Vec<u8>>> {
+            self.num_reads.fetch_add(1, Ordering::Relaxed);
+            let objects = self.objects.lock().unwrap();
+            let result = match objects.get(key) {
+                Some(data) => data
+                    .get(range.start as usize..range.end as usize)
+                    .map(|data| data.to_vec())
+                    .ok_or_else(|| io::ErrorKind::UnexpectedEof.into()),
+                None => Err(not_found(key)),
+            };
+            Box::pin(async { result })
+        }
+
+        fn size<'a>(&'a self, key: &'a str) -> BoxFuture<'a, io::Result<u64>> {
+            let objects = self.objects.lock().unwrap();
+            let result = objects
+                .get(key)
+                .map(|data| data.len() as u64)
+                .ok_or_else(|| not_found(key));
+            Box::pin(async { result })
+        }
+
+        fn list<'a>(&'a self, prefix: &'a str) -> BoxFuture<'a, io::Result<Vec<String>>> {
+            let objects = self.objects.lock().unwrap();
+            let keys = objects
+                .keys()
+                .filter(|key| key.starts_with(prefix))
+                .cloned()
+                .collect();
+            Box::pin(async { Ok(keys) })
+        }
+
+        fn copy<'a>(&'a self, from: &'a str, to: &'a str) -> BoxFuture<'a, io::Result<()>> {
+            let mut objects = self.objects.lock().unwrap();
+            let result = match objects.get(from).cloned() {
+                Some(data) => {
+                    objects.insert(to.to_owned(), data);
+                    Ok(())
+                }
+                None => Err(not_found(from)),
+            };
+            Box::pin(async { result })
+        }
+
+        fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, io::Result<()>> {
+            let result = match self.objects.lock().unwrap().remove(key) {
+                Some(_) => Ok(()),
+                None => Err(not_found(key)),
+            };
+            Box::pin(async { result })
+        }
+    }
+
+    #[tokio::test]
+    async fn object_store_env() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = Arc::new(TokioEnv::current());
+        let store = Arc::new(MemoryStore::default());
+        let env = ObjectStoreEnv::new(base.clone(), store.clone());
+        super::super::check_env(&env, Path::new("/table")).await;
+        store.num_reads.store(0, Ordering::Relaxed);
+
+        // Reads are cached until the object changes.
+        let cache_dir = dir.path().join("cache");
+        let env = env.with_read_cache(&cache_dir, 8);
+        let path = Path::new("/table/file");
+        let mut writer = env.open_sequential_writer(path).await.unwrap();
+        writer.write(b"hello world").await.unwrap();
+        writer.sync_data().await.unwrap();
+        let reader = env.open_positional_reader(path).await.unwrap();
+        let mut buf = [0u8; 5];
+        for _ in 0..2 {
+            reader.read_exact_at(&mut buf, 6).await.unwrap();
+            assert_eq!(&buf, b"world");
+        }
+        assert_eq!(store.num_reads.load(Ordering::Relaxed), 1);
+        assert_eq!(base.read_dir(&cache_dir).await.unwrap().len(), 1);
+
+        // Ranges beyond the capacity evict the least recently read ones.
+        reader.read_exact_at(&mut buf, 0).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        assert_eq!(store.num_reads.load(Ordering::Relaxed), 2);
+        assert_eq!(base.read_dir(&cache_dir).await.unwrap().len(), 1);
+
+        let mut writer = env.open_sequential_writer(path).await.unwrap();
+        writer.write(b"HELLO").await.unwrap();
+        writer.sync_data().await.unwrap();
+        assert!(base.read_dir(&cache_dir).await.unwrap().is_empty());
+        let reader = env.open_positional_reader(path).await.unwrap();
+        reader.read_exact_at(&mut buf, 0).await.unwrap();
+        assert_eq!(&buf, b"HELLO");
+        assert_eq!(store.num_reads.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn table() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(MemoryStore::default());
+        let env = ObjectStoreEnv::new(Arc::new(TokioEnv::current()), store.clone())
+            .with_read_cache(dir.path(), 1 << 20);
+        let env = Arc::new(env);
+        let opts = Options::default();
+        let table = Table::open_with_env(env.clone(), "/table", opts.clone())
+            .await
+            .unwrap();
+        for i in 0..256u64 {
+            let buf = i.to_be_bytes();
+            table.put(&buf, i, &buf).await.unwrap();
+        }
+        table.checkpoint().await.unwrap();
+        drop(table);
+
+        let table = Table::open_with_env(env, "/table", opts).await.unwrap();
+        for _ in 0..2 {
+            for i in 0..256u64 {
+                let buf = i.to_be_bytes();
+                assert_eq!(table.get(&buf, i).await.unwrap(), Some(buf.to_vec()));
+            }
+        }
+        assert!(store.num_reads.load(Ordering::Relaxed) > 0);
+    }
+}
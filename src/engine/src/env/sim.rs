@@ -0,0 +1,681 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    future::Future,
+    io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex, Weak},
+    task::{Context, Poll, Wake, Waker},
+    time::Duration,
+};
+
+use super::{BoxFuture, Env, PositionalReader, SequentialWriter};
+
+/// The id of the task of `SimEnv::block_on`.
+const MAIN_TASK: u64 = 0;
+
+/// An `Env` for deterministic simulation tests.
+///
+/// Tasks run one at a time on the thread that calls `block_on`, in an order chosen by the seed,
+/// and file operations yield to other tasks at random. Time is virtual: when all tasks are
+/// blocked, the clock jumps to the earliest `sleep`, so timers complete without waiting.
+///
+/// Files live in memory, and only what is synced survives a crash: the data written to a file
+/// after `sync_data`, and the creation, renaming and removal of a file after `sync_dir` of its
+/// directory. `crash_after` makes file operations fail from a crash point on, and `restart`
+/// returns an env with what survives, which is the synced state plus a random prefix of the rest
+/// of each file and each directory. So writes may be torn in the middle, and files and directories
+/// that are not synced in order may survive in a different order.
+///
+/// A run is reproducible with the seed as long as the code under test doesn't depend on the wall
+/// clock, hash orders or other threads.
+#[derive(Clone)]
+pub struct SimEnv {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    state: Mutex<State>,
+    /// The ids of the tasks that are woken.
+    ready: Mutex<Vec<u64>>,
+}
+
+struct State {
+    rng: Rng,
+    now: Duration,
+    next_id: u64,
+    tasks: BTreeMap<u64, BoxFuture<'static, ()>>,
+    timers: BTreeMap<(Duration, u64), Waker>,
+    fs: Fs,
+    /// The number of file operations that change files.
+    num_ops: u64,
+    crash_at: Option<u64>,
+    crashed: bool,
+}
+
+impl SimEnv {
+    pub fn new(seed: u64) -> Self {
+        Self::with_state(Rng(seed), Duration::ZERO, Fs::default())
+    }
+
+    fn with_state(rng: Rng, now: Duration, fs: Fs) -> Self {
+        let state = State {
+            rng,
+            now,
+            next_id: MAIN_TASK + 1,
+            tasks: BTreeMap::new(),
+            timers: BTreeMap::new(),
+            fs,
+            num_ops: 0,
+            crash_at: None,
+            crashed: false,
+        };
+        let inner = Inner {
+            state: Mutex::new(state),
+            ready: Mutex::new(Vec::new()),
+        };
+        Self {
+            inner: Arc::new(inner),
+        }
+    }
+
+    /// Runs `future` and the spawned tasks until `future` completes.
+    ///
+    /// Panics if all tasks are blocked without timers, which would never complete.
+    pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        let mut future = Box::pin(future);
+        self.inner.wake(MAIN_TASK);
+        loop {
+            let id = match self.next_ready() {
+                Some(id) => id,
+                None => {
+                    self.advance_clock();
+                    continue;
+                }
+            };
+            let waker = Waker::from(Arc::new(TaskWaker {
+                id,
+                inner: Arc::downgrade(&self.inner),
+            }));
+            let mut cx = Context::from_waker(&waker);
+            if id == MAIN_TASK {
+                if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                    return output;
+                }
+                continue;
+            }
+            // The task is taken out while it runs, since it may spawn other tasks.
+            let task = self.inner.state.lock().unwrap().tasks.remove(&id);
+            if let Some(mut task) = task {
+                if task.as_mut().poll(&mut cx).is_pending() {
+                    self.inner.state.lock().unwrap().tasks.insert(id, task);
+                }
+            }
+        }
+    }
+
+    fn next_ready(&self) -> Option<u64> {
+        let mut state = self.inner.state.lock().unwrap();
+        let mut ready = self.inner.ready.lock().unwrap();
+        if ready.is_empty() {
+            return None;
+        }
+        let i = state.rng.below(ready.len() as u64) as usize;
+        Some(ready.swap_remove(i))
+    }
+
+    /// Moves the clock to the earliest timer and fires the timers that are due.
+    fn advance_clock(&self) {
+        let wakers = {
+            let mut state = self.inner.state.lock().unwrap();
+            let now = match state.timers.keys().next() {
+                Some(&(deadline, _)) => deadline,
+                None => panic!("all tasks are blocked without timers"),
+            };
+            state.now = state.now.max(now);
+            let later = state.timers.split_off(&(now, u64::MAX));
+            std::mem::replace(&mut state.timers, later)
+        };
+        for (_, waker) in wakers {
+            waker.wake();
+        }
+    }
+
+    /// Returns the time elapsed on the virtual clock.
+    pub fn now(&self) -> Duration {
+        self.inner.state.lock().unwrap().now
+    }
+
+    /// Returns the number of file operations that change files so far, which are the operations
+    /// that `crash_after` counts.
+    pub fn num_ops(&self) -> u64 {
+        self.inner.state.lock().unwrap().num_ops
+    }
+
+    /// Crashes at the `n`th file operation that changes files from now on, counting from zero.
+    ///
+    /// The operation and all the file operations after it fail, so nothing is changed after the
+    /// crash.
+    pub fn crash_after(&self, n: u64) {
+        let mut state = self.inner.state.lock().unwrap();
+        state.crash_at = Some(state.num_ops + n);
+    }
+
+    pub fn crashed(&self) -> bool {
+        self.inner.state.lock().unwrap().crashed
+    }
+
+    /// Simulates a restart after a crash or a power loss, and returns an env with the files that
+    /// survive.
+    ///
+    /// The tasks of this env are dropped, and its files are not changed anymore.
+    pub fn restart(&self) -> SimEnv {
+        let tasks = {
+            let mut state = self.inner.state.lock().unwrap();
+            state.crashed = true;
+            state.timers.clear();
+            std::mem::take(&mut state.tasks)
+        };
+        // Tasks are dropped without the lock, since they may hold this env.
+        drop(tasks);
+        self.inner.ready.lock().unwrap().clear();
+        let mut guard = self.inner.state.lock().unwrap();
+        let state = &mut *guard;
+        let fs = state.fs.recover(&mut state.rng);
+        let rng = Rng(state.rng.next());
+        Self::with_state(rng, state.now, fs)
+    }
+
+    /// Starts a file operation, which may yield to other tasks first.
+    async fn begin(&self, changes_files: bool) -> io::Result<()> {
+        let should_yield = self.inner.state.lock().unwrap().rng.below(2) == 0;
+        if should_yield {
+            YieldNow(false).await;
+        }
+        let mut state = self.inner.state.lock().unwrap();
+        if !state.crashed && changes_files {
+            if state.crash_at == Some(state.num_ops) {
+                state.crashed = true;
+            } else {
+                state.num_ops += 1;
+            }
+        }
+        if state.crashed {
+            // Any error works, since nothing is done after a crash.
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        Ok(())
+    }
+
+    fn with_fs<T>(&self, f: impl FnOnce(&mut Fs) -> io::Result<T>) -> io::Result<T> {
+        f(&mut self.inner.state.lock().unwrap().fs)
+    }
+}
+
+impl Inner {
+    fn wake(&self, id: u64) {
+        let mut ready = self.ready.lock().unwrap();
+        if !ready.contains(&id) {
+            ready.push(id);
+        }
+    }
+}
+
+struct TaskWaker {
+    id: u64,
+    inner: Weak<Inner>,
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        if let Some(inner) = self.inner.upgrade() {
+            inner.wake(self.id);
+        }
+    }
+}
+
+struct Sleep {
+    inner: Weak<Inner>,
+    deadline: Duration,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let inner = match self.inner.upgrade() {
+            Some(inner) => inner,
+            None => return Poll::Ready(()),
+        };
+        let mut state = inner.state.lock().unwrap();
+        if state.now >= self.deadline {
+            return Poll::Ready(());
+        }
+        let id = state.next_id;
+        state.next_id += 1;
+        state.timers.insert((self.deadline, id), cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+struct YieldNow(bool);
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.0 {
+            return Poll::Ready(());
+        }
+        self.0 = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+impl Env for SimEnv {
+    fn spawn(&self, task: BoxFuture<'static, ()>) {
+        let id = {
+            let mut state = self.inner.state.lock().unwrap();
+            let id = state.next_id;
+            state.next_id += 1;
+            state.tasks.insert(id, task);
+            id
+        };
+        self.inner.wake(id);
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        let deadline = self.now() + duration;
+        Box::pin(Sleep {
+            inner: Arc::downgrade(&self.inner),
+            deadline,
+        })
+    }
+
+    fn open_sequential_writer<'a>(
+        &'a self,
+        path: &'a Path,
+    ) -> BoxFuture<'a, io::Result<Box<dyn SequentialWriter>>> {
+        Box::pin(async move {
+            self.begin(true).await?;
+            let ino = self.with_fs(|fs| fs.create(path))?;
+            let writer = SimWriter {
+                env: self.clone(),
+                ino,
+            };
+            Ok(Box::new(writer) as Box<dyn SequentialWriter>)
+        })
+    }
+
+    fn open_positional_reader<'a>(
+        &'a self,
+        path: &'a Path,
+    ) -> BoxFuture<'a, io::Result<Box<dyn PositionalReader>>> {
+        Box::pin(async move {
+            self.begin(false).await?;
+            let ino = self.with_fs(|fs| fs.lookup(path))?;
+            let reader = SimReader {
+                env: self.clone(),
+                ino,
+            };
+            Ok(Box::new(reader) as Box<dyn PositionalReader>)
+        })
+    }
+
+    fn file_size<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<u64>> {
+        Box::pin(async move {
+            self.begin(false).await?;
+            self.with_fs(|fs| {
+                let ino = fs.lookup(path)?;
+                Ok(fs.files[&ino].data.len() as u64)
+            })
+        })
+    }
+
+    fn create_dir_all<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            self.begin(true).await?;
+            self.with_fs(|fs| {
+                fs.dirs.extend(path.ancestors().map(Path::to_owned));
+                Ok(())
+            })
+        })
+    }
+
+    fn read_dir<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<Vec<PathBuf>>> {
+        Box::pin(async move {
+            self.begin(false).await?;
+            self.with_fs(|fs| fs.read_dir(path))
+        })
+    }
+
+    fn rename<'a>(&'a self, from: &'a Path, to: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            self.begin(true).await?;
+            self.with_fs(|fs| {
+                let ino = fs.lookup(from)?;
+                fs.check_parent(to)?;
+                fs.change(vec![(from.to_owned(), None), (to.to_owned(), Some(ino))]);
+                Ok(())
+            })
+        })
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            self.begin(true).await?;
+            self.with_fs(|fs| {
+                fs.lookup(path)?;
+                fs.change(vec![(path.to_owned(), None)]);
+                Ok(())
+            })
+        })
+    }
+
+    fn sync_dir<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            self.begin(true).await?;
+            self.with_fs(|fs| {
+                fs.sync_dir(path);
+                Ok(())
+            })
+        })
+    }
+}
+
+struct SimWriter {
+    env: SimEnv,
+    ino: u64,
+}
+
+impl SequentialWriter for SimWriter {
+    fn write<'a>(&'a mut self, buf: &'a [u8]) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            self.env.begin(true).await?;
+            self.env.with_fs(|fs| {
+                let file = fs.files.get_mut(&self.ino).unwrap();
+                file.data.extend_from_slice(buf);
+                Ok(())
+            })
+        })
+    }
+
+    fn sync_data(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async move {
+            self.env.begin(true).await?;
+            self.env.with_fs(|fs| {
+                let file = fs.files.get_mut(&self.ino).unwrap();
+                file.synced = file.data.len();
+                Ok(())
+            })
+        })
+    }
+}
+
+struct SimReader {
+    env: SimEnv,
+    ino: u64,
+}
+
+impl PositionalReader for SimReader {
+    fn read_exact_at<'a>(
+        &'a self,
+        buf: &'a mut [u8],
+        offset: u64,
+    ) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            self.env.begin(false).await?;
+            self.env.with_fs(|fs| {
+                let data = &fs.files[&self.ino].data;
+                let start = offset as usize;
+                match data.get(start..start + buf.len()) {
+                    Some(src) => {
+                        buf.copy_from_slice(src);
+                        Ok(())
+                    }
+                    None => Err(io::ErrorKind::UnexpectedEof.into()),
+                }
+            })
+        })
+    }
+}
+
+/// An in-memory file system that tracks what survives a crash.
+#[derive(Default)]
+struct Fs {
+    /// The directories, which are durable once they are created.
+    dirs: BTreeSet<PathBuf>,
+    /// The files by their inode numbers. Files that are removed are kept for their open handles.
+    files: BTreeMap<u64, File>,
+    next_ino: u64,
+    /// The entries of the directories, which map paths to inode numbers.
+    entries: BTreeMap<PathBuf, u64>,
+    /// The entries that survive a crash.
+    durable_entries: BTreeMap<PathBuf, u64>,
+    /// The changes of entries that are not synced yet, in order. Each change is applied as a
+    /// whole, and it is synced with the directory of its last entry.
+    changes: Vec<Vec<(PathBuf, Option<u64>)>>,
+}
+
+struct File {
+    data: Vec<u8>,
+    /// The length of the data that survives a crash.
+    synced: usize,
+}
+
+impl Fs {
+    fn lookup(&self, path: &Path) -> io::Result<u64> {
+        self.entries
+            .get(path)
+            .copied()
+            .ok_or_else(|| io::ErrorKind::NotFound.into())
+    }
+
+    fn check_parent(&self, path: &Path) -> io::Result<()> {
+        match path.parent() {
+            Some(dir) if self.dirs.contains(dir) => Ok(()),
+            _ => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    /// Creates a file or truncates an existing one.
+    fn create(&mut self, path: &Path) -> io::Result<u64> {
+        if let Some(&ino) = self.entries.get(path) {
+            let file = self.files.get_mut(&ino).unwrap();
+            file.data.clear();
+            file.synced = 0;
+            return Ok(ino);
+        }
+        self.check_parent(path)?;
+        let ino = self.next_ino;
+        self.next_ino += 1;
+        let file = File {
+            data: Vec::new(),
+            synced: 0,
+        };
+        self.files.insert(ino, file);
+        self.change(vec![(path.to_owned(), Some(ino))]);
+        Ok(ino)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        if !self.dirs.contains(path) {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        let is_child = |child: &&PathBuf| child.parent() == Some(path);
+        let files = self.entries.keys().filter(is_child);
+        let dirs = self.dirs.iter().filter(is_child);
+        Ok(files.chain(dirs).cloned().collect())
+    }
+
+    fn change(&mut self, change: Vec<(PathBuf, Option<u64>)>) {
+        apply_change(&mut self.entries, &change);
+        self.changes.push(change);
+    }
+
+    fn sync_dir(&mut self, dir: &Path) {
+        let mut changes = std::mem::take(&mut self.changes);
+        changes.retain(|change| {
+            if change_dir(change) != Some(dir) {
+                return true;
+            }
+            apply_change(&mut self.durable_entries, change);
+            false
+        });
+        self.changes = changes;
+    }
+
+    /// Returns what survives a crash.
+    fn recover(&self, rng: &mut Rng) -> Fs {
+        let mut entries = self.durable_entries.clone();
+        let mut changes: BTreeMap<&Path, Vec<_>> = BTreeMap::new();
+        for change in &self.changes {
+            if let Some(dir) = change_dir(change) {
+                changes.entry(dir).or_default().push(change);
+            }
+        }
+        for changes in changes.values() {
+            let n = rng.below(changes.len() as u64 + 1) as usize;
+            for change in &changes[..n] {
+                apply_change(&mut entries, change);
+            }
+        }
+        let mut files = BTreeMap::new();
+        for (&ino, file) in &self.files {
+            if !entries.values().any(|&entry| entry == ino) {
+                continue;
+            }
+            let unsynced = (file.data.len() - file.synced) as u64;
+            let len = file.synced + rng.below(unsynced + 1) as usize;
+            let data = file.data[..len].to_vec();
+            files.insert(ino, File { data, synced: len });
+        }
+        Fs {
+            dirs: self.dirs.clone(),
+            files,
+            next_ino: self.next_ino,
+            entries: entries.clone(),
+            durable_entries: entries,
+            changes: Vec::new(),
+        }
+    }
+}
+
+fn change_dir(change: &[(PathBuf, Option<u64>)]) -> Option<&Path> {
+    change.last().and_then(|(path, _)| path.parent())
+}
+
+fn apply_change(entries: &mut BTreeMap<PathBuf, u64>, change: &[(PathBuf, Option<u64>)]) {
+    for (path, ino) in change {
+        match ino {
+            Some(ino) => entries.insert(path.clone(), *ino),
+            None => entries.remove(path),
+        };
+    }
+}
+
+/// A small and fast pseudo random number generator.
+pub(crate) struct Rng(pub u64);
+
+impl Rng {
+    pub fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a number in `0..n`, which must be positive.
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::env::check_env;
+
+    #[test]
+    fn conformance() {
+        let env = SimEnv::new(0);
+        env.block_on(check_env(&env, Path::new("/sim")));
+    }
+
+    #[test]
+    fn schedule() {
+        fn run(seed: u64) -> Vec<u64> {
+            let env = SimEnv::new(seed);
+            let order = Arc::new(Mutex::new(Vec::new()));
+            for i in 0..8 {
+                let (task_env, order) = (env.clone(), order.clone());
+                env.spawn(Box::pin(async move {
+                    task_env.sleep(Duration::from_secs(i % 2)).await;
+                    order.lock().unwrap().push(i);
+                }));
+            }
+            env.block_on(env.sleep(Duration::from_secs(3600)));
+            assert_eq!(env.now(), Duration::from_secs(3600));
+            let order = order.lock().unwrap().clone();
+            // Tasks that sleep longer run later.
+            assert!(order[..4].iter().all(|i| i % 2 == 0));
+            order
+        }
+        assert_eq!(run(1), run(1));
+        assert!((2..10).any(|seed| run(seed) != run(1)));
+    }
+
+    #[test]
+    fn crash() {
+        let dir = Path::new("/sim");
+        let (synced, unsynced) = (dir.join("synced"), dir.join("unsynced"));
+        let mut torn = false;
+        for seed in 0..32 {
+            let env = SimEnv::new(seed);
+            env.block_on(async {
+                env.create_dir_all(dir).await.unwrap();
+                let mut writer = env.open_sequential_writer(&synced).await.unwrap();
+                writer.write(b"hello").await.unwrap();
+                writer.sync_data().await.unwrap();
+                writer.write(b" world").await.unwrap();
+                env.sync_dir(dir).await.unwrap();
+                let mut writer = env.open_sequential_writer(&unsynced).await.unwrap();
+                writer.write(b"lost").await.unwrap();
+                writer.sync_data().await.unwrap();
+                env.rename(&synced, &dir.join("renamed")).await.unwrap();
+
+                env.crash_after(1);
+                let mut writer = env.open_sequential_writer(&dir.join("new")).await.unwrap();
+                assert!(writer.write(b"failed").await.is_err());
+                assert!(env.crashed());
+                assert!(env.file_size(&synced).await.is_err());
+            });
+
+            let env = env.restart();
+            env.block_on(async {
+                let mut names = Vec::new();
+                for path in env.read_dir(dir).await.unwrap() {
+                    let size = env.file_size(&path).await.unwrap();
+                    names.push(path.file_name().unwrap().to_str().unwrap().to_owned());
+                    if names.last().unwrap() == "new" {
+                        assert_eq!(size, 0);
+                        continue;
+                    }
+                    let reader = env.open_positional_reader(&path).await.unwrap();
+                    let mut buf = vec![0; size as usize];
+                    reader.read_exact_at(&mut buf, 0).await.unwrap();
+                    assert!(buf.starts_with(b"hello") || buf == b"lost", "{:?}", buf);
+                    torn |= buf.len() > 5 && buf.len() < 11;
+                }
+                // The synced file survives with or without the rename, and the others may not.
+                let synced = names
+                    .iter()
+                    .filter(|name| *name == "synced" || *name == "renamed");
+                assert_eq!(synced.count(), 1, "{:?}", names);
+            });
+        }
+        assert!(torn);
+    }
+}
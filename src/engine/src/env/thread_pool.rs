@@ -0,0 +1,221 @@
+use std::{
+    fs::{self, File},
+    future::Future,
+    io::{self, Write},
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        mpsc::{self, Sender},
+        Arc, Mutex,
+    },
+    task::{Context, Poll, Wake, Waker},
+    thread,
+    time::{Duration, Instant},
+};
+
+use fs2::FileExt as _;
+
+use super::{BoxFuture, Env, FileLock, PositionalReader, SequentialWriter};
+
+/// An `Env` implementation that runs tasks on a fixed number of threads.
+///
+/// File operations are done synchronously on the calling thread.
+#[derive(Clone)]
+pub struct ThreadPoolEnv {
+    sender: Arc<Mutex<Sender<Arc<Task>>>>,
+}
+
+impl ThreadPoolEnv {
+    /// Creates an env with `num_threads` threads.
+    pub fn new(num_threads: usize) -> Self {
+        let (sender, receiver) = mpsc::channel::<Arc<Task>>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for i in 0..num_threads {
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name(format!("photondb-env-{}", i))
+                .spawn(move || loop {
+                    // The threads exit when all tasks and the env are dropped.
+                    let task = match receiver.lock().unwrap().recv() {
+                        Ok(task) => task,
+                        Err(_) => break,
+                    };
+                    task.run();
+                })
+                .expect("failed to spawn thread");
+        }
+        Self {
+            sender: Arc::new(Mutex::new(sender)),
+        }
+    }
+}
+
+impl Env for ThreadPoolEnv {
+    fn spawn(&self, task: BoxFuture<'static, ()>) {
+        let sender = self.sender.lock().unwrap().clone();
+        let task = Arc::new(Task {
+            future: Mutex::new(Some(task)),
+            sender: Mutex::new(sender),
+        });
+        task.wake();
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(Sleep {
+            deadline: Instant::now() + duration,
+            waker: None,
+        })
+    }
+
+    fn open_sequential_writer<'a>(
+        &'a self,
+        path: &'a Path,
+    ) -> BoxFuture<'a, io::Result<Box<dyn SequentialWriter>>> {
+        Box::pin(async move {
+            let file = File::create(path)?;
+            Ok(Box::new(SyncFile(file)) as _)
+        })
+    }
+
+    fn open_positional_reader<'a>(
+        &'a self,
+        path: &'a Path,
+    ) -> BoxFuture<'a, io::Result<Box<dyn PositionalReader>>> {
+        Box::pin(async move {
+            let file = File::open(path)?;
+            Ok(Box::new(SyncFile(file)) as _)
+        })
+    }
+
+    fn file_size<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<u64>> {
+        Box::pin(async move { fs::metadata(path).map(|m| m.len()) })
+    }
+
+    fn create_dir_all<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move { fs::create_dir_all(path) })
+    }
+
+    fn read_dir<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<Vec<PathBuf>>> {
+        Box::pin(async move { read_dir(path) })
+    }
+
+    fn rename<'a>(&'a self, from: &'a Path, to: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move { fs::rename(from, to) })
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move { fs::remove_file(path) })
+    }
+
+    fn sync_dir<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move { sync_dir(path) })
+    }
+
+    fn lock_file<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<Box<dyn FileLock>>> {
+        Box::pin(async move { Ok(Box::new(lock_file(path)?) as _) })
+    }
+}
+
+struct Task {
+    future: Mutex<Option<BoxFuture<'static, ()>>>,
+    sender: Mutex<Sender<Arc<Task>>>,
+}
+
+impl Task {
+    fn run(self: Arc<Self>) {
+        // Holds the lock during polling, so that a task woken up in the meantime waits for it.
+        let mut future = self.future.lock().unwrap();
+        if let Some(mut task) = future.take() {
+            let waker = Waker::from(self.clone());
+            let mut cx = Context::from_waker(&waker);
+            if task.as_mut().poll(&mut cx).is_pending() {
+                *future = Some(task);
+            }
+        }
+    }
+}
+
+impl Wake for Task {
+    fn wake(self: Arc<Self>) {
+        let sender = self.sender.lock().unwrap().clone();
+        // The receiver is gone only if all threads have exited.
+        let _ = sender.send(self);
+    }
+}
+
+struct Sleep {
+    deadline: Instant,
+    waker: Option<Arc<Mutex<Waker>>>,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let now = Instant::now();
+        if now >= self.deadline {
+            return Poll::Ready(());
+        }
+        match &self.waker {
+            Some(waker) => *waker.lock().unwrap() = cx.waker().clone(),
+            None => {
+                let waker = Arc::new(Mutex::new(cx.waker().clone()));
+                let timer_waker = waker.clone();
+                let timeout = self.deadline - now;
+                thread::spawn(move || {
+                    thread::sleep(timeout);
+                    timer_waker.lock().unwrap().wake_by_ref();
+                });
+                self.waker = Some(waker);
+            }
+        }
+        Poll::Pending
+    }
+}
+
+struct SyncFile(File);
+
+impl SequentialWriter for SyncFile {
+    fn write<'a>(&'a mut self, buf: &'a [u8]) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move { self.0.write_all(buf) })
+    }
+
+    fn sync_data(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async move { self.0.sync_data() })
+    }
+}
+
+impl PositionalReader for SyncFile {
+    fn read_exact_at<'a>(
+        &'a self,
+        buf: &'a mut [u8],
+        offset: u64,
+    ) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move { self.0.read_exact_at(buf, offset) })
+    }
+}
+pub(super) fn read_dir(path: &Path) -> io::Result<Vec<PathBuf>> {
+    fs::read_dir(path)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect()
+}
+
+pub(super) fn sync_dir(path: &Path) -> io::Result<()> {
+    File::open(path)?.sync_all()
+}
+
+/// An advisory lock on a file, which is released when the file is closed.
+pub(super) struct LockedFile(#[allow(dead_code)] File);
+
+impl FileLock for LockedFile {}
+
+pub(super) fn lock_file(path: &Path) -> io::Result<LockedFile> {
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)?;
+    file.try_lock_exclusive()?;
+    Ok(LockedFile(file))
+}
@@ -0,0 +1,173 @@
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::runtime::Handle;
+
+use super::{
+    thread_pool::{lock_file, read_dir, sync_dir},
+    BoxFuture, Env, FileLock, PositionalReader, SequentialWriter,
+};
+
+/// An `Env` implementation based on tokio.
+///
+/// Blocking file operations run on the blocking thread pool of the runtime.
+#[derive(Clone)]
+pub struct TokioEnv {
+    handle: Handle,
+}
+
+impl TokioEnv {
+    /// Creates an env with the runtime of the current context.
+    ///
+    /// # Panics
+    ///
+    /// Panics if it is not called from the context of a tokio runtime.
+    pub fn current() -> Self {
+        Self::with_handle(Handle::current())
+    }
+
+    /// Creates an env with the given runtime handle.
+    pub fn with_handle(handle: Handle) -> Self {
+        Self { handle }
+    }
+}
+
+async fn spawn_blocking<F, T>(handle: &Handle, f: F) -> io::Result<T>
+where
+    F: FnOnce() -> io::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    handle.spawn_blocking(f).await?
+}
+
+impl Env for TokioEnv {
+    fn spawn(&self, task: BoxFuture<'static, ()>) {
+        self.handle.spawn(task);
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        // The timer must be registered within the runtime context.
+        let _guard = self.handle.enter();
+        Box::pin(tokio::time::sleep(duration))
+    }
+
+    fn open_sequential_writer<'a>(
+        &'a self,
+        path: &'a Path,
+    ) -> BoxFuture<'a, io::Result<Box<dyn SequentialWriter>>> {
+        let path = path.to_owned();
+        Box::pin(async move {
+            let file = spawn_blocking(&self.handle, move || File::create(path)).await?;
+            let file = TokioFile {
+                file: Arc::new(file),
+                handle: self.handle.clone(),
+            };
+            Ok(Box::new(file) as _)
+        })
+    }
+
+    fn open_positional_reader<'a>(
+        &'a self,
+        path: &'a Path,
+    ) -> BoxFuture<'a, io::Result<Box<dyn PositionalReader>>> {
+        let path = path.to_owned();
+        Box::pin(async move {
+            let file = spawn_blocking(&self.handle, move || File::open(path)).await?;
+            let file = TokioFile {
+                file: Arc::new(file),
+                handle: self.handle.clone(),
+            };
+            Ok(Box::new(file) as _)
+        })
+    }
+
+    fn file_size<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<u64>> {
+        let path = path.to_owned();
+        Box::pin(spawn_blocking(&self.handle, move || {
+            fs::metadata(path).map(|m| m.len())
+        }))
+    }
+
+    fn create_dir_all<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+        let path = path.to_owned();
+        Box::pin(spawn_blocking(&self.handle, move || {
+            fs::create_dir_all(path)
+        }))
+    }
+
+    fn read_dir<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<Vec<PathBuf>>> {
+        let path = path.to_owned();
+        Box::pin(spawn_blocking(&self.handle, move || read_dir(&path)))
+    }
+
+    fn rename<'a>(&'a self, from: &'a Path, to: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+        let from = from.to_owned();
+        let to = to.to_owned();
+        Box::pin(spawn_blocking(&self.handle, move || fs::rename(from, to)))
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+        let path = path.to_owned();
+        Box::pin(spawn_blocking(&self.handle, move || fs::remove_file(path)))
+    }
+
+    fn sync_dir<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+        let path = path.to_owned();
+        Box::pin(spawn_blocking(&self.handle, move || sync_dir(&path)))
+    }
+
+    fn lock_file<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<Box<dyn FileLock>>> {
+        let path = path.to_owned();
+        Box::pin(async move {
+            let lock = spawn_blocking(&self.handle, move || lock_file(&path)).await?;
+            Ok(Box::new(lock) as _)
+        })
+    }
+}
+
+struct TokioFile {
+    file: Arc<File>,
+    handle: Handle,
+}
+
+impl SequentialWriter for TokioFile {
+    fn write<'a>(&'a mut self, buf: &'a [u8]) -> BoxFuture<'a, io::Result<()>> {
+        let file = self.file.clone();
+        let buf = buf.to_vec();
+        Box::pin(spawn_blocking(&self.handle, move || {
+            (&*file).write_all(&buf)
+        }))
+    }
+
+    fn sync_data(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        let file = self.file.clone();
+        Box::pin(spawn_blocking(&self.handle, move || file.sync_data()))
+    }
+}
+
+impl PositionalReader for TokioFile {
+    fn read_exact_at<'a>(
+        &'a self,
+        buf: &'a mut [u8],
+        offset: u64,
+    ) -> BoxFuture<'a, io::Result<()>> {
+        let file = self.file.clone();
+        let len = buf.len();
+        Box::pin(async move {
+            let data = spawn_blocking(&self.handle, move || {
+                let mut data = vec![0; len];
+                file.read_exact_at(&mut data, offset)?;
+                Ok(data)
+            })
+            .await?;
+            buf.copy_from_slice(&data);
+            Ok(())
+        })
+    }
+}
@@ -1,3 +1,36 @@
 #![feature(test)]
 
+/// Returns an error from the enclosing function if the fail point is configured to return, with
+/// the `failpoints` feature.
+///
+/// Tests configure the fail points with `fail::cfg` to stop operations at precise points, as if
+/// the process was killed there. The fail points are compiled out without the feature.
+macro_rules! fail_point {
+    ($name:literal) => {
+        #[cfg(feature = "failpoints")]
+        fail::fail_point!($name, |_| {
+            let err = std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                concat!("fail point ", $name),
+            );
+            Err(err.into())
+        });
+    };
+}
+
+/// Records a field of the current span, with the `tracing` feature.
+///
+/// Spans of the tree operations are created with `tracing::instrument`, where the fields that are
+/// only known as the operations go are declared as `Empty`. The value is evaluated, but discarded,
+/// without the feature.
+macro_rules! record {
+    ($field:literal, $value:expr) => {{
+        #[cfg(feature = "tracing")]
+        tracing::Span::current().record($field, &$value);
+        #[cfg(not(feature = "tracing"))]
+        let _ = $value;
+    }};
+}
+
+pub mod env;
 pub mod tree;
@@ -1,42 +1,690 @@
+use std::{
+    cmp,
+    collections::{hash_map::RandomState, BTreeMap, HashMap, HashSet, VecDeque},
+    hash::{BuildHasher, Hasher},
+    ops::Range,
+    path::Path,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "stream")]
+use bytes::Bytes;
+use futures::{future::try_join_all, AsyncRead, AsyncWrite};
+use tokio::sync::Mutex as AsyncMutex;
+#[cfg(feature = "tracing")]
+use tracing::field::Empty;
+
 use super::{
+    append_timestamp, chain,
+    contention::ContentionTracker,
+    export::{ExportReader, ExportWriter},
+    ghost,
+    jobs::{Job, JobScheduler},
     page::*,
-    pagecache::{PageAddr, PageCache, PageView},
-    pagestore::PageStore,
+    pagecache::{AllocKind, CacheTier, PageAddr, PageCache, PageView},
+    pagestore::{dealloc_chain, PageFileReader, PageHandle, PageStore},
     pagetable::PageTable,
-    Error, Ghost, Options, Result,
+    scheduler::{Scheduler, Work},
+    slab::MAX_CLASS_SIZE,
+    split_timestamp,
+    verify::check_page,
+    AccessTracker, ChangeBatch, ChangePublisher, ChangeStream, Comparator, Conflict, Error, Event,
+    EventKind, EventListener, EventLog, FlushPolicy, Ghost, IoOp, IoStats, LifetimeStats,
+    ManifestInfo, MemoryUsage, Options, RateLimiter, RepairReport, Result, Stats, SyncMode,
+    TreeInfo, VerifyReport, WriteStats,
 };
+#[cfg(feature = "tokio-env")]
+use crate::env::TokioEnv;
+use crate::env::{Env, PositionalReader, Rng};
 
 const ROOT_ID: u64 = 0;
+/// The manifest counter of the largest LSN that is allocated or written, so that LSNs allocated by
+/// `BTree::next_lsn` are never reused after restarts.
+const LAST_LSN_COUNTER: &str = "last_lsn";
+/// The name of the manifest counter that records `BTree::history_ts_low`.
+const HISTORY_TS_LOW_COUNTER: &str = "history_ts_low";
+/// The name of the manifest counter that records `BTree::applied_lsn`.
+const APPLIED_LSN_COUNTER: &str = "applied_lsn";
+/// The name of the manifest counter that records `BTree::applied_epoch`.
+const APPLIED_EPOCH_COUNTER: &str = "applied_epoch";
+/// The name of the manifest counter that records `BTree::len_estimate`.
+const LIVE_KEYS_COUNTER: &str = "live_keys";
+/// The prefix of the manifest counter whose name records the name of `Options::comparator`.
+///
+/// Counters only have numeric values, so the name is the rest of the counter name.
+const COMPARATOR_COUNTER_PREFIX: &str = "comparator:";
 const ROOT_INDEX: Index = Index::with_id(ROOT_ID);
 
-struct Node {
+/// A leaf of an ingested page file.
+struct IngestedLeaf {
+    id: u64,
+    ver: PageVer,
+    first: Vec<u8>,
+    last: Vec<u8>,
+    live_keys: i64,
+}
+
+struct Node<'g> {
+    id: u64,
+    view: PageView,
+    // The key range of the node. An empty end means that the range is unbounded.
+    range: Range<&'g [u8]>,
+}
+
+/// A node that owns its range, so that it can be kept across leaves by scans.
+///
+/// The view of the node is only valid as long as the same `Ghost` is pinned.
+struct OwnedNode {
     id: u64,
     view: PageView,
+    range: Range<Vec<u8>>,
+}
+
+impl OwnedNode {
+    fn new(node: &Node<'_>) -> Self {
+        Self {
+            id: node.id,
+            view: node.view,
+            range: node.range.start.to_vec()..node.range.end.to_vec(),
+        }
+    }
+
+    fn as_node(&self) -> Node<'_> {
+        Node {
+            id: self.id,
+            view: self.view,
+            range: self.range.start.as_slice()..self.range.end.as_slice(),
+        }
+    }
+}
+
+/// An iterator over the entries of a node.
+///
+/// Equal entries are resolved to the newest one, which is on the page with the lowest rank in the
+/// merging iterator, and entries that have been moved to the right sibling by splits are skipped.
+struct NodeIter<'g, K, V>
+where
+    K: Decodable + Comparable,
+    V: Decodable,
+{
+    iter: MergingIter<DataPageIter<'g, K, V>>,
+    high: Option<&'g [u8]>,
+    done: bool,
+    cmp: Arc<dyn Comparator>,
+}
+
+impl<'g, K, V> ForwardIter for NodeIter<'g, K, V>
+where
+    K: Decodable + Comparable + RawKey + Clone,
+    V: Decodable,
+{
+    type Key = K;
+    type Value = V;
+
+    fn last(&self) -> Option<&(K, V)> {
+        if self.done {
+            None
+        } else {
+            self.iter.last()
+        }
+    }
+
+    fn next(&mut self) -> Option<&(K, V)> {
+        if self.done {
+            return None;
+        }
+        match self.iter.next() {
+            Some((k, _)) => {
+                let cmp = self.cmp.as_ref();
+                if matches!(self.high, Some(high) if k.as_raw().compare_with(&high, cmp).is_ge()) {
+                    self.done = true;
+                    return None;
+                }
+                self.iter.last()
+            }
+            None => None,
+        }
+    }
+}
+
+impl<'g, K, V> RewindableIter for NodeIter<'g, K, V>
+where
+    K: Decodable + Comparable + RawKey + Clone,
+    V: Decodable,
+{
+    fn rewind(&mut self) {
+        self.iter.rewind();
+        self.done = false;
+    }
+}
+
+/// How a leaf is written by a checkpoint with `FlushPolicy::DeltaFlush`.
+enum FlushedNode {
+    /// The leaf is unchanged since it was written at the address.
+    Unchanged(u64),
+    /// The deltas of the leaf since it was written.
+    Delta(PagePtr),
+}
+
+/// Tracks the LSNs of the updates in flight, so that checkpoints only report the LSNs of
+/// completed updates as durable.
+struct WriteTracker {
+    // The number of updates in flight at each LSN.
+    inflight: Mutex<BTreeMap<u64, usize>>,
+    // The largest LSN of the completed updates.
+    completed: AtomicU64,
+}
+
+impl WriteTracker {
+    fn new(lsn: u64) -> Self {
+        Self {
+            inflight: Mutex::default(),
+            completed: AtomicU64::new(lsn),
+        }
+    }
+
+    /// Records that an update at `lsn` is in flight until the returned write is dropped.
+    fn begin(&self, lsn: u64) -> InflightWrite<'_> {
+        *self.inflight.lock().unwrap().entry(lsn).or_default() += 1;
+        InflightWrite { tracker: self, lsn }
+    }
+
+    /// Records that the updates at `lsn` are completed.
+    fn complete(&self, lsn: u64) {
+        self.completed.fetch_max(lsn, Ordering::AcqRel);
+    }
+
+    /// Returns the largest LSN of the completed updates, capped below the LSNs of the updates in
+    /// flight, so that all the updates that have begun at or below it are completed.
+    fn low_watermark(&self) -> u64 {
+        let inflight = self.inflight.lock().unwrap();
+        let completed = self.completed.load(Ordering::Acquire);
+        match inflight.keys().next() {
+            Some(&lsn) => completed.min(lsn.saturating_sub(1)),
+            None => completed,
+        }
+    }
 }
 
-type NodeIter<'a, K, V> = MergingIter<DataPageIter<'a, K, V>>;
+/// An update in flight, which is only counted as completed by `complete`.
+struct InflightWrite<'a> {
+    tracker: &'a WriteTracker,
+    lsn: u64,
+}
+
+impl InflightWrite<'_> {
+    fn complete(self) {
+        // Completes the update before it leaves the updates in flight.
+        self.tracker.complete(self.lsn);
+    }
+}
+
+impl Drop for InflightWrite<'_> {
+    fn drop(&mut self) {
+        let mut inflight = self.tracker.inflight.lock().unwrap();
+        if let Some(count) = inflight.get_mut(&self.lsn) {
+            *count -= 1;
+            if *count == 0 {
+                inflight.remove(&self.lsn);
+            }
+        }
+    }
+}
 
 pub struct BTree {
     opts: Options,
+    env: Arc<dyn Env>,
     table: PageTable,
     cache: PageCache,
     store: PageStore,
+    sched: Scheduler,
+    jobs: JobScheduler,
+    contention: ContentionTracker,
+    access: AccessTracker,
+    // Serializes checkpoints, so that the manifest always records the latest one.
+    checkpoint_lock: AsyncMutex<()>,
+    last_checkpoint: Mutex<Instant>,
+    // The largest LSN of all updates.
+    max_lsn: AtomicU64,
+    // The largest LSN of the updates covered by the last checkpoint, below the updates that were
+    // in flight when it started.
+    durable_lsn: AtomicU64,
+    writes: WriteTracker,
+    // The number of checkpoints started, and the number of the last one that succeeded. A
+    // checkpoint that starts after an update completes makes it durable.
+    checkpoint_epoch: AtomicU64,
+    durable_epoch: AtomicU64,
+    // Versions of keys older than this timestamp are dropped by consolidations.
+    history_ts_low: AtomicU64,
+    // The epoch and the LSN of the last batch applied by `apply_batch_with_lsn`.
+    applied: Mutex<(u64, u64)>,
+    // Serializes `apply_batch_with_lsn`, so that a batch is applied at most once.
+    apply_lock: AsyncMutex<()>,
+    // The approximate number of keys whose newest versions are puts. Writes count each put as a
+    // new key and each delete as a removed one, and consolidations correct the count of a leaf
+    // from its entries.
+    live_keys: AtomicI64,
+    changes: ChangePublisher,
+    events: EventLog,
+    num_oversize_writes: AtomicU64,
+    num_delta_merges: AtomicU64,
+    oversize_bytes: AtomicU64,
+    rate_limiter: Option<RateLimiter>,
+    // The bytes of keys and values written since the last checkpoint.
+    dirty_bytes: AtomicU64,
+    num_throttled_writes: AtomicU64,
+    throttle_micros: AtomicU64,
+    // The lifetime statistics recovered from the last checkpoint.
+    recovered_stats: LifetimeStats,
+    num_puts: AtomicU64,
+    num_deletes: AtomicU64,
+    write_bytes: AtomicU64,
+    num_checkpoints: AtomicU64,
+    // The number of levels from the root to the leaves, which only grows when the root splits.
+    height: AtomicUsize,
+}
+
+/// Counts the retries of an operation on conflicts.
+struct Retry<'a> {
+    tree: &'a BTree,
+    key: &'a [u8],
+    ghost: &'a Ghost,
+    retries: usize,
+}
+
+impl<'a> Retry<'a> {
+    fn new(tree: &'a BTree, key: &'a [u8], ghost: &'a Ghost) -> Self {
+        Self {
+            tree,
+            key,
+            ghost,
+            retries: 0,
+        }
+    }
+
+    /// Returns `Ok` if the operation should retry on `err`, or the error to return otherwise.
+    fn on_error(&mut self, err: Error) -> Result<()> {
+        let (node_id, cause) = match err {
+            Error::Again { node_id, cause } => (node_id, cause),
+            err => return Err(err),
+        };
+        if self.retries < self.tree.opts.max_retries {
+            self.retries += 1;
+            self.ghost.perf(|perf| perf.num_retries += 1);
+            return Ok(());
+        }
+        self.tree.contention.record(node_id, self.key);
+        Err(Error::Contention {
+            node_id,
+            retries: self.retries,
+            last_cause: cause,
+        })
+    }
+}
+
+/// A cursor over the entries in a range that are visible at an LSN, in key order.
+///
+/// The cursor reads one leaf at a time, each at a single version of the leaf, and buffers its
+/// entries, so it holds no pages between calls and doesn't block consolidations or splits. Once
+/// the buffer runs out, the cursor seeks to the upper boundary of the leaf version that it read,
+/// which counts the splits that are not reconciled to the parent yet, and skips the entries up to
+/// the last key that it returned, in case a retry on `Error::Again` sees them again.
+///
+/// So keys are returned in strictly increasing order, and a key that is visible at the LSN
+/// throughout the scan is returned exactly once, however the leaves are split in between.
+/// Whether a key written at or below the LSN during the scan is returned depends on whether its
+/// leaf is read before or after the write.
+pub struct Cursor<'a> {
+    tree: &'a BTree,
+    end: Vec<u8>,
+    lsn: u64,
+    // The start of the next leaf to read, or `None` at the end of the range.
+    next: Option<Vec<u8>>,
+    // The last key that is buffered.
+    last: Option<Vec<u8>>,
+    entries: VecDeque<(Vec<u8>, Vec<u8>)>,
+}
+
+impl<'a> Cursor<'a> {
+    /// Returns the next entry, or `None` at the end of the range.
+    pub async fn next(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        loop {
+            if let Some(entry) = self.entries.pop_front() {
+                return Ok(Some(entry));
+            }
+            match self.next.take() {
+                Some(start) => self.fill(&start).await?,
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// Turns the cursor into a stream of the entries, to compose with the combinators of
+    /// `futures::StreamExt`.
+    ///
+    /// The stream ends after it yields an error. Unlike the stream, `next` can be called again
+    /// after an error to resume from where the cursor is, and doesn't wrap the keys and values.
+    #[cfg(feature = "stream")]
+    pub fn into_stream(self) -> impl futures::Stream<Item = Result<(Bytes, Bytes)>> + 'a {
+        futures::stream::unfold(Some(self), |cursor| async move {
+            let mut cursor = cursor?;
+            match cursor.next().await {
+                Ok(Some((key, value))) => Some((Ok((key.into(), value.into())), Some(cursor))),
+                Ok(None) => None,
+                Err(err) => Some((Err(err), None)),
+            }
+        })
+    }
+
+    async fn fill(&mut self, start: &[u8]) -> Result<()> {
+        let ghost = &Ghost::pin();
+        let tree = self.tree;
+        let last = self.last.as_deref();
+        let entries = &mut self.entries;
+        // The ghost is unpinned between leaves, so the parent of the last leaf can't be kept.
+        let next = tree
+            .scan_leaf(start, &self.end, self.lsn, &mut None, ghost, |k, v| {
+                if let Some(v) = v {
+                    if !matches!(last, Some(last) if tree.compare(k.raw, last).is_le()) {
+                        entries.push_back((k.raw.to_vec(), v.to_vec()));
+                    }
+                }
+                true
+            })
+            .await;
+        if let Some((k, _)) = self.entries.back() {
+            self.last = Some(k.clone());
+        }
+        // The cursor resumes from the same leaf after an error.
+        self.next = match next {
+            Ok(next) => next,
+            Err(err) => {
+                self.next = Some(start.to_vec());
+                return Err(err);
+            }
+        };
+        Ok(())
+    }
 }
 
 impl BTree {
-    pub async fn open(opts: Options) -> Result<Self> {
-        let table = PageTable::default();
-        let cache = PageCache::default();
-        let store = PageStore::open(opts.clone()).await?;
+    /// Opens a tree in `path` with the tokio runtime of the current context.
+    #[cfg(feature = "tokio-env")]
+    pub async fn open(path: impl AsRef<Path>, opts: Options) -> Result<Self> {
+        Self::open_with_env(Arc::new(TokioEnv::current()), path, opts).await
+    }
+
+    /// Opens a tree in `path` with the given `Env`.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "open",
+            skip_all,
+            fields(path = %path.as_ref().display(), pages = Empty, lsn = Empty)
+        )
+    )]
+    pub async fn open_with_env(
+        env: Arc<dyn Env>,
+        path: impl AsRef<Path>,
+        opts: Options,
+    ) -> Result<Self> {
+        let start = Instant::now();
+        if let Some(size) = opts.page_size {
+            if !size.is_power_of_two() || size > MAX_CLASS_SIZE {
+                return Err(Error::InvalidArgument(format!(
+                    "page size {} is not a power of two up to {}",
+                    size, MAX_CLASS_SIZE
+                )));
+            }
+        }
+        let events = EventLog::new(opts.event_log_size);
+        // Nodes are only tracked for eviction if the cache has a budget, or for delta flushes,
+        // which find the pages that nodes are written with from the cache.
+        let mut cache = if opts.cache_size < usize::MAX
+            || opts.shared_cache.is_some()
+            || opts.flush_policy == FlushPolicy::DeltaFlush
+        {
+            PageCache::with_policy(opts.cache_policy.build())
+        } else {
+            PageCache::default()
+        };
+        if opts.slab_alloc || opts.numa_aware {
+            cache = cache.with_slabs(opts.page_size.unwrap_or(0), opts.numa_aware);
+        }
+        if let Some(shared) = &opts.shared_cache {
+            cache = cache.with_shared(shared.clone());
+        }
+        let store = PageStore::open(env.clone(), path.as_ref(), opts.clone()).await?;
+        // Recovers the page table from the last checkpoint, where all pages are on disk.
+        let manifest = store.recovered();
+        if !manifest.page_table.is_empty() && manifest.root_id != ROOT_ID {
+            return Err(Error::Corrupted(
+                format!(
+                    "manifest has root page {}, expected {}",
+                    manifest.root_id, ROOT_ID
+                )
+                .into(),
+            ));
+        }
+        let entries: Vec<_> = manifest
+            .page_table
+            .iter()
+            .map(|&(id, addr)| (id, PageAddr::Disk(addr).into()))
+            .collect();
+        let table = PageTable::with_entries(&entries, manifest.next_page_id);
+        let counter = |name: &str| {
+            manifest
+                .counters
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, value)| *value)
+        };
+        // Stores that are not checkpointed with a named comparator yet are not checked.
+        let comparator = opts.comparator.name();
+        if let Some(recorded) = manifest
+            .counters
+            .iter()
+            .find_map(|(name, _)| name.strip_prefix(COMPARATOR_COUNTER_PREFIX))
+        {
+            if recorded != comparator {
+                return Err(Error::InvalidArgument(format!(
+                    "{} is ordered by comparator {:?}, not {:?}",
+                    path.as_ref().display(),
+                    recorded,
+                    comparator
+                )));
+            }
+        }
+        let last_lsn = counter(LAST_LSN_COUNTER);
+        let history_ts_low = counter(HISTORY_TS_LOW_COUNTER).unwrap_or(0);
+        let applied_epoch = counter(APPLIED_EPOCH_COUNTER).unwrap_or(0);
+        let applied_lsn = counter(APPLIED_LSN_COUNTER).unwrap_or(0);
+        // Changes since the last checkpoint may be lost in a crash, so the stream positions of the
+        // last open can't be resumed from.
+        let stream_epoch = RandomState::new().build_hasher().finish().max(1);
+        let live_keys = counter(LIVE_KEYS_COUNTER).unwrap_or(0);
+        let max_lsn = manifest
+            .files
+            .iter()
+            .map(|(_, lsn)| *lsn)
+            .chain(last_lsn)
+            .max();
+        record!("pages", entries.len());
+        record!("lsn", max_lsn.unwrap_or(0));
+        events.record(EventKind::ManifestRecovered {
+            pages: entries.len(),
+            files: manifest.files.len(),
+            lsn: max_lsn.unwrap_or(0),
+        });
+        let recovered_stats = if opts.persist_stats {
+            LifetimeStats::from_counters(&manifest.counters)
+        } else {
+            LifetimeStats::default()
+        };
         let tree = Self {
-            opts,
             table,
             cache,
             store,
+            sched: Scheduler::default(),
+            jobs: JobScheduler::new(opts.max_background_jobs),
+            contention: ContentionTracker::default(),
+            access: AccessTracker::new(opts.delta_length_policy, opts.data_delta_length),
+            checkpoint_lock: AsyncMutex::new(()),
+            last_checkpoint: Mutex::new(Instant::now()),
+            max_lsn: AtomicU64::new(max_lsn.unwrap_or(0)),
+            durable_lsn: AtomicU64::new(max_lsn.unwrap_or(0)),
+            writes: WriteTracker::new(max_lsn.unwrap_or(0)),
+            checkpoint_epoch: AtomicU64::new(0),
+            durable_epoch: AtomicU64::new(0),
+            history_ts_low: AtomicU64::new(history_ts_low),
+            applied: Mutex::new((applied_epoch, applied_lsn)),
+            apply_lock: AsyncMutex::new(()),
+            live_keys: AtomicI64::new(live_keys as i64),
+            changes: ChangePublisher::new(opts.replication_buffer_size, stream_epoch),
+            events,
+            num_oversize_writes: AtomicU64::new(0),
+            num_delta_merges: AtomicU64::new(0),
+            oversize_bytes: AtomicU64::new(0),
+            rate_limiter: opts.write_rate_limit.map(RateLimiter::new),
+            dirty_bytes: AtomicU64::new(0),
+            num_throttled_writes: AtomicU64::new(0),
+            throttle_micros: AtomicU64::new(0),
+            recovered_stats,
+            num_puts: AtomicU64::new(0),
+            num_deletes: AtomicU64::new(0),
+            write_bytes: AtomicU64::new(0),
+            num_checkpoints: AtomicU64::new(0),
+            height: AtomicUsize::new(2),
+            env,
+            opts,
+        };
+        let tree = if entries.is_empty() {
+            tree.init()?
+        } else {
+            let height = tree.recover_height().await?;
+            tree.height.store(height, Ordering::Relaxed);
+            tree
+        };
+        let elapsed = start.elapsed();
+        tree.events.record(EventKind::Opened { elapsed });
+        let lsn = tree.last_lsn();
+        tree.notify(|listener| listener.on_recovery_done(lsn, elapsed));
+        Ok(tree)
+    }
+
+    /// Subscribes to the changes committed after this call.
+    pub fn subscribe(&self) -> ChangeStream {
+        self.changes.subscribe()
+    }
+
+    /// Subscribes to the changes after the stream LSN `lsn` of `epoch`.
+    pub fn subscribe_from(&self, epoch: u64, lsn: u64) -> Result<ChangeStream> {
+        self.changes.subscribe_from(epoch, lsn)
+    }
+
+    /// Applies the changes of `batch` unless a batch of the same epoch and the same or a later LSN
+    /// is applied already, and returns whether the batch is applied.
+    ///
+    /// The epoch and the LSN of the last applied batch are recorded at checkpoints, along with the
+    /// changes of the batches before it, so a replica that restarts or reconnects can apply a
+    /// stream again from an earlier position.
+    pub async fn apply_batch_with_lsn(&self, batch: &ChangeBatch, ghost: &Ghost) -> Result<bool> {
+        let _guard = self.apply_lock.lock().await;
+        let (epoch, lsn) = *self.applied.lock().unwrap();
+        if batch.epoch == epoch && batch.lsn <= lsn {
+            return Ok(false);
+        }
+        for change in &batch.changes {
+            match &change.value {
+                Some(value) => self.put(&change.key, change.lsn, value, ghost).await?,
+                None => self.delete(&change.key, change.lsn, ghost).await?,
+            }
+        }
+        *self.applied.lock().unwrap() = (batch.epoch, batch.lsn);
+        Ok(true)
+    }
+
+    /// Returns the epoch of the last batch applied by `apply_batch_with_lsn`, or 0 if there is
+    /// none.
+    pub fn applied_epoch(&self) -> u64 {
+        self.applied.lock().unwrap().0
+    }
+
+    /// Returns the LSN of the last batch applied by `apply_batch_with_lsn`, or 0 if there is none.
+    pub fn applied_lsn(&self) -> u64 {
+        self.applied.lock().unwrap().1
+    }
+
+    /// Returns the approximate number of keys whose newest versions are puts, without scanning
+    /// the tree.
+    ///
+    /// Overwrites of existing keys and deletes of missing keys are miscounted until their leaves
+    /// are consolidated. Tables written before the count was recorded start from zero.
+    pub fn len_estimate(&self) -> u64 {
+        self.live_keys.load(Ordering::Relaxed).max(0) as u64
+    }
+
+    /// Returns the latest events, from the oldest to the newest.
+    pub fn events(&self) -> Vec<Event> {
+        self.events.events()
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            stall: self.sched.stats(),
+            alloc: self.cache.alloc_stats(),
+            cache: self.cache.stats(),
+            contention: self.contention.stats(),
+            write: WriteStats {
+                num_oversize_writes: self.num_oversize_writes.load(Ordering::Relaxed),
+                num_delta_merges: self.num_delta_merges.load(Ordering::Relaxed),
+                oversize_bytes: self.oversize_bytes.load(Ordering::Relaxed),
+                dirty_bytes: self.dirty_bytes.load(Ordering::Relaxed),
+                num_throttled_writes: self.num_throttled_writes.load(Ordering::Relaxed),
+                throttle_micros: self.throttle_micros.load(Ordering::Relaxed),
+            },
+            lifetime: self.lifetime_stats(),
+            tier: self.store.tier_stats(),
+            jobs: self.jobs.stats(),
+            ghost: ghost::stats(),
+            height: self.height.load(Ordering::Relaxed),
+        }
+    }
+
+    fn lifetime_stats(&self) -> LifetimeStats {
+        let flush_bytes = self
+            .store
+            .io_stats()
+            .ops
+            .iter()
+            .find(|stats| stats.op == IoOp::Flush)
+            .map_or(0, |stats| stats.io.write_bytes);
+        let stats = LifetimeStats {
+            num_puts: self.num_puts.load(Ordering::Relaxed),
+            num_deletes: self.num_deletes.load(Ordering::Relaxed),
+            write_bytes: self.write_bytes.load(Ordering::Relaxed),
+            flush_bytes,
+            num_checkpoints: self.num_checkpoints.load(Ordering::Relaxed),
         };
-        tree.init()
+        self.recovered_stats.add(&stats)
+    }
+
+    pub fn io_stats(&self) -> IoStats {
+        self.store.io_stats()
     }
 
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "debug",
+            skip_all,
+            fields(lsn = lsn, node = Empty, bytes = Empty)
+        )
+    )]
     pub async fn get<'a, 'g>(
         &'a self,
         key: &[u8],
@@ -44,19 +692,145 @@ impl BTree {
         ghost: &'g Ghost,
     ) -> Result<Option<&'g [u8]>> {
         let key = Key::new(key, lsn);
+        let _guard = self.sched.begin(Work::Read);
+        let mut retry = Retry::new(self, key.raw, ghost);
+        loop {
+            match self.try_get(key, ghost).await {
+                Err(err) => retry.on_error(err)?,
+                Ok(value) => {
+                    record!("bytes", value.map_or(0, |v| v.len()));
+                    return Ok(value);
+                }
+            }
+        }
+    }
+
+    /// Returns true if `key` has a value at `lsn`, without copying the value out of the tree.
+    ///
+    /// The walk of the leaf stops at the first version of the key at exactly `lsn`, like `get`.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(lsn = lsn, node = Empty))
+    )]
+    pub async fn contains(&self, key: &[u8], lsn: u64, ghost: &Ghost) -> Result<bool> {
+        let key = Key::new(key, lsn);
+        let _guard = self.sched.begin(Work::Read);
+        let mut retry = Retry::new(self, key.raw, ghost);
         loop {
             match self.try_get(key, ghost).await {
-                Err(Error::Again) => continue,
-                other => return other,
+                Err(err) => retry.on_error(err)?,
+                Ok(value) => return Ok(value.is_some()),
+            }
+        }
+    }
+
+    /// Gets the values of multiple keys, in the order of the keys.
+    ///
+    /// Keys are sorted and grouped by leaf, so that each leaf is found only once. The leaves are
+    /// then read concurrently, so that the leaves on disk are loaded in parallel.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "debug",
+            skip_all,
+            fields(keys = keys.len(), lsn = lsn, nodes = Empty)
+        )
+    )]
+    pub async fn get_many<'g>(
+        &self,
+        keys: &[&[u8]],
+        lsn: u64,
+        ghost: &'g Ghost,
+    ) -> Result<Vec<Option<&'g [u8]>>> {
+        let _guard = self.sched.begin(Work::Read);
+        let mut order: Vec<usize> = (0..keys.len()).collect();
+        order.sort_by(|&a, &b| self.compare(keys[a], keys[b]));
+        let mut groups = Vec::new();
+        let mut i = 0;
+        while i < order.len() {
+            let key = keys[order[i]];
+            let mut retry = Retry::new(self, key, ghost);
+            let node = loop {
+                match self.try_find_leaf(key, ghost).await {
+                    Err(err) => retry.on_error(err)?,
+                    Ok(node) => break node,
+                }
+            };
+            let start = i;
+            i += 1;
+            while i < order.len() {
+                let key = keys[order[i]];
+                if !node.range.end.is_empty() && self.compare(key, node.range.end).is_ge() {
+                    break;
+                }
+                i += 1;
+            }
+            let group: Vec<_> = order[start..i].iter().map(|&i| keys[i]).collect();
+            groups.push((node, group));
+        }
+        record!("nodes", groups.len());
+
+        let results = try_join_all(
+            groups
+                .iter()
+                .map(|(node, group)| self.lookup_values(node, group, lsn, ghost)),
+        )
+        .await?;
+        let mut values = vec![None; keys.len()];
+        for (&i, value) in order.iter().zip(results.into_iter().flatten()) {
+            values[i] = value;
+        }
+        Ok(values)
+    }
+
+    /// Looks up the values of keys in a leaf, and loads the leaf first if it is on disk.
+    async fn lookup_values<'g>(
+        &self,
+        node: &Node<'_>,
+        keys: &[&[u8]],
+        lsn: u64,
+        ghost: &'g Ghost,
+    ) -> Result<Vec<Option<&'g [u8]>>> {
+        let mut values = Vec::with_capacity(keys.len());
+        let view = match self
+            .access_page_with_view(node.id, &node.view, CacheTier::Hot, ghost)
+            .await
+        {
+            Ok(page) => page.into(),
+            // The leaf has changed since it was found, so looks up the keys one by one.
+            Err(Error::Again { .. }) => {
+                for key in keys {
+                    values.push(self.get(key, lsn, ghost).await?);
+                }
+                return Ok(values);
             }
+            Err(err) => return Err(err),
+        };
+        let node = Node {
+            id: node.id,
+            view,
+            range: node.range.clone(),
+        };
+        for key in keys {
+            values.push(self.lookup_value(Key::new(key, lsn), &node, ghost).await?);
         }
+        Ok(values)
     }
 
     async fn try_get<'a, 'g>(&'a self, key: Key<'_>, ghost: &'g Ghost) -> Result<Option<&'g [u8]>> {
-        let node = self.try_find_node(key.raw, ghost).await?;
+        let node = self.try_find_node(key.raw, CacheTier::Hot, ghost).await?;
+        record!("node", node.id);
         self.lookup_value(key, &node, ghost).await
     }
 
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "debug",
+            skip_all,
+            fields(lsn = lsn, bytes = key.len() + value.len(), node = Empty, delta_len = Empty)
+        )
+    )]
     pub async fn put<'g>(
         &self,
         key: &[u8],
@@ -69,264 +843,3998 @@ impl BTree {
         self.update(key, value, ghost).await
     }
 
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "debug",
+            skip_all,
+            fields(lsn = lsn, bytes = key.len(), node = Empty, delta_len = Empty)
+        )
+    )]
     pub async fn delete<'g>(&self, key: &[u8], lsn: u64, ghost: &'g Ghost) -> Result<()> {
         let key = Key::new(key, lsn);
         let value = Value::Delete;
         self.update(key, value, ghost).await
     }
 
-    async fn update<'g>(&self, key: Key<'_>, value: Value<'_>, ghost: &'g Ghost) -> Result<()> {
-        let mut iter = OptionIter::from((key, value));
-        let mut page = DataPageBuilder::default().build_from_iter(&self.cache, &mut iter)?;
+    /// Calls `f` with the entries in `start..end` that are visible at `lsn`, in key order, until
+    /// `f` returns false. An empty `end` means that the range is unbounded.
+    ///
+    /// Values are transformed by `Options::value_transformer` if it is set.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "debug",
+            skip_all,
+            fields(lsn = lsn, nodes = Empty, bytes = Empty)
+        )
+    )]
+    pub async fn scan<'g, F>(
+        &self,
+        start: &[u8],
+        end: &[u8],
+        lsn: u64,
+        ghost: &'g Ghost,
+        mut f: F,
+    ) -> Result<()>
+    where
+        F: FnMut(&'g [u8], &[u8]) -> bool,
+    {
+        let mut bytes = 0;
+        let nodes = self
+            .scan_entries(start, end, lsn, ghost, |k, v| match v {
+                Some(v) => {
+                    bytes += k.raw.len() + v.len();
+                    f(k.raw, v)
+                }
+                None => true,
+            })
+            .await?;
+        record!("nodes", nodes);
+        record!("bytes", bytes);
+        Ok(())
+    }
+
+    /// Gets the value of `key` as of timestamp `ts`, which is the newest version of the key at or
+    /// before `ts` that is visible at `lsn`.
+    ///
+    /// `key` is a user key without a timestamp, and the table must be opened with a comparator
+    /// that has timestamps, see `TimestampComparator`. A delete at a timestamp hides the older
+    /// versions of the key. Reads at timestamps older than `history_ts_low` may not find the
+    /// versions that are dropped.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(ts = ts, lsn = lsn))
+    )]
+    pub async fn get_at(
+        &self,
+        key: &[u8],
+        ts: u64,
+        lsn: u64,
+        ghost: &Ghost,
+    ) -> Result<Option<Vec<u8>>> {
+        if !self.opts.comparator.has_timestamp() {
+            return Err(Error::NotSupported(
+                "the comparator of the table has no timestamps".to_owned(),
+            ));
+        }
+        let start = append_timestamp(key, ts);
+        let mut value = None;
+        self.scan_entries(&start, &[], lsn, ghost, |k, v| {
+            if matches!(split_timestamp(k.raw), Some((user_key, _)) if user_key == key) {
+                value = v.map(|v| v.to_vec());
+            }
+            false
+        })
+        .await?;
+        Ok(value)
+    }
+
+    /// Like `scan`, but calls `f` with the LSNs of the entries, and also with the entries that are
+    /// deleted at `lsn`, whose values are `None`. Returns the number of leaves scanned.
+    async fn scan_entries<'g, F>(
+        &self,
+        start: &[u8],
+        end: &[u8],
+        lsn: u64,
+        ghost: &'g Ghost,
+        mut f: F,
+    ) -> Result<usize>
+    where
+        F: FnMut(Key<'g>, Option<&[u8]>) -> bool,
+    {
+        let mut cursor = start.to_vec();
+        let mut nodes = 0;
+        // The parent of the last leaf, through which the next leaf is found.
+        let mut parent = None;
         loop {
-            match self.try_update(key.raw, page.as_ptr(), ghost).await {
-                Ok(_) => return Ok(()),
-                Err(Error::Again) => continue,
+            let next = self
+                .scan_leaf(&cursor, end, lsn, &mut parent, ghost, &mut f)
+                .await?;
+            nodes += 1;
+            match next {
+                Some(next) => cursor = next,
+                None => return Ok(nodes),
+            }
+        }
+    }
+
+    /// Like `scan_entries`, but only scans the leaf that contains `start`, and returns the start
+    /// of the next leaf if the scan should continue.
+    ///
+    /// The entries are read from one version of the leaf, and the returned start is the upper
+    /// boundary of that version, so the next leaf picks up the entries that are moved by splits.
+    ///
+    /// If `parent` is set, the leaf is looked up from it instead of from the root, and `parent` is
+    /// set to the parent of the leaf for the next call. It must be found under the same `ghost`.
+    async fn scan_leaf<'g, F>(
+        &self,
+        start: &[u8],
+        end: &[u8],
+        lsn: u64,
+        parent: &mut Option<OwnedNode>,
+        ghost: &'g Ghost,
+        mut f: F,
+    ) -> Result<Option<Vec<u8>>>
+    where
+        F: FnMut(Key<'g>, Option<&[u8]>) -> bool,
+    {
+        // Scans one leaf at a time, so that stalled writes don't wait for the whole scan.
+        let _guard = self.sched.begin(Work::Read);
+        let mut retry = Retry::new(self, start, ghost);
+        loop {
+            match self
+                .try_scan_node(start, end, lsn, parent, ghost, &mut f)
+                .await
+            {
                 Err(err) => {
-                    unsafe {
-                        self.cache.dealloc(page.as_ptr());
-                    }
-                    return Err(err);
+                    // Descends from the root on retries, which reconciles the stale nodes.
+                    *parent = None;
+                    retry.on_error(err)?
+                }
+                Ok(next) => return Ok(next),
+            }
+        }
+    }
+
+    /// Returns a cursor over the entries in `start..end` that are visible at `lsn`. An empty
+    /// `end` means that the range is unbounded.
+    ///
+    /// Values are transformed by `Options::value_transformer` if it is set.
+    pub fn cursor(&self, start: &[u8], end: &[u8], lsn: u64) -> Cursor<'_> {
+        Cursor {
+            tree: self,
+            end: end.to_vec(),
+            lsn,
+            next: Some(start.to_vec()),
+            last: None,
+            entries: VecDeque::new(),
+        }
+    }
+
+    /// Scans the leaf that contains `start` and returns the start of the next leaf if the scan
+    /// should continue.
+    async fn try_scan_node<'g, F>(
+        &self,
+        start: &[u8],
+        end: &[u8],
+        lsn: u64,
+        parent: &mut Option<OwnedNode>,
+        ghost: &'g Ghost,
+        f: &mut F,
+    ) -> Result<Option<Vec<u8>>>
+    where
+        F: FnMut(Key<'g>, Option<&[u8]>) -> bool,
+    {
+        let sibling = match parent {
+            Some(parent) => self.try_find_child(start, &parent.as_node(), ghost).await?,
+            None => None,
+        };
+        let mut node = match sibling.as_ref() {
+            Some(sibling) => sibling.as_node(),
+            None => {
+                let (node, found) = self.try_find_leaf_with_parent(start, ghost).await?;
+                *parent = found.as_ref().map(OwnedNode::new);
+                node
+            }
+        };
+        // Leaves swapped in by scans are admitted to the cold tier, so that large scans don't push
+        // the hot nodes out of the cache.
+        node.view = self
+            .access_page_with_view(node.id, &node.view, CacheTier::Cold, ghost)
+            .await?
+            .into();
+        let mut iter = self.iter_node::<Key, Value>(&node, ghost).await?;
+        let mut last: Option<&[u8]> = None;
+        while let Some((k, v)) = iter.next() {
+            // Versions of a key are ordered from the newest to the oldest.
+            if self.compare(k.raw, start).is_lt()
+                || k.lsn > lsn
+                || matches!(last, Some(last) if self.compare(k.raw, last).is_eq())
+            {
+                continue;
+            }
+            if !end.is_empty() && self.compare(k.raw, end).is_ge() {
+                return Ok(None);
+            }
+            last = Some(k.raw);
+            let more = match *v {
+                Value::Put(value) => {
+                    let transformed = self.transform_value(k.raw, value);
+                    f(*k, Some(transformed.as_deref().unwrap_or(value)))
                 }
+                Value::Delete => f(*k, None),
+            };
+            if !more {
+                return Ok(None);
             }
         }
+        // The node may have been split without being reconciled to its parent yet.
+        let high = iter.high.unwrap_or(node.range.end);
+        if high.is_empty() || (!end.is_empty() && self.compare(high, end).is_ge()) {
+            Ok(None)
+        } else {
+            Ok(Some(high.to_vec()))
+        }
     }
 
-    async fn try_update<'g>(&self, key: &[u8], mut delta: PagePtr, ghost: &'g Ghost) -> Result<()> {
-        let mut node = self.try_find_node(key, ghost).await?;
+    async fn update<'g>(&self, key: Key<'_>, value: Value<'_>, ghost: &'g Ghost) -> Result<()> {
+        if self.opts.read_only {
+            return Err(Error::ReadOnly);
+        }
+        // Updates the LSN first, so that a checkpoint that sees the update also sees the LSN.
+        self.max_lsn.fetch_max(key.lsn, Ordering::AcqRel);
+        let write = self.writes.begin(key.lsn);
+        let mut iter = OptionIter::from((key, value));
+        let alloc = self.cache.with_kind(AllocKind::PutDelta);
+        let mut page = self.page_builder().build_from_iter(&alloc, &mut iter)?;
+        let oversize = page.size() > self.opts.max_delta_size;
+        if oversize {
+            self.num_oversize_writes.fetch_add(1, Ordering::Relaxed);
+            self.oversize_bytes
+                .fetch_add(page.size() as u64, Ordering::Relaxed);
+        }
+        let mut retry = Retry::new(self, key.raw, ghost);
         loop {
-            delta.set_ver(node.view.ver());
-            delta.set_len(node.view.len() + 1);
-            delta.set_next(node.view.as_addr().into());
-            match self.table.cas(node.id, delta.next(), delta.into()) {
-                Ok(_) => {
-                    if delta.len() >= self.opts.data_delta_length {
-                        node.view = delta.into();
-                        let _ = self.try_consolidate_node::<Key, Value>(&node, ghost).await;
+            // Writes yield to reads when the cache is over budget after evictions, and then flush
+            // the tree so that the changed nodes can be evicted.
+            if !self.maybe_evict(ghost) {
+                self.sched.stall().await;
+                self.maybe_flush_for_eviction(ghost).await;
+                if let Some(max_stall) = self.opts.max_write_stall {
+                    if let Err(err) = self.wait_for_budget(max_stall, ghost).await {
+                        unsafe { self.cache.dealloc(page.as_ptr()) };
+                        return Err(err);
                     }
-                    return Ok(());
                 }
-                Err(addr) => {
-                    if let Some(view) = self.page_view(addr.into()) {
-                        if view.ver() == node.view.ver() {
-                            node.view = view;
-                            continue;
+            }
+            let err = match self
+                .try_update(key.raw, page.as_ptr(), oversize, ghost)
+                .await
+            {
+                Ok(backlog) => {
+                    write.complete();
+                    let value = match value {
+                        Value::Put(value) => {
+                            self.num_puts.fetch_add(1, Ordering::Relaxed);
+                            self.live_keys.fetch_add(1, Ordering::Relaxed);
+                            Some(value)
                         }
+                        Value::Delete => {
+                            self.num_deletes.fetch_add(1, Ordering::Relaxed);
+                            self.live_keys.fetch_sub(1, Ordering::Relaxed);
+                            None
+                        }
+                    };
+                    let size = key.raw.len() + value.map_or(0, |v| v.len());
+                    self.write_bytes.fetch_add(size as u64, Ordering::Relaxed);
+                    self.dirty_bytes.fetch_add(size as u64, Ordering::Relaxed);
+                    self.changes.publish(key.raw, key.lsn, value);
+                    let epoch = self.checkpoint_epoch.load(Ordering::Acquire);
+                    if self.opts.sync_mode == SyncMode::Always {
+                        self.sync_after(epoch, ghost).await?;
                     }
-                    return Err(Error::Again);
+                    self.maybe_throttle(size, backlog).await;
+                    return Ok(());
                 }
+                Err(err) => err,
+            };
+            if let Err(err) = retry.on_error(err) {
+                unsafe {
+                    self.cache.dealloc(page.as_ptr());
+                }
+                return Err(err);
             }
         }
     }
-}
 
-impl BTree {
-    fn init(self) -> Result<Self> {
-        let ghost = Ghost::pin();
-        // Initializes the tree as root -> leaf.
-        let root_id = self.table.alloc(ghost.guard()).unwrap();
-        let leaf_id = self.table.alloc(ghost.guard()).unwrap();
-        let mut leaf_page = DataPageBuilder::default().build(&self.cache)?;
-        self.table.set(leaf_id, leaf_page.as_ptr().into());
-        let mut root_iter = OptionIter::from(([].as_slice(), Index::with_id(leaf_id)));
-        let mut root_page =
-            DataPageBuilder::default().build_from_iter(&self.cache, &mut root_iter)?;
-        root_page.set_index(true);
-        self.table.set(root_id, root_page.as_ptr().into());
-        Ok(self)
+    /// Installs a delta on the leaf of `key`, and consolidates the leaf if the chain is too long
+    /// for `Options::delta_length_policy` or the delta is `oversize`. A chain that is only too
+    /// long may have its newest deltas merged instead, see `Options::data_delta_merge_length`.
+    ///
+    /// Returns the number of deltas left on the leaf.
+    async fn try_update(
+        &self,
+        key: &[u8],
+        delta: PagePtr,
+        oversize: bool,
+        ghost: &Ghost,
+    ) -> Result<u8> {
+        let mut node = self.try_find_node(key, CacheTier::Hot, ghost).await?;
+        record!("node", node.id);
+        if chain::install_delta(&self.table, node.id, node.view, delta).is_err() {
+            return Err(Error::Again {
+                node_id: node.id,
+                cause: Conflict::CasFailure,
+            });
+        }
+        record!("delta_len", delta.len());
+        self.access.record_write(node.id);
+        let delta_length = self.access.delta_length(node.id);
+        if oversize
+            || (delta.len() >= delta_length && !self.prefers_owner_node(delta, delta_length))
+            || delta.chain_size() as usize > self.opts.data_chain_size
+        {
+            node.view = delta.into();
+            if !oversize
+                && delta.chain_size() as usize <= self.opts.data_chain_size
+                && self.opts.data_delta_merge_length >= 2
+            {
+                if let Ok(Some(len)) = self.try_merge_deltas(&node, ghost) {
+                    return Ok(len);
+                }
+            }
+            if self.try_consolidate_leaf(&node, ghost).await.is_ok() {
+                return Ok(0);
+            }
+        }
+        Ok(delta.len())
     }
 
-    fn node(&self, id: u64) -> Node {
-        let addr = self.page_addr(id);
-        // Our access pattern ensures that the address must be valid.
-        let view = self.page_view(addr).unwrap();
-        Node { id, view }
+    /// Returns true if the leaf of `delta` should be consolidated by a thread on the NUMA node that
+    /// its base page is on, which is the case with `Options::numa_aware` until the chain grows to
+    /// twice `delta_length`.
+    fn prefers_owner_node(&self, delta: PagePtr, delta_length: u8) -> bool {
+        if !self.opts.numa_aware || delta.len() >= delta_length.saturating_mul(2) {
+            return false;
+        }
+        let mut base = delta;
+        while let PageAddr::Mem(addr) = base.next().into() {
+            match unsafe { PagePtr::new(addr as *mut u8) } {
+                Some(next) => base = next,
+                None => break,
+            }
+        }
+        self.cache.is_remote(base)
     }
 
-    fn page_addr(&self, id: u64) -> PageAddr {
-        self.table.get(id).into()
+    /// Merges the newest `Options::data_delta_merge_length` deltas of the leaf into one delta, so
+    /// that the chain gets shorter without rewriting the base page.
+    ///
+    /// Returns the number of deltas left on the leaf, or `None` if the newest pages are not all
+    /// data deltas in memory, in which case the leaf should be consolidated instead.
+    fn try_merge_deltas(&self, node: &Node<'_>, ghost: &Ghost) -> Result<Option<u8>> {
+        let num_pages = self.opts.data_delta_merge_length.min(node.view.len());
+        let mut page = match node.view {
+            PageView::Mem(page) => page,
+            PageView::Disk(..) => return Ok(None),
+        };
+        let mut merger = MergingIterBuilder::new(self.opts.comparator.clone()).dedup(true);
+        let mut addrs = Vec::with_capacity(num_pages as usize);
+        loop {
+            // Pages with a length are above the base page.
+            if page.len() == 0 {
+                return Ok(None);
+            }
+            match unsafe { TypedPageRef::<Key, Value>::cast(page) } {
+                TypedPageRef::Data(data) => merger.add(data.iter()),
+                TypedPageRef::Split(_) | TypedPageRef::Unknown => return Ok(None),
+            }
+            addrs.push(u64::from(page));
+            if addrs.len() == num_pages as usize {
+                break;
+            }
+            page = match PageAddr::from(page.next()) {
+                PageAddr::Mem(ptr) => match unsafe { PagePtr::new(ptr as *mut u8) } {
+                    Some(next) => next,
+                    None => return Ok(None),
+                },
+                PageAddr::Disk(_) => return Ok(None),
+            };
+        }
+        // Only the newest of equal entries is kept.
+        let mut iter = merger.build();
+        let alloc = self.cache.with_kind(AllocKind::Consolidation);
+        let mut delta = self.page_builder().build_from_iter(&alloc, &mut iter)?;
+        let chain_size = page.chain_size().saturating_sub(page.size() as u32);
+        let chain_size = chain_size.saturating_add(delta.size() as u32);
+        delta.set_ver(node.view.ver());
+        delta.set_len(node.view.len() - num_pages + 1);
+        delta.set_chain_size(chain_size);
+        delta.set_next(page.next());
+        let delta = delta.as_ptr();
+        if self
+            .table
+            .cas(node.id, node.view.as_addr().into(), delta.into())
+            .is_err()
+        {
+            unsafe { self.cache.dealloc(delta) };
+            return Err(Error::Again {
+                node_id: node.id,
+                cause: Conflict::CasFailure,
+            });
+        }
+        self.num_delta_merges.fetch_add(1, Ordering::Relaxed);
+        let cache = self.cache.clone();
+        let bytes = addrs
+            .iter()
+            .filter_map(|&addr| unsafe { PagePtr::new(addr as *mut u8) })
+            .map(|page| page.size())
+            .sum();
+        ghost.defer(bytes, move || unsafe {
+            for addr in addrs {
+                if let Some(page) = PagePtr::new(addr as *mut u8) {
+                    cache.dealloc(page);
+                }
+            }
+        });
+        Ok(Some(delta.len()))
     }
 
-    fn page_view(&self, addr: PageAddr) -> Option<PageView> {
-        match addr {
-            PageAddr::Mem(addr) => {
-                let page = unsafe { PagePtr::new(addr as *mut u8) };
-                page.map(PageView::from)
+    /// Writes all nodes to the store and records the page table in the manifest, so that the
+    /// tree can be recovered from the checkpoint when it is opened again.
+    pub async fn checkpoint(&self, ghost: &Ghost) -> Result<()> {
+        if self.opts.read_only {
+            return Err(Error::ReadOnly);
+        }
+        let _lock = self.checkpoint_lock.lock().await;
+        self.checkpoint_locked(ghost).await
+    }
+
+    /// Checkpoints the tree, and returns the durable LSN.
+    ///
+    /// Updates are only durable at checkpoints, so this is what applications wait for before they
+    /// acknowledge their commits. All the updates that complete before the call are recovered
+    /// when the tree is opened again. A read-only tree returns the LSN of the checkpoint that it
+    /// is opened from.
+    pub async fn flush(&self, ghost: &Ghost) -> Result<u64> {
+        if !self.opts.read_only {
+            self.checkpoint(ghost).await?;
+        }
+        Ok(self.durable_lsn())
+    }
+
+    /// Waits until the updates that complete before the call are durable, and returns the durable
+    /// LSN.
+    ///
+    /// Unlike `flush`, this follows `Options::sync_mode`: with `SyncMode::Every`, it waits for the
+    /// next periodic checkpoint, which it runs itself if the background one is late. Concurrent
+    /// waiters share checkpoints.
+    pub async fn wait_durable(&self, ghost: &Ghost) -> Result<u64> {
+        if self.opts.read_only {
+            return Ok(self.durable_lsn());
+        }
+        let epoch = self.checkpoint_epoch.load(Ordering::Acquire);
+        if let SyncMode::Every(interval) = self.opts.sync_mode {
+            let elapsed = self.last_checkpoint.lock().unwrap().elapsed();
+            if elapsed < interval {
+                self.env.sleep(interval - elapsed).await;
             }
-            PageAddr::Disk(addr) => self
+        }
+        self.sync_after(epoch, ghost).await?;
+        Ok(self.durable_lsn())
+    }
+
+    /// Allocates an LSN that is larger than all the LSNs allocated or written before.
+    ///
+    /// Allocated LSNs are recorded at checkpoints, so they are never reused after restarts, except
+    /// those allocated after the last checkpoint, whose writes are lost anyway.
+    pub fn next_lsn(&self) -> u64 {
+        self.max_lsn.fetch_add(1, Ordering::AcqRel) + 1
+    }
+
+    /// Returns the largest LSN that is allocated or written.
+    pub fn last_lsn(&self) -> u64 {
+        self.max_lsn.load(Ordering::Acquire)
+    }
+
+    /// Returns the largest LSN of the updates covered by the last checkpoint, below the updates
+    /// that were in flight when it started. LSNs allocated by `next_lsn` only count once their
+    /// updates complete.
+    pub fn durable_lsn(&self) -> u64 {
+        self.durable_lsn.load(Ordering::Acquire)
+    }
+
+    /// Raises the timestamp before which the history of keys is dropped, and returns the new one.
+    ///
+    /// Once a leaf is consolidated, only the versions of its keys at or after `ts`, and the newest
+    /// version before `ts`, are kept, regardless of the LSNs of the versions. The timestamp never
+    /// goes back, and is recorded at checkpoints. This has no effect unless the comparator has
+    /// timestamps.
+    pub fn set_history_ts_low(&self, ts: u64) -> u64 {
+        self.history_ts_low.fetch_max(ts, Ordering::AcqRel).max(ts)
+    }
+
+    /// Returns the timestamp before which the history of keys is dropped.
+    pub fn history_ts_low(&self) -> u64 {
+        self.history_ts_low.load(Ordering::Acquire)
+    }
+
+    /// Checkpoints the tree and closes it, so that it is opened again from the checkpoint.
+    ///
+    /// Background jobs run in the tasks of operations, so none of them are running once the tree
+    /// is owned. The lock of the directory is released when the tree is dropped at the end, and the
+    /// nodes in memory are freed once the pinned values that may see them are dropped. A read-only
+    /// tree is closed as is.
+    ///
+    /// Dropping the tree without closing it also releases the lock, but it can't wait for a
+    /// checkpoint, so the writes since the last one are lost, as if the process crashed.
+    pub async fn close(self, ghost: &Ghost) -> Result<()> {
+        if !self.opts.read_only {
+            self.checkpoint(ghost).await?;
+        }
+        Ok(())
+    }
+
+    /// Checkpoints the tree and exports it to `dir`, and returns the largest LSN that the backup
+    /// may contain.
+    ///
+    /// See `PageStore::backup` for the meaning of `since_lsn`. A read-only tree exports the
+    /// checkpoint that it is opened from.
+    pub async fn backup(&self, dir: &Path, since_lsn: u64, ghost: &Ghost) -> Result<u64> {
+        let _lock = self.checkpoint_lock.lock().await;
+        if !self.opts.read_only {
+            self.checkpoint_locked(ghost).await?;
+        }
+        self.store.backup(dir, since_lsn).await
+    }
+
+    /// Returns the approximate size of the leaves in `start..end`. An empty `end` means that the
+    /// range is unbounded.
+    ///
+    /// The size is summed from the pages of the leaves that overlap with the range, in memory or
+    /// on disk, without reading the leaves. Leaves that partially overlap with the range are
+    /// counted in proportion to their entries in the range if they are in memory, or by half
+    /// otherwise.
+    pub async fn approximate_size(&self, start: &[u8], end: &[u8], ghost: &Ghost) -> Result<u64> {
+        Ok(self.approximate_range(start, end, ghost).await?.0)
+    }
+
+    /// Returns the approximate number of entries in `start..end`, including the old versions and
+    /// deletes. An empty `end` means that the range is unbounded.
+    ///
+    /// The entries of the leaves in memory are counted from their pages, and the entries of the
+    /// leaves on disk are estimated from their sizes, with at most one leaf read as a sample.
+    pub async fn approximate_count(&self, start: &[u8], end: &[u8], ghost: &Ghost) -> Result<u64> {
+        Ok(self.approximate_range(start, end, ghost).await?.1)
+    }
+
+    /// Returns the approximate size and number of entries in `start..end`.
+    async fn approximate_range(
+        &self,
+        start: &[u8],
+        end: &[u8],
+        ghost: &Ghost,
+    ) -> Result<(u64, u64)> {
+        let mut retry = Retry::new(self, start, ghost);
+        loop {
+            match self.try_approximate_range(start, end, ghost).await {
+                Ok(approx) => return Ok(approx),
+                Err(err) => retry.on_error(err)?,
+            }
+        }
+    }
+
+    async fn try_approximate_range(
+        &self,
+        start: &[u8],
+        end: &[u8],
+        ghost: &Ghost,
+    ) -> Result<(u64, u64)> {
+        let in_range = |key: &[u8]| {
+            self.compare(key, start).is_ge() && (end.is_empty() || self.compare(key, end).is_lt())
+        };
+        let leaves = self.try_find_leaves(start, end, ghost).await?;
+
+        let (mut size, mut count) = (0, 0);
+        // The sizes of the leaves on disk, whose entries are estimated at the end.
+        let mut disk_size = 0;
+        let mut disk_sample = None;
+        for (index, range) in &leaves {
+            let (low, high) = (range.start.as_slice(), range.end.as_slice());
+            let is_partial = self.compare(low, start).is_lt()
+                || (!end.is_empty() && (high.is_empty() || self.compare(high, end).is_gt()));
+            let node = self.node(index.id, low..high)?;
+            if node.view.ver() != index.ver {
+                // The node has been split, so reconciles it before the next try.
+                self.try_find_leaf(low, ghost).await?;
+                return Err(Error::Again {
+                    node_id: node.id,
+                    cause: Conflict::StaleNode,
+                });
+            }
+            if let PageView::Disk(_, addr) = node.view {
+                let mut page_size = self.store.page_size(addr).ok_or(Error::Again {
+                    node_id: node.id,
+                    cause: Conflict::StaleNode,
+                })?;
+                if is_partial {
+                    page_size /= 2;
+                }
+                size += page_size;
+                disk_size += page_size;
+                disk_sample.get_or_insert((node.id, addr));
+                continue;
+            }
+
+            let (mut page_size, mut total, mut covered) = (0, 0, 0);
+            let mut is_split = false;
+            self.walk_node(&node, ghost, |page| {
+                page_size += page.size() as u64;
+                match unsafe { TypedPageRef::<Key, Value>::cast(page) } {
+                    TypedPageRef::Data(data) => {
+                        total += data.len() as u64;
+                        covered += if is_partial || is_split {
+                            (0..data.len())
+                                .filter(|&i| {
+                                    let key = data.get(i).unwrap().0.raw;
+                                    in_range(key)
+                                        && (high.is_empty() || self.compare(key, high).is_lt())
+                                })
+                                .count() as u64
+                        } else {
+                            data.len() as u64
+                        };
+                    }
+                    // The pages below a split still have the entries moved to the right sibling.
+                    TypedPageRef::Split(_) => is_split = true,
+                    TypedPageRef::Unknown => {}
+                }
+                false
+            })
+            .await?;
+            size += (page_size * covered).checked_div(total).unwrap_or(0);
+            count += covered;
+        }
+
+        if let Some((id, addr)) = disk_sample {
+            let alloc = self.cache.with_kind(AllocKind::SwapIn);
+            let page = self
                 .store
-                .page_info(addr)
-                .map(|info| PageView::Disk(info, addr)),
+                .load_page(addr, &alloc)
+                .await?
+                .ok_or(Error::Again {
+                    node_id: id,
+                    cause: Conflict::StaleNode,
+                })?;
+            // Only the first page of a leaf written as deltas is sampled.
+            let sample_size = self.store.page_size(addr).unwrap_or(0);
+            let entries = match unsafe { TypedPageRef::<Key, Value>::cast(page) } {
+                TypedPageRef::Data(data) => data.len() as u64,
+                TypedPageRef::Split(_) | TypedPageRef::Unknown => 0,
+            };
+            unsafe { dealloc_chain(&alloc, page) };
+            count += (disk_size * entries).checked_div(sample_size).unwrap_or(0);
         }
+        Ok((size, count))
     }
 
-    fn dealloc_page_chain<'g>(&self, mut addr: PageAddr, ghost: &'g Ghost) {
-        let cache = self.cache.clone();
-        ghost.guard().defer(move || unsafe {
-            while let PageAddr::Mem(ptr) = addr {
-                if let Some(page) = PagePtr::new(ptr as *mut u8) {
-                    addr = page.next().into();
-                    cache.dealloc(page);
-                } else {
-                    break;
+    /// Returns the leaves that overlap `start..end` in order, with their indexes and ranges. An
+    /// empty `end` means that the range is unbounded.
+    ///
+    /// The index nodes are walked from the root, and swapped in if they are on disk, but the
+    /// versions of the leaves are left to the callers to check.
+    async fn try_find_leaves(
+        &self,
+        start: &[u8],
+        end: &[u8],
+        ghost: &Ghost,
+    ) -> Result<Vec<(Index, Range<Vec<u8>>)>> {
+        let mut leaves = Vec::new();
+        let mut stack = vec![(ROOT_INDEX, Vec::new()..Vec::new())];
+        while let Some((index, range)) = stack.pop() {
+            let node = self.node(index.id, range.start.as_slice()..range.end.as_slice())?;
+            if !node.view.is_index() {
+                leaves.push((index, range));
+                continue;
+            }
+            if node.view.ver() != index.ver {
+                // The node has been split, so reconciles it before the next try.
+                self.try_find_leaf(&range.start, ghost).await?;
+                return Err(Error::Again {
+                    node_id: node.id,
+                    cause: Conflict::StaleNode,
+                });
+            }
+            let mut iter = self.iter_node::<&[u8], Index>(&node, ghost).await?;
+            iter.rewind();
+            let mut children = Vec::new();
+            while let Some(&(key, index)) = iter.next() {
+                children.push((key, index));
+            }
+            // Children are pushed in the reverse order, so that they are popped in order.
+            for (i, &(low, index)) in children.iter().enumerate().rev() {
+                let high = children.get(i + 1).map_or(node.range.end, |c| c.0);
+                if (!end.is_empty() && self.compare(low, end).is_ge())
+                    || (!high.is_empty() && self.compare(high, start).is_le())
+                {
+                    continue;
                 }
+                stack.push((index, low.to_vec()..high.to_vec()));
             }
-        });
+        }
+        Ok(leaves)
     }
 
-    async fn load_page_with_view(&self, id: u64, view: &PageView) -> Result<PagePtr> {
-        match *view {
-            PageView::Mem(page) => Ok(page),
-            PageView::Disk(_, addr) => {
-                // self.swapin_page(id, addr).await,
-                todo!()
+    /// Returns about `n` keys sampled from the tree, sorted by the comparator.
+    ///
+    /// Each sample walks a random path from the root to a leaf, and picks a random key that is not
+    /// deleted from the leaf, so the samples are about uniform as long as the leaves have similar
+    /// numbers of keys. Samples that land on leaves without such keys are dropped, and so are
+    /// duplicate samples, so fewer than `n` keys may be returned.
+    /// Leaves on disk are swapped in like scans do.
+    pub async fn sample_keys(&self, n: usize, ghost: &Ghost) -> Result<Vec<Vec<u8>>> {
+        let seed = RandomState::new().build_hasher().finish();
+        self.sample_keys_with_rng(n, &mut Rng(seed), ghost).await
+    }
+
+    async fn sample_keys_with_rng(
+        &self,
+        n: usize,
+        rng: &mut Rng,
+        ghost: &Ghost,
+    ) -> Result<Vec<Vec<u8>>> {
+        let mut retry = Retry::new(self, &[], ghost);
+        let mut keys = loop {
+            match self.try_sample_keys(n, rng, ghost).await {
+                Ok(keys) => break keys,
+                Err(err) => retry.on_error(err)?,
+            }
+        };
+        keys.sort_unstable_by(|a, b| self.compare(a, b));
+        keys.dedup();
+        Ok(keys)
+    }
+
+    async fn try_sample_keys(
+        &self,
+        n: usize,
+        rng: &mut Rng,
+        ghost: &Ghost,
+    ) -> Result<Vec<Vec<u8>>> {
+        let leaves = self.try_find_leaves(&[], &[], ghost).await?;
+        // The number of samples from each leaf, so that each leaf is read once.
+        let mut counts = vec![0; leaves.len()];
+        for _ in 0..n {
+            counts[rng.below(leaves.len() as u64) as usize] += 1;
+        }
+
+        let mut samples = Vec::with_capacity(n);
+        for (i, (index, range)) in leaves.iter().enumerate() {
+            if counts[i] == 0 {
+                continue;
+            }
+            let (low, high) = (range.start.as_slice(), range.end.as_slice());
+            let mut node = self.node(index.id, low..high)?;
+            if node.view.ver() != index.ver {
+                // The node has been split, so reconciles it before the next try.
+                self.try_find_leaf(low, ghost).await?;
+                return Err(Error::Again {
+                    node_id: node.id,
+                    cause: Conflict::StaleNode,
+                });
+            }
+            node.view = self
+                .access_page_with_view(node.id, &node.view, CacheTier::Cold, ghost)
+                .await?
+                .into();
+            let mut iter = self.iter_node::<Key, Value>(&node, ghost).await?;
+            let mut keys = Vec::new();
+            let mut last: Option<&[u8]> = None;
+            while let Some((k, v)) = iter.next() {
+                // Versions of a key are ordered from the newest to the oldest.
+                if matches!(last, Some(last) if self.compare(k.raw, last).is_eq()) {
+                    continue;
+                }
+                last = Some(k.raw);
+                if let Value::Put(_) = v {
+                    keys.push(k.raw);
+                }
+            }
+            if keys.is_empty() {
+                continue;
+            }
+            for _ in 0..counts[i] {
+                let key = keys[rng.below(keys.len() as u64) as usize];
+                samples.push(key.to_vec());
             }
         }
+        Ok(samples)
     }
 
-    async fn load_page_with_addr(&self, id: u64, addr: PageAddr) -> Result<Option<PagePtr>> {
-        match addr {
-            PageAddr::Mem(addr) => {
-                let page = unsafe { PagePtr::new(addr as *mut u8) };
-                Ok(page)
+    /// Walks the tree and returns its shape with the space usage of the page files.
+    ///
+    /// Index nodes on disk are swapped in, but leaves are not.
+    pub async fn inspect(&self, ghost: &Ghost) -> Result<TreeInfo> {
+        let _lock = self.checkpoint_lock.lock().await;
+        let mut retry = Retry::new(self, &[], ghost);
+        let mut info = loop {
+            match self.try_inspect(ghost).await {
+                Ok(info) => break info,
+                Err(err) => retry.on_error(err)?,
             }
-            PageAddr::Disk(addr) => {
-                // self.swapin_page(id, addr).await,
-                todo!()
+        };
+        info.files = self.store.file_infos().await;
+        Ok(info)
+    }
+
+    /// Returns a breakdown of the memory used by the tree.
+    ///
+    /// The pages in memory are counted by walking the tree, where index nodes on disk are swapped
+    /// in, but leaves are not.
+    pub async fn memory_usage(&self, ghost: &Ghost) -> Result<MemoryUsage> {
+        let mut retry = Retry::new(self, &[], ghost);
+        let mut usage = loop {
+            match self.try_memory_usage(ghost).await {
+                Ok(usage) => break usage,
+                Err(err) => retry.on_error(err)?,
             }
+        };
+        usage.cache_bytes = self.cache.size() as u64;
+        usage.page_table_bytes = self.table.memory_size() as u64;
+        usage.deferred_bytes = ghost::stats().deferred_bytes;
+        usage.num_pending_jobs = self.jobs.num_pending() as u64;
+        Ok(usage)
+    }
+
+    /// Returns the manifest of the last checkpoint.
+    pub async fn manifest(&self) -> ManifestInfo {
+        self.store.manifest_info().await
+    }
+
+    /// Repairs the tree in `path`, which must not be opened, by rebuilding it from the leaves that
+    /// are still readable.
+    pub async fn repair(env: Arc<dyn Env>, path: &Path, opts: Options) -> Result<RepairReport> {
+        let cache = PageCache::default();
+        PageStore::repair(env, path, &opts, ROOT_ID, &cache).await
+    }
+
+    /// Migrates the tree in `path`, which must not be opened, to the current format version, and
+    /// returns the version that it is migrated from.
+    pub async fn migrate(env: Arc<dyn Env>, path: &Path, opts: Options) -> Result<u32> {
+        PageStore::migrate(env, path, &opts).await
+    }
+
+    /// Links the leaves of a page file written by `PageFileWriter` into the tree.
+    ///
+    /// The leaves are written to the page files of the tree as they are, and then linked as the
+    /// right siblings of the leaf that covers their keys, one at a time from the last one, so no
+    /// entries go through the write path. The keys of the file must not overlap with the keys of
+    /// the tree, including the deleted ones, or this fails with `Error::Overlap` before anything is
+    /// linked. If keys in the range of the file are written during the ingestion, it fails with
+    /// some of the leaves linked.
+    ///
+    /// Like writes, the leaves are durable at the next checkpoint. They are not published to
+    /// `ChangeStream`s.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(pages = Empty))
+    )]
+    pub async fn ingest(&self, path: &Path, ghost: &Ghost) -> Result<()> {
+        if self.opts.read_only {
+            return Err(Error::ReadOnly);
+        }
+        let file_size = self.env.file_size(path).await?;
+        let file = self.env.open_positional_reader(path).await?;
+        let reader = PageFileReader::open(file, file_size).await?;
+        let handles = reader.read_index().await?;
+        record!("pages", handles.len());
+        if handles.is_empty() {
+            return Ok(());
+        }
+        // Checkpoints remove the page files that the tree doesn't refer to, so they are held off
+        // until the leaves are linked.
+        let _lock = self.checkpoint_lock.lock().await;
+        let mut leaves = Vec::with_capacity(handles.len());
+        let mut linked = 0;
+        let mut max_lsn = 0;
+        let mut result = self
+            .write_ingested_leaves(&reader, &handles, &mut leaves, &mut max_lsn, ghost)
+            .await;
+        if result.is_ok() {
+            result = self.link_ingested_leaves(&leaves, &mut linked, ghost).await;
+        }
+        if result.is_ok() {
+            self.writes.complete(max_lsn);
         }
+        for leaf in &leaves[..leaves.len() - linked] {
+            self.table.dealloc(leaf.id, ghost.guard());
+        }
+        let live_keys = leaves[leaves.len() - linked..]
+            .iter()
+            .map(|leaf| leaf.live_keys)
+            .sum();
+        self.live_keys.fetch_add(live_keys, Ordering::Relaxed);
+        result
     }
 
-    async fn walk_node<F>(&self, node: &Node, mut f: F) -> Result<()>
-    where
-        F: FnMut(PagePtr) -> bool,
-    {
-        let mut page = self.load_page_with_view(node.id, &node.view).await?;
-        loop {
-            if f(page) {
-                break;
+    /// Writes the leaves of an ingested page file to the page files of the tree, allocates the
+    /// nodes of the leaves, and sets `max_lsn` to the largest LSN of the written entries.
+    async fn write_ingested_leaves(
+        &self,
+        reader: &PageFileReader<Box<dyn PositionalReader>>,
+        handles: &[PageHandle],
+        leaves: &mut Vec<IngestedLeaf>,
+        max_lsn: &mut u64,
+        ghost: &Ghost,
+    ) -> Result<()> {
+        /// The size of the leaves written to each page file.
+        const BATCH_SIZE: usize = 64 << 20;
+
+        let alloc = self.cache.with_kind(AllocKind::SwapIn);
+        let mut batch = Vec::new();
+        let mut batch_size = 0;
+        let mut result = Ok(());
+        for (i, handle) in handles.iter().enumerate() {
+            match self
+                .read_ingested_leaf(reader, handle, &alloc, &mut batch, leaves, ghost)
+                .await
+            {
+                Ok(lsn) => *max_lsn = (*max_lsn).max(lsn),
+                Err(err) => {
+                    result = Err(err);
+                    break;
+                }
             }
-            let next = page.next().into();
-            match self.load_page_with_addr(node.id, next).await? {
-                Some(next) => page = next,
-                None => break,
+            batch_size += batch.last().unwrap().1.size();
+            if batch_size >= BATCH_SIZE || i + 1 == handles.len() {
+                result = self.write_ingested_batch(&batch, *max_lsn).await;
+                for (_, page) in batch.drain(..) {
+                    unsafe { self.cache.dealloc(page) };
+                }
+                batch_size = 0;
+                if result.is_err() {
+                    break;
+                }
             }
         }
+        for (_, page) in batch {
+            unsafe { self.cache.dealloc(page) };
+        }
+        result
+    }
+
+    /// Reads and checks a leaf of an ingested page file, adds it to `batch` and `leaves`, and
+    /// returns the largest LSN of its entries.
+    async fn read_ingested_leaf(
+        &self,
+        reader: &PageFileReader<Box<dyn PositionalReader>>,
+        handle: &PageHandle,
+        alloc: &impl PageAlloc<Error = Error>,
+        batch: &mut Vec<(u64, PagePtr)>,
+        leaves: &mut Vec<IngestedLeaf>,
+        ghost: &Ghost,
+    ) -> Result<u64> {
+        let corrupted = |what: &str| {
+            Error::Corrupted(format!("page {} of ingested file {}", handle.id, what).into())
+        };
+        let image = reader.read_page(handle).await?;
+        let page = match decode_page_image(&image, alloc)? {
+            Some(page) => page,
+            None => return Err(corrupted("has a malformed image")),
+        };
+        let id = match self.table.alloc(ghost.guard()) {
+            Some(id) => id,
+            None => {
+                unsafe { alloc.dealloc(page) };
+                return Err(Error::Alloc);
+            }
+        };
+        batch.push((id, page));
+        leaves.push(IngestedLeaf {
+            id,
+            ver: page.ver(),
+            first: Vec::new(),
+            last: Vec::new(),
+            live_keys: 0,
+        });
+        let data = match unsafe { DataPageRef::<Key, Value>::new_checked(page) } {
+            Some(data) if !page.is_index() && data.len() > 0 => data,
+            _ => return Err(corrupted("is not a leaf")),
+        };
+        let cmp = self.opts.comparator.as_ref();
+        // Sort prefixes are compared as bytes.
+        if page.has_sort_prefixes() && !cmp.is_bytewise() {
+            return Err(corrupted("is written with another comparator"));
+        }
+        let first = data.get(0).unwrap().0.raw;
+        if let Some(problem) = check_page(&data, first..&[], cmp).into_iter().next() {
+            return Err(corrupted(&problem));
+        }
+        if let [.., prev, _] = leaves.as_slice() {
+            if cmp.compare(&prev.last, first).is_ge() {
+                return Err(corrupted("overlaps with the previous page"));
+            }
+        }
+        let max_lsn = (0..data.len())
+            .map(|i| data.get(i).unwrap().0.lsn)
+            .max()
+            .unwrap();
+        let leaf = leaves.last_mut().unwrap();
+        leaf.first = first.to_vec();
+        leaf.last = data.get(data.len() - 1).unwrap().0.raw.to_vec();
+        leaf.live_keys = self.count_live_keys(&mut data.iter(), None);
+        Ok(max_lsn)
+    }
+
+    /// Writes a batch of ingested leaves to a page file, and points their nodes to it.
+    async fn write_ingested_batch(&self, batch: &[(u64, PagePtr)], max_lsn: u64) -> Result<()> {
+        let addrs = self.store.write_pages(batch, max_lsn).await?;
+        // Updates the LSN first, so that a checkpoint that sees the leaves also sees the LSN.
+        self.max_lsn.fetch_max(max_lsn, Ordering::AcqRel);
+        for (&(id, _), addr) in batch.iter().zip(addrs) {
+            self.table.set(id, PageAddr::Disk(addr).into());
+        }
         Ok(())
     }
 
-    async fn iter_node<'g, K, V>(&self, node: &Node, ghost: &'g Ghost) -> Result<NodeIter<'g, K, V>>
-    where
-        K: Decodable + Ord,
-        V: Decodable,
-    {
-        let mut merger = MergingIterBuilder::default();
-        self.walk_node(node, |page| {
-            let page = unsafe { TypedPageRef::cast(page) };
-            if let TypedPageRef::Data(data) = page {
-                merger.add(data.iter());
+    /// Links the ingested leaves from the last one, and counts the linked ones in `linked`.
+    async fn link_ingested_leaves(
+        &self,
+        leaves: &[IngestedLeaf],
+        linked: &mut usize,
+        ghost: &Ghost,
+    ) -> Result<()> {
+        let (first, last) = (&leaves[0].first, &leaves[leaves.len() - 1].last);
+        let mut retry = Retry::new(self, first, ghost);
+        while *linked < leaves.len() {
+            let leaf = &leaves[leaves.len() - 1 - *linked];
+            match self.try_link_ingested_leaf(first, last, leaf, ghost).await {
+                Ok(true) => *linked += 1,
+                Ok(false) => {}
+                Err(err) => retry.on_error(err)?,
             }
-            false
-        })
-        .await?;
-        Ok(merger.build())
+        }
+        // Reconciles the last link.
+        loop {
+            match self.try_find_leaf(first, ghost).await {
+                Ok(_) => return Ok(()),
+                Err(err) => retry.on_error(err)?,
+            }
+        }
     }
 
-    async fn lookup_value<'a, 'g>(
-        &'a self,
-        key: Key<'_>,
-        node: &Node,
-        ghost: &'g Ghost,
-    ) -> Result<Option<&'g [u8]>> {
-        let mut value = None;
-        self.walk_node(node, |page| {
-            let page = unsafe { TypedPageRef::<'g, Key, Value>::cast(page) };
-            if let TypedPageRef::Data(data) = page {
-                if let Some((k, v)) = data.seek(&key) {
-                    if k.raw == key.raw {
-                        value = v.into();
-                        return true;
-                    }
+    /// Links `leaf` as the right sibling of the leaf that covers `first`, which is the first key
+    /// of the ingested file, and returns false if the leaf is not linked yet.
+    ///
+    /// Before the first link, the entries after `last` are split off to another leaf, so that the
+    /// leaves are linked to a range without entries.
+    async fn try_link_ingested_leaf(
+        &self,
+        first: &[u8],
+        last: &[u8],
+        leaf: &IngestedLeaf,
+        ghost: &Ghost,
+    ) -> Result<bool> {
+        let node = self.try_find_node(first, CacheTier::Hot, ghost).await?;
+        // Links add split pages to the node, which consolidations remove once they are
+        // reconciled.
+        if node.view.len() >= self.opts.data_delta_length.max(2) {
+            self.try_consolidate_leaf(&node, ghost).await?;
+            return Ok(false);
+        }
+        let end = node.range.end;
+        if !end.is_empty() && self.compare(&leaf.last, end).is_ge() {
+            return Err(Error::Overlap(format!(
+                "ingested keys overlap with the leaf that starts at {}",
+                end.escape_ascii()
+            )));
+        }
+        let mut iter = self.iter_node::<Key, Value>(&node, ghost).await?;
+        let mut tail = Vec::new();
+        while let Some(&(k, v)) = iter.next() {
+            if self.compare(k.raw, first).is_ge() {
+                if self.compare(k.raw, last).is_le() {
+                    return Err(Error::Overlap(format!(
+                        "ingested keys overlap with key {}",
+                        k.raw.escape_ascii()
+                    )));
                 }
+                tail.push((k, v));
             }
-            false
-        })
-        .await?;
-        Ok(value)
+        }
+        if !tail.is_empty() {
+            self.try_split_at(&node, &tail, ghost)?;
+            return Ok(false);
+        }
+
+        let alloc = self.cache.with_kind(AllocKind::Split);
+        let index = Index::new(leaf.id, leaf.ver);
+        let split = SplitPageBuilder::default().build_with_index(
+            &alloc,
+            leaf.first.as_slice()..end,
+            index,
+        )?;
+        let split = split.as_ptr();
+        if chain::install_split(&self.table, node.id, &node.view, split).is_err() {
+            unsafe { self.cache.dealloc(split) };
+            return Err(Error::Again {
+                node_id: node.id,
+                cause: Conflict::CasFailure,
+            });
+        }
+        Ok(true)
     }
 
-    async fn lookup_index<'a, 'g>(
-        &'a self,
-        key: &[u8],
-        node: &Node,
-        ghost: &'g Ghost,
-    ) -> Result<Option<Index>> {
-        let mut index = None;
-        self.walk_node(node, |page| {
-            let page = unsafe { TypedPageRef::<'g, &[u8], Index>::cast(page) };
-            if let TypedPageRef::Data(data) = page {
-                if let Some((_, v)) = data.seek_back(&key) {
-                    index = v.into();
-                    return true;
-                }
+    /// Builds the tree from `iter`, whose entries are sorted by the comparator, at `lsn`.
+    ///
+    /// The leaves are built from the entries directly, and then the root is rebuilt over them, so
+    /// the entries go through neither delta chains nor consolidations. It is meant for initial
+    /// loads, so the tree must be empty, or this fails with `Error::Overlap`. The leaves are built
+    /// in memory before they are installed.
+    ///
+    /// Like writes, the entries are durable at the next checkpoint. They are not published to
+    /// `ChangeStream`s.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a key is empty, or not after the previous key in the order of the comparator.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(leaves = Empty))
+    )]
+    pub async fn build_from_sorted_iter<I, K, V>(
+        &self,
+        iter: I,
+        lsn: u64,
+        ghost: &Ghost,
+    ) -> Result<()>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        if self.opts.read_only {
+            return Err(Error::ReadOnly);
+        }
+        let mut leaves = Vec::new();
+        let (mut num_entries, mut size) = (0, 0);
+        let mut result =
+            self.build_sorted_leaves(iter, lsn, &mut leaves, &mut num_entries, &mut size);
+        record!("leaves", leaves.len());
+        let mut ids = Vec::with_capacity(leaves.len());
+        if result.is_ok() && !leaves.is_empty() {
+            // Holds off checkpoints, so that they never see the first leaf without the new root.
+            let _lock = self.checkpoint_lock.lock().await;
+            result = self
+                .install_sorted_leaves(&leaves, &mut ids, lsn, ghost)
+                .await;
+        }
+        if let Err(err) = result {
+            for id in ids {
+                self.table.dealloc(id, ghost.guard());
             }
-            false
-        })
-        .await?;
-        Ok(index)
+            for (_, page) in leaves {
+                unsafe { self.cache.dealloc(page) };
+            }
+            return Err(err);
+        }
+        self.live_keys.fetch_add(num_entries, Ordering::Relaxed);
+        self.dirty_bytes.fetch_add(size, Ordering::Relaxed);
+        Ok(())
     }
 
-    async fn try_find_node<'g>(&self, key: &[u8], ghost: &'g Ghost) -> Result<Node> {
-        let mut cursor = ROOT_INDEX;
-        let mut parent = None;
-        loop {
-            let node = self.node(cursor.id);
-            if node.view.ver() != cursor.ver {
-                self.try_reconcile_node(&node, parent.as_ref(), ghost)?;
-                return Err(Error::Again);
+    /// Builds leaves of about `Options::node_size` bytes from the sorted entries, adds their
+    /// first keys and pages to `leaves`, and counts the entries in `num_entries` and their bytes
+    /// in `size`.
+    fn build_sorted_leaves<I, K, V>(
+        &self,
+        iter: I,
+        lsn: u64,
+        leaves: &mut Vec<(Vec<u8>, PagePtr)>,
+        num_entries: &mut i64,
+        size: &mut u64,
+    ) -> Result<()>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        let alloc = self.cache.with_kind(AllocKind::Consolidation);
+        let mut build = |entries: &[(K, V)]| -> Result<Vec<u8>> {
+            let data: Vec<_> = entries
+                .iter()
+                .map(|(k, v)| (Key::new(k.as_ref(), lsn), Value::Put(v.as_ref())))
+                .collect();
+            let mut iter = SliceIter::from(data.as_slice());
+            let mut page = self.page_builder().build_from_iter(&alloc, &mut iter)?;
+            leaves.push((entries[0].0.as_ref().to_vec(), page.as_ptr()));
+            Ok(entries[entries.len() - 1].0.as_ref().to_vec())
+        };
+
+        let mut entries: Vec<(K, V)> = Vec::new();
+        let mut page_size = 0;
+        // The last key of the built leaves.
+        let mut last_key: Option<Vec<u8>> = None;
+        for (key, value) in iter {
+            let (k, v) = (key.as_ref(), value.as_ref());
+            assert!(!k.is_empty(), "keys must not be empty");
+            let last = entries
+                .last()
+                .map(|(k, _)| k.as_ref())
+                .or(last_key.as_deref());
+            if let Some(last) = last {
+                assert!(
+                    self.compare(last, k).is_lt(),
+                    "keys must be sorted in increasing order"
+                );
             }
-            if node.view.is_index() {
-                cursor = self.lookup_index(key, &node, ghost).await?.unwrap();
-                parent = Some(node);
-            } else {
-                return Ok(node);
+            // Each entry also takes an offset and a sort prefix.
+            let entry_size = Key::new(k, lsn).encode_size() + Value::Put(v).encode_size() + 16;
+            *num_entries += 1;
+            *size += (k.len() + v.len()) as u64;
+            if !entries.is_empty()
+                && (page_size + entry_size > self.opts.node_size(false)
+                    || entries.len() >= self.opts.data_node_entries)
+            {
+                last_key = Some(build(&entries)?);
+                entries.clear();
+                page_size = 0;
             }
+            entries.push((key, value));
+            page_size += entry_size;
         }
+        if !entries.is_empty() {
+            build(&entries)?;
+        }
+        Ok(())
     }
 
-    fn try_reconcile_node<'g>(
+    /// Replaces the leaf of the empty tree with the first built leaf, and then the root with an
+    /// index of all the built leaves, whose nodes are allocated to `ids`.
+    ///
+    /// The replaced leaf gets a new version, so writes that find it before the new root retry
+    /// until the root is installed.
+    async fn install_sorted_leaves(
         &self,
-        node: &Node,
-        parent: Option<&Node>,
-        ghost: &'g Ghost,
+        leaves: &[(Vec<u8>, PagePtr)],
+        ids: &mut Vec<u64>,
+        lsn: u64,
+        ghost: &Ghost,
     ) -> Result<()> {
-        todo!()
+        for &(_, page) in &leaves[1..] {
+            let id = self.table.alloc(ghost.guard()).ok_or(Error::Alloc)?;
+            self.table.set(id, page.into());
+            ids.push(id);
+        }
+        // Updates the LSN first, so that a checkpoint that sees the leaves also sees the LSN.
+        self.max_lsn.fetch_max(lsn, Ordering::AcqRel);
+
+        let mut retry = Retry::new(self, &[], ghost);
+        let (node, root) = loop {
+            let err = match self.try_replace_empty_leaf(leaves, ids, ghost).await {
+                Ok(replaced) => break replaced,
+                Err(err) => err,
+            };
+            retry.on_error(err)?;
+        };
+        // Only swap-ins can change the root in between.
+        loop {
+            let view = self.page_view(self.page_addr(ROOT_ID)).unwrap();
+            if chain::install_page(&self.table, ROOT_ID, &view, root).is_ok() {
+                self.dealloc_page_chain(view.as_addr(), ghost);
+                break;
+            }
+        }
+        self.dealloc_page_chain(node.view.as_addr(), ghost);
+        self.writes.complete(lsn);
+        Ok(())
     }
 
-    async fn try_consolidate_node<'g, K, V>(
+    /// Replaces the only leaf of the tree with the first built leaf if the leaf has no entries,
+    /// and returns the replaced leaf and the new root to install.
+    async fn try_replace_empty_leaf<'g>(
         &self,
-        node: &Node,
+        leaves: &[(Vec<u8>, PagePtr)],
+        ids: &[u64],
         ghost: &'g Ghost,
-    ) -> Result<DataPageRef<'g, K, V>>
+    ) -> Result<(Node<'g>, PagePtr)> {
+        let node = self.try_find_node(&[], CacheTier::Hot, ghost).await?;
+        let mut entries = self.iter_node::<Key, Value>(&node, ghost).await?;
+        if !node.range.end.is_empty() || entries.next().is_some() {
+            return Err(Error::Overlap(
+                "sorted entries can only be built into an empty tree".to_string(),
+            ));
+        }
+
+        let mut data = vec![([].as_slice(), Index::new(node.id, node.view.ver().next()))];
+        for (&id, (key, page)) in ids.iter().zip(&leaves[1..]) {
+            data.push((key.as_slice(), Index::new(id, page.ver())));
+        }
+        let mut iter = SliceIter::from(data.as_slice());
+        let alloc = self.cache.with_kind(AllocKind::Consolidation);
+        let mut root = self.page_builder().build_from_iter(&alloc, &mut iter)?;
+        root.set_index(true);
+        let root = root.as_ptr();
+        if chain::replace_page(&self.table, node.id, &node.view, leaves[0].1).is_err() {
+            unsafe { self.cache.dealloc(root) };
+            return Err(Error::Again {
+                node_id: node.id,
+                cause: Conflict::CasFailure,
+            });
+        }
+        Ok((node, root))
+    }
+
+    /// Writes the entries in `start..end` that are visible at `lsn` to `writer` in the format of
+    /// `export`, and returns the number of entries written. An empty `end` means that the range is
+    /// unbounded.
+    ///
+    /// Each entry keeps the LSN of its newest version at `lsn`. The entries are scanned in chunks,
+    /// so writes during the export may be seen by the chunks after them.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(lsn = lsn, entries = Empty))
+    )]
+    pub async fn export<W>(
+        &self,
+        start: &[u8],
+        end: &[u8],
+        lsn: u64,
+        writer: &mut W,
+        ghost: &Ghost,
+    ) -> Result<u64>
     where
-        K: Encodable + Decodable + Ord,
-        V: Encodable + Decodable,
+        W: AsyncWrite + Unpin,
     {
-        let mut iter = self.iter_node::<K, V>(node, ghost).await?;
-        let mut page = DataPageBuilder::default().build_from_iter(&self.cache, &mut iter)?;
-        page.set_ver(node.view.ver());
-        page.set_index(node.view.is_index());
-
-        let new_ptr = page.as_ptr();
-        let old_addr = node.view.as_addr();
-        self.table
-            .cas(node.id, old_addr.into(), new_ptr.into())
-            .map_err(|_| {
-                unsafe { self.cache.dealloc(new_ptr) };
-                Error::Again
-            })?;
+        /// The size of the entries written at a time.
+        const CHUNK_SIZE: usize = 1 << 20;
 
-        self.dealloc_page_chain(old_addr, ghost);
-        Ok(page.as_ref())
+        let mut writer = ExportWriter::new(writer);
+        let mut cursor = start.to_vec();
+        // The last key written, which the next chunk starts from.
+        let mut last: Option<Vec<u8>> = None;
+        loop {
+            let mut more = false;
+            self.scan_entries(&cursor, end, lsn, ghost, |k, v| {
+                if matches!(&last, Some(last) if self.compare(k.raw, last).is_eq()) {
+                    return true;
+                }
+                if let Some(v) = v {
+                    writer.add(k.raw, v, k.lsn);
+                }
+                if writer.buffered() >= CHUNK_SIZE {
+                    last = Some(k.raw.to_vec());
+                    more = true;
+                    return false;
+                }
+                true
+            })
+            .await?;
+            writer.flush().await?;
+            if !more {
+                break;
+            }
+            cursor.clone_from(last.as_ref().unwrap());
+        }
+        let num_entries = writer.finish().await?;
+        record!("entries", num_entries);
+        Ok(num_entries)
+    }
+
+    /// Puts the entries of a file written by `export` to the tree at their own LSNs, and returns
+    /// the number of entries.
+    ///
+    /// The entries are checked as they are read, so if the file is corrupted, this fails with
+    /// `Error::Corrupted` after the entries before the corruption are put.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(entries = Empty))
+    )]
+    pub async fn import<R>(&self, reader: &mut R, ghost: &Ghost) -> Result<u64>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut reader = ExportReader::open(reader).await?;
+        let mut last: Option<Vec<u8>> = None;
+        let mut num_entries = 0;
+        while let Some((key, value, lsn)) = reader.next().await? {
+            if matches!(&last, Some(last) if self.compare(last, &key).is_ge()) {
+                return Err(Error::Corrupted(
+                    format!(
+                        "the export file has key {} out of order",
+                        key.escape_ascii()
+                    )
+                    .into(),
+                ));
+            }
+            self.put(&key, lsn, &value, ghost).await?;
+            last = Some(key);
+            num_entries += 1;
+        }
+        record!("entries", num_entries);
+        Ok(num_entries)
+    }
+
+    /// Verifies the tree of the last checkpoint on disk, and returns all the problems found.
+    ///
+    /// The tree is walked from the root without swapping nodes in. Each page is checked against
+    /// its checksum, the entries of each node must be in order and within the range that its
+    /// parent assigns to it, and all the nodes in the page table must be reachable from the root.
+    /// Errors are only returned if the manifest can't be read.
+    pub async fn verify(&self) -> Result<VerifyReport> {
+        // A checkpoint may remove the files of the last one.
+        let _lock = self.checkpoint_lock.lock().await;
+        let manifest = self.store.manifest().await;
+        let addrs: HashMap<u64, u64> = manifest.page_table.iter().copied().collect();
+        let mut report = VerifyReport::default();
+        if addrs.is_empty() {
+            // The tree is not checkpointed yet.
+            return Ok(report);
+        }
+        let cmp = self.opts.comparator.as_ref();
+        let mut reached = HashSet::from([manifest.root_id]);
+        let mut leaf_depths = HashSet::new();
+        // The nodes to verify, with their parents, the versions and ranges that the parents
+        // assign to them, and their depths.
+        let mut stack = vec![(
+            manifest.root_id,
+            None,
+            ROOT_INDEX.ver,
+            Vec::new(),
+            Vec::new(),
+            1,
+        )];
+        while let Some((id, parent, ver, low, high, depth)) = stack.pop() {
+            let mut problem = |msg: String| {
+                let from = match parent {
+                    Some(parent) => format!(" (child of node {})", parent),
+                    None => String::new(),
+                };
+                report
+                    .problems
+                    .push(format!("node {}{}: {}", id, from, msg));
+            };
+            let addr = match addrs.get(&id) {
+                Some(&addr) => addr,
+                None => {
+                    problem("not in the page table".to_owned());
+                    continue;
+                }
+            };
+            let page = match self.store.load_page(addr, &self.cache).await {
+                Ok(Some(page)) => page,
+                Ok(None) => {
+                    problem(format!("page at {} is not in the page files", addr));
+                    continue;
+                }
+                Err(err) => {
+                    problem(err.to_string());
+                    continue;
+                }
+            };
+            report.num_nodes += 1;
+            if page.ver() != ver {
+                problem(format!(
+                    "version {:?} doesn't match the index entry {:?}",
+                    page.ver(),
+                    ver
+                ));
+            }
+            let range = low.as_slice()..high.as_slice();
+            if page.is_index() {
+                let page_ref = unsafe { DataPageRef::<&[u8], Index>::new(page) };
+                report.num_entries += page_ref.len() as u64;
+                check_page(&page_ref, range, cmp)
+                    .into_iter()
+                    .for_each(&mut problem);
+                match page_ref.get(0) {
+                    Some((first, _)) if cmp.compare(first, &low).is_ne() => problem(format!(
+                        "first index entry {} doesn't match the node range start {}",
+                        first.escape_ascii(),
+                        low.escape_ascii()
+                    )),
+                    Some(_) => {}
+                    None => problem("index node has no entries".to_owned()),
+                }
+                for i in 0..page_ref.len() {
+                    let (key, index) = page_ref.get(i).unwrap();
+                    if !reached.insert(index.id) {
+                        problem(format!("child {} is reached more than once", index.id));
+                        continue;
+                    }
+                    let child_high = match page_ref.get(i + 1) {
+                        Some((next, _)) => next.to_vec(),
+                        None => high.clone(),
+                    };
+                    let child_low = key.to_vec();
+                    stack.push((
+                        index.id,
+                        Some(id),
+                        index.ver,
+                        child_low,
+                        child_high,
+                        depth + 1,
+                    ));
+                }
+            } else {
+                // Leaves written as deltas are checked page by page.
+                let mut next = Some(page);
+                while let Some(page) = next {
+                    let page_ref = unsafe { DataPageRef::<Key, Value>::new(page) };
+                    report.num_entries += page_ref.len() as u64;
+                    check_page(&page_ref, range.clone(), cmp)
+                        .into_iter()
+                        .for_each(&mut problem);
+                    next = match PageAddr::from(page.next()) {
+                        PageAddr::Mem(next) => unsafe { PagePtr::new(next as *mut u8) },
+                        PageAddr::Disk(_) => None,
+                    };
+                }
+                leaf_depths.insert(depth);
+            }
+            unsafe { dealloc_chain(&self.cache, page) };
+        }
+        if leaf_depths.len() > 1 {
+            let mut depths: Vec<_> = leaf_depths.into_iter().collect();
+            depths.sort_unstable();
+            report
+                .problems
+                .push(format!("leaves are at different depths {:?}", depths));
+        }
+        let mut unreachable: Vec<_> = addrs.keys().filter(|id| !reached.contains(id)).collect();
+        unreachable.sort_unstable();
+        for id in unreachable {
+            report
+                .problems
+                .push(format!("node {}: unreachable from the root", id));
+        }
+        Ok(report)
+    }
+
+    async fn try_inspect(&self, ghost: &Ghost) -> Result<TreeInfo> {
+        let mut info = TreeInfo::default();
+        self.try_walk_tree(ghost, |node, depth, pages| {
+            info.height = info.height.max(depth);
+            if node.view.is_index() {
+                info.num_index_nodes += 1;
+            } else {
+                info.num_leaf_nodes += 1;
+                if pages.is_empty() {
+                    info.num_disk_leaves += 1;
+                    return;
+                }
+            }
+            let chain_size = node.view.chain_size() as u64;
+            info.mem_bytes += chain_size;
+            info.max_chain_size = info.max_chain_size.max(chain_size);
+            info.num_mem_pages += pages.len() as u64;
+        })
+        .await?;
+        Ok(info)
+    }
+
+    async fn try_memory_usage(&self, ghost: &Ghost) -> Result<MemoryUsage> {
+        let mut usage = MemoryUsage::default();
+        self.try_walk_tree(ghost, |node, _, pages| {
+            for page in pages {
+                let size = page.size() as u64;
+                if node.view.is_index() {
+                    usage.index_bytes += size;
+                } else if page.len() == 0 {
+                    usage.base_bytes += size;
+                } else {
+                    usage.delta_bytes += size;
+                }
+            }
+        })
+        .await?;
+        Ok(usage)
+    }
+
+    /// Walks the nodes from the root, and calls `f` with each node, its depth, and its pages in
+    /// memory from the newest, which are empty for leaves on disk.
+    ///
+    /// Index nodes on disk are swapped in, but leaves are not.
+    async fn try_walk_tree<F>(&self, ghost: &Ghost, mut f: F) -> Result<()>
+    where
+        F: FnMut(&Node<'_>, usize, &[PagePtr]),
+    {
+        let mut stack = vec![(ROOT_INDEX, Vec::new(), 1)];
+        let mut pages = Vec::new();
+        while let Some((index, low, depth)) = stack.pop() {
+            let node = self.node(index.id, [].as_slice()..[].as_slice())?;
+            if node.view.ver() != index.ver {
+                // The node has been split, so reconciles it before the next try.
+                self.try_find_node(&low, CacheTier::Hot, ghost).await?;
+                return Err(Error::Again {
+                    node_id: node.id,
+                    cause: Conflict::StaleNode,
+                });
+            }
+            pages.clear();
+            if !node.view.is_index() {
+                if let PageView::Disk(..) = node.view {
+                    f(&node, depth, &pages);
+                    continue;
+                }
+            } else {
+                let mut iter = self.iter_node::<&[u8], Index>(&node, ghost).await?;
+                iter.rewind();
+                while let Some((key, index)) = iter.next() {
+                    stack.push((*index, key.to_vec(), depth + 1));
+                }
+            }
+            self.walk_node(&node, ghost, |page| {
+                pages.push(page);
+                false
+            })
+            .await?;
+            f(&node, depth, &pages);
+        }
+        Ok(())
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "checkpoint", skip_all, fields(pages = Empty, bytes = Empty))
+    )]
+    async fn checkpoint_locked(&self, ghost: &Ghost) -> Result<()> {
+        let start = Instant::now();
+        self.notify(|listener| listener.on_flush_begin());
+        let result = self.try_checkpoint_with_retry(ghost).await;
+        self.notify(|listener| listener.on_flush_end(start.elapsed(), &result));
+        result
+    }
+
+    async fn try_checkpoint_with_retry(&self, ghost: &Ghost) -> Result<()> {
+        let epoch = self.checkpoint_epoch.fetch_add(1, Ordering::AcqRel) + 1;
+        let mut retry = Retry::new(self, &[], ghost);
+        while let Err(err) = self.try_checkpoint(ghost).await {
+            retry.on_error(err)?;
+        }
+        self.durable_epoch.store(epoch, Ordering::Release);
+        *self.last_checkpoint.lock().unwrap() = Instant::now();
+        Ok(())
+    }
+
+    /// Calls `f` with `Options::event_listener` if it is set.
+    fn notify(&self, f: impl FnOnce(&dyn EventListener)) {
+        if let Some(listener) = &self.opts.event_listener {
+            f(listener.as_ref());
+        }
+    }
+
+    /// Makes the updates that complete before `epoch` of checkpoints durable, by the checkpoint
+    /// of another task if one starts after that, or by a new one otherwise.
+    async fn sync_after(&self, epoch: u64, ghost: &Ghost) -> Result<()> {
+        if self.durable_epoch.load(Ordering::Acquire) > epoch {
+            return Ok(());
+        }
+        let _lock = self.checkpoint_lock.lock().await;
+        if self.durable_epoch.load(Ordering::Acquire) > epoch {
+            return Ok(());
+        }
+        self.checkpoint_locked(ghost).await
+    }
+
+    /// Waits until evictions get the cache within budget, or fails with `Error::Stalled` after
+    /// `max_stall`.
+    async fn wait_for_budget(&self, max_stall: Duration, ghost: &Ghost) -> Result<()> {
+        /// The interval between evictions while waiting.
+        const INTERVAL: Duration = Duration::from_millis(1);
+
+        let start = Instant::now();
+        while !self.maybe_evict(ghost) {
+            if start.elapsed() >= max_stall {
+                return Err(Error::Stalled(format!(
+                    "the cache has {} bytes over a budget of {} for {:?}",
+                    self.cache.resident_size(),
+                    self.opts.cache_size,
+                    max_stall
+                )));
+            }
+            self.env.sleep(INTERVAL).await;
+        }
+        Ok(())
+    }
+
+    /// Delays a write of `size` bytes that leaves `backlog` deltas on its leaf, if the tree falls
+    /// behind by `Options::write_rate_limit`.
+    async fn maybe_throttle(&self, size: usize, backlog: u8) {
+        let limiter = match &self.rate_limiter {
+            Some(limiter) => limiter,
+            None => return,
+        };
+        let limit = limiter.limit();
+        if self.dirty_bytes.load(Ordering::Relaxed) <= limit.max_dirty_bytes
+            && backlog <= limit.max_delta_backlog
+        {
+            return;
+        }
+        let delay = limiter.acquire(size as u64);
+        if delay.is_zero() {
+            return;
+        }
+        self.num_throttled_writes.fetch_add(1, Ordering::Relaxed);
+        self.throttle_micros
+            .fetch_add(delay.as_micros() as u64, Ordering::Relaxed);
+        self.env.sleep(delay).await;
+    }
+
+    /// Returns the interval of periodic checkpoints, or `None` if they are disabled, see
+    /// `PeriodicCheckpoints`.
+    pub(super) fn periodic_interval(&self) -> Option<Duration> {
+        if self.opts.read_only {
+            return None;
+        }
+        self.opts.periodic_interval()
+    }
+
+    pub(super) fn env(&self) -> &Arc<dyn Env> {
+        &self.env
+    }
+
+    /// Runs a periodic checkpoint if `interval` has elapsed since the last checkpoint and there
+    /// are writes to checkpoint, and returns the time until the next one is due.
+    ///
+    /// The checkpoint is skipped if other background jobs take its slot. Errors are ignored, since
+    /// the next checkpoint will try again.
+    pub(super) async fn periodic_checkpoint(&self, interval: Duration, ghost: &Ghost) -> Duration {
+        let elapsed = self.last_checkpoint.lock().unwrap().elapsed();
+        if elapsed < interval {
+            return interval - elapsed;
+        }
+        if self.dirty_bytes.load(Ordering::Relaxed) > 0 {
+            if let Some(_job) = self.jobs.try_begin(Job::Checkpoint) {
+                let _ = self.checkpoint(ghost).await;
+            }
+        }
+        interval
+    }
+
+    /// Flushes the nodes changed since the last checkpoint, so that they can be evicted, unless
+    /// another write is doing that already.
+    ///
+    /// Flushes are batched until the writes since the last checkpoint reach a share of the cache
+    /// budget, so that a cache that is over budget for other reasons doesn't flush on every write.
+    /// The flush takes precedence over periodic checkpoints, and it is a checkpoint itself. Errors
+    /// are ignored, since the next write that can't evict will try again.
+    async fn maybe_flush_for_eviction(&self, ghost: &Ghost) {
+        /// The share of the cache budget that triggers a flush.
+        const FLUSH_RATIO: usize = 8;
+
+        let dirty_bytes = self.dirty_bytes.load(Ordering::Relaxed) as usize;
+        let budget = match &self.opts.shared_cache {
+            Some(shared) => self.opts.cache_size.min(shared.capacity()),
+            None => self.opts.cache_size,
+        };
+        if dirty_bytes == 0
+            || dirty_bytes < budget / FLUSH_RATIO
+            || self.jobs.is_pending(Job::EvictionFlush)
+        {
+            return;
+        }
+        let _job = self.jobs.begin(Job::EvictionFlush).await;
+        let _ = self.checkpoint(ghost).await;
+    }
+
+    async fn try_checkpoint(&self, ghost: &Ghost) -> Result<()> {
+        // The writes before this point are covered by the checkpoint, and so are the batches
+        // applied before it. Writes in flight may not be, so the durable LSN is taken below them.
+        let dirty_bytes = self.dirty_bytes.load(Ordering::Relaxed);
+        let durable_lsn = self.writes.low_watermark();
+        let (applied_epoch, applied_lsn) = *self.applied.lock().unwrap();
+        // Nodes that are still on disk keep their addresses, and the others are written as
+        // consolidated images.
+        let mut page_table = Vec::new();
+        let mut pages = Vec::new();
+        let mut heads = Vec::new();
+        let result = self
+            .collect_checkpoint_pages(&mut page_table, &mut pages, &mut heads, ghost)
+            .await;
+        // Loaded after the pages are collected, so that it covers all the updates in the pages.
+        let max_lsn = self.max_lsn.load(Ordering::Acquire);
+        let live_keys = self.len_estimate();
+        let result = match result {
+            Ok(()) => self.store.write_pages(&pages, max_lsn).await,
+            Err(err) => Err(err),
+        };
+        record!("pages", pages.len());
+        record!(
+            "bytes",
+            pages.iter().map(|(_, page)| page.size()).sum::<usize>()
+        );
+        for &(_, page) in &pages {
+            unsafe { self.cache.dealloc(page) };
+        }
+        for ((&(id, _), head), addr) in pages.iter().zip(heads).zip(result?) {
+            page_table.push((id, addr));
+            // The node can be evicted to its new image if it doesn't change.
+            if let PageAddr::Mem(head) = head {
+                let head = unsafe { PagePtr::new(head as *mut u8).unwrap() };
+                self.cache.insert_clean(id, head, addr, CacheTier::Hot);
+            }
+        }
+        page_table.sort_unstable();
+        let next_page_id = self.table.next_id();
+        let mut counters = if self.opts.persist_stats {
+            // Counts this checkpoint in the recorded statistics.
+            let mut stats = self.lifetime_stats();
+            stats.num_checkpoints += 1;
+            stats.to_counters()
+        } else {
+            Vec::new()
+        };
+        counters.push((LAST_LSN_COUNTER.to_owned(), max_lsn));
+        if self.opts.comparator.has_timestamp() {
+            let ts = self.history_ts_low.load(Ordering::Acquire);
+            counters.push((HISTORY_TS_LOW_COUNTER.to_owned(), ts));
+        }
+        if applied_lsn > 0 {
+            counters.push((APPLIED_EPOCH_COUNTER.to_owned(), applied_epoch));
+            counters.push((APPLIED_LSN_COUNTER.to_owned(), applied_lsn));
+        }
+        counters.push((LIVE_KEYS_COUNTER.to_owned(), live_keys));
+        let comparator = COMPARATOR_COUNTER_PREFIX.to_owned() + &self.opts.comparator.name();
+        counters.push((comparator, 0));
+        self.store
+            .checkpoint(ROOT_ID, next_page_id, page_table, counters)
+            .await?;
+        self.dirty_bytes.fetch_sub(dirty_bytes, Ordering::Relaxed);
+        self.durable_lsn.fetch_max(durable_lsn, Ordering::AcqRel);
+        self.num_checkpoints.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    async fn collect_checkpoint_pages(
+        &self,
+        page_table: &mut Vec<(u64, u64)>,
+        pages: &mut Vec<(u64, PagePtr)>,
+        heads: &mut Vec<PageAddr>,
+        ghost: &Ghost,
+    ) -> Result<()> {
+        let mut stack = vec![(ROOT_INDEX, Vec::new())];
+        while let Some((index, low)) = stack.pop() {
+            let node = self.node(index.id, [].as_slice()..[].as_slice())?;
+            if node.view.ver() != index.ver {
+                // The node has been split, so reconciles it before the next try.
+                self.try_find_node(&low, CacheTier::Hot, ghost).await?;
+                return Err(Error::Again {
+                    node_id: node.id,
+                    cause: Conflict::StaleNode,
+                });
+            }
+            if let PageView::Disk(info, addr) = node.view {
+                if !info.is_index {
+                    page_table.push((node.id, addr));
+                    continue;
+                }
+            }
+            if !node.view.is_index() && self.opts.flush_policy == FlushPolicy::DeltaFlush {
+                match self.flush_deltas(&node)? {
+                    Some(FlushedNode::Unchanged(addr)) => {
+                        page_table.push((node.id, addr));
+                        continue;
+                    }
+                    Some(FlushedNode::Delta(page)) => {
+                        pages.push((node.id, page));
+                        heads.push(node.view.as_addr());
+                        continue;
+                    }
+                    None => {}
+                }
+            }
+            let mut page = if node.view.is_index() {
+                let mut iter = self.iter_node::<&[u8], Index>(&node, ghost).await?;
+                let page = self
+                    .page_builder()
+                    .build_from_iter(&self.cache, &mut iter)?;
+                let page_ref = page.as_ref::<&[u8], Index>();
+                for i in 0..page_ref.len() {
+                    let (key, index) = page_ref.get(i).unwrap();
+                    stack.push((index, key.to_vec()));
+                }
+                page
+            } else {
+                let mut iter = self.iter_node::<Key, Value>(&node, ghost).await?;
+                self.page_builder()
+                    .build_from_iter(&self.cache, &mut iter)?
+            };
+            page.set_ver(node.view.ver());
+            page.set_index(node.view.is_index());
+            pages.push((node.id, page.as_ptr()));
+            heads.push(node.view.as_addr());
+        }
+        Ok(())
+    }
+}
+
+impl BTree {
+    /// Builds the delta page of a leaf with `FlushPolicy::DeltaFlush`, from the deltas chained
+    /// since the leaf was last written, on top of the page that it was written with.
+    ///
+    /// Returns `None` if the leaf should be consolidated instead, when the pages it was written
+    /// with are unknown, or there are split pages since then, or the chain on disk is already
+    /// `Options::data_delta_length` long.
+    fn flush_deltas(&self, node: &Node<'_>) -> Result<Option<FlushedNode>> {
+        let head = match node.view {
+            PageView::Mem(page) => page,
+            PageView::Disk(..) => return Ok(None),
+        };
+        let (clean, addr) = match self.cache.clean_page(node.id) {
+            Some(clean) => clean,
+            None => return Ok(None),
+        };
+        let info = match self.store.page_info(addr) {
+            Some(info) if info.ver == node.view.ver() && !info.is_index => info,
+            _ => return Ok(None),
+        };
+        if u64::from(head) == clean {
+            return Ok(Some(FlushedNode::Unchanged(addr)));
+        }
+        if info.len >= self.opts.data_delta_length {
+            return Ok(None);
+        }
+        // Only the newest of equal entries is kept, like merged deltas in memory.
+        let mut merger = MergingIterBuilder::new(self.opts.comparator.clone()).dedup(true);
+        let mut page = head;
+        while u64::from(page) != clean {
+            match unsafe { TypedPageRef::<Key, Value>::cast(page) } {
+                TypedPageRef::Data(data) => merger.add(data.iter()),
+                TypedPageRef::Split(_) | TypedPageRef::Unknown => return Ok(None),
+            }
+            page = match PageAddr::from(page.next()) {
+                PageAddr::Mem(ptr) => match unsafe { PagePtr::new(ptr as *mut u8) } {
+                    Some(next) => next,
+                    None => return Ok(None),
+                },
+                PageAddr::Disk(_) => return Ok(None),
+            };
+        }
+        let mut iter = merger.build();
+        let mut delta = self
+            .page_builder()
+            .build_from_iter(&self.cache, &mut iter)?;
+        delta.set_ver(node.view.ver());
+        delta.set_len(info.len + 1);
+        delta.set_next(PageAddr::Disk(addr).into());
+        Ok(Some(FlushedNode::Delta(delta.as_ptr())))
+    }
+
+    /// Returns the height of a recovered tree by walking down its leftmost nodes.
+    ///
+    /// The index nodes on the way are read from disk without being swapped in, so that the cache
+    /// is left as it is recovered.
+    async fn recover_height(&self) -> Result<usize> {
+        let alloc = self.cache.with_kind(AllocKind::SwapIn);
+        let mut id = ROOT_ID;
+        let mut height = 1;
+        loop {
+            let addr = match self.node(id, [].as_slice()..[].as_slice())?.view {
+                PageView::Disk(info, addr) if info.is_index => addr,
+                // All the nodes are on disk when the tree is recovered, so this is a leaf.
+                _ => return Ok(height),
+            };
+            let page =
+                self.store.load_page(addr, &alloc).await?.ok_or_else(|| {
+                    Error::Corrupted(format!("node {} is not on disk", id).into())
+                })?;
+            let first = unsafe { DataPageRef::<&[u8], Index>::new(page) }
+                .get(0)
+                .map(|(_, index)| index.id);
+            unsafe { alloc.dealloc(page) };
+            id = match first {
+                Some(first) => first,
+                None => {
+                    return Err(Error::Corrupted(
+                        format!("index node {} is empty", id).into(),
+                    ))
+                }
+            };
+            height += 1;
+        }
+    }
+
+    fn init(self) -> Result<Self> {
+        let ghost = Ghost::pin();
+        // Initializes the tree as root -> leaf.
+        let root_id = self.table.alloc(ghost.guard()).unwrap();
+        let leaf_id = self.table.alloc(ghost.guard()).unwrap();
+        let mut leaf_page = self.page_builder().build(&self.cache)?;
+        self.table.set(leaf_id, leaf_page.as_ptr().into());
+        let mut root_iter = OptionIter::from(([].as_slice(), Index::with_id(leaf_id)));
+        let mut root_page = self
+            .page_builder()
+            .build_from_iter(&self.cache, &mut root_iter)?;
+        root_page.set_index(true);
+        self.table.set(root_id, root_page.as_ptr().into());
+        Ok(self)
+    }
+
+    fn node<'g>(&self, id: u64, range: Range<&'g [u8]>) -> Result<Node<'g>> {
+        let addr = self.page_addr(id);
+        // Our access pattern ensures that a memory address must be valid, but a disk address may
+        // have been swapped in and then released by a checkpoint.
+        match self.page_view(addr) {
+            Some(view) => Ok(Node { id, view, range }),
+            None => Err(Error::Again {
+                node_id: id,
+                cause: Conflict::StaleNode,
+            }),
+        }
+    }
+
+    fn page_addr(&self, id: u64) -> PageAddr {
+        self.table.get(id).into()
+    }
+
+    fn page_view(&self, addr: PageAddr) -> Option<PageView> {
+        match addr {
+            PageAddr::Mem(addr) => {
+                let page = unsafe { PagePtr::new(addr as *mut u8) };
+                page.map(PageView::from)
+            }
+            PageAddr::Disk(addr) => self
+                .store
+                .page_info(addr)
+                .map(|info| PageView::Disk(info, addr)),
+        }
+    }
+
+    /// Retires a node that has been removed from the tree.
+    ///
+    /// The node must be unreachable for new readers. Its pages and id are reclaimed after all the
+    /// ghosts that may still see the node are dropped, and then the id is reused by new nodes.
+    fn retire_node(&self, id: u64, addr: PageAddr, ghost: &Ghost) {
+        self.dealloc_page_chain(addr, ghost);
+        self.table.dealloc(id, ghost.guard());
+    }
+
+    fn dealloc_page_chain<'g>(&self, mut addr: PageAddr, ghost: &'g Ghost) {
+        let cache = self.cache.clone();
+        // The chain size of the first page covers all the pages in memory.
+        let bytes = match addr {
+            PageAddr::Mem(ptr) => {
+                unsafe { PagePtr::new(ptr as *mut u8) }.map_or(0, |page| page.chain_size() as usize)
+            }
+            PageAddr::Disk(_) => 0,
+        };
+        ghost.defer(bytes, move || unsafe {
+            while let PageAddr::Mem(ptr) = addr {
+                if let Some(page) = PagePtr::new(ptr as *mut u8) {
+                    addr = page.next().into();
+                    cache.dealloc(page);
+                } else {
+                    break;
+                }
+            }
+        });
+    }
+
+    async fn load_page_with_view(
+        &self,
+        id: u64,
+        view: &PageView,
+        ghost: &Ghost,
+    ) -> Result<PagePtr> {
+        match *view {
+            PageView::Mem(page) => Ok(page),
+            PageView::Disk(_, addr) => {
+                let _guard = self.sched.begin(Work::SwapIn);
+                self.swapin_page(id, addr, CacheTier::Hot, ghost).await
+            }
+        }
+    }
+
+    /// Loads the first page of a node for a lookup, and records the access to the node.
+    ///
+    /// The node is admitted to `tier` if it is swapped in.
+    async fn access_page_with_view(
+        &self,
+        id: u64,
+        view: &PageView,
+        tier: CacheTier,
+        ghost: &Ghost,
+    ) -> Result<PagePtr> {
+        match *view {
+            PageView::Mem(page) => {
+                self.cache.access(id);
+                Ok(page)
+            }
+            PageView::Disk(_, addr) => {
+                let _guard = self.sched.begin(Work::SwapIn);
+                self.swapin_page(id, addr, tier, ghost).await
+            }
+        }
+    }
+
+    async fn load_page_with_addr(&self, id: u64, addr: PageAddr) -> Result<Option<PagePtr>> {
+        match addr {
+            PageAddr::Mem(addr) => {
+                let page = unsafe { PagePtr::new(addr as *mut u8) };
+                Ok(page)
+            }
+            // Disk pages are swapped in before new pages are chained to them.
+            PageAddr::Disk(addr) => Err(Error::Corrupted(
+                format!("node {} chains to disk page {}", id, addr).into(),
+            )),
+        }
+    }
+
+    /// Loads the page at `addr` and replaces the disk address of the node with it.
+    async fn swapin_page(
+        &self,
+        id: u64,
+        addr: u64,
+        tier: CacheTier,
+        ghost: &Ghost,
+    ) -> Result<PagePtr> {
+        let alloc = self.cache.with_kind(AllocKind::SwapIn);
+        let stale = Error::Again {
+            node_id: id,
+            cause: Conflict::StaleNode,
+        };
+        // Swap-ins make room for themselves before the load, since reads don't stall, and the
+        // node being loaded must not be evicted before it is used.
+        {
+            let ghost = Ghost::pin();
+            self.maybe_evict(&ghost);
+        }
+        let page = match self.store.load_page(addr, &alloc).await? {
+            Some(page) => page,
+            None => return Err(stale),
+        };
+        ghost.perf(|perf| {
+            perf.num_swapins += 1;
+            perf.swapin_bytes += page.size() as u64;
+        });
+        let old = PageAddr::Disk(addr).into();
+        if self.table.cas(id, old, page.into()).is_err() {
+            unsafe { dealloc_chain(&self.cache, page) };
+            return Err(stale);
+        }
+        self.cache.insert_clean(id, page, addr, tier);
+        Ok(page)
+    }
+
+    /// Evicts unchanged nodes to disk until the cache is within `Options::cache_size` and
+    /// `Options::shared_cache`, and returns false if the cache is still over budget.
+    fn maybe_evict(&self, ghost: &Ghost) -> bool {
+        if !self.is_over_budget() {
+            return true;
+        }
+        // A checkpoint may remove the files that the evicted nodes are in.
+        let _lock = match self.checkpoint_lock.try_lock() {
+            Ok(lock) => lock,
+            Err(_) => return false,
+        };
+        let resident_size = self.cache.resident_size();
+        let mut nodes = 0;
+        let mut within_budget = true;
+        while self.is_over_budget() {
+            let evicted = self.cache.evict(|id, page, addr| {
+                self.store.page_info(addr).is_some()
+                    && self
+                        .table
+                        .cas(id, page.into(), PageAddr::Disk(addr).into())
+                        .is_ok()
+            });
+            match evicted {
+                // The pages are freed later, but they are not counted as resident anymore.
+                Some(page) => {
+                    self.dealloc_page_chain(PageAddr::Mem(page.into()), ghost);
+                    nodes += 1;
+                }
+                None => {
+                    within_budget = false;
+                    break;
+                }
+            }
+        }
+        if nodes > 0 {
+            let bytes = resident_size.saturating_sub(self.cache.resident_size());
+            self.events.record(EventKind::Eviction { nodes, bytes });
+            self.notify(|listener| listener.on_eviction(nodes, bytes));
+        }
+        within_budget
+    }
+
+    fn is_over_budget(&self) -> bool {
+        self.cache.resident_size() > self.opts.cache_size || self.cache.is_shared_over_budget()
+    }
+
+    /// Calls `f` on the pages of the node from the newest to the oldest, until it returns true.
+    ///
+    /// A page of an unknown kind fails the walk, since skipping it would lose its changes to the
+    /// node, so `f` only sees the known kinds.
+    async fn walk_node<F>(&self, node: &Node<'_>, ghost: &Ghost, mut f: F) -> Result<()>
+    where
+        F: FnMut(PagePtr) -> bool,
+    {
+        let mut page = self.load_page_with_view(node.id, &node.view, ghost).await?;
+        ghost.perf(|perf| {
+            perf.num_nodes += 1;
+            perf.max_chain_length = perf.max_chain_length.max(page.len() as u64 + 1);
+        });
+        loop {
+            ghost.perf(|perf| perf.num_pages += 1);
+            if page.try_kind().is_none() {
+                return Err(Error::NotSupported(format!(
+                    "node {} has a page of unknown kind {}",
+                    node.id,
+                    page.raw_kind()
+                )));
+            }
+            if f(page) {
+                break;
+            }
+            let next = page.next().into();
+            match self.load_page_with_addr(node.id, next).await? {
+                Some(next) => page = next,
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    async fn iter_node<'g, K, V>(
+        &self,
+        node: &Node<'_>,
+        ghost: &'g Ghost,
+    ) -> Result<NodeIter<'g, K, V>>
+    where
+        K: Decodable + Comparable,
+        V: Decodable,
+    {
+        // Pages are ranked from the newest to the oldest.
+        let mut merger = MergingIterBuilder::new(self.opts.comparator.clone()).dedup(true);
+        let mut high = None;
+        self.walk_node(node, ghost, |page| {
+            let page = unsafe { TypedPageRef::cast(page) };
+            match page {
+                TypedPageRef::Data(data) => merger.add(data.iter()),
+                // The newest split determines the upper bound of the node.
+                TypedPageRef::Split(split) => {
+                    high.get_or_insert(split.range().start);
+                }
+                TypedPageRef::Unknown => {}
+            }
+            false
+        })
+        .await?;
+        Ok(NodeIter {
+            iter: merger.build(),
+            high,
+            done: false,
+            cmp: self.opts.comparator.clone(),
+        })
+    }
+
+    async fn lookup_value<'a, 'g>(
+        &'a self,
+        key: Key<'_>,
+        node: &Node<'_>,
+        ghost: &'g Ghost,
+    ) -> Result<Option<&'g [u8]>> {
+        let mut sibling = None;
+        loop {
+            let node = sibling.as_ref().unwrap_or(node);
+            self.access.record_read(node.id);
+            // Writes of a key may be installed out of the order of their LSNs, so a newer page
+            // may have an older version, and the walk only stops early at the exact LSN.
+            let mut found: Option<(u64, Option<&'g [u8]>)> = None;
+            let mut redirect = None;
+            self.walk_node(node, ghost, |page| {
+                match unsafe { TypedPageRef::<'g, Key, Value>::cast(page) } {
+                    TypedPageRef::Data(data) => {
+                        if let Some((k, v)) = data.seek(&key, self.opts.comparator.as_ref()) {
+                            if self.compare(k.raw, key.raw).is_eq() {
+                                if !matches!(found, Some((lsn, _)) if lsn >= k.lsn) {
+                                    found = Some((k.lsn, v.into()));
+                                }
+                                return k.lsn == key.lsn;
+                            }
+                        }
+                    }
+                    TypedPageRef::Split(split) => {
+                        redirect = self.split_redirect(key.raw, &split);
+                        return redirect.is_some();
+                    }
+                    TypedPageRef::Unknown => {}
+                }
+                false
+            })
+            .await?;
+            match redirect {
+                Some((index, range)) => {
+                    sibling = Some(self.find_sibling(index, range, ghost).await?);
+                }
+                None => return Ok(found.and_then(|(_, value)| value)),
+            }
+        }
+    }
+
+    /// Returns the right sibling and its range if `key` is moved to it by the `split`.
+    ///
+    /// The pages below a split page are stale for the keys at or after the split key, which are
+    /// only found in the right sibling from then on. Searches are routed by the parents of nodes,
+    /// so they only get here with the views of nodes that are taken before their splits are
+    /// reconciled.
+    fn split_redirect<'g>(
+        &self,
+        key: &[u8],
+        split: &SplitPageRef<'g>,
+    ) -> Option<(Index, Range<&'g [u8]>)> {
+        let range = split.range();
+        if self.compare(key, range.start).is_ge() {
+            Some((split.index(), range))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the right sibling that a split page redirects a search to, with its first page
+    /// loaded.
+    ///
+    /// The sibling must still be at the version that the split page records. Otherwise, it may
+    /// have been split again, and the split page that moved the key further may be consolidated
+    /// away already.
+    async fn find_sibling<'k>(
+        &self,
+        index: Index,
+        range: Range<&'k [u8]>,
+        ghost: &Ghost,
+    ) -> Result<Node<'k>> {
+        let mut node = self.node(index.id, range)?;
+        if node.view.ver() != index.ver {
+            return Err(Error::Again {
+                node_id: node.id,
+                cause: Conflict::StaleNode,
+            });
+        }
+        node.view = self
+            .access_page_with_view(node.id, &node.view, CacheTier::Hot, ghost)
+            .await?
+            .into();
+        Ok(node)
+    }
+
+    /// Returns the index of the child that covers `key` and the range of the child.
+    async fn lookup_index<'k, 'g: 'k>(
+        &self,
+        key: &'k [u8],
+        node: &Node<'k>,
+        ghost: &'g Ghost,
+    ) -> Result<Option<(Index, Range<&'k [u8]>)>> {
+        let mut sibling = None;
+        loop {
+            let node = sibling.as_ref().unwrap_or(node);
+            // Index entries are scattered in the chain, so we need to look at all pages to find
+            // the greatest entry that is no greater than `key` and the next entry after it.
+            let mut found: Option<(&[u8], Index)> = None;
+            let mut high = node.range.end;
+            let mut redirect = None;
+            self.walk_node(node, ghost, |page| {
+                let cmp = self.opts.comparator.as_ref();
+                match unsafe { TypedPageRef::<'g, &'k [u8], Index>::cast(page) } {
+                    TypedPageRef::Data(data) => {
+                        if let Some((k, v)) = data.seek_back(&key, cmp) {
+                            // Newer pages come first, so the newest one wins on equal keys.
+                            if !matches!(found, Some((found, _)) if self.compare(k, found).is_le())
+                            {
+                                found = Some((k, v));
+                            }
+                        }
+                        if let Some((k, _)) = data.seek_next(&key, cmp) {
+                            if high.is_empty() || self.compare(k, high).is_lt() {
+                                high = k;
+                            }
+                        }
+                    }
+                    TypedPageRef::Split(split) => {
+                        redirect = self.split_redirect(key, &split);
+                        if redirect.is_some() {
+                            return true;
+                        }
+                        // The entries at or after the split key are stale in the older pages.
+                        let start = split.range().start;
+                        if high.is_empty() || self.compare(start, high).is_lt() {
+                            high = start;
+                        }
+                    }
+                    TypedPageRef::Unknown => {}
+                }
+                false
+            })
+            .await?;
+            match redirect {
+                Some((index, range)) => {
+                    sibling = Some(self.find_sibling(index, range, ghost).await?);
+                }
+                None => return Ok(found.map(|(low, index)| (index, low..high))),
+            }
+        }
+    }
+
+    /// Returns the leaf that covers `key` with its first page loaded, which is admitted to `tier`
+    /// if it is swapped in.
+    async fn try_find_node<'k, 'g: 'k>(
+        &self,
+        key: &'k [u8],
+        tier: CacheTier,
+        ghost: &'g Ghost,
+    ) -> Result<Node<'k>> {
+        let mut node = self.try_find_leaf(key, ghost).await?;
+        node.view = self
+            .access_page_with_view(node.id, &node.view, tier, ghost)
+            .await?
+            .into();
+        Ok(node)
+    }
+
+    /// Returns the leaf that covers `key` through its `parent`, whose first page may be still on
+    /// disk.
+    ///
+    /// Returns `None` if `key` is out of the range of `parent`, or if the view of `parent` is too
+    /// old to tell the current version of the leaf, in which case the leaf is found from the root.
+    async fn try_find_child(
+        &self,
+        key: &[u8],
+        parent: &Node<'_>,
+        ghost: &Ghost,
+    ) -> Result<Option<OwnedNode>> {
+        let range = &parent.range;
+        if self.compare(key, range.start).is_lt()
+            || (!range.end.is_empty() && self.compare(key, range.end).is_ge())
+        {
+            return Ok(None);
+        }
+        let (index, range) = match self.lookup_index(key, parent, ghost).await? {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        let node = self.node(index.id, range)?;
+        if node.view.ver() != index.ver || node.view.is_index() {
+            return Ok(None);
+        }
+        Ok(Some(OwnedNode::new(&node)))
+    }
+
+    /// Returns the leaf that covers `key`, whose first page may be still on disk.
+    async fn try_find_leaf<'k, 'g: 'k>(&self, key: &'k [u8], ghost: &'g Ghost) -> Result<Node<'k>> {
+        let (node, _) = self.try_find_leaf_with_parent(key, ghost).await?;
+        Ok(node)
+    }
+
+    /// Like `try_find_leaf`, but also returns the parent of the leaf, unless the leaf is the root.
+    async fn try_find_leaf_with_parent<'k, 'g: 'k>(
+        &self,
+        key: &'k [u8],
+        ghost: &'g Ghost,
+    ) -> Result<(Node<'k>, Option<Node<'k>>)> {
+        let mut cursor = ROOT_INDEX;
+        let mut range = [].as_slice()..[].as_slice();
+        let mut parent = None;
+        loop {
+            let mut node = self.node(cursor.id, range)?;
+            if node.view.ver() != cursor.ver {
+                self.try_reconcile_node(&node, parent.as_ref(), ghost)
+                    .await?;
+                return Err(Error::Again {
+                    node_id: node.id,
+                    cause: Conflict::StaleNode,
+                });
+            }
+            if node.view.is_index() {
+                node.view = self
+                    .access_page_with_view(node.id, &node.view, CacheTier::Hot, ghost)
+                    .await?
+                    .into();
+                (cursor, range) = self.lookup_index(key, &node, ghost).await?.unwrap();
+                parent = Some(node);
+            } else {
+                return Ok((node, parent));
+            }
+        }
+    }
+
+    /// Completes a split of the node by installing the new sibling to its parent.
+    async fn try_reconcile_node<'g>(
+        &self,
+        node: &Node<'_>,
+        parent: Option<&Node<'_>>,
+        ghost: &'g Ghost,
+    ) -> Result<()> {
+        let parent = match parent {
+            Some(parent) => parent,
+            None => return Ok(()),
+        };
+
+        let mut split = None;
+        self.walk_node(node, ghost, |page| {
+            let page = unsafe { TypedPageRef::<'g, &[u8], Index>::cast(page) };
+            if let TypedPageRef::Split(page) = page {
+                split = Some(page);
+                return true;
+            }
+            false
+        })
+        .await?;
+        let split = match split {
+            Some(split) => split,
+            None => return Ok(()),
+        };
+        // The split is installed on the node, but not on its parent yet.
+        fail_point!("split_before_reconcile");
+
+        // Updates the version of the node and adds the new sibling.
+        let data = [
+            (node.range.start, Index::new(node.id, node.view.ver())),
+            (split.range().start, split.index()),
+        ];
+        let mut iter = SliceIter::from(&data);
+        let alloc = self.cache.with_kind(AllocKind::Split);
+        let mut delta = self.page_builder().build_from_iter(&alloc, &mut iter)?;
+        delta.set_ver(parent.view.ver());
+        delta.set_len(parent.view.len() + 1);
+        let chain_size = chain::chained_size(delta.as_ptr(), &parent.view);
+        delta.set_chain_size(chain_size);
+        delta.set_next(parent.view.as_addr().into());
+        delta.set_index(true);
+        let delta = delta.as_ptr();
+        if self
+            .table
+            .cas(parent.id, delta.next(), delta.into())
+            .is_err()
+        {
+            unsafe { self.cache.dealloc(delta) };
+            return Err(Error::Again {
+                node_id: parent.id,
+                cause: Conflict::CasFailure,
+            });
+        }
+        self.events.record(EventKind::Reconcile {
+            node: node.id,
+            parent: parent.id,
+        });
+
+        if delta.len() >= self.opts.data_delta_length {
+            let parent = Node {
+                id: parent.id,
+                view: delta.into(),
+                range: parent.range.clone(),
+            };
+            let _ = self
+                .try_consolidate_node::<&[u8], Index>(&parent, ghost)
+                .await;
+        }
+        Ok(())
+    }
+
+    /// Compares raw keys in the order of `Options::comparator`.
+    fn compare(&self, a: &[u8], b: &[u8]) -> cmp::Ordering {
+        a.compare_with(&b, self.opts.comparator.as_ref())
+    }
+
+    /// Returns a builder of data pages with the layout for `Options::comparator`.
+    fn page_builder(&self) -> DataPageBuilder {
+        DataPageBuilder::default().sort_prefixes(self.opts.comparator.is_bytewise())
+    }
+
+    fn transform_value(&self, key: &[u8], value: &[u8]) -> Option<Vec<u8>> {
+        self.opts
+            .value_transformer
+            .as_ref()
+            .and_then(|transformer| transformer.transform(key, value))
+    }
+
+    /// Consolidates the leaf that covers `key`, for tests to interleave consolidations with other
+    /// operations.
+    #[cfg(test)]
+    pub async fn consolidate(&self, key: &[u8], ghost: &Ghost) -> Result<()> {
+        let mut retry = Retry::new(self, key, ghost);
+        loop {
+            let err = match self.try_find_node(key, CacheTier::Hot, ghost).await {
+                Ok(node) => match self.try_consolidate_leaf(&node, ghost).await {
+                    Ok(()) => return Ok(()),
+                    Err(err) => err,
+                },
+                Err(err) => err,
+            };
+            retry.on_error(err)?;
+        }
+    }
+
+    /// Consolidates the leaf, rewriting its values if `Options::rewrite_on_consolidation` is set,
+    /// and dropping the versions of keys older than `history_ts_low`.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "consolidate",
+            level = "debug",
+            skip_all,
+            fields(node = node.id, delta_len = node.view.len(), bytes = Empty)
+        )
+    )]
+    async fn try_consolidate_leaf(&self, node: &Node<'_>, ghost: &Ghost) -> Result<()> {
+        let start = Instant::now();
+        let mut iter = self.iter_node::<Key, Value>(node, ghost).await?;
+        self.check_split_reconciled(node, iter.high)?;
+        let attributed = self.attributed_live_keys(&node.view, iter.high);
+        let alloc = self.cache.with_kind(AllocKind::Consolidation);
+        let rewrite = self.opts.rewrite_on_consolidation && self.opts.value_transformer.is_some();
+        let history_ts_low = if self.opts.comparator.has_timestamp() {
+            self.history_ts_low.load(Ordering::Acquire)
+        } else {
+            0
+        };
+        if !rewrite && history_ts_low == 0 {
+            let page = self.page_builder().build_from_iter(&alloc, &mut iter)?;
+            let correction = self.live_keys_correction(&page, attributed);
+            return self
+                .install_consolidated_page::<Key, Value>(node, page, correction, start, ghost)
+                .await;
+        }
+        let mut entries = Vec::new();
+        let mut values = Vec::new();
+        // The user key and the timestamp of the newest version older than `history_ts_low`.
+        let mut oldest: Option<(&[u8], u64)> = None;
+        while let Some(&(k, v)) = iter.next() {
+            if let Some((user_key, ts)) = split_timestamp(k.raw) {
+                if ts < history_ts_low {
+                    match oldest {
+                        // The version is shadowed by a newer one that is old enough, so no reads
+                        // at or after `history_ts_low` see it.
+                        Some(oldest) if oldest.0 == user_key && oldest.1 != ts => continue,
+                        Some(oldest) if oldest.0 == user_key => {}
+                        _ => oldest = Some((user_key, ts)),
+                    }
+                }
+            }
+            values.push(match v {
+                Value::Put(value) if rewrite => self.transform_value(k.raw, value),
+                _ => None,
+            });
+            entries.push((k, v));
+        }
+        for ((_, v), value) in entries.iter_mut().zip(&values) {
+            if let Some(value) = value {
+                *v = Value::Put(value);
+            }
+        }
+        let mut iter = SliceIter::from(entries.as_slice());
+        let page = self.page_builder().build_from_iter(&alloc, &mut iter)?;
+        let correction = self.live_keys_correction(&page, attributed);
+        self.install_consolidated_page::<Key, Value>(node, page, correction, start, ghost)
+            .await
+    }
+
+    /// Returns the number of live keys that the chain of a leaf contributes to `live_keys`: the
+    /// keys of its base page whose newest versions are puts, plus one for each put and minus one
+    /// for each delete in its deltas. Entries at or after `high` belong to the right sibling.
+    ///
+    /// Returns `None` if the leaf is on disk, since it can't be consolidated before it is swapped
+    /// in.
+    fn attributed_live_keys(&self, view: &PageView, high: Option<&[u8]>) -> Option<i64> {
+        let mut page = match *view {
+            PageView::Mem(page) => Some(page),
+            PageView::Disk(..) => return None,
+        };
+        let mut count = 0;
+        while let Some(p) = page {
+            if let TypedPageRef::Data(data) = unsafe { TypedPageRef::<Key, Value>::cast(p) } {
+                let mut iter = data.iter();
+                if p.len() == 0 {
+                    count += self.count_live_keys(&mut iter, high);
+                } else {
+                    while let Some(&(k, v)) = iter.next() {
+                        if matches!(high, Some(high) if self.compare(k.raw, high).is_ge()) {
+                            break;
+                        }
+                        count += if let Value::Put(_) = v { 1 } else { -1 };
+                    }
+                }
+            }
+            page = match PageAddr::from(p.next()) {
+                PageAddr::Mem(ptr) => unsafe { PagePtr::new(ptr as *mut u8) },
+                PageAddr::Disk(_) => None,
+            };
+        }
+        Some(count)
+    }
+
+    /// Returns the correction to `live_keys` when a leaf whose chain contributes `attributed` is
+    /// replaced by the consolidated `page`.
+    fn live_keys_correction(&self, page: &DataPageBuf, attributed: Option<i64>) -> i64 {
+        match attributed {
+            Some(attributed) => {
+                let page = page.as_ref::<Key, Value>();
+                self.count_live_keys(&mut page.iter(), None) - attributed
+            }
+            None => 0,
+        }
+    }
+
+    /// Returns the number of keys before `high` whose newest versions in `iter` are puts.
+    fn count_live_keys<'a, I>(&self, iter: &mut I, high: Option<&[u8]>) -> i64
+    where
+        I: ForwardIter<Key = Key<'a>, Value = Value<'a>>,
+    {
+        let mut count = 0;
+        let mut last: Option<&[u8]> = None;
+        while let Some(&(k, v)) = iter.next() {
+            if matches!(high, Some(high) if self.compare(k.raw, high).is_ge()) {
+                break;
+            }
+            // Versions of a key are ordered from the newest.
+            if matches!(last, Some(last) if self.compare(last, k.raw).is_eq()) {
+                continue;
+            }
+            last = Some(k.raw);
+            if let Value::Put(_) = v {
+                count += 1;
+            }
+        }
+        count
+    }
+
+    /// Consolidates the node, and then splits it if it is too large.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "consolidate",
+            level = "debug",
+            skip_all,
+            fields(node = node.id, delta_len = node.view.len(), bytes = Empty)
+        )
+    )]
+    async fn try_consolidate_node<'g, K, V>(&self, node: &Node<'_>, ghost: &'g Ghost) -> Result<()>
+    where
+        K: Encodable + Decodable + Comparable + RawKey + Clone,
+        V: Encodable + Decodable,
+    {
+        let start = Instant::now();
+        let mut iter = self.iter_node::<K, V>(node, ghost).await?;
+        self.check_split_reconciled(node, iter.high)?;
+        let alloc = self.cache.with_kind(AllocKind::Consolidation);
+        let page = self.page_builder().build_from_iter(&alloc, &mut iter)?;
+        self.install_consolidated_page::<K, V>(node, page, 0, start, ghost)
+            .await
+    }
+
+    /// Returns `Error::Again` if the node has a split at `high` that its parent doesn't know yet.
+    ///
+    /// Consolidations drop the split pages of a node, and the entries moved by them, so a split
+    /// must be reconciled to the parent first, or the right sibling becomes unreachable. The
+    /// split is reconciled if the range that the parent assigns to the node ends at it.
+    fn check_split_reconciled(&self, node: &Node<'_>, high: Option<&[u8]>) -> Result<()> {
+        let end = node.range.end;
+        match high {
+            Some(high) if end.is_empty() || self.compare(high, end).is_lt() => Err(Error::Again {
+                node_id: node.id,
+                cause: Conflict::StaleNode,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Replaces the node with its consolidated page, applies `correction` to `live_keys`, and
+    /// then splits the node if it is too large.
+    ///
+    /// The consolidation is recorded as an event if it takes too long since `start`.
+    async fn install_consolidated_page<K, V>(
+        &self,
+        node: &Node<'_>,
+        mut page: DataPageBuf,
+        correction: i64,
+        start: Instant,
+        ghost: &Ghost,
+    ) -> Result<()>
+    where
+        K: Encodable + Decodable + Comparable + RawKey,
+        V: Encodable + Decodable,
+    {
+        let new_ptr = page.as_ptr();
+        let old_addr = node.view.as_addr();
+        record!("bytes", new_ptr.size());
+        chain::install_page(&self.table, node.id, &node.view, new_ptr).map_err(|_| {
+            unsafe { self.cache.dealloc(new_ptr) };
+            Error::Again {
+                node_id: node.id,
+                cause: Conflict::CasFailure,
+            }
+        })?;
+        self.dealloc_page_chain(old_addr, ghost);
+        self.live_keys.fetch_add(correction, Ordering::Relaxed);
+        let elapsed = start.elapsed();
+        if elapsed > self.opts.slow_consolidation_threshold {
+            self.events.record(EventKind::SlowConsolidation {
+                node: node.id,
+                delta_len: node.view.len(),
+                elapsed,
+            });
+        }
+        self.notify(|listener| listener.on_consolidation(node.id, node.view.len(), elapsed));
+
+        let page = page.as_ref::<K, V>();
+        if self.should_split(&page) {
+            let node = Node {
+                id: node.id,
+                view: new_ptr.into(),
+                range: node.range.clone(),
+            };
+            if node.id == ROOT_ID {
+                self.try_split_root(&node, page, ghost)?;
+            } else {
+                self.try_split_node(&node, page, ghost).await?;
+            }
+        }
+        Ok(())
+    }
+
+    fn should_split<K, V>(&self, page: &DataPageRef<'_, K, V>) -> bool
+    where
+        K: Decodable + Comparable,
+        V: Decodable,
+    {
+        let is_index = page.is_index();
+        page.len() >= 2
+            && (page.size() > self.opts.node_size(is_index)
+                || page.len() > self.opts.node_entries(is_index))
+    }
+
+    /// Splits the node by moving the upper half of its entries to a new right sibling.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "split",
+            level = "debug",
+            skip_all,
+            fields(node = node.id, entries = page.len(), right = Empty, bytes = Empty)
+        )
+    )]
+    async fn try_split_node<'g, K, V>(
+        &self,
+        node: &Node<'_>,
+        page: DataPageRef<'g, K, V>,
+        ghost: &'g Ghost,
+    ) -> Result<()>
+    where
+        K: Encodable + Decodable + Comparable + RawKey,
+        V: Encodable + Decodable,
+    {
+        let data: Vec<_> = (0..page.len()).map(|i| page.get(i).unwrap()).collect();
+        match self.split_point(&data) {
+            Some(mid) => self.try_split_at(node, &data[mid..], ghost),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the position that splits `data` in about halves, or `None` if all the entries have
+    /// the same raw key.
+    fn split_point<K, V>(&self, data: &[(K, V)]) -> Option<usize>
+    where
+        K: RawKey,
+    {
+        let is_same = |i: usize| {
+            self.compare(data[i].0.as_raw(), data[i - 1].0.as_raw())
+                .is_eq()
+        };
+        // Entries of the same raw key must stay in the same node.
+        let mut mid = data.len() / 2;
+        while mid < data.len() && is_same(mid) {
+            mid += 1;
+        }
+        if mid == data.len() {
+            mid = data.len() / 2;
+            while mid > 0 && is_same(mid) {
+                mid -= 1;
+            }
+            if mid == 0 {
+                return None;
+            }
+        }
+        Some(mid)
+    }
+
+    /// Splits the root by moving its entries to two new children, and replacing it with a page
+    /// that only points to them, which grows the tree by one level.
+    ///
+    /// The root keeps its id and version, so that descents always start from `ROOT_INDEX`. The
+    /// children of the old root keep their versions too, so the operations that find them from the
+    /// old root are still valid.
+    fn try_split_root<K, V>(
+        &self,
+        node: &Node<'_>,
+        page: DataPageRef<'_, K, V>,
+        ghost: &Ghost,
+    ) -> Result<()>
+    where
+        K: Encodable + Decodable + Comparable + RawKey,
+        V: Encodable + Decodable,
+    {
+        let data: Vec<_> = (0..page.len()).map(|i| page.get(i).unwrap()).collect();
+        let mid = match self.split_point(&data) {
+            Some(mid) => mid,
+            None => return Ok(()),
+        };
+        let left = self.alloc_node(&data[..mid], node.view.is_index(), ghost)?;
+        let right = match self.alloc_node(&data[mid..], node.view.is_index(), ghost) {
+            Ok(right) => right,
+            Err(err) => {
+                self.retire_node(left.0, PageAddr::Mem(left.1.into()), ghost);
+                return Err(err);
+            }
+        };
+        let retire_children = || {
+            for (id, ptr) in [left, right] {
+                self.retire_node(id, PageAddr::Mem(ptr.into()), ghost);
+            }
+        };
+
+        let entries = [
+            (node.range.start, Index::new(left.0, left.1.ver())),
+            (data[mid].0.as_raw(), Index::new(right.0, right.1.ver())),
+        ];
+        let mut iter = SliceIter::from(&entries);
+        let alloc = self.cache.with_kind(AllocKind::Split);
+        let mut root = match self.page_builder().build_from_iter(&alloc, &mut iter) {
+            Ok(root) => root,
+            Err(err) => {
+                retire_children();
+                return Err(err);
+            }
+        };
+        root.set_index(true);
+        let root = root.as_ptr();
+        if chain::install_page(&self.table, node.id, &node.view, root).is_err() {
+            unsafe { self.cache.dealloc(root) };
+            retire_children();
+            return Err(Error::Again {
+                node_id: node.id,
+                cause: Conflict::CasFailure,
+            });
+        }
+        self.dealloc_page_chain(node.view.as_addr(), ghost);
+        let height = self.height.fetch_add(1, Ordering::Relaxed) + 1;
+        self.events.record(EventKind::RootSplit {
+            left: left.0,
+            right: right.0,
+            height,
+        });
+        self.notify(|listener| listener.on_split(left.0, right.0));
+        Ok(())
+    }
+
+    /// Allocates a new node with a page of `data`.
+    fn alloc_node<K, V>(
+        &self,
+        data: &[(K, V)],
+        is_index: bool,
+        ghost: &Ghost,
+    ) -> Result<(u64, PagePtr)>
+    where
+        K: Encodable + Decodable + Comparable + RawKey,
+        V: Encodable + Decodable,
+    {
+        let mut iter = SliceIter::from(data);
+        let alloc = self.cache.with_kind(AllocKind::Split);
+        let mut page = self.page_builder().build_from_iter(&alloc, &mut iter)?;
+        page.set_index(is_index);
+        let ptr = page.as_ptr();
+        match self.table.alloc(ghost.guard()) {
+            Some(id) => {
+                self.table.set(id, ptr.into());
+                Ok((id, ptr))
+            }
+            None => {
+                unsafe { self.cache.dealloc(ptr) };
+                Err(Error::Alloc)
+            }
+        }
+    }
+
+    /// Splits the node by moving `data`, which are the entries of the node from the first key of
+    /// `data` on, to a new right sibling.
+    fn try_split_at<K, V>(&self, node: &Node<'_>, data: &[(K, V)], ghost: &Ghost) -> Result<()>
+    where
+        K: Encodable + Decodable + Comparable + RawKey,
+        V: Encodable + Decodable,
+    {
+        let (right_id, right_ptr) = self.alloc_node(data, node.view.is_index(), ghost)?;
+        record!("right", right_id);
+        record!("bytes", right_ptr.size());
+
+        let range = data[0].0.as_raw()..node.range.end;
+        let index = Index::new(right_id, right_ptr.ver());
+        let alloc = self.cache.with_kind(AllocKind::Split);
+        let split = SplitPageBuilder::default().build_with_index(&alloc, range, index)?;
+        let split = split.as_ptr();
+        if chain::install_split(&self.table, node.id, &node.view, split).is_err() {
+            unsafe { self.cache.dealloc(split) };
+            self.retire_node(right_id, PageAddr::Mem(right_ptr.into()), ghost);
+            return Err(Error::Again {
+                node_id: node.id,
+                cause: Conflict::CasFailure,
+            });
+        }
+        self.events.record(EventKind::Split {
+            node: node.id,
+            right: right_id,
+        });
+        self.notify(|listener| listener.on_split(node.id, right_id));
+        Ok(())
+    }
+}
+
+impl Drop for BTree {
+    fn drop(&mut self) {
+        // Frees the nodes in memory once the pinned values and the scans that may still see them
+        // are dropped. The deferred free holds the cache, so its share of a shared cache is only
+        // given back after the nodes are freed.
+        let mut heads = Vec::new();
+        let mut bytes = 0;
+        for (_, addr) in self.table.entries() {
+            if let PageAddr::Mem(ptr) = PageAddr::from(addr) {
+                if let Some(page) = unsafe { PagePtr::new(ptr as *mut u8) } {
+                    bytes += page.chain_size() as usize;
+                    heads.push(ptr);
+                }
+            }
+        }
+        let cache = self.cache.clone();
+        Ghost::pin().defer(bytes, move || {
+            for ptr in heads {
+                unsafe { dealloc_chain(&cache, PagePtr::new(ptr as *mut u8).unwrap()) };
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn split_by_entries() {
+        const N: u64 = 64;
+        const MAX_ENTRIES: usize = 4;
+        let opts = Options {
+            data_node_entries: MAX_ENTRIES,
+            data_delta_length: 2,
+            ..Default::default()
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let tree = BTree::open(dir.path(), opts.clone()).await.unwrap();
+        let ghost = &Ghost::pin();
+        for i in 0..N {
+            let buf = i.to_be_bytes();
+            tree.put(&buf, i, &buf, ghost).await.unwrap();
+        }
+        for i in 0..N {
+            let buf = i.to_be_bytes();
+            let value = tree.get(&buf, i, ghost).await.unwrap();
+            assert_eq!(value, Some(buf.as_slice()));
+        }
+
+        let root = tree.node(ROOT_ID, [].as_slice()..[].as_slice()).unwrap();
+        let mut iter = tree.iter_node::<&[u8], Index>(&root, ghost).await.unwrap();
+        let mut num_leaves = 0;
+        while iter.next().is_some() {
+            num_leaves += 1;
+        }
+        // A leaf splits when it is consolidated with more than `MAX_ENTRIES` entries.
+        let max_leaf_entries = MAX_ENTRIES + opts.data_delta_length as usize;
+        assert!(num_leaves >= N as usize / max_leaf_entries);
+    }
+
+    #[tokio::test]
+    async fn free_nodes_on_close() {
+        let shared = super::super::SharedCache::new(usize::MAX);
+        let opts = Options {
+            shared_cache: Some(shared.clone()),
+            ..Default::default()
+        };
+        let dir = tempfile::tempdir().unwrap();
+        for round in 0..30u64 {
+            let tree = BTree::open(dir.path(), opts.clone()).await.unwrap();
+            let cache = tree.cache.clone();
+            let ghost = Ghost::pin();
+            for i in 0..256u64 {
+                let buf = i.to_be_bytes();
+                tree.put(&buf, round * 256 + i, &buf, &ghost).await.unwrap();
+            }
+            tree.close(&ghost).await.unwrap();
+            assert!(cache.size() > 0);
+
+            // The nodes are freed once the ghosts that may see them are dropped, before the share
+            // of the cache is given back.
+            drop(ghost);
+            for _ in 0..1000 {
+                ghost::flush_epoch();
+                if cache.size() == 0 {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+            assert_eq!(cache.size(), 0);
+            assert_eq!(shared.usage(), 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn consolidate_with_pending_split() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = BTree::open(dir.path(), Options::default()).await.unwrap();
+        let ghost = &Ghost::pin();
+        for i in 0..8u64 {
+            let buf = i.to_be_bytes();
+            tree.put(&buf, i, &buf, ghost).await.unwrap();
+        }
+        let node = tree
+            .try_find_node(&[], CacheTier::Hot, ghost)
+            .await
+            .unwrap();
+        let mut iter = tree.iter_node::<Key, Value>(&node, ghost).await.unwrap();
+        let mut data = Vec::new();
+        while let Some(&entry) = iter.next() {
+            data.push(entry);
+        }
+        tree.try_split_at(&node, &data[4..], ghost).unwrap();
+
+        // The split is not reconciled to the parent yet, so the consolidation must not drop it.
+        let split = Node {
+            id: node.id,
+            view: tree.node(node.id, node.range.clone()).unwrap().view,
+            range: node.range.clone(),
+        };
+        let err = tree.try_consolidate_leaf(&split, ghost).await.unwrap_err();
+        assert!(matches!(err, Error::Again { .. }));
+        tree.consolidate(&[], ghost).await.unwrap();
+        for i in 0..8u64 {
+            let buf = i.to_be_bytes();
+            assert_eq!(
+                tree.get(&buf, 8, ghost).await.unwrap(),
+                Some(buf.as_slice())
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn unknown_page_kind() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = BTree::open(dir.path(), Options::default()).await.unwrap();
+        let ghost = &Ghost::pin();
+        tree.put(b"key", 1, b"value", ghost).await.unwrap();
+        let node = tree
+            .try_find_node(b"key", CacheTier::Hot, ghost)
+            .await
+            .unwrap();
+        let mut page = match tree.page_addr(node.id) {
+            PageAddr::Mem(ptr) => unsafe { PagePtr::new(ptr as *mut u8) }.unwrap(),
+            PageAddr::Disk(_) => unreachable!(),
+        };
+        let kind = page.kind();
+        page.set_raw_kind(reserved::RANGE_TOMBSTONE);
+        let err = tree.get(b"key", 1, ghost).await.unwrap_err();
+        assert!(matches!(err, Error::NotSupported(_)), "{:?}", err);
+        page.set_kind(kind);
+        assert_eq!(
+            tree.get(b"key", 1, ghost).await.unwrap(),
+            Some(b"value".as_slice())
+        );
+    }
+
+    #[tokio::test]
+    async fn split_root() {
+        const N: u64 = 1024;
+        let opts = Options {
+            data_node_entries: 4,
+            index_node_entries: 4,
+            data_delta_length: 2,
+            event_log_size: 1 << 16,
+            ..Default::default()
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let tree = BTree::open(dir.path(), opts.clone()).await.unwrap();
+        let ghost = &Ghost::pin();
+        assert_eq!(tree.stats().height, 2);
+        for i in 0..N {
+            let buf = i.to_be_bytes();
+            tree.put(&buf, i, &buf, ghost).await.unwrap();
+        }
+        let height = tree.stats().height;
+        assert!(height > 3, "{}", height);
+        let splits: Vec<_> = tree
+            .events()
+            .into_iter()
+            .filter_map(|event| match event.kind {
+                EventKind::RootSplit { height, .. } => Some(height),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(splits, (3..=height).collect::<Vec<_>>());
+        for i in 0..N {
+            let buf = i.to_be_bytes();
+            let value = tree.get(&buf, i, ghost).await.unwrap();
+            assert_eq!(value, Some(buf.as_slice()));
+        }
+        assert_eq!(tree.inspect(ghost).await.unwrap().height, height);
+        let count = tree.approximate_count(&[], &[], ghost).await.unwrap();
+        assert_eq!(count, N);
+        tree.checkpoint(ghost).await.unwrap();
+        let report = tree.verify().await.unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+        drop(tree);
+
+        // The height is recovered with the tree.
+        let tree = BTree::open(dir.path(), opts).await.unwrap();
+        assert_eq!(tree.stats().height, height);
+        assert_eq!(tree.inspect(ghost).await.unwrap().height, height);
+    }
+
+    #[tokio::test]
+    async fn lookup_with_pending_split() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = BTree::open(dir.path(), Options::default()).await.unwrap();
+        let ghost = &Ghost::pin();
+        for i in 0..8u64 {
+            let buf = i.to_be_bytes();
+            tree.put(&buf, i, &buf, ghost).await.unwrap();
+        }
+        let node = tree
+            .try_find_node(&[], CacheTier::Hot, ghost)
+            .await
+            .unwrap();
+        let iter = tree.iter_node::<Key, Value>(&node, ghost).await.unwrap();
+        let data: Vec<_> = StdIter::new(iter).collect();
+        tree.try_split_at(&node, &data[4..], ghost).unwrap();
+        let split = tree.node(node.id, node.range.clone()).unwrap();
+
+        // The split is reconciled by the put, which goes to the right sibling.
+        let key = 6u64.to_be_bytes();
+        tree.put(&key, 8, b"new", ghost).await.unwrap();
+        // The left node still has the old entries below its split page, so a lookup from it must
+        // follow the split to see the put.
+        let value = tree
+            .lookup_value(Key::new(&key, 8), &split, ghost)
+            .await
+            .unwrap();
+        assert_eq!(value, Some(b"new".as_slice()));
+        for i in 0..8u64 {
+            let buf = i.to_be_bytes();
+            let value = tree
+                .lookup_value(Key::new(&buf, 7), &split, ghost)
+                .await
+                .unwrap();
+            assert_eq!(value, Some(buf.as_slice()));
+        }
+    }
+
+    #[tokio::test]
+    async fn scan_through_parent() {
+        let opts = Options {
+            data_node_size: 256,
+            ..Default::default()
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let tree = BTree::open(dir.path(), opts).await.unwrap();
+        let ghost = &Ghost::pin();
+        const N: u64 = 200;
+        for i in 0..N {
+            let buf = i.to_be_bytes();
+            tree.put(&buf, i, &buf, ghost).await.unwrap();
+        }
+
+        let mut keys = Vec::new();
+        let mut parent = None;
+        let mut next = tree
+            .scan_leaf(&[], &[], N, &mut parent, ghost, |k, _| {
+                keys.push(k.raw.to_vec());
+                true
+            })
+            .await
+            .unwrap();
+        assert!(parent.is_some());
+        // Splits the next leaf without reconciling it, so the parent is stale for it.
+        let start = next.clone().unwrap();
+        let node = tree
+            .try_find_node(&start, CacheTier::Hot, ghost)
+            .await
+            .unwrap();
+        let iter = tree.iter_node::<Key, Value>(&node, ghost).await.unwrap();
+        let data: Vec<_> = StdIter::new(iter).collect();
+        tree.try_split_at(&node, &data[data.len() / 2..], ghost)
+            .unwrap();
+        while let Some(start) = next {
+            next = tree
+                .scan_leaf(&start, &[], N, &mut parent, ghost, |k, _| {
+                    keys.push(k.raw.to_vec());
+                    true
+                })
+                .await
+                .unwrap();
+        }
+        let expected: Vec<_> = (0..N).map(|i| i.to_be_bytes().to_vec()).collect();
+        assert_eq!(keys, expected);
+    }
+
+    #[tokio::test]
+    async fn consolidate_by_chain_size() {
+        let opts = Options {
+            data_chain_size: 8 * 1024,
+            ..Default::default()
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let tree = BTree::open(dir.path(), opts).await.unwrap();
+        let ghost = &Ghost::pin();
+        // The deltas are not oversize, but the chain is too large with a few of them.
+        let value = [0; 3 * 1024];
+        for i in 0..4u64 {
+            tree.put(&i.to_be_bytes(), i, &value, ghost).await.unwrap();
+            let key = i.to_be_bytes();
+            let mut retry = Retry::new(&tree, &key, ghost);
+            let node = loop {
+                match tree.try_find_leaf(&key, ghost).await {
+                    Err(err) => retry.on_error(err).unwrap(),
+                    Ok(node) => break node,
+                }
+            };
+            assert!(node.view.len() < 3, "{}", node.view.len());
+            assert!(node.view.chain_size() < 12 * 1024);
+        }
+    }
+
+    #[tokio::test]
+    async fn merge_deltas() {
+        let opts = Options {
+            data_delta_length: 4,
+            data_delta_merge_length: 3,
+            ..Default::default()
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let tree = BTree::open(dir.path(), opts).await.unwrap();
+        let ghost = &Ghost::pin();
+        for i in 0..16u64 {
+            tree.put(&i.to_be_bytes(), 0, &[0; 64], ghost)
+                .await
+                .unwrap();
+        }
+        tree.consolidate(&[], ghost).await.unwrap();
+        let node = tree
+            .try_find_node(&[], CacheTier::Hot, ghost)
+            .await
+            .unwrap();
+        let base = node.view.as_addr();
+
+        // The newest deltas are merged, and the base page is kept.
+        let key = 0u64.to_be_bytes();
+        for lsn in 1..32u64 {
+            tree.put(&key, lsn, &lsn.to_be_bytes(), ghost)
+                .await
+                .unwrap();
+            let node = tree
+                .try_find_node(&key, CacheTier::Hot, ghost)
+                .await
+                .unwrap();
+            assert!(node.view.len() < 4);
+            let mut page = tree
+                .load_page_with_view(node.id, &node.view, ghost)
+                .await
+                .unwrap();
+            while page.len() > 0 {
+                page = unsafe { PagePtr::new(page.next() as *mut u8) }.unwrap();
+            }
+            assert_eq!(PageAddr::Mem(page.into()), base);
+        }
+        assert!(tree.stats().write.num_delta_merges >= 10);
+        for lsn in 1..32u64 {
+            let value = tree.get(&key, lsn, ghost).await.unwrap();
+            assert_eq!(value, Some(lsn.to_be_bytes().as_slice()));
+        }
+        let value = tree.get(&key, 0, ghost).await.unwrap();
+        assert_eq!(value, Some([0; 64].as_slice()));
+    }
+
+    #[tokio::test]
+    async fn delta_flush() {
+        let opts = Options {
+            data_delta_length: 4,
+            flush_policy: FlushPolicy::DeltaFlush,
+            ..Default::default()
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let tree = BTree::open(dir.path(), opts.clone()).await.unwrap();
+        let ghost = &Ghost::pin();
+        for i in 0..16u64 {
+            tree.put(&i.to_be_bytes(), 0, &[0; 64], ghost)
+                .await
+                .unwrap();
+        }
+        // The first flush of the leaf is consolidated.
+        tree.checkpoint(ghost).await.unwrap();
+        assert!(tree.store.manifest().await.page_bases.is_empty());
+        let leaf_addr = |page_table: &[(u64, u64)]| page_table[1].1;
+        let addr = leaf_addr(&tree.store.manifest().await.page_table);
+        // Unchanged leaves keep their pages.
+        tree.checkpoint(ghost).await.unwrap();
+        assert_eq!(leaf_addr(&tree.store.manifest().await.page_table), addr);
+
+        // The next flushes write the new deltas on top of the page on disk.
+        tree.put(&0u64.to_be_bytes(), 1, &[1; 64], ghost)
+            .await
+            .unwrap();
+        tree.checkpoint(ghost).await.unwrap();
+        let manifest = tree.store.manifest().await;
+        let delta_addr = leaf_addr(&manifest.page_table);
+        assert_eq!(manifest.page_bases, vec![(delta_addr, addr)]);
+        tree.delete(&1u64.to_be_bytes(), 2, ghost).await.unwrap();
+        tree.checkpoint(ghost).await.unwrap();
+        assert_eq!(tree.store.manifest().await.page_bases.len(), 2);
+        tree.close(ghost).await.unwrap();
+
+        // The chain is loaded back, and takes more deltas until it is consolidated.
+        let tree = BTree::open(dir.path(), opts).await.unwrap();
+        assert!(tree.verify().await.unwrap().problems.is_empty());
+        let key = 0u64.to_be_bytes();
+        let value = tree.get(&key, 1, ghost).await.unwrap();
+        assert_eq!(value, Some([1; 64].as_slice()));
+        let value = tree.get(&key, 0, ghost).await.unwrap();
+        assert_eq!(value, Some([0; 64].as_slice()));
+        let key = 1u64.to_be_bytes();
+        assert_eq!(tree.get(&key, 2, ghost).await.unwrap(), None);
+        tree.put(&key, 3, &[2; 64], ghost).await.unwrap();
+        tree.checkpoint(ghost).await.unwrap();
+        assert_eq!(tree.store.manifest().await.page_bases.len(), 3);
+        tree.put(&key, 4, &[2; 64], ghost).await.unwrap();
+        tree.put(&key, 5, &[3; 64], ghost).await.unwrap();
+        tree.checkpoint(ghost).await.unwrap();
+        assert!(tree.store.manifest().await.page_bases.is_empty());
+        assert!(tree.verify().await.unwrap().problems.is_empty());
+        for i in 2..16u64 {
+            let value = tree.get(&i.to_be_bytes(), 5, ghost).await.unwrap();
+            assert_eq!(value, Some([0; 64].as_slice()));
+        }
+        let value = tree.get(&key, 5, ghost).await.unwrap();
+        assert_eq!(value, Some([3; 64].as_slice()));
+    }
+
+    #[tokio::test]
+    async fn racing_swapins() {
+        // Slabs make the sizes of pages exact, whatever pages are freed before.
+        let opts = Options {
+            data_delta_length: 4,
+            flush_policy: FlushPolicy::DeltaFlush,
+            slab_alloc: true,
+            ..Default::default()
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let tree = BTree::open(dir.path(), opts.clone()).await.unwrap();
+        let ghost = &Ghost::pin();
+        for i in 0..16u64 {
+            tree.put(&i.to_be_bytes(), 0, &[0; 64], ghost)
+                .await
+                .unwrap();
+        }
+        tree.checkpoint(ghost).await.unwrap();
+        tree.put(&0u64.to_be_bytes(), 1, &[1; 64], ghost)
+            .await
+            .unwrap();
+        tree.checkpoint(ghost).await.unwrap();
+        assert_eq!(tree.store.manifest().await.page_bases.len(), 1);
+        tree.close(ghost).await.unwrap();
+
+        let tree = BTree::open(dir.path(), opts).await.unwrap();
+        let (id, addr) = tree.store.manifest().await.page_table[1];
+        assert_eq!(tree.page_addr(id), PageAddr::Disk(addr));
+        let size = tree.cache.size();
+        let chain = tree.store.load_page(addr, &tree.cache).await.unwrap();
+        let chain = chain.unwrap();
+        assert!(matches!(PageAddr::from(chain.next()), PageAddr::Mem(_)));
+        let chain_size = tree.cache.size() - size;
+        unsafe { dealloc_chain(&tree.cache, chain) };
+        assert_eq!(tree.cache.size(), size);
+
+        // The loser of the race frees the whole chain that it loads.
+        let (a, b) = futures::join!(
+            tree.swapin_page(id, addr, CacheTier::Hot, ghost),
+            tree.swapin_page(id, addr, CacheTier::Hot, ghost),
+        );
+        assert!(a.is_ok() != b.is_ok());
+        assert_eq!(tree.cache.size(), size + chain_size);
+        let value = tree.get(&0u64.to_be_bytes(), 1, ghost).await.unwrap();
+        assert_eq!(value, Some([1; 64].as_slice()));
+    }
+
+    #[tokio::test]
+    async fn len_estimate() {
+        let opts = Options {
+            data_delta_length: 16,
+            data_node_entries: 16,
+            ..Default::default()
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let tree = BTree::open(dir.path(), opts.clone()).await.unwrap();
+        let ghost = &Ghost::pin();
+        let consolidate_all = || async {
+            for i in 0..128u64 {
+                tree.consolidate(&i.to_be_bytes(), ghost).await.unwrap();
+            }
+        };
+        for i in 0..64u64 {
+            tree.put(&i.to_be_bytes(), 1, b"v", ghost).await.unwrap();
+        }
+        consolidate_all().await;
+        assert_eq!(tree.len_estimate(), 64);
+
+        // An overwrite and a delete of a missing key are miscounted until the leaves are
+        // consolidated.
+        tree.put(&0u64.to_be_bytes(), 2, b"w", ghost).await.unwrap();
+        tree.delete(&1u64.to_be_bytes(), 2, ghost).await.unwrap();
+        tree.delete(&100u64.to_be_bytes(), 2, ghost).await.unwrap();
+        tree.put(&63u64.to_be_bytes(), 2, b"w", ghost)
+            .await
+            .unwrap();
+        assert_eq!(tree.len_estimate(), 64);
+        consolidate_all().await;
+        assert_eq!(tree.len_estimate(), 63);
+        tree.close(ghost).await.unwrap();
+
+        let tree = BTree::open(dir.path(), opts).await.unwrap();
+        assert_eq!(tree.len_estimate(), 63);
+    }
+
+    #[tokio::test]
+    async fn memory_usage() {
+        let opts = Options {
+            data_node_entries: 4,
+            data_delta_length: 4,
+            ..Default::default()
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let tree = BTree::open(dir.path(), opts).await.unwrap();
+        let ghost = &Ghost::pin();
+        for i in 0..64u64 {
+            let buf = i.to_be_bytes();
+            tree.put(&buf, i, &buf, ghost).await.unwrap();
+        }
+        tree.put(&0u64.to_be_bytes(), 64, b"delta", ghost)
+            .await
+            .unwrap();
+        let usage = tree.memory_usage(ghost).await.unwrap();
+        assert!(usage.base_bytes > 0);
+        assert!(usage.delta_bytes > 0);
+        assert!(usage.index_bytes > 0);
+        let pages = usage.base_bytes + usage.delta_bytes + usage.index_bytes;
+        assert!(pages <= usage.cache_bytes);
+        // The first segment of the page table covers the nodes.
+        assert_eq!(usage.page_table_bytes, 1024 * 8);
+        assert_eq!(usage.num_pending_jobs, 0);
+    }
+
+    #[tokio::test]
+    async fn sample_keys() {
+        let opts = Options {
+            data_node_entries: 16,
+            ..Default::default()
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let tree = BTree::open(dir.path(), opts).await.unwrap();
+        let ghost = &Ghost::pin();
+        let mut rng = Rng(0);
+        assert!(tree
+            .sample_keys_with_rng(8, &mut rng, ghost)
+            .await
+            .unwrap()
+            .is_empty());
+
+        for i in 0..1024u64 {
+            let buf = i.to_be_bytes();
+            tree.put(&buf, 1, &buf, ghost).await.unwrap();
+        }
+        for i in 512..1024u64 {
+            tree.delete(&i.to_be_bytes(), 2, ghost).await.unwrap();
+        }
+        let keys = tree
+            .sample_keys_with_rng(64, &mut rng, ghost)
+            .await
+            .unwrap();
+        // The samples of the leaves with only deleted keys are dropped.
+        assert!(keys.len() > 16, "{:?}", keys);
+        assert!(keys.windows(2).all(|w| w[0] < w[1]));
+        let keys: Vec<_> = keys
+            .iter()
+            .map(|k| u64::from_be_bytes(k.as_slice().try_into().unwrap()))
+            .collect();
+        // Deleted keys are not sampled, and the samples spread over the live keys.
+        assert!(keys.iter().all(|&k| k < 512));
+        assert!(keys[0] < 128 && keys[keys.len() - 1] >= 384, "{:?}", keys);
+    }
+
+    #[tokio::test]
+    async fn flush() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = BTree::open(dir.path(), Options::default()).await.unwrap();
+        let ghost = &Ghost::pin();
+        assert_eq!(tree.durable_lsn(), 0);
+        for i in 1..=16u64 {
+            let buf = i.to_be_bytes();
+            tree.put(&buf, i, &buf, ghost).await.unwrap();
+        }
+        assert_eq!(tree.durable_lsn(), 0);
+        assert_eq!(tree.flush(ghost).await.unwrap(), 16);
+        // Flushes without updates keep the durable LSN.
+        assert_eq!(tree.flush(ghost).await.unwrap(), 16);
+        drop(tree);
+
+        let opts = Options {
+            read_only: true,
+            ..Default::default()
+        };
+        let tree = BTree::open(dir.path(), opts).await.unwrap();
+        assert_eq!(tree.flush(ghost).await.unwrap(), 16);
+    }
+
+    #[tokio::test]
+    async fn durable_lsn_of_completed_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = BTree::open(dir.path(), Options::default()).await.unwrap();
+        let ghost = &Ghost::pin();
+        tree.put(b"a", 1, b"a", ghost).await.unwrap();
+        // LSNs that are allocated without updates are not durable.
+        let lsn = tree.next_lsn();
+        assert_eq!(tree.flush(ghost).await.unwrap(), 1);
+        // Neither are the updates in flight, nor the ones after them.
+        let write = tree.writes.begin(lsn);
+        tree.put(b"b", lsn + 1, b"b", ghost).await.unwrap();
+        assert_eq!(tree.flush(ghost).await.unwrap(), 1);
+        write.complete();
+        assert_eq!(tree.flush(ghost).await.unwrap(), lsn + 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn durable_lsn_with_concurrent_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let handle = tokio::runtime::Handle::current();
+        for _ in 0..8 {
+            let tree = BTree::open(dir.path(), Options::default()).await.unwrap();
+            let first = tree.last_lsn() + 1;
+            let done = std::sync::atomic::AtomicBool::new(false);
+            let durable_lsn = std::thread::scope(|s| {
+                s.spawn(|| {
+                    for _ in 0..256 {
+                        let lsn = tree.next_lsn();
+                        let buf = lsn.to_be_bytes();
+                        let ghost = &Ghost::pin();
+                        handle.block_on(tree.put(&buf, lsn, &buf, ghost)).unwrap();
+                    }
+                    done.store(true, Ordering::Release);
+                });
+                let flusher = s.spawn(|| {
+                    let mut durable_lsn = 0;
+                    while !done.load(Ordering::Acquire) {
+                        let ghost = &Ghost::pin();
+                        durable_lsn = handle.block_on(tree.flush(ghost)).unwrap();
+                    }
+                    durable_lsn
+                });
+                flusher.join().unwrap()
+            });
+            // The writes up to the durable LSN are recovered after a crash.
+            drop(tree);
+            let tree = BTree::open(dir.path(), Options::default()).await.unwrap();
+            let ghost = &Ghost::pin();
+            for lsn in first..=durable_lsn {
+                let buf = lsn.to_be_bytes();
+                let value = tree.get(&buf, lsn, ghost).await.unwrap();
+                assert_eq!(value, Some(buf.as_slice()), "{} of {}", lsn, durable_lsn);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn sync_mode() {
+        async fn put(tree: &BTree, lsn: u64) {
+            let buf = lsn.to_be_bytes();
+            tree.put(&buf, lsn, &buf, &Ghost::pin()).await.unwrap();
+        }
+
+        let ghost = &Ghost::pin();
+
+        let dir = tempfile::tempdir().unwrap();
+        let opts = Options {
+            sync_mode: SyncMode::Always,
+            ..Default::default()
+        };
+        let tree = BTree::open(dir.path(), opts).await.unwrap();
+        put(&tree, 1).await;
+        assert_eq!(tree.durable_lsn(), 1);
+        // Concurrent writes share checkpoints.
+        futures::future::join_all((2..=8).map(|lsn| put(&tree, lsn))).await;
+        assert_eq!(tree.durable_lsn(), 8);
+        assert!(tree.lifetime_stats().num_checkpoints <= 8);
+        drop(tree);
+
+        for sync_mode in [SyncMode::Every(Duration::from_millis(20)), SyncMode::Never] {
+            let dir = tempfile::tempdir().unwrap();
+            let opts = Options {
+                sync_mode,
+                ..Default::default()
+            };
+            let tree = BTree::open(dir.path(), opts).await.unwrap();
+            put(&tree, 1).await;
+            assert_eq!(tree.durable_lsn(), 0);
+            let start = Instant::now();
+            assert_eq!(tree.wait_durable(ghost).await.unwrap(), 1);
+            if let SyncMode::Every(interval) = sync_mode {
+                assert!(start.elapsed() >= interval / 2);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn close() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = BTree::open(dir.path(), Options::default()).await.unwrap();
+        let ghost = &Ghost::pin();
+        for i in 0..64u64 {
+            let buf = i.to_be_bytes();
+            tree.put(&buf, i, &buf, ghost).await.unwrap();
+        }
+        tree.close(ghost).await.unwrap();
+
+        // The tree is unlocked and opened from the final checkpoint.
+        let tree = BTree::open(dir.path(), Options::default()).await.unwrap();
+        assert!(matches!(
+            tree.events()[0].kind,
+            EventKind::ManifestRecovered { pages, lsn: 63, .. } if pages > 0
+        ));
+        for i in 0..64u64 {
+            let buf = i.to_be_bytes();
+            assert_eq!(tree.get(&buf, i, ghost).await.unwrap(), Some(&buf[..]));
+        }
+        tree.close(ghost).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn events() {
+        let opts = Options {
+            data_node_entries: 4,
+            data_delta_length: 2,
+            ..Default::default()
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let tree = BTree::open(dir.path(), opts.clone()).await.unwrap();
+        let ghost = &Ghost::pin();
+        for i in 0..64u64 {
+            let buf = i.to_be_bytes();
+            tree.put(&buf, i, &buf, ghost).await.unwrap();
+        }
+        let events = tree.events();
+        assert!(matches!(
+            events[0].kind,
+            EventKind::ManifestRecovered { pages: 0, .. }
+        ));
+        assert!(matches!(events[1].kind, EventKind::Opened { .. }));
+        let splits = events
+            .iter()
+            .filter(|event| matches!(event.kind, EventKind::Split { .. }))
+            .count();
+        let reconciles = events
+            .iter()
+            .filter(|event| matches!(event.kind, EventKind::Reconcile { .. }))
+            .count();
+        assert!(splits > 0);
+        assert!(reconciles > 0 && reconciles <= splits);
+        assert!(events.windows(2).all(|w| w[0].time <= w[1].time));
+
+        drop(tree);
+
+        // The log only keeps the latest events.
+        let opts = Options {
+            event_log_size: 4,
+            ..opts
+        };
+        let tree = BTree::open(dir.path(), opts).await.unwrap();
+        for i in 64..128u64 {
+            let buf = i.to_be_bytes();
+            tree.put(&buf, i, &buf, ghost).await.unwrap();
+        }
+        assert_eq!(tree.events().len(), 4);
+    }
+
+    #[derive(Default)]
+    struct RecordingListener(Mutex<Vec<&'static str>>);
+
+    impl RecordingListener {
+        fn count(&self, name: &str) -> usize {
+            self.0
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|&&n| n == name)
+                .count()
+        }
+    }
+
+    impl EventListener for RecordingListener {
+        fn on_flush_begin(&self) {
+            self.0.lock().unwrap().push("flush_begin");
+        }
+
+        fn on_flush_end(&self, _: Duration, result: &Result<()>) {
+            assert!(result.is_ok());
+            self.0.lock().unwrap().push("flush_end");
+        }
+
+        fn on_consolidation(&self, _: u64, _: u8, _: Duration) {
+            self.0.lock().unwrap().push("consolidation");
+        }
+
+        fn on_split(&self, _: u64, _: u64) {
+            self.0.lock().unwrap().push("split");
+        }
+
+        fn on_eviction(&self, nodes: usize, _: usize) {
+            assert!(nodes > 0);
+            self.0.lock().unwrap().push("eviction");
+        }
+
+        fn on_recovery_done(&self, _: u64, _: Duration) {
+            self.0.lock().unwrap().push("recovery_done");
+        }
+    }
+
+    #[tokio::test]
+    async fn event_listener() {
+        let listener = Arc::new(RecordingListener::default());
+        let opts = Options {
+            data_node_entries: 4,
+            data_delta_length: 2,
+            cache_size: 4096,
+            event_listener: Some(listener.clone()),
+            ..Default::default()
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let tree = BTree::open(dir.path(), opts.clone()).await.unwrap();
+        assert_eq!(*listener.0.lock().unwrap(), ["recovery_done"]);
+        let ghost = &Ghost::pin();
+        for i in 0..64u64 {
+            let buf = i.to_be_bytes();
+            tree.put(&buf, i, &buf, ghost).await.unwrap();
+        }
+        assert!(listener.count("consolidation") > 0);
+        assert!(listener.count("split") > 0);
+        tree.checkpoint(ghost).await.unwrap();
+        assert!(listener.count("flush_begin") > 0);
+        assert_eq!(listener.count("flush_begin"), listener.count("flush_end"));
+        // Clean nodes are evicted once the cache is over budget.
+        for i in 0..64u64 {
+            tree.get(&i.to_be_bytes(), 64, ghost).await.unwrap();
+        }
+        assert!(listener.count("eviction") > 0);
+        tree.close(ghost).await.unwrap();
+
+        BTree::open(dir.path(), opts).await.unwrap();
+        assert_eq!(listener.count("recovery_done"), 2);
+    }
+
+    /// Records the spans and their fields, on a single thread.
+    #[cfg(feature = "tracing")]
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<RecordedSpans>>);
+
+    #[cfg(feature = "tracing")]
+    #[derive(Default)]
+    struct RecordedSpans {
+        spans: Vec<(&'static tracing::Metadata<'static>, Vec<String>)>,
+        entered: Vec<tracing::span::Id>,
+    }
+
+    #[cfg(feature = "tracing")]
+    impl Recorder {
+        /// Returns the names of the fields of the spans of `name`.
+        fn spans(&self, name: &str) -> Vec<Vec<String>> {
+            let inner = self.0.lock().unwrap();
+            inner
+                .spans
+                .iter()
+                .filter(|(meta, _)| meta.name() == name)
+                .map(|(_, fields)| fields.clone())
+                .collect()
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    impl tracing::Subscriber for Recorder {
+        fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            let mut fields = Vec::new();
+            span.record(
+                &mut |field: &tracing::field::Field, _: &dyn std::fmt::Debug| {
+                    fields.push(field.name().to_owned())
+                },
+            );
+            let mut inner = self.0.lock().unwrap();
+            inner.spans.push((span.metadata(), fields));
+            tracing::span::Id::from_u64(inner.spans.len() as u64)
+        }
+
+        fn record(&self, id: &tracing::span::Id, values: &tracing::span::Record<'_>) {
+            let mut inner = self.0.lock().unwrap();
+            let fields = &mut inner.spans[id.into_u64() as usize - 1].1;
+            values.record(
+                &mut |field: &tracing::field::Field, _: &dyn std::fmt::Debug| {
+                    fields.push(field.name().to_owned())
+                },
+            );
+        }
+
+        fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+
+        fn event(&self, _: &tracing::Event<'_>) {}
+
+        fn enter(&self, id: &tracing::span::Id) {
+            self.0.lock().unwrap().entered.push(id.clone());
+        }
+
+        fn exit(&self, _: &tracing::span::Id) {
+            self.0.lock().unwrap().entered.pop();
+        }
+
+        fn current_span(&self) -> tracing_core::span::Current {
+            let inner = self.0.lock().unwrap();
+            match inner.entered.last() {
+                Some(id) => {
+                    let meta = inner.spans[id.into_u64() as usize - 1].0;
+                    tracing_core::span::Current::new(id.clone(), meta)
+                }
+                None => tracing_core::span::Current::none(),
+            }
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    #[tokio::test]
+    async fn spans() {
+        let recorder = Recorder::default();
+        let _guard = tracing::subscriber::set_default(recorder.clone());
+        let opts = Options {
+            data_node_entries: 4,
+            data_delta_length: 2,
+            ..Default::default()
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let tree = BTree::open(dir.path(), opts).await.unwrap();
+        let ghost = &Ghost::pin();
+        for i in 0..16u64 {
+            let buf = i.to_be_bytes();
+            tree.put(&buf, i, &buf, ghost).await.unwrap();
+        }
+        tree.get(&0u64.to_be_bytes(), 0, ghost).await.unwrap();
+        tree.checkpoint(ghost).await.unwrap();
+
+        let has = |fields: &[String], name: &str| fields.iter().any(|n| n == name);
+        assert_eq!(recorder.spans("open").len(), 1);
+        let puts = recorder.spans("put");
+        assert_eq!(puts.len(), 16);
+        assert!(puts
+            .iter()
+            .all(|fields| has(fields, "node") && has(fields, "delta_len")));
+        assert!(recorder
+            .spans("get")
+            .iter()
+            .all(|fields| has(fields, "node") && has(fields, "bytes")));
+        assert!(!recorder.spans("consolidate").is_empty());
+        assert!(recorder
+            .spans("split")
+            .iter()
+            .all(|fields| has(fields, "right")));
+        let checkpoints = recorder.spans("checkpoint");
+        assert!(has(&checkpoints[0], "pages") && has(&checkpoints[0], "bytes"));
     }
 }
@@ -0,0 +1,278 @@
+//! Lock-free installation of pages to the chains of nodes.
+//!
+//! The first page of a node is swapped in the page table with a CAS. Deltas and split pages are
+//! chained on top of the first page, and consolidated pages replace the whole chain. A page keeps
+//! the version of the page below it, except that a split page or a replaced page bumps it, so that
+//! the nodes that are found before a split can tell that their parents are stale.
+
+use super::{
+    page::PagePtr,
+    pagecache::{PageAddr, PageView},
+    pagetable::PageTable,
+};
+
+/// Chains `delta` to the node `id` whose first page is `view`.
+///
+/// Deltas and consolidations keep the version of the node, so the CAS is retried as long as the
+/// first page has the same version. Deltas are never chained to a node that has been evicted in
+/// between.
+///
+/// Returns the address of the first page if the node has another version or is on disk.
+pub fn install_delta(
+    table: &PageTable,
+    id: u64,
+    mut view: PageView,
+    mut delta: PagePtr,
+) -> Result<(), u64> {
+    loop {
+        delta.set_ver(view.ver());
+        delta.set_len(view.len() + 1);
+        delta.set_chain_size(chained_size(delta, &view));
+        delta.set_next(view.as_addr().into());
+        let addr = match table.cas(id, delta.next(), delta.into()) {
+            Ok(_) => return Ok(()),
+            Err(addr) => addr,
+        };
+        match PageAddr::from(addr) {
+            PageAddr::Mem(ptr) => match unsafe { PagePtr::new(ptr as *mut u8) } {
+                Some(page) if page.ver() == view.ver() => view = page.into(),
+                _ => return Err(addr),
+            },
+            PageAddr::Disk(_) => return Err(addr),
+        }
+    }
+}
+
+/// Replaces the chain of the node `id` whose first page is `view` with the consolidated `page`.
+///
+/// The chain must not be changed since `page` is built from it, so the CAS is not retried.
+pub fn install_page(
+    table: &PageTable,
+    id: u64,
+    view: &PageView,
+    mut page: PagePtr,
+) -> Result<(), u64> {
+    page.set_ver(view.ver());
+    page.set_index(view.is_index());
+    table
+        .cas(id, view.as_addr().into(), page.into())
+        .map(|_| ())
+}
+
+/// Replaces the chain of the node `id` whose first page is `view` with `page`, which bumps the
+/// version of the node like a split, so that the nodes that are found before the replacement can
+/// tell that their parents are stale.
+///
+/// The CAS is not retried.
+pub fn replace_page(
+    table: &PageTable,
+    id: u64,
+    view: &PageView,
+    mut page: PagePtr,
+) -> Result<(), u64> {
+    page.set_ver(view.ver().next());
+    page.set_index(view.is_index());
+    table
+        .cas(id, view.as_addr().into(), page.into())
+        .map(|_| ())
+}
+
+/// Chains the `split` page to the node `id` whose first page is `view`, which bumps the version
+/// of the node.
+///
+/// The split is computed from the chain, so the CAS is not retried.
+pub fn install_split(
+    table: &PageTable,
+    id: u64,
+    view: &PageView,
+    mut split: PagePtr,
+) -> Result<(), u64> {
+    split.set_ver(view.ver().next());
+    split.set_len(view.len() + 1);
+    split.set_chain_size(chained_size(split, view));
+    split.set_next(view.as_addr().into());
+    split.set_index(view.is_index());
+    table.cas(id, split.next(), split.into()).map(|_| ())
+}
+
+/// Returns the chain size of `page` when it is chained to the node whose first page is `view`.
+pub fn chained_size(page: PagePtr, view: &PageView) -> u32 {
+    (page.size() as u64 + view.chain_size() as u64).min(u32::MAX as u64) as u32
+}
+
+/// Model checks of the installations with loom, run with
+/// `RUSTFLAGS="--cfg loom" cargo test -p photondb-engine --release --lib loom`.
+///
+/// The models use small pages whose content is a set of bits, one for each write. Pages are
+/// allocated on the heap, since loom threads have small stacks, and are freed at the end of each
+/// execution, as epochs would do.
+#[cfg(all(test, loom))]
+mod loom_test {
+    use std::{
+        alloc::{alloc_zeroed, dealloc},
+        sync::Mutex,
+    };
+
+    use crossbeam_epoch::unprotected;
+    use loom::{sync::Arc, thread};
+
+    use super::*;
+    use crate::tree::page::{PageAlloc, PageBuilder, PageKind};
+
+    struct HeapAlloc;
+
+    unsafe impl PageAlloc for HeapAlloc {
+        type Error = ();
+
+        fn alloc(&self, size: usize) -> Result<PagePtr, ()> {
+            unsafe { PagePtr::new(alloc_zeroed(Self::alloc_layout(size))).ok_or(()) }
+        }
+
+        unsafe fn dealloc(&self, page: PagePtr) {
+            dealloc(page.as_raw(), Self::alloc_layout(page.size()));
+        }
+    }
+
+    /// The pages of an execution.
+    #[derive(Default)]
+    struct Pages(Mutex<Vec<PagePtr>>);
+
+    // Pages are only shared through the page table.
+    unsafe impl Send for Pages {}
+    unsafe impl Sync for Pages {}
+
+    impl Pages {
+        fn new_page(&self, kind: PageKind, bits: u64) -> PagePtr {
+            let mut page = PageBuilder::new(kind).build(&HeapAlloc, 8).unwrap();
+            unsafe { (page.content_mut() as *mut u64).write(bits) };
+            self.0.lock().unwrap().push(page);
+            page
+        }
+    }
+
+    impl Drop for Pages {
+        fn drop(&mut self) {
+            for page in self.0.get_mut().unwrap().drain(..) {
+                unsafe { HeapAlloc.dealloc(page) };
+            }
+        }
+    }
+
+    fn bits(page: PagePtr) -> u64 {
+        unsafe { (page.content() as *const u64).read() }
+    }
+
+    fn first_page(table: &PageTable, id: u64) -> PageView {
+        unsafe { PagePtr::new(table.get(id) as *mut u8).unwrap().into() }
+    }
+
+    /// Returns the bits of the chain of the node, and checks the versions and the lengths of the
+    /// pages on the way.
+    fn chain_bits(table: &PageTable, id: u64) -> u64 {
+        let mut page = unsafe { PagePtr::new(table.get(id) as *mut u8) };
+        let mut bits = 0;
+        while let Some(p) = page {
+            bits |= self::bits(p);
+            page = unsafe { PagePtr::new(p.next() as *mut u8) };
+            match page {
+                Some(next) => {
+                    let ver = if p.kind() == PageKind::Split {
+                        next.ver().next()
+                    } else {
+                        next.ver()
+                    };
+                    assert_eq!(p.ver(), ver);
+                    assert_eq!(p.len(), next.len() + 1);
+                }
+                None => assert_eq!(p.len(), 0),
+            }
+        }
+        bits
+    }
+
+    /// Creates a node with a base page of `bits`.
+    fn new_node(pages: &Pages, bits: u64) -> (PageTable, u64) {
+        let table = PageTable::default();
+        let id = table.alloc(unsafe { unprotected() }).unwrap();
+        table.set(id, pages.new_page(PageKind::Data, bits).into());
+        (table, id)
+    }
+
+    #[test]
+    fn concurrent_deltas() {
+        loom::model(|| {
+            let pages = Arc::new(Pages::default());
+            let (table, id) = new_node(&pages, 1);
+            let handles: Vec<_> = [2, 4]
+                .into_iter()
+                .map(|bit| {
+                    let (table, pages) = (table.clone(), pages.clone());
+                    thread::spawn(move || {
+                        let delta = pages.new_page(PageKind::Data, bit);
+                        install_delta(&table, id, first_page(&table, id), delta).unwrap();
+                    })
+                })
+                .collect();
+            for handle in handles {
+                handle.join().unwrap();
+            }
+            // Both deltas are chained, whichever wins the first CAS.
+            assert_eq!(chain_bits(&table, id), 7);
+            assert_eq!(first_page(&table, id).len(), 2);
+        });
+    }
+
+    #[test]
+    fn delta_and_consolidation() {
+        loom::model(|| {
+            let pages = Arc::new(Pages::default());
+            let (table, id) = new_node(&pages, 1);
+            let writer = {
+                let (table, pages) = (table.clone(), pages.clone());
+                thread::spawn(move || {
+                    let delta = pages.new_page(PageKind::Data, 2);
+                    install_delta(&table, id, first_page(&table, id), delta).unwrap();
+                })
+            };
+            let view = first_page(&table, id);
+            let page = pages.new_page(PageKind::Data, chain_bits(&table, id));
+            let consolidated = install_page(&table, id, &view, page).is_ok();
+            writer.join().unwrap();
+            // The delta is either consolidated, or chained to the consolidated page, or makes the
+            // consolidation fail.
+            assert_eq!(chain_bits(&table, id), 3);
+            if consolidated {
+                assert!(first_page(&table, id).len() <= 1);
+            }
+        });
+    }
+
+    #[test]
+    fn delta_and_split() {
+        loom::model(|| {
+            let pages = Arc::new(Pages::default());
+            let (table, id) = new_node(&pages, 1);
+            let writer = {
+                let (table, pages) = (table.clone(), pages.clone());
+                thread::spawn(move || {
+                    let delta = pages.new_page(PageKind::Data, 2);
+                    install_delta(&table, id, first_page(&table, id), delta).is_ok()
+                })
+            };
+            // Splits retry on failures, as the tree does.
+            loop {
+                let view = first_page(&table, id);
+                let split = pages.new_page(PageKind::Split, 4);
+                if install_split(&table, id, &view, split).is_ok() {
+                    break;
+                }
+            }
+            let installed = writer.join().unwrap();
+            // A delta that sees the version before the split is never chained after it, so it is
+            // either chained before the split, or fails and retries from the parent.
+            let bits = chain_bits(&table, id);
+            assert_eq!(bits & 2 != 0, installed);
+            assert_eq!(bits & 5, 5);
+        });
+    }
+}
@@ -0,0 +1,245 @@
+use std::{cmp::Ordering, fmt};
+
+/// Defines the order of keys in a table.
+///
+/// A table must be opened with the same comparator every time, since its nodes on disk are sorted
+/// and routed by the comparator.
+pub trait Comparator: Send + Sync {
+    /// Returns the name of the order.
+    ///
+    /// The name is recorded at checkpoints, and a table can't be opened with a comparator of
+    /// another name, since its keys are sorted in the recorded order. Change the name whenever the
+    /// order changes.
+    fn name(&self) -> String;
+
+    /// Compares two non-empty keys.
+    ///
+    /// The order must be total. The empty key is always ordered before other keys, since it is
+    /// the lower bound of the leftmost node, so it is never passed to comparators.
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering;
+
+    /// Returns true if the order is the lexicographic order of bytes.
+    ///
+    /// Pages only store the sort prefixes of their keys under such an order, since the prefixes
+    /// are compared as bytes.
+    fn is_bytewise(&self) -> bool {
+        false
+    }
+
+    /// Returns true if keys end with timestamps, see [`TimestampComparator`].
+    fn has_timestamp(&self) -> bool {
+        false
+    }
+}
+
+/// The default comparator that orders keys lexicographically by bytes.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct BytewiseComparator;
+
+impl Comparator for BytewiseComparator {
+    fn name(&self) -> String {
+        "photondb.BytewiseComparator".to_owned()
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        a.cmp(b)
+    }
+
+    fn is_bytewise(&self) -> bool {
+        true
+    }
+}
+
+/// The size of the timestamps of keys, which are big-endian `u64`s.
+pub const TIMESTAMP_SIZE: usize = 8;
+
+/// Appends `ts` to `key`.
+pub fn append_timestamp(key: &[u8], ts: u64) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(key.len() + TIMESTAMP_SIZE);
+    buf.extend_from_slice(key);
+    buf.extend_from_slice(&ts.to_be_bytes());
+    buf
+}
+
+/// Splits a key into the user key and its timestamp, or returns `None` if the key is too short to
+/// have a timestamp.
+pub fn split_timestamp(key: &[u8]) -> Option<(&[u8], u64)> {
+    let (user_key, ts) = split_timestamp_bytes(key);
+    let ts = ts.try_into().ok()?;
+    Some((user_key, u64::from_be_bytes(ts)))
+}
+
+fn split_timestamp_bytes(key: &[u8]) -> (&[u8], &[u8]) {
+    if key.len() < TIMESTAMP_SIZE {
+        (key, &[])
+    } else {
+        key.split_at(key.len() - TIMESTAMP_SIZE)
+    }
+}
+
+/// A comparator of keys that end with timestamps, like the user-defined timestamps of RocksDB.
+///
+/// Keys are ordered by the user keys in the order of the inner comparator, and then by their
+/// timestamps from the newest to the oldest, so that `Table::get_at` reads the version of a key as
+/// of a timestamp. The timestamps are independent of LSNs: each timestamp of a key is a separate
+/// key with its own LSN versions.
+///
+/// Keys are built with [`append_timestamp`]. Keys that are too short to have a timestamp are
+/// ordered after all the timestamps of their user keys.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct TimestampComparator<C = BytewiseComparator> {
+    inner: C,
+}
+
+impl<C: Comparator> TimestampComparator<C> {
+    pub fn new(inner: C) -> Self {
+        Self { inner }
+    }
+}
+
+impl<C: Comparator> Comparator for TimestampComparator<C> {
+    fn name(&self) -> String {
+        format!("photondb.TimestampComparator({})", self.inner.name())
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        let (a, a_ts) = split_timestamp_bytes(a);
+        let (b, b_ts) = split_timestamp_bytes(b);
+        let ord = match (a.is_empty(), b.is_empty()) {
+            (true, true) => Ordering::Equal,
+            (true, false) => Ordering::Less,
+            (false, true) => Ordering::Greater,
+            (false, false) => self.inner.compare(a, b),
+        };
+        ord.then_with(|| b_ts.cmp(a_ts))
+    }
+
+    fn has_timestamp(&self) -> bool {
+        true
+    }
+}
+
+/// Checks that `cmp` orders `samples` of keys as the tree expects, and panics if it doesn't.
+///
+/// The order must be total, and it must be the order of bytes if `Comparator::is_bytewise` says
+/// so, since sort prefixes are compared as bytes then. Empty samples are skipped, since the empty
+/// key is never passed to comparators.
+pub fn check_comparator(cmp: &dyn Comparator, samples: &[&[u8]]) {
+    assert_eq!(cmp.name(), cmp.name(), "name of the comparator changes");
+    let keys: Vec<_> = samples.iter().filter(|k| !k.is_empty()).collect();
+    for &a in &keys {
+        for &b in &keys {
+            let ab = cmp.compare(a, b);
+            assert_eq!(
+                cmp.compare(b, a),
+                ab.reverse(),
+                "keys {:?} and {:?} are ordered differently both ways",
+                a,
+                b
+            );
+            if a == b {
+                assert_eq!(ab, Ordering::Equal, "key {:?} is not equal to itself", a);
+            }
+            if cmp.is_bytewise() {
+                assert_eq!(
+                    ab,
+                    a.cmp(b),
+                    "keys {:?} and {:?} are not in byte order",
+                    a,
+                    b
+                );
+            }
+            for &c in &keys {
+                let bc = cmp.compare(b, c);
+                if ab != Ordering::Greater && bc != Ordering::Greater {
+                    assert_eq!(
+                        cmp.compare(a, c),
+                        ab.then(bc),
+                        "keys {:?}, {:?} and {:?} are not ordered transitively",
+                        a,
+                        b,
+                        c
+                    );
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Debug for dyn Comparator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Comparator")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Reverse;
+
+    impl Comparator for Reverse {
+        fn name(&self) -> String {
+            "test.Reverse".to_owned()
+        }
+
+        fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+            b.cmp(a)
+        }
+
+        fn is_bytewise(&self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn conformance() {
+        let keys = [
+            b"".as_slice(),
+            b"a",
+            b"ab",
+            b"b",
+            &[0],
+            &[0xff; 8],
+            &[0xff; 9],
+        ];
+        let timestamped: Vec<_> = keys
+            .iter()
+            .flat_map(|k| [append_timestamp(k, 0), append_timestamp(k, u64::MAX)])
+            .collect();
+        let timestamped: Vec<_> = timestamped
+            .iter()
+            .map(|k| k.as_slice())
+            .chain(keys)
+            .collect();
+        check_comparator(&BytewiseComparator, &keys);
+        check_comparator(&TimestampComparator::new(BytewiseComparator), &timestamped);
+        // The order is not the order of bytes that it claims to be.
+        let result = std::panic::catch_unwind(|| check_comparator(&Reverse, &keys));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn timestamp_comparator() {
+        let cmp = TimestampComparator::new(BytewiseComparator);
+        let mut keys = [
+            append_timestamp(b"b", 1),
+            append_timestamp(b"a", 1),
+            append_timestamp(b"a", 2),
+            append_timestamp(b"ab", 3),
+            b"a".to_vec(),
+        ];
+        keys.sort_by(|a, b| cmp.compare(a, b));
+        let keys: Vec<_> = keys.iter().map(|k| split_timestamp(k)).collect();
+        assert_eq!(
+            keys,
+            vec![
+                Some((b"a".as_slice(), 2)),
+                Some((b"a".as_slice(), 1)),
+                None,
+                Some((b"ab".as_slice(), 3)),
+                Some((b"b".as_slice(), 1)),
+            ]
+        );
+    }
+}
@@ -0,0 +1,41 @@
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use super::{ContentionStats, NodeContention};
+
+/// Tracks the operations that give up on contended nodes.
+#[derive(Default)]
+pub struct ContentionTracker {
+    num_contentions: AtomicU64,
+    nodes: Mutex<HashMap<u64, NodeContention>>,
+}
+
+impl ContentionTracker {
+    /// Records that an operation on `key` gave up on the node.
+    pub fn record(&self, node_id: u64, key: &[u8]) {
+        self.num_contentions.fetch_add(1, Ordering::Relaxed);
+        let mut nodes = self.nodes.lock().unwrap();
+        let node = nodes.entry(node_id).or_insert_with(|| NodeContention {
+            node_id,
+            ..Default::default()
+        });
+        node.num_contentions += 1;
+        node.last_key.clear();
+        node.last_key.extend_from_slice(key);
+    }
+
+    pub fn stats(&self) -> ContentionStats {
+        let mut hot_nodes: Vec<_> = self.nodes.lock().unwrap().values().cloned().collect();
+        hot_nodes.sort_by_key(|node| Reverse(node.num_contentions));
+        ContentionStats {
+            num_contentions: self.num_contentions.load(Ordering::Relaxed),
+            hot_nodes,
+        }
+    }
+}
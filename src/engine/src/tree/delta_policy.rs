@@ -0,0 +1,132 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// The policies to choose from in `Options` for the number of deltas that a leaf takes before it
+/// is consolidated.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DeltaLengthPolicy {
+    /// Consolidates every leaf at `Options::data_delta_length` deltas.
+    Fixed,
+    /// Consolidates each leaf at a number of deltas between `min` and `max`, by the recent reads
+    /// and writes of the leaf.
+    ///
+    /// Reads walk the whole chain of a leaf, so leaves that are mostly read are consolidated at
+    /// `min` deltas, while leaves that are only written tolerate chains of `max` deltas and are
+    /// rewritten less often.
+    Adaptive { min: u8, max: u8 },
+}
+
+const NUM_SLOTS: usize = 4096;
+// The counters of a slot are halved once they add up to this, so that they follow recent
+// accesses.
+const WINDOW: u64 = 64;
+
+/// Tracks the recent reads and writes of nodes to choose their delta lengths.
+///
+/// Nodes are hashed to a fixed number of slots, so nodes that share a slot share their counters.
+/// Each slot packs the reads in the high half and the writes in the low half.
+pub struct AccessTracker {
+    policy: DeltaLengthPolicy,
+    default_length: u8,
+    slots: Box<[AtomicU64]>,
+}
+
+impl AccessTracker {
+    pub fn new(policy: DeltaLengthPolicy, default_length: u8) -> Self {
+        let slots = match policy {
+            DeltaLengthPolicy::Fixed => Vec::new(),
+            DeltaLengthPolicy::Adaptive { .. } => {
+                (0..NUM_SLOTS).map(|_| AtomicU64::new(0)).collect()
+            }
+        };
+        Self {
+            policy,
+            default_length,
+            slots: slots.into_boxed_slice(),
+        }
+    }
+
+    pub fn record_read(&self, node_id: u64) {
+        self.record(node_id, 1 << 32);
+    }
+
+    pub fn record_write(&self, node_id: u64) {
+        self.record(node_id, 1);
+    }
+
+    fn record(&self, node_id: u64, delta: u64) {
+        if let Some(slot) = self.slot(node_id) {
+            let _ = slot.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                let (reads, writes) = unpack(bits + delta);
+                if reads + writes >= WINDOW {
+                    Some(pack(reads / 2, writes / 2))
+                } else {
+                    Some(bits + delta)
+                }
+            });
+        }
+    }
+
+    /// Returns the number of deltas that the node takes before it is consolidated.
+    pub fn delta_length(&self, node_id: u64) -> u8 {
+        let (min, max) = match self.policy {
+            DeltaLengthPolicy::Fixed => return self.default_length,
+            DeltaLengthPolicy::Adaptive { min, max } => (min.max(1), max.max(min).max(1)),
+        };
+        let (reads, writes) = match self.slot(node_id) {
+            Some(slot) => unpack(slot.load(Ordering::Relaxed)),
+            None => (0, 0),
+        };
+        if reads + writes == 0 {
+            return self.default_length.clamp(min, max);
+        }
+        let range = (max - min) as u64;
+        max - (range * reads / (reads + writes)) as u8
+    }
+
+    fn slot(&self, node_id: u64) -> Option<&AtomicU64> {
+        if self.slots.is_empty() {
+            return None;
+        }
+        // Fibonacci hashing spreads consecutive ids over the slots.
+        let hash = node_id.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 32;
+        Some(&self.slots[hash as usize % self.slots.len()])
+    }
+}
+
+fn pack(reads: u64, writes: u64) -> u64 {
+    reads << 32 | writes
+}
+
+fn unpack(bits: u64) -> (u64, u64) {
+    (bits >> 32, bits & u32::MAX as u64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn adaptive_delta_length() {
+        let fixed = AccessTracker::new(DeltaLengthPolicy::Fixed, 8);
+        fixed.record_read(1);
+        assert_eq!(fixed.delta_length(1), 8);
+
+        let policy = DeltaLengthPolicy::Adaptive { min: 2, max: 32 };
+        let tracker = AccessTracker::new(policy, 8);
+        assert_eq!(tracker.delta_length(1), 8);
+        for _ in 0..1000 {
+            tracker.record_write(1);
+        }
+        assert_eq!(tracker.delta_length(1), 32);
+        // The counters follow the recent accesses.
+        for _ in 0..1000 {
+            tracker.record_read(1);
+        }
+        assert_eq!(tracker.delta_length(1), 2);
+        for _ in 0..WINDOW / 2 {
+            tracker.record_write(1);
+        }
+        let length = tracker.delta_length(1);
+        assert!(length > 2 && length < 32, "{}", length);
+    }
+}
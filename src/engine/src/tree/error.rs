@@ -1,15 +1,205 @@
+use std::{fmt, io};
+
 use thiserror::Error;
 
+/// The errors of the tree.
+///
+/// `Again` is internal: operations retry on it, and give up with `Contention` after
+/// `Options::max_retries`, so it is never returned by `Table`. Errors for which
+/// `Error::is_retryable` returns true are transient, and the operation may succeed if the caller
+/// tries it again later. The others need some action of the caller.
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("Alloc")]
     Alloc,
-    #[error("Again")]
-    Again,
+    /// A conflict with a concurrent operation on a node, which is retried internally.
+    #[error("Again: {cause:?} on node {node_id}")]
+    Again { node_id: u64, cause: Conflict },
+    #[error(
+        "Contention: gave up after {retries} retries on node {node_id}, last cause {last_cause:?}"
+    )]
+    Contention {
+        node_id: u64,
+        retries: usize,
+        last_cause: Conflict,
+    },
+    #[error("Lagged: skipped {skipped} changes")]
+    Lagged { skipped: u64 },
+    #[error("ReadOnly: the table is opened with Options::read_only")]
+    ReadOnly,
+    /// A resource is held by others, like the directory of a table by another writer.
+    #[error("Busy: {0}")]
+    Busy(String),
+    /// I/O that didn't finish in time, like a request to object storage.
+    #[error("Timeout: {0}")]
+    Timeout(String),
+    /// A write that has waited for the cache to get within budget for longer than
+    /// `Options::max_write_stall`.
+    #[error("Stalled: {0}")]
+    Stalled(String),
+    #[error("Deadlock: {0}")]
+    Deadlock(String),
     #[error("Corrupted: {0}")]
-    Corrupted(String),
+    Corrupted(Corruption),
+    /// An argument or an option that is not valid.
+    #[error("InvalidArgument: {0}")]
+    InvalidArgument(String),
+    /// An operation or a format that the table doesn't support.
+    #[error("NotSupported: {0}")]
+    NotSupported(String),
+    #[error("Overlap: {0}")]
+    Overlap(String),
     #[error(transparent)]
-    Io(#[from] std::io::Error),
+    Io(io::Error),
+}
+
+impl Error {
+    /// Returns true if the error is a conflict that operations retry internally.
+    pub fn is_internal(&self) -> bool {
+        matches!(self, Self::Again { .. })
+    }
+
+    /// Returns true if the operation may succeed if it is tried again later.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            Self::Again { .. }
+                | Self::Contention { .. }
+                | Self::Busy(_)
+                | Self::Timeout(_)
+                | Self::Stalled(_)
+        )
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::TimedOut => Self::Timeout(err.to_string()),
+            _ => Self::Io(err),
+        }
+    }
+}
+
+/// The context of `Error::Corrupted`, so that callers and repair tools can find what is
+/// corrupted without parsing the message.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Corruption {
+    /// What is corrupted.
+    pub message: String,
+    /// The page file that the corrupted bytes are in.
+    pub file_id: Option<u64>,
+    /// The offset of the corrupted bytes in the file.
+    pub offset: Option<u64>,
+    /// The node of the corrupted page.
+    pub page_id: Option<u64>,
+    /// The checksum that is recorded for the bytes.
+    pub expected_checksum: Option<u32>,
+    /// The checksum that is computed from the bytes.
+    pub actual_checksum: Option<u32>,
+}
+
+impl Corruption {
+    pub fn new(message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_file(self, file_id: u64, offset: u64) -> Self {
+        Self {
+            file_id: Some(file_id),
+            offset: Some(offset),
+            ..self
+        }
+    }
+
+    pub fn with_page(self, page_id: u64) -> Self {
+        Self {
+            page_id: Some(page_id),
+            ..self
+        }
+    }
+
+    pub fn with_checksum(self, expected: u32, actual: u32) -> Self {
+        Self {
+            expected_checksum: Some(expected),
+            actual_checksum: Some(actual),
+            ..self
+        }
+    }
+}
+
+impl From<String> for Corruption {
+    fn from(message: String) -> Self {
+        Self::new(message)
+    }
+}
+
+impl From<&str> for Corruption {
+    fn from(message: &str) -> Self {
+        Self::new(message)
+    }
+}
+
+impl fmt::Display for Corruption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// The cause of a conflict with concurrent operations on a node.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Conflict {
+    /// The node has been split, and the split is not reconciled to the parent yet.
+    StaleNode,
+    /// The node has been updated between a read and a CAS.
+    CasFailure,
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn classification() {
+        let again = Error::Again {
+            node_id: 1,
+            cause: Conflict::CasFailure,
+        };
+        assert!(again.is_internal() && again.is_retryable());
+        assert!(Error::Stalled(String::new()).is_retryable());
+        assert!(!Error::Stalled(String::new()).is_internal());
+        assert!(!Error::ReadOnly.is_retryable());
+        assert!(!Error::InvalidArgument(String::new()).is_retryable());
+
+        let err = Error::from(io::Error::new(io::ErrorKind::TimedOut, "request"));
+        assert!(matches!(err, Error::Timeout(_)));
+        let err = Error::from(io::Error::from(io::ErrorKind::NotFound));
+        assert!(matches!(err, Error::Io(_)));
+    }
+
+    #[test]
+    fn corruption() {
+        let err = Error::Corrupted(
+            Corruption::new("bad page")
+                .with_file(3, 128)
+                .with_page(7)
+                .with_checksum(1, 2),
+        );
+        assert_eq!(err.to_string(), "Corrupted: bad page");
+        match err {
+            Error::Corrupted(c) => {
+                assert_eq!(
+                    (c.file_id, c.offset, c.page_id),
+                    (Some(3), Some(128), Some(7))
+                );
+                assert_eq!((c.expected_checksum, c.actual_checksum), (Some(1), Some(2)));
+            }
+            _ => unreachable!(),
+        }
+    }
+}
@@ -0,0 +1,223 @@
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use super::{Error, Result};
+
+/// An event of a tree, recorded for post-mortem debugging.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Event {
+    /// The time when the event is recorded.
+    pub time: SystemTime,
+    pub kind: EventKind,
+}
+
+/// The kinds of events of a tree.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EventKind {
+    /// The manifest of the last checkpoint is recovered when the tree is opened.
+    ManifestRecovered {
+        /// The number of nodes in the recovered page table.
+        pages: usize,
+        /// The number of page files in the manifest.
+        files: usize,
+        /// The largest LSN of the recovered checkpoint.
+        lsn: u64,
+    },
+    /// The tree is opened, and ready for operations.
+    Opened {
+        /// The time taken to open the tree.
+        elapsed: Duration,
+    },
+    /// A node is split by moving the upper half of its entries to a new right sibling.
+    Split { node: u64, right: u64 },
+    /// The root is split by moving its entries to two new children, which grows the tree by one
+    /// level.
+    RootSplit {
+        left: u64,
+        right: u64,
+        /// The height of the tree after the split.
+        height: usize,
+    },
+    /// The split of a node is installed on its parent.
+    Reconcile { node: u64, parent: u64 },
+    /// A consolidation takes longer than `Options::slow_consolidation_threshold`.
+    SlowConsolidation {
+        node: u64,
+        /// The length of the chain that is consolidated.
+        delta_len: u8,
+        elapsed: Duration,
+    },
+    /// Nodes are evicted to bring the cache within its budget.
+    Eviction {
+        /// The number of evicted nodes.
+        nodes: usize,
+        /// The resident size of the cache that is released.
+        bytes: usize,
+    },
+}
+
+/// Receives the events of a tree as they happen, see `Options::event_listener`.
+///
+/// The methods are called on the threads of the operations that cause the events, sometimes in
+/// the middle of writes, so they should return quickly and must not call back into the table. All
+/// methods do nothing by default.
+pub trait EventListener: Send + Sync {
+    /// Called when a checkpoint starts to flush the tree.
+    fn on_flush_begin(&self) {}
+
+    /// Called when a checkpoint ends, with the time taken and whether it succeeds.
+    fn on_flush_end(&self, _elapsed: Duration, _result: &Result<()>) {}
+
+    /// Called when the chain of a node is replaced by its consolidated page.
+    fn on_consolidation(&self, _node: u64, _delta_len: u8, _elapsed: Duration) {}
+
+    /// Called when a node is split by moving the upper half of its entries to a new right sibling.
+    ///
+    /// Root splits move the entries of the root to two new children instead, and are reported as
+    /// a split of the left child.
+    fn on_split(&self, _node: u64, _right: u64) {}
+
+    /// Called when nodes are evicted to bring the cache within its budget, with the number of
+    /// evicted nodes and the resident size that is released.
+    fn on_eviction(&self, _nodes: usize, _bytes: usize) {}
+
+    /// Called when the tree is recovered from its last checkpoint and ready for operations, with
+    /// the largest LSN that is recovered and the time taken to open the tree.
+    fn on_recovery_done(&self, _lsn: u64, _elapsed: Duration) {}
+}
+
+/// Checks that `listener` can be called as the tree calls it, and panics if it doesn't.
+///
+/// Every method is called concurrently from several threads, with failed flushes as well as
+/// successful ones, since events of a tree happen in the threads of its operations.
+pub fn check_event_listener(listener: &dyn EventListener) {
+    std::thread::scope(|s| {
+        for i in 0..4u64 {
+            s.spawn(move || {
+                for j in 0..64u64 {
+                    let node = i * 64 + j;
+                    let elapsed = Duration::from_micros(node);
+                    listener.on_flush_begin();
+                    let result = if j % 2 == 0 {
+                        Ok(())
+                    } else {
+                        Err(Error::Io(std::io::ErrorKind::Other.into()))
+                    };
+                    listener.on_flush_end(elapsed, &result);
+                    listener.on_consolidation(node, (j % 8) as u8, elapsed);
+                    listener.on_split(node, node + 256);
+                    listener.on_eviction(j as usize + 1, 4096);
+                }
+                listener.on_recovery_done(i, Duration::ZERO);
+            });
+        }
+    });
+}
+
+impl fmt::Debug for dyn EventListener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EventListener")
+    }
+}
+
+/// A ring buffer of the latest events of a tree.
+///
+/// Events are rare compared to operations, so they are recorded under a lock.
+pub(super) struct EventLog {
+    events: Mutex<VecDeque<Event>>,
+    capacity: usize,
+}
+
+impl EventLog {
+    /// Creates a log that keeps the latest `capacity` events, or none if `capacity` is zero.
+    pub(super) fn new(capacity: usize) -> Self {
+        Self {
+            events: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+        }
+    }
+
+    pub(super) fn record(&self, kind: EventKind) {
+        if self.capacity == 0 {
+            return;
+        }
+        let event = Event {
+            time: SystemTime::now(),
+            kind,
+        };
+        let mut events = self.events.lock().unwrap();
+        if events.len() == self.capacity {
+            events.pop_front();
+        }
+        events.push_back(event);
+    }
+
+    /// Returns the recorded events, from the oldest to the newest.
+    pub(super) fn events(&self) -> Vec<Event> {
+        self.events.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    #[derive(Default)]
+    struct FlushCounter {
+        flushes: AtomicUsize,
+        failures: AtomicUsize,
+        // Panics on failed flushes.
+        strict: bool,
+    }
+
+    impl EventListener for FlushCounter {
+        fn on_flush_end(&self, _: Duration, result: &Result<()>) {
+            assert!(!self.strict || result.is_ok());
+            self.flushes.fetch_add(1, Ordering::Relaxed);
+            if result.is_err() {
+                self.failures.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    #[test]
+    fn conformance() {
+        let listener = FlushCounter::default();
+        check_event_listener(&listener);
+        assert!(listener.flushes.load(Ordering::Relaxed) > 0);
+        assert!(listener.failures.load(Ordering::Relaxed) > 0);
+        let listener = FlushCounter {
+            strict: true,
+            ..Default::default()
+        };
+        let result = std::panic::catch_unwind(|| check_event_listener(&listener));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn ring_buffer() {
+        let log = EventLog::new(2);
+        for node in 0..3 {
+            log.record(EventKind::Split { node, right: 0 });
+        }
+        let nodes: Vec<_> = log
+            .events()
+            .into_iter()
+            .map(|event| match event.kind {
+                EventKind::Split { node, .. } => node,
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(nodes, [1, 2]);
+
+        let log = EventLog::new(0);
+        log.record(EventKind::Split { node: 0, right: 0 });
+        assert!(log.events().is_empty());
+    }
+}
@@ -0,0 +1,345 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    fmt,
+    sync::Arc,
+};
+
+/// Decides which nodes to evict when the cache exceeds its budget.
+///
+/// A policy only tracks the nodes that can be evicted, which are the nodes that are unchanged since
+/// they are loaded from or written to disk. Accesses to other nodes are recorded as well, so that
+/// policies can keep the history of nodes that are not in the cache.
+pub trait EvictionPolicy: Send {
+    /// Starts tracking a node.
+    fn insert(&mut self, id: u64);
+
+    /// Records an access to a node.
+    fn access(&mut self, id: u64);
+
+    /// Stops tracking a node.
+    fn remove(&mut self, id: u64);
+
+    /// Returns the next node to evict and stops tracking it, or `None` if no nodes are tracked.
+    fn evict(&mut self) -> Option<u64>;
+}
+
+/// Checks that `policy`, which must track no nodes, tracks nodes as the cache expects, and panics
+/// if it doesn't.
+///
+/// Each tracked node must be evicted exactly once, nodes that are removed or never inserted must
+/// not be evicted, and inserting a tracked node again must not track it twice.
+pub fn check_eviction_policy(policy: &mut dyn EvictionPolicy) {
+    const N: u64 = 64;
+    assert_eq!(
+        policy.evict(),
+        None,
+        "a node is evicted before any is tracked"
+    );
+    // Nodes that are not tracked are accessed and removed as well.
+    for id in N..N * 2 {
+        policy.access(id);
+        policy.remove(id);
+    }
+    for id in 0..N {
+        policy.insert(id);
+    }
+    for id in (0..N).step_by(3) {
+        policy.access(id);
+        policy.insert(id);
+    }
+    for id in (0..N).step_by(4) {
+        policy.remove(id);
+    }
+    let tracked: BTreeSet<u64> = (0..N).filter(|id| id % 4 != 0).collect();
+    let mut evicted = BTreeSet::new();
+    while let Some(id) = policy.evict() {
+        assert!(
+            tracked.contains(&id),
+            "node {} is evicted but not tracked",
+            id
+        );
+        assert!(evicted.insert(id), "node {} is evicted twice", id);
+    }
+    assert_eq!(evicted, tracked, "some tracked nodes are not evicted");
+    // Evicted nodes can be tracked again.
+    policy.insert(0);
+    assert_eq!(policy.evict(), Some(0));
+    assert_eq!(policy.evict(), None);
+}
+
+/// The eviction policies to choose from in `Options`.
+#[derive(Clone)]
+pub enum CachePolicy {
+    /// Evicts the nodes that are not accessed since the clock hand passed them last time.
+    Clock,
+    /// Evicts the least frequently accessed of the least recently accessed nodes, so that nodes
+    /// that are read once by scans don't push hot nodes out of the cache.
+    TinyLfu,
+    /// Builds a policy of the application for each table opened with the options.
+    Custom(Arc<dyn Fn() -> Box<dyn EvictionPolicy> + Send + Sync>),
+}
+
+impl CachePolicy {
+    pub(super) fn build(&self) -> Box<dyn EvictionPolicy> {
+        match self {
+            Self::Clock => Box::new(Clock::default()),
+            Self::TinyLfu => Box::new(TinyLfu::default()),
+            Self::Custom(build) => build(),
+        }
+    }
+}
+
+impl fmt::Debug for CachePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Clock => f.write_str("Clock"),
+            Self::TinyLfu => f.write_str("TinyLfu"),
+            Self::Custom(_) => f.write_str("Custom"),
+        }
+    }
+}
+
+/// The CLOCK policy, which approximates LRU with a reference bit per node.
+#[derive(Default)]
+pub struct Clock {
+    // The nodes in the order that the hand visits them, which may contain removed nodes.
+    ring: VecDeque<u64>,
+    // The reference bits of the tracked nodes.
+    referenced: HashMap<u64, bool>,
+}
+
+impl EvictionPolicy for Clock {
+    fn insert(&mut self, id: u64) {
+        if self.referenced.insert(id, false).is_none() {
+            self.ring.push_back(id);
+            if self.ring.len() > self.referenced.len() * 2 {
+                self.compact();
+            }
+        }
+    }
+
+    fn access(&mut self, id: u64) {
+        if let Some(referenced) = self.referenced.get_mut(&id) {
+            *referenced = true;
+        }
+    }
+
+    fn remove(&mut self, id: u64) {
+        self.referenced.remove(&id);
+    }
+
+    fn evict(&mut self) -> Option<u64> {
+        while let Some(id) = self.ring.pop_front() {
+            match self.referenced.get_mut(&id) {
+                Some(referenced) if *referenced => {
+                    *referenced = false;
+                    self.ring.push_back(id);
+                }
+                Some(_) => {
+                    self.referenced.remove(&id);
+                    return Some(id);
+                }
+                None => {}
+            }
+        }
+        None
+    }
+}
+
+impl Clock {
+    /// Drops the removed nodes from the ring.
+    fn compact(&mut self) {
+        let mut seen = HashSet::with_capacity(self.referenced.len());
+        let referenced = &self.referenced;
+        self.ring
+            .retain(|id| referenced.contains_key(id) && seen.insert(*id));
+    }
+}
+
+/// The number of the least recently accessed nodes that `TinyLfu` picks a victim from.
+const TINY_LFU_CANDIDATES: usize = 8;
+
+/// A TinyLFU policy, which estimates the access frequencies of nodes with a sketch.
+///
+/// The victim is the least frequently accessed one of the least recently accessed nodes. The
+/// sketch remembers nodes after they are evicted, and it ages periodically, so that the estimates
+/// follow recent accesses.
+#[derive(Default)]
+pub struct TinyLfu {
+    sketch: FrequencySketch,
+    tick: u64,
+    // The tracked nodes by their last accesses.
+    recency: BTreeMap<u64, u64>,
+    // The last accesses of the tracked nodes.
+    ticks: HashMap<u64, u64>,
+}
+
+impl TinyLfu {
+    fn touch(&mut self, id: u64) {
+        self.tick += 1;
+        if let Some(tick) = self.ticks.insert(id, self.tick) {
+            self.recency.remove(&tick);
+        }
+        self.recency.insert(self.tick, id);
+    }
+}
+
+impl EvictionPolicy for TinyLfu {
+    fn insert(&mut self, id: u64) {
+        self.sketch.increment(id);
+        self.touch(id);
+    }
+
+    fn access(&mut self, id: u64) {
+        self.sketch.increment(id);
+        if self.ticks.contains_key(&id) {
+            self.touch(id);
+        }
+    }
+
+    fn remove(&mut self, id: u64) {
+        if let Some(tick) = self.ticks.remove(&id) {
+            self.recency.remove(&tick);
+        }
+    }
+
+    fn evict(&mut self) -> Option<u64> {
+        let sketch = &self.sketch;
+        let (_, &id) = self
+            .recency
+            .iter()
+            .take(TINY_LFU_CANDIDATES)
+            .min_by_key(|&(&tick, &id)| (sketch.estimate(id), tick))?;
+        self.remove(id);
+        Some(id)
+    }
+}
+
+/// The number of counters in each row of a `FrequencySketch`.
+const SKETCH_WIDTH: usize = 4096;
+const SKETCH_DEPTH: usize = 4;
+/// The largest count of a counter.
+const SKETCH_MAX_COUNT: u8 = 15;
+
+/// A count-min sketch of access frequencies, whose counters are halved after every
+/// `SKETCH_WIDTH * 8` increments.
+struct FrequencySketch {
+    rows: Vec<[u8; SKETCH_WIDTH]>,
+    num_increments: usize,
+}
+
+impl Default for FrequencySketch {
+    fn default() -> Self {
+        Self {
+            rows: vec![[0; SKETCH_WIDTH]; SKETCH_DEPTH],
+            num_increments: 0,
+        }
+    }
+}
+
+impl FrequencySketch {
+    fn index(id: u64, row: usize) -> usize {
+        const SEEDS: [u64; SKETCH_DEPTH] = [
+            0x9E37_79B9_7F4A_7C15,
+            0xC2B2_AE3D_27D4_EB4F,
+            0x1656_67B1_9E37_79F9,
+            0x85EB_CA77_C2B2_AE63,
+        ];
+        let hash = (id ^ (id >> 32)).wrapping_mul(SEEDS[row]);
+        (hash >> 32) as usize % SKETCH_WIDTH
+    }
+
+    fn increment(&mut self, id: u64) {
+        for (i, row) in self.rows.iter_mut().enumerate() {
+            let count = &mut row[Self::index(id, i)];
+            *count = (*count + 1).min(SKETCH_MAX_COUNT);
+        }
+        self.num_increments += 1;
+        if self.num_increments == SKETCH_WIDTH * 8 {
+            for row in self.rows.iter_mut() {
+                for count in row.iter_mut() {
+                    *count /= 2;
+                }
+            }
+            self.num_increments = 0;
+        }
+    }
+
+    fn estimate(&self, id: u64) -> u8 {
+        self.rows
+            .iter()
+            .enumerate()
+            .map(|(i, row)| row[Self::index(id, i)])
+            .min()
+            .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Evicts nodes in the order of insertion, but forgets to stop tracking removed nodes.
+    #[derive(Default)]
+    struct LeakyFifo(VecDeque<u64>);
+
+    impl EvictionPolicy for LeakyFifo {
+        fn insert(&mut self, id: u64) {
+            if !self.0.contains(&id) {
+                self.0.push_back(id);
+            }
+        }
+
+        fn access(&mut self, _: u64) {}
+
+        fn remove(&mut self, _: u64) {}
+
+        fn evict(&mut self) -> Option<u64> {
+            self.0.pop_front()
+        }
+    }
+
+    #[test]
+    fn conformance() {
+        check_eviction_policy(&mut Clock::default());
+        check_eviction_policy(&mut TinyLfu::default());
+        let result = std::panic::catch_unwind(|| check_eviction_policy(&mut LeakyFifo::default()));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn clock() {
+        let mut policy = Clock::default();
+        for id in 0..4 {
+            policy.insert(id);
+        }
+        policy.access(0);
+        policy.access(2);
+        policy.remove(3);
+        // The hand clears the reference bit of 0 on the way.
+        assert_eq!(policy.evict(), Some(1));
+        policy.insert(4);
+        assert_eq!(policy.evict(), Some(0));
+        assert_eq!(policy.evict(), Some(4));
+        assert_eq!(policy.evict(), Some(2));
+        assert_eq!(policy.evict(), None);
+    }
+
+    #[test]
+    fn tiny_lfu() {
+        let mut policy = TinyLfu::default();
+        // A hot node is accessed repeatedly, and then a scan reads many nodes once.
+        policy.insert(0);
+        for _ in 0..4 {
+            policy.access(0);
+        }
+        for id in 1..=TINY_LFU_CANDIDATES as u64 {
+            policy.insert(id);
+        }
+        for id in 1..=TINY_LFU_CANDIDATES as u64 {
+            assert_eq!(policy.evict(), Some(id));
+        }
+        assert_eq!(policy.evict(), Some(0));
+        assert_eq!(policy.evict(), None);
+    }
+}
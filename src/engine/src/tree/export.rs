@@ -0,0 +1,197 @@
+//! The format of the files written by `Table::export`.
+//!
+//! An export file is a sequence of entries in key order, so that it can be read by other systems
+//! without this crate. Integers are in little-endian:
+//!
+//! - header: the magic `PHDBEXP1`
+//! - entry: tag `1` (u8), key length (u32), key, value length (u32), value, LSN (u64)
+//! - footer: tag `0` (u8), the number of entries (u64), and the CRC32 of all the bytes before it
+//!   (u32)
+
+use std::io;
+
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::{Error, Result};
+
+const MAGIC: &[u8; 8] = b"PHDBEXP1";
+const TAG_END: u8 = 0;
+const TAG_ENTRY: u8 = 1;
+
+/// Encodes entries to an export file.
+pub struct ExportWriter<'a, W> {
+    writer: &'a mut W,
+    buf: Vec<u8>,
+    hasher: crc32fast::Hasher,
+    num_entries: u64,
+}
+
+impl<'a, W: AsyncWrite + Unpin> ExportWriter<'a, W> {
+    pub fn new(writer: &'a mut W) -> Self {
+        Self {
+            writer,
+            buf: MAGIC.to_vec(),
+            hasher: crc32fast::Hasher::new(),
+            num_entries: 0,
+        }
+    }
+
+    /// Buffers an entry, which is written when `flush` is called.
+    pub fn add(&mut self, key: &[u8], value: &[u8], lsn: u64) {
+        self.buf.push(TAG_ENTRY);
+        self.buf
+            .extend_from_slice(&(key.len() as u32).to_le_bytes());
+        self.buf.extend_from_slice(key);
+        self.buf
+            .extend_from_slice(&(value.len() as u32).to_le_bytes());
+        self.buf.extend_from_slice(value);
+        self.buf.extend_from_slice(&lsn.to_le_bytes());
+        self.num_entries += 1;
+    }
+
+    /// Returns the size of the buffered entries.
+    pub fn buffered(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Writes the buffered entries.
+    pub async fn flush(&mut self) -> io::Result<()> {
+        self.hasher.update(&self.buf);
+        self.writer.write_all(&self.buf).await?;
+        self.buf.clear();
+        Ok(())
+    }
+
+    /// Writes the footer, flushes the writer, and returns the number of entries.
+    pub async fn finish(mut self) -> io::Result<u64> {
+        self.buf.push(TAG_END);
+        self.buf.extend_from_slice(&self.num_entries.to_le_bytes());
+        self.hasher.update(&self.buf);
+        let checksum = self.hasher.clone().finalize();
+        self.buf.extend_from_slice(&checksum.to_le_bytes());
+        self.writer.write_all(&self.buf).await?;
+        self.writer.flush().await?;
+        Ok(self.num_entries)
+    }
+}
+
+/// Decodes entries from an export file.
+pub struct ExportReader<'a, R> {
+    reader: &'a mut R,
+    hasher: crc32fast::Hasher,
+    num_entries: u64,
+}
+
+impl<'a, R: AsyncRead + Unpin> ExportReader<'a, R> {
+    /// Reads the header of the file.
+    pub async fn open(reader: &'a mut R) -> Result<ExportReader<'a, R>> {
+        let mut this = Self {
+            reader,
+            hasher: crc32fast::Hasher::new(),
+            num_entries: 0,
+        };
+        let mut magic = [0; 8];
+        this.read_exact(&mut magic).await?;
+        if &magic != MAGIC {
+            return Err(Error::Corrupted("the export file has a bad magic".into()));
+        }
+        Ok(this)
+    }
+
+    /// Returns the key, value, and LSN of the next entry, or `None` at the end of the file, whose
+    /// footer is checked then.
+    pub async fn next(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>, u64)>> {
+        let mut tag = [0; 1];
+        self.read_exact(&mut tag).await?;
+        match tag[0] {
+            TAG_ENTRY => {
+                let key = self.read_bytes().await?;
+                let value = self.read_bytes().await?;
+                let mut lsn = [0; 8];
+                self.read_exact(&mut lsn).await?;
+                self.num_entries += 1;
+                Ok(Some((key, value, u64::from_le_bytes(lsn))))
+            }
+            TAG_END => {
+                let mut num_entries = [0; 8];
+                self.read_exact(&mut num_entries).await?;
+                let checksum = self.hasher.clone().finalize();
+                let mut expected = [0; 4];
+                self.read_exact(&mut expected).await?;
+                if u64::from_le_bytes(num_entries) != self.num_entries
+                    || u32::from_le_bytes(expected) != checksum
+                {
+                    return Err(Error::Corrupted("the export file has a bad footer".into()));
+                }
+                Ok(None)
+            }
+            tag => Err(Error::Corrupted(
+                format!("the export file has an unknown tag {}", tag).into(),
+            )),
+        }
+    }
+
+    async fn read_bytes(&mut self) -> Result<Vec<u8>> {
+        let mut len = [0; 4];
+        self.read_exact(&mut len).await?;
+        let mut buf = vec![0; u32::from_le_bytes(len) as usize];
+        self.read_exact(&mut buf).await?;
+        Ok(buf)
+    }
+
+    async fn read_exact(&mut self, buf: &mut [u8]) -> Result<()> {
+        self.reader.read_exact(buf).await.map_err(|err| {
+            if err.kind() == io::ErrorKind::UnexpectedEof {
+                Error::Corrupted("the export file is truncated".into())
+            } else {
+                err.into()
+            }
+        })?;
+        self.hasher.update(buf);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::executor::block_on;
+
+    use super::*;
+
+    #[test]
+    fn export_file() {
+        let mut file = Vec::new();
+        block_on(async {
+            let mut writer = ExportWriter::new(&mut file);
+            writer.add(b"a", b"1", 1);
+            writer.flush().await.unwrap();
+            writer.add(b"b", b"", 2);
+            assert_eq!(writer.finish().await.unwrap(), 2);
+        });
+
+        block_on(async {
+            let mut buf = file.as_slice();
+            let mut reader = ExportReader::open(&mut buf).await.unwrap();
+            let entry = reader.next().await.unwrap();
+            assert_eq!(entry, Some((b"a".to_vec(), b"1".to_vec(), 1)));
+            let entry = reader.next().await.unwrap();
+            assert_eq!(entry, Some((b"b".to_vec(), Vec::new(), 2)));
+            assert_eq!(reader.next().await.unwrap(), None);
+        });
+
+        let read_all = |file: &[u8]| {
+            block_on(async {
+                let mut buf = file;
+                let mut reader = ExportReader::open(&mut buf).await?;
+                while reader.next().await?.is_some() {}
+                Ok::<_, Error>(())
+            })
+        };
+        let err = read_all(&file[..file.len() - 1]).unwrap_err();
+        assert!(matches!(err, Error::Corrupted(_)));
+        // Flips a byte of the first value.
+        file[18] ^= 1;
+        let err = read_all(&file).unwrap_err();
+        assert!(matches!(err, Error::Corrupted(_)));
+    }
+}
@@ -0,0 +1,73 @@
+//! Entry points of the fuzz targets in `fuzz/`, with the `fuzzing` feature.
+//!
+//! Each function decodes arbitrary bytes and reads everything that is decoded, which must never
+//! panic or read out of bounds, however the bytes are corrupted. Run a target with
+//! `cargo fuzz run page_image` in `src/engine`.
+
+use std::{mem::size_of, path::Path};
+
+use super::{
+    page::{
+        decode_page_image, Comparable, DataPageRef, Decodable, Index, Key, PageAlloc, PagePtr,
+        Value,
+    },
+    pagecache::PageCache,
+    pagestore::decode_manifest_file,
+    BytewiseComparator,
+};
+
+/// Decodes a page image, as pages are loaded from page files.
+pub fn page_image(image: &[u8]) {
+    let cache = PageCache::default();
+    if let Some(page) = decode_page_image(image, &cache).unwrap() {
+        unsafe {
+            // A decoded page is safe to read unchecked.
+            if page.is_index() {
+                read_page(DataPageRef::<&[u8], Index>::new(page));
+            } else {
+                read_page(DataPageRef::<Key, Value>::new(page));
+            }
+            cache.dealloc(page);
+        }
+    }
+}
+
+/// Decodes the raw bytes of a data page with bounds checks.
+pub fn data_page(bytes: &[u8]) {
+    // Pages are aligned to eight bytes, and the buffer is large enough to read the header of a
+    // short page.
+    let mut buf = vec![0u64; bytes.len() / size_of::<u64>() + 3];
+    unsafe {
+        let ptr = buf.as_mut_ptr() as *mut u8;
+        ptr.copy_from_nonoverlapping(bytes.as_ptr(), bytes.len());
+        let page = PagePtr::new(ptr).unwrap();
+        if page.size() > bytes.len() {
+            return;
+        }
+        if let Some(page) = DataPageRef::<&[u8], Index>::new_checked(page) {
+            read_page(page);
+        }
+        if let Some(page) = DataPageRef::<Key, Value>::new_checked(page) {
+            read_page(page);
+        }
+    }
+}
+
+/// Decodes the content of a manifest file.
+pub fn manifest(bytes: &[u8]) {
+    let _ = decode_manifest_file(Path::new("MANIFEST-000001"), bytes);
+}
+
+/// Reads all the entries of a page, and seeks each key in the page.
+fn read_page<K, V>(page: DataPageRef<'_, K, V>)
+where
+    K: Decodable + Comparable,
+    V: Decodable,
+{
+    for i in 0..page.len() {
+        let (key, _) = page.get(i).unwrap();
+        page.seek(&key, &BytewiseComparator);
+        page.seek_back(&key, &BytewiseComparator);
+        page.seek_next(&key, &BytewiseComparator);
+    }
+}
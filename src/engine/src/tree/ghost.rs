@@ -1,16 +1,186 @@
+use std::{
+    cell::RefCell,
+    fmt,
+    ops::Deref,
+    slice,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
 pub use crossbeam_epoch::Guard;
 
+use super::{GhostStats, PerfContext};
+
+// Ghosts pin the threads to the global collector of crossbeam, so the counters are global too.
+static NUM_PINNED: AtomicU64 = AtomicU64::new(0);
+static NUM_DEFERRED: AtomicU64 = AtomicU64::new(0);
+static DEFERRED_BYTES: AtomicU64 = AtomicU64::new(0);
+
 pub struct Ghost {
     guard: Guard,
+    perf: Option<RefCell<PerfContext>>,
 }
 
 impl Ghost {
     pub fn pin() -> Self {
+        Self::with_perf(None)
+    }
+
+    /// Pins the thread and collects the `PerfContext` of the operation.
+    pub fn pin_with_perf() -> Self {
+        Self::with_perf(Some(RefCell::default()))
+    }
+
+    fn with_perf(perf: Option<RefCell<PerfContext>>) -> Self {
         let guard = crossbeam_epoch::pin();
-        Self { guard }
+        NUM_PINNED.fetch_add(1, Ordering::Relaxed);
+        Self { guard, perf }
     }
 
     pub fn guard(&self) -> &Guard {
         &self.guard
     }
+
+    /// Runs `f` after all the ghosts that are pinned now are dropped, and counts `bytes` as
+    /// awaiting reclamation until then.
+    pub fn defer<F>(&self, bytes: usize, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        NUM_DEFERRED.fetch_add(1, Ordering::Relaxed);
+        DEFERRED_BYTES.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.guard.defer(move || {
+            f();
+            NUM_DEFERRED.fetch_sub(1, Ordering::Relaxed);
+            DEFERRED_BYTES.fetch_sub(bytes as u64, Ordering::Relaxed);
+        });
+    }
+
+    /// Updates the `PerfContext` of the operation if it is collected.
+    pub fn perf(&self, f: impl FnOnce(&mut PerfContext)) {
+        if let Some(perf) = &self.perf {
+            f(&mut perf.borrow_mut());
+        }
+    }
+
+    /// Adds the collected counters to `perf`.
+    pub fn merge_perf_into(&self, perf: &mut PerfContext) {
+        if let Some(collected) = &self.perf {
+            let collected = collected.borrow();
+            perf.num_nodes += collected.num_nodes;
+            perf.num_pages += collected.num_pages;
+            perf.max_chain_length = perf.max_chain_length.max(collected.max_chain_length);
+            perf.num_swapins += collected.num_swapins;
+            perf.swapin_bytes += collected.swapin_bytes;
+            perf.num_retries += collected.num_retries;
+        }
+    }
+}
+
+impl Drop for Ghost {
+    fn drop(&mut self) {
+        NUM_PINNED.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Returns the statistics of the ghosts of all the trees in the process.
+pub fn stats() -> GhostStats {
+    GhostStats {
+        num_pinned: NUM_PINNED.load(Ordering::Relaxed),
+        num_deferred: NUM_DEFERRED.load(Ordering::Relaxed),
+        deferred_bytes: DEFERRED_BYTES.load(Ordering::Relaxed),
+    }
+}
+
+/// Tries to advance the epoch, and runs the deferred callbacks that no ghost can see anymore.
+///
+/// Callbacks are only run once the epoch advances twice after they are deferred, and the epoch
+/// can't advance past the ghosts that are still pinned, so a ghost that is held for long blocks
+/// the reclamation of all the trees.
+pub fn flush_epoch() {
+    for _ in 0..3 {
+        crossbeam_epoch::pin().flush();
+    }
+}
+
+/// A value that borrows the page it is read from.
+///
+/// The value pins the thread to the current epoch, so that the page is not reclaimed until the
+/// value is dropped. Pages retired in the meantime are not reclaimed either, so values should not
+/// be held for long.
+pub struct PinnedValue {
+    _ghost: Ghost,
+    ptr: *const u8,
+    len: usize,
+}
+
+impl PinnedValue {
+    /// Creates a value that borrows `len` bytes at `ptr`.
+    ///
+    /// # Safety
+    ///
+    /// The bytes must stay valid until `ghost` is dropped.
+    pub(super) unsafe fn new(ghost: Ghost, ptr: *const u8, len: usize) -> Self {
+        Self {
+            _ghost: ghost,
+            ptr,
+            len,
+        }
+    }
+}
+
+impl Deref for PinnedValue {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl AsRef<[u8]> for PinnedValue {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl fmt::Debug for PinnedValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("PinnedValue").field(&self.deref()).finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{atomic::AtomicBool, Arc},
+        thread,
+        time::Duration,
+    };
+
+    use super::*;
+
+    #[test]
+    fn flush_deferred() {
+        let ghost = Ghost::pin();
+        let done = Arc::new(AtomicBool::new(false));
+        let flag = done.clone();
+        ghost.defer(1024, move || flag.store(true, Ordering::Relaxed));
+        // Other tests share the counters, but ours are not reclaimed while the ghost is pinned.
+        let stats = stats();
+        assert!(stats.num_pinned >= 1);
+        assert!(stats.num_deferred >= 1);
+        assert!(stats.deferred_bytes >= 1024);
+        flush_epoch();
+        assert!(!done.load(Ordering::Relaxed));
+
+        drop(ghost);
+        // Ghosts pinned by other tests may hold the epoch for a while.
+        for _ in 0..1000 {
+            flush_epoch();
+            if done.load(Ordering::Relaxed) {
+                return;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        panic!("the deferred callback is not run");
+    }
 }
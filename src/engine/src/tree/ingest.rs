@@ -0,0 +1,136 @@
+use std::{path::Path, sync::Arc};
+
+use super::{
+    page::{encode_page_image, DataPageBuilder, Encodable, Key, PageAlloc, SliceIter, Value},
+    pagestore::{self, PageInfo, RunId},
+    slab::SlabAlloc,
+    Comparator, Options, Result,
+};
+#[cfg(feature = "tokio-env")]
+use crate::env::TokioEnv;
+use crate::env::{Env, SequentialWriter};
+
+/// Writes sorted entries to a page file offline, which `Table::ingest` links into a table.
+///
+/// The entries are written at one LSN, and cut into leaves of about `Options::page_size` bytes, or
+/// `Options::data_node_size` bytes if it is not set, so a file is built without the write path of a
+/// table. The file must be ingested into a table with the same comparator.
+pub struct PageFileWriter {
+    writer: pagestore::PageFileWriter<Box<dyn SequentialWriter>>,
+    alloc: SlabAlloc,
+    cmp: Arc<dyn Comparator>,
+    node_size: usize,
+    node_entries: usize,
+    restart_interval: u32,
+    lsn: u64,
+    // The entries of the next leaf, and their encoded size.
+    entries: Vec<(Vec<u8>, Vec<u8>)>,
+    size: usize,
+    // The last key of the written leaves.
+    last_key: Option<Vec<u8>>,
+    num_pages: u64,
+    num_entries: u64,
+}
+
+impl PageFileWriter {
+    /// Creates a page file in `path` with the tokio runtime of the current context, whose
+    /// entries are written at `lsn`.
+    ///
+    /// `opts` must be the options of the table that the file is ingested into.
+    #[cfg(feature = "tokio-env")]
+    pub async fn create(path: impl AsRef<Path>, lsn: u64, opts: &Options) -> Result<Self> {
+        Self::create_with_env(Arc::new(TokioEnv::current()), path, lsn, opts).await
+    }
+
+    /// Creates a page file in `path` with the given `Env`.
+    pub async fn create_with_env(
+        env: Arc<dyn Env>,
+        path: impl AsRef<Path>,
+        lsn: u64,
+        opts: &Options,
+    ) -> Result<Self> {
+        let file = env.open_sequential_writer(path.as_ref()).await?;
+        Ok(Self {
+            writer: pagestore::PageFileWriter::new(file, RunId::random(), 0),
+            alloc: SlabAlloc::default(),
+            cmp: opts.comparator.clone(),
+            node_size: opts.node_size(false),
+            node_entries: opts.data_node_entries,
+            restart_interval: opts.page_restart_interval,
+            lsn,
+            entries: Vec::new(),
+            size: 0,
+            last_key: None,
+            num_pages: 0,
+            num_entries: 0,
+        })
+    }
+
+    /// Adds an entry to the file.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `key` is empty, or not after the previous key in the order of the comparator.
+    pub async fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        assert!(!key.is_empty(), "keys must not be empty");
+        let last_key = self
+            .entries
+            .last()
+            .map(|(k, _)| k.as_slice())
+            .or(self.last_key.as_deref());
+        if let Some(last_key) = last_key {
+            assert!(
+                self.cmp.compare(last_key, key).is_lt(),
+                "keys must be added in increasing order"
+            );
+        }
+        // Each entry also takes an offset and a sort prefix.
+        let size = Key::new(key, self.lsn).encode_size() + Value::Put(value).encode_size() + 16;
+        if !self.entries.is_empty()
+            && (self.size + size > self.node_size || self.entries.len() >= self.node_entries)
+        {
+            self.write_page().await?;
+        }
+        self.entries.push((key.to_vec(), value.to_vec()));
+        self.size += size;
+        self.num_entries += 1;
+        Ok(())
+    }
+
+    /// Returns the number of entries added to the file.
+    pub fn num_entries(&self) -> u64 {
+        self.num_entries
+    }
+
+    /// Writes the remaining entries and the index of the file, syncs it, and returns the size of
+    /// the file.
+    pub async fn finish(mut self) -> Result<u64> {
+        if !self.entries.is_empty() {
+            self.write_page().await?;
+        }
+        self.writer.finish().await
+    }
+
+    async fn write_page(&mut self) -> Result<()> {
+        let entries: Vec<_> = self
+            .entries
+            .iter()
+            .map(|(k, v)| (Key::new(k, self.lsn), Value::Put(v)))
+            .collect();
+        let mut iter = SliceIter::from(entries.as_slice());
+        let mut page = DataPageBuilder::default()
+            .sort_prefixes(self.cmp.is_bytewise())
+            .build_from_iter(&self.alloc, &mut iter)?;
+        let page = page.as_ptr();
+        let image = encode_page_image(page, self.restart_interval);
+        let info = PageInfo::from(page);
+        unsafe { self.alloc.dealloc(page) };
+        // Pages are numbered in the file, and get their ids in the table when they are ingested.
+        self.writer.add_page(self.num_pages, info, &image).await?;
+        self.num_pages += 1;
+        self.last_key = self.entries.pop().map(|(k, _)| k);
+        self.entries.clear();
+        self.size = 0;
+        Ok(())
+    }
+}
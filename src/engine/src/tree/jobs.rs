@@ -0,0 +1,198 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Instant,
+};
+
+use tokio::sync::Notify;
+
+use super::JobStats;
+
+/// The kinds of background jobs, in the order of their priorities.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Job {
+    /// Flushes the tree so that the nodes changed since the last checkpoint can be evicted.
+    EvictionFlush = 0,
+    /// Runs a periodic checkpoint.
+    Checkpoint = 1,
+}
+
+/// Schedules the background jobs that writes run on behalf of the tree.
+///
+/// Jobs run in the tasks of the writes that trigger them, and at most `max_jobs` of them run at a
+/// time. Eviction flushes wait for a slot, while periodic checkpoints are skipped if there is no
+/// slot or an eviction flush is waiting for one, since the next write will try again. So writes
+/// that are blocked on memory are never queued behind routine checkpoints.
+pub struct JobScheduler {
+    max_jobs: usize,
+    state: Mutex<State>,
+    notify: Notify,
+    counters: JobCounters,
+}
+
+#[derive(Default)]
+struct State {
+    running: [usize; 2],
+    waiting: [usize; 2],
+}
+
+impl JobScheduler {
+    pub fn new(max_jobs: usize) -> Self {
+        Self {
+            max_jobs: max_jobs.max(1),
+            state: Mutex::default(),
+            notify: Notify::new(),
+            counters: JobCounters::default(),
+        }
+    }
+
+    /// Returns true if a job of the kind is running or waiting to run.
+    pub fn is_pending(&self, job: Job) -> bool {
+        let state = self.state.lock().unwrap();
+        state.waiting[job as usize] > 0 || state.running[job as usize] > 0
+    }
+
+    /// Returns the number of jobs that are running or waiting to run.
+    pub fn num_pending(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        state.running.iter().chain(&state.waiting).sum()
+    }
+
+    /// Waits until the job can run, and returns a guard that ends it when dropped.
+    pub async fn begin(&self, job: Job) -> JobGuard<'_> {
+        let start = Instant::now();
+        self.state.lock().unwrap().waiting[job as usize] += 1;
+        let mut wait = WaitGuard {
+            sched: self,
+            job,
+            done: false,
+        };
+        loop {
+            let notified = self.notify.notified();
+            {
+                let mut state = self.state.lock().unwrap();
+                if self.can_run(&state, job) {
+                    state.waiting[job as usize] -= 1;
+                    state.running[job as usize] += 1;
+                    wait.done = true;
+                    break;
+                }
+            }
+            notified.await;
+        }
+        self.counters
+            .wait_micros
+            .fetch_add(start.elapsed().as_micros() as u64, Ordering::Relaxed);
+        self.started(job)
+    }
+
+    /// Returns a guard of the job if it can run right away.
+    pub fn try_begin(&self, job: Job) -> Option<JobGuard<'_>> {
+        {
+            let mut state = self.state.lock().unwrap();
+            if !self.can_run(&state, job) {
+                self.counters.num_skipped[job as usize].fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+            state.running[job as usize] += 1;
+        }
+        Some(self.started(job))
+    }
+
+    /// Returns true if a job can run, which requires a free slot and no jobs of higher priorities
+    /// waiting for one.
+    fn can_run(&self, state: &State, job: Job) -> bool {
+        let running: usize = state.running.iter().sum();
+        running < self.max_jobs && state.waiting[..job as usize].iter().all(|&n| n == 0)
+    }
+
+    fn started(&self, job: Job) -> JobGuard<'_> {
+        self.counters.num_jobs[job as usize].fetch_add(1, Ordering::Relaxed);
+        JobGuard { sched: self, job }
+    }
+
+    pub fn stats(&self) -> JobStats {
+        let counters = &self.counters;
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        JobStats {
+            num_eviction_flushes: load(&counters.num_jobs[Job::EvictionFlush as usize]),
+            num_checkpoints: load(&counters.num_jobs[Job::Checkpoint as usize]),
+            num_skipped_checkpoints: load(&counters.num_skipped[Job::Checkpoint as usize]),
+            wait_micros: load(&counters.wait_micros),
+        }
+    }
+}
+
+/// A guard that ends a job when dropped.
+pub struct JobGuard<'a> {
+    sched: &'a JobScheduler,
+    job: Job,
+}
+
+impl Drop for JobGuard<'_> {
+    fn drop(&mut self) {
+        self.sched.state.lock().unwrap().running[self.job as usize] -= 1;
+        self.sched.notify.notify_waiters();
+    }
+}
+
+/// A guard that stops waiting for a job if `JobScheduler::begin` is cancelled.
+struct WaitGuard<'a> {
+    sched: &'a JobScheduler,
+    job: Job,
+    done: bool,
+}
+
+impl Drop for WaitGuard<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.sched.state.lock().unwrap().waiting[self.job as usize] -= 1;
+            self.sched.notify.notify_waiters();
+        }
+    }
+}
+
+#[derive(Default)]
+struct JobCounters {
+    num_jobs: [AtomicU64; 2],
+    num_skipped: [AtomicU64; 2],
+    wait_micros: AtomicU64,
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use tokio::time::timeout;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn priorities() {
+        let sched = JobScheduler::new(1);
+        let checkpoint = sched.try_begin(Job::Checkpoint).unwrap();
+        assert!(sched.try_begin(Job::Checkpoint).is_none());
+
+        // An eviction flush waits for the running checkpoint, and checkpoints can't run before it.
+        let flush = sched.begin(Job::EvictionFlush);
+        tokio::pin!(flush);
+        let wait = Duration::from_millis(10);
+        assert!(timeout(wait, &mut flush).await.is_err());
+        assert!(sched.is_pending(Job::EvictionFlush));
+        drop(checkpoint);
+        assert!(sched.try_begin(Job::Checkpoint).is_none());
+        let flush = flush.await;
+        assert!(sched.try_begin(Job::Checkpoint).is_none());
+        drop(flush);
+        assert!(!sched.is_pending(Job::EvictionFlush));
+        assert!(sched.try_begin(Job::Checkpoint).is_some());
+
+        let stats = sched.stats();
+        assert_eq!(stats.num_eviction_flushes, 1);
+        assert_eq!(stats.num_checkpoints, 2);
+        assert_eq!(stats.num_skipped_checkpoints, 3);
+        assert!(stats.wait_micros >= 10_000);
+    }
+}
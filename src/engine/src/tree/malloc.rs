@@ -0,0 +1,108 @@
+//! The allocator that pages are allocated from.
+//!
+//! Pages are allocated by jemalloc with the `jemalloc` feature, which is enabled by default, or by
+//! mimalloc with the `mimalloc` feature. The system allocator is used without either feature, for
+//! targets that the other allocators don't support.
+//!
+//! Unlike `GlobalAlloc`, the allocator reports the usable size of an allocation, which the cache
+//! accounts for. An allocation can be deallocated with its usable size instead of the requested
+//! size.
+
+pub use imp::{alloc, dealloc, usable_size};
+
+#[cfg(feature = "jemalloc")]
+mod imp {
+    use std::alloc::{GlobalAlloc, Layout};
+
+    use jemallocator::Jemalloc;
+
+    pub unsafe fn alloc(layout: Layout) -> *mut u8 {
+        Jemalloc.alloc(layout)
+    }
+
+    pub unsafe fn dealloc(ptr: *mut u8, layout: Layout) {
+        Jemalloc.dealloc(ptr, layout)
+    }
+
+    pub unsafe fn usable_size(ptr: *const u8) -> usize {
+        jemallocator::usable_size(ptr)
+    }
+}
+
+#[cfg(all(feature = "mimalloc", not(feature = "jemalloc")))]
+mod imp {
+    use std::alloc::{GlobalAlloc, Layout};
+
+    use mimalloc::MiMalloc;
+
+    pub unsafe fn alloc(layout: Layout) -> *mut u8 {
+        MiMalloc.alloc(layout)
+    }
+
+    pub unsafe fn dealloc(ptr: *mut u8, layout: Layout) {
+        MiMalloc.dealloc(ptr, layout)
+    }
+
+    pub unsafe fn usable_size(ptr: *const u8) -> usize {
+        libmimalloc_sys::mi_usable_size(ptr as *const _)
+    }
+}
+
+/// The system allocator doesn't report usable sizes, so every allocation is prefixed with its
+/// size, which is padded to the alignment of the allocation.
+#[cfg(not(any(feature = "jemalloc", feature = "mimalloc")))]
+mod imp {
+    use std::alloc::{GlobalAlloc, Layout, System};
+
+    pub unsafe fn alloc(layout: Layout) -> *mut u8 {
+        let (prefixed, offset) = prefixed_layout(layout);
+        let base = System.alloc(prefixed);
+        if base.is_null() {
+            return base;
+        }
+        let ptr = base.add(offset);
+        (ptr as *mut usize).sub(1).write(layout.size());
+        ptr
+    }
+
+    pub unsafe fn dealloc(ptr: *mut u8, layout: Layout) {
+        let layout = Layout::from_size_align_unchecked(usable_size(ptr), layout.align());
+        let (prefixed, offset) = prefixed_layout(layout);
+        System.dealloc(ptr.sub(offset), prefixed)
+    }
+
+    pub unsafe fn usable_size(ptr: *const u8) -> usize {
+        (ptr as *const usize).sub(1).read()
+    }
+
+    /// Returns the layout of an allocation that is prefixed with its size, and the offset of the
+    /// allocation in it.
+    fn prefixed_layout(layout: Layout) -> (Layout, usize) {
+        let offset = layout.align().max(std::mem::size_of::<usize>());
+        let layout = Layout::from_size_align(layout.size() + offset, offset).unwrap();
+        (layout, offset)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::alloc::Layout;
+
+    use super::*;
+
+    #[test]
+    fn alloc_and_dealloc() {
+        for (size, align) in [(1, 8), (100, 8), (4096, 8), (64, 4096)] {
+            let layout = Layout::from_size_align(size, align).unwrap();
+            unsafe {
+                let ptr = alloc(layout);
+                assert!(!ptr.is_null());
+                assert_eq!(ptr as usize % align, 0);
+                let usable = usable_size(ptr);
+                assert!(usable >= size);
+                ptr.write_bytes(1, usable);
+                dealloc(ptr, Layout::from_size_align(usable, align).unwrap());
+            }
+        }
+    }
+}
@@ -1,43 +1,337 @@
+use std::{sync::Arc, time::Duration};
+
 mod table;
 pub use table::Table;
 
 mod error;
-pub use error::{Error, Result};
+pub use error::{Conflict, Corruption, Error, Result};
 
 mod ghost;
+pub use ghost::PinnedValue;
 use ghost::{Ghost, Guard};
 
 mod btree;
 use btree::BTree;
+pub use btree::Cursor;
+
+mod periodic;
+use periodic::PeriodicCheckpoints;
+
+mod stats;
+pub use stats::{
+    AllocStats, CacheStats, ContentionStats, FileIoStats, GhostStats, IoCounters, IoOp, IoStats,
+    JobStats, LatencyHistogram, LifetimeStats, ManifestInfo, MemoryUsage, NodeContention,
+    OpIoStats, PageFileInfo, PerfContext, StallStats, Stats, TierStats, TreeInfo, WriteStats,
+};
+
+mod replication;
+use replication::ChangePublisher;
+pub use replication::{Change, ChangeBatch, ChangeStream};
+
+mod events;
+use events::EventLog;
+pub use events::{check_event_listener, Event, EventKind, EventListener};
+
+mod eviction;
+pub use eviction::{check_eviction_policy, CachePolicy, Clock, EvictionPolicy, TinyLfu};
+
+mod delta_policy;
+use delta_policy::AccessTracker;
+pub use delta_policy::DeltaLengthPolicy;
+
+mod rate_limiter;
+use rate_limiter::RateLimiter;
+pub use rate_limiter::WriteRateLimit;
+
+mod tiering;
+pub use tiering::{ColdTier, TieringPolicy};
 
+mod verify;
+pub use verify::{RepairReport, VerifyReport};
+
+mod comparator;
+pub use comparator::{
+    append_timestamp, check_comparator, split_timestamp, BytewiseComparator, Comparator,
+    TimestampComparator, TIMESTAMP_SIZE,
+};
+
+mod transformer;
+pub use transformer::{check_value_transformer, ValueTransformer};
+
+mod ingest;
+pub use ingest::PageFileWriter;
+
+mod export;
+
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub mod fuzz;
+
+mod chain;
+mod contention;
+mod jobs;
+mod malloc;
+#[cfg(test)]
+mod model;
+mod numa;
 mod page;
 mod pagecache;
+pub use pagecache::SharedCache;
 mod pagestore;
 mod pagetable;
+mod scheduler;
+mod shard;
+#[cfg(test)]
+mod simulation;
+mod slab;
 
 #[derive(Clone, Debug)]
 pub struct Options {
+    /// Opens an existing table for reads without changing any files, so that another process can
+    /// read a table while it is written.
+    ///
+    /// Writes and checkpoints fail with `Error::ReadOnly`. The table reads the data of the last
+    /// checkpoint when it is opened, and reads fail if the writer removes the files of that
+    /// checkpoint.
+    pub read_only: bool,
+    /// The budget of the cache. Nodes that are unchanged since they are loaded from or written to
+    /// disk are evicted when the cache exceeds the budget, and writes stall if that's not enough.
     pub cache_size: usize,
+    /// A budget that the cache shares with the tables opened with the same one, in addition to
+    /// `cache_size`, so that the tables of a process can be capped in total.
+    pub shared_cache: Option<SharedCache>,
+    /// The policy to choose the nodes to evict.
+    pub cache_policy: CachePolicy,
+    /// Allocates small pages from slabs of a few size classes instead of the general-purpose
+    /// allocator, and the pages up to `page_size` if it is set.
+    ///
+    /// This reduces the allocator overhead and fragmentation under heavy writes, at the cost of
+    /// keeping the slabs until the table is closed.
+    pub slab_alloc: bool,
+    /// Allocates pages from slabs on the NUMA node of the thread that allocates them, as
+    /// `slab_alloc` does on a single node, and leaves the consolidation of a leaf whose base page
+    /// is on another node to the threads on that node, until its chain grows to twice the delta
+    /// length.
+    ///
+    /// This only takes effect on Linux machines with multiple NUMA nodes, and works best when the
+    /// threads of the runtime are pinned to cores.
+    pub numa_aware: bool,
+    /// The size that nodes are consolidated to, or `None` to split leaves by `data_node_size`.
+    ///
+    /// Leaves whose consolidated pages exceed it are split, and so are index nodes at half of it,
+    /// so that the pages in memory and on disk stay within it, except for single entries that are
+    /// larger. With `slab_alloc`, pages up to this size are allocated from the size classes of the
+    /// slabs. It must be a power of two up to 64KB, like 8KB, 16KB or 64KB.
+    pub page_size: Option<usize>,
     pub data_node_size: usize,
+    pub data_node_entries: usize,
     pub data_delta_length: u8,
+    /// The maximum size of a delta page on a leaf.
+    ///
+    /// A write with a larger delta page is consolidated into the leaf right away, so that chain
+    /// walks don't go through oversize deltas. Such writes are counted in `WriteStats`.
+    pub max_delta_size: usize,
+    /// The maximum size of the pages of a leaf, beyond which the leaf is consolidated even if it
+    /// has fewer than `data_delta_length` deltas, so that a few large deltas don't make chains
+    /// that take much memory and long walks.
+    pub data_chain_size: usize,
+    /// The number of the newest deltas that are merged into one delta when a leaf has
+    /// `data_delta_length` deltas, instead of consolidating the whole leaf.
+    ///
+    /// Hot leaves with large base pages then don't rewrite their base pages on every
+    /// consolidation. The leaf is still consolidated once its pages exceed `data_chain_size`.
+    /// Values less than 2 disable the merges.
+    pub data_delta_merge_length: u8,
+    /// How the number of deltas that a leaf takes before it is consolidated is chosen, which is
+    /// `data_delta_length` for all leaves by default.
+    pub delta_length_policy: DeltaLengthPolicy,
+    pub index_node_entries: usize,
+    /// Delays writes when checkpoints or consolidations fall behind, or `None` to never delay
+    /// them.
+    pub write_rate_limit: Option<WriteRateLimit>,
+    /// The longest time that a write waits for the cache to get within `cache_size`, after which
+    /// it fails with `Error::Stalled`, or `None` to let writes go over budget after they stall
+    /// once.
+    pub max_write_stall: Option<Duration>,
+    /// The maximum number of retries of an operation on conflicts, after which the operation
+    /// fails with `Error::Contention`.
+    pub max_retries: usize,
+    /// The interval between periodic checkpoints of the page table, or `None` to disable them.
+    ///
+    /// Checkpoints run in the background while there are writes since the last checkpoint, and
+    /// stop when the table is closed.
+    pub checkpoint_interval: Option<Duration>,
+    /// When writes are made durable, which is only at checkpoints.
+    pub sync_mode: SyncMode,
+    /// How checkpoints write the leaves that have changed since they were last written.
+    pub flush_policy: FlushPolicy,
+    /// The maximum number of background jobs, like flushes and periodic checkpoints, that run at a
+    /// time.
+    pub max_background_jobs: usize,
+    /// The number of entries between the full keys of pages written to disk, where the other keys
+    /// only store the bytes that differ from the previous key. Zero writes pages as they are in
+    /// memory.
+    pub page_restart_interval: u32,
+    /// Records `LifetimeStats` at checkpoints, so that they continue across restarts.
+    pub persist_stats: bool,
+    /// The order of keys, which must be the same every time the table is opened.
+    ///
+    /// Opening a table that is checkpointed with a comparator of another `Comparator::name`
+    /// returns `Error::InvalidArgument`.
+    pub comparator: Arc<dyn Comparator>,
+    /// The transformer of the values returned by scans.
+    pub value_transformer: Option<Arc<dyn ValueTransformer>>,
+    /// Writes the transformed values back when leaves are consolidated.
+    ///
+    /// This has no effect without `value_transformer`.
+    pub rewrite_on_consolidation: bool,
+    /// The number of changes buffered for each `ChangeStream`, beyond which a slow stream lags,
+    /// and the number of the last changes kept for `Table::subscribe_from`.
+    pub replication_buffer_size: usize,
+    /// The number of the latest events kept for `Table::events`, or zero to record no events.
+    pub event_log_size: usize,
+    /// Consolidations that take longer than this are recorded as events.
+    pub slow_consolidation_threshold: Duration,
+    /// Receives the events of the tree as they happen, including the ones that are too frequent
+    /// to be recorded for `Table::events`.
+    pub event_listener: Option<Arc<dyn EventListener>>,
+    /// Migrates cold page files to a cheaper tier of storage, or `None` to keep all page files in
+    /// the table directory.
+    pub cold_tier: Option<ColdTier>,
 }
 
 impl Default for Options {
     fn default() -> Self {
         Self {
+            read_only: false,
             cache_size: usize::MAX,
+            shared_cache: None,
+            cache_policy: CachePolicy::Clock,
+            slab_alloc: false,
+            numa_aware: false,
+            page_size: None,
             data_node_size: 8 * 1024,
+            data_node_entries: usize::MAX,
             data_delta_length: 8,
+            max_delta_size: 4 * 1024,
+            data_chain_size: 16 * 1024,
+            data_delta_merge_length: 0,
+            delta_length_policy: DeltaLengthPolicy::Fixed,
+            index_node_entries: 256,
+            write_rate_limit: None,
+            max_write_stall: None,
+            max_retries: usize::MAX,
+            checkpoint_interval: None,
+            sync_mode: SyncMode::Never,
+            flush_policy: FlushPolicy::ConsolidateOnFlush,
+            max_background_jobs: 1,
+            page_restart_interval: 16,
+            persist_stats: false,
+            comparator: Arc::new(BytewiseComparator),
+            value_transformer: None,
+            rewrite_on_consolidation: false,
+            replication_buffer_size: 4096,
+            event_log_size: 1024,
+            slow_consolidation_threshold: Duration::from_millis(10),
+            event_listener: None,
+            cold_tier: None,
         }
     }
 }
 
 impl Options {
+    /// Returns the interval between periodic checkpoints, by `checkpoint_interval` and
+    /// `sync_mode`.
+    fn periodic_interval(&self) -> Option<Duration> {
+        let sync_interval = match self.sync_mode {
+            SyncMode::Every(interval) => Some(interval),
+            _ => None,
+        };
+        match (self.checkpoint_interval, sync_interval) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// Returns the size beyond which nodes are split.
     fn node_size(&self, is_index: bool) -> usize {
+        let size = self.page_size.unwrap_or(self.data_node_size);
+        if is_index {
+            size / 2
+        } else {
+            size
+        }
+    }
+
+    fn node_entries(&self, is_index: bool) -> usize {
         if is_index {
-            self.data_node_size / 2
+            self.index_node_entries
         } else {
-            self.data_node_size
+            self.data_node_entries
         }
     }
 }
+
+/// When writes are made durable.
+///
+/// Writes are only durable at checkpoints, which write the changed nodes and sync the page files
+/// and the manifest. Checkpoints run in the tasks of writes and waiters, or in the background for
+/// periodic checkpoints, which stop when the table is closed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SyncMode {
+    /// Each write returns after a checkpoint that covers it. Concurrent writes share checkpoints.
+    Always,
+    /// Writes are checkpointed in the background at this interval, so a write is durable within
+    /// about one interval. `Table::wait_durable` waits for the next checkpoint, and runs it if the
+    /// background checkpoint is late.
+    Every(Duration),
+    /// Writes are only checkpointed by other triggers, like `Options::checkpoint_interval`,
+    /// cache pressure, or an explicit `Table::checkpoint`, `Table::flush` or
+    /// `Table::wait_durable`.
+    Never,
+}
+
+/// How checkpoints write the leaves that have changed since they were last written.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FlushPolicy {
+    /// Each changed leaf is consolidated and written as one page, so that a swap-in reads a leaf
+    /// with one read.
+    ConsolidateOnFlush,
+    /// The deltas of a leaf since it was last written are merged and written as one delta page,
+    /// which refers to the page of the leaf on disk, instead of rewriting the whole leaf.
+    ///
+    /// Leaves that take a few writes between checkpoints are written with much less I/O, at the
+    /// cost of a read for each page of the chain on swap-ins, which are issued concurrently, and
+    /// of keeping the pages below the deltas on disk. A leaf is consolidated again once its
+    /// chain on disk has
+    /// `Options::data_delta_length` deltas, or if it is split or consolidated in memory since it
+    /// was written.
+    DeltaFlush,
+}
+
+/// Options of a get.
+#[derive(Debug, Default)]
+pub struct GetOptions<'a> {
+    /// Adds the counters of the get to the context, to find the keys that visit long chains or go
+    /// to disk.
+    pub perf_context: Option<&'a mut PerfContext>,
+}
+
+/// Options of a scan.
+#[derive(Debug, Default)]
+pub struct ScanOptions {
+    /// Copies the entries of one leaf at a time, and unpins the thread before they are passed to
+    /// the callback, so that long scans don't hold back the reclamation of the pages that are
+    /// replaced in the meantime.
+    ///
+    /// The scan then goes through a `Cursor`, which sees each leaf as it is when the leaf is
+    /// read.
+    pub release_epochs: bool,
+}
+
+/// Options of a put.
+#[derive(Debug, Default)]
+pub struct PutOptions<'a> {
+    /// Adds the counters of the put to the context.
+    pub perf_context: Option<&'a mut PerfContext>,
+}
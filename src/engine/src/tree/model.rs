@@ -0,0 +1,250 @@
+//! Model tests of the tree against `BTreeMap`.
+//!
+//! Each case runs batches of operations on a tree and on a model that keeps all the versions of
+//! each key. The operations of a batch run as concurrent tasks on a `SimEnv`, which interleaves
+//! them by the seed of the case and the delays before the operations. Reads in a batch see the
+//! snapshot before the batch, or an older one, so their results don't depend on the interleaving.
+
+use std::{collections::BTreeMap, ops::Bound, sync::Arc, time::Duration};
+
+use futures::future::join_all;
+use proptest::prelude::*;
+
+use super::{btree::BTree, ghost::Ghost, Options};
+use crate::env::{Env, SimEnv};
+
+const NUM_KEYS: u8 = 48;
+
+#[derive(Clone, Debug)]
+enum Op {
+    Put {
+        key: u8,
+        len: usize,
+    },
+    Delete {
+        key: u8,
+    },
+    /// Reads at `back` LSNs before the snapshot of the batch.
+    Get {
+        key: u8,
+        back: u64,
+    },
+    Scan {
+        start: u8,
+        end: Option<u8>,
+        back: u64,
+    },
+    Consolidate {
+        key: u8,
+    },
+    Checkpoint,
+}
+
+/// Operations with the delays before them in milliseconds.
+type Task = Vec<(u64, Op)>;
+
+fn key(i: u8) -> Vec<u8> {
+    format!("key{:03}", i).into_bytes()
+}
+
+/// Returns a value that is unique to `lsn`, unless it is empty.
+fn value(lsn: u64, len: usize) -> Vec<u8> {
+    lsn.to_be_bytes()
+        .iter()
+        .copied()
+        .cycle()
+        .take(len)
+        .collect()
+}
+
+#[derive(Default)]
+struct Model {
+    versions: BTreeMap<Vec<u8>, BTreeMap<u64, Option<Vec<u8>>>>,
+}
+
+impl Model {
+    fn get(&self, key: &[u8], lsn: u64) -> Option<Vec<u8>> {
+        let versions = self.versions.get(key)?;
+        versions.range(..=lsn).next_back()?.1.clone()
+    }
+
+    fn scan(&self, start: &[u8], end: &[u8], lsn: u64) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let end = if end.is_empty() {
+            Bound::Unbounded
+        } else if start < end {
+            Bound::Excluded(end)
+        } else {
+            return Vec::new();
+        };
+        self.versions
+            .range::<[u8], _>((Bound::Included(start), end))
+            .filter_map(|(key, _)| Some((key.clone(), self.get(key, lsn)?)))
+            .collect()
+    }
+}
+
+async fn scan(tree: &BTree, start: &[u8], end: &[u8], lsn: u64) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let ghost = &Ghost::pin();
+    let mut entries = Vec::new();
+    tree.scan(start, end, lsn, ghost, |k, v| {
+        entries.push((k.to_vec(), v.to_vec()));
+        true
+    })
+    .await
+    .unwrap();
+    entries
+}
+
+/// Runs a task, whose writes have the given LSNs, and returns the mismatched reads.
+async fn run_task(
+    tree: &BTree,
+    env: &SimEnv,
+    model: &Model,
+    task: &[(u64, Op)],
+    lsns: &[u64],
+    snapshot: u64,
+) -> Vec<String> {
+    let mut lsns = lsns.iter();
+    let mut mismatches = Vec::new();
+    for (delay, op) in task {
+        env.sleep(Duration::from_millis(*delay)).await;
+        let ghost = &Ghost::pin();
+        match *op {
+            Op::Put { key: k, len } => {
+                let lsn = *lsns.next().unwrap();
+                tree.put(&key(k), lsn, &value(lsn, len), ghost)
+                    .await
+                    .unwrap();
+            }
+            Op::Delete { key: k } => {
+                let lsn = *lsns.next().unwrap();
+                tree.delete(&key(k), lsn, ghost).await.unwrap();
+            }
+            Op::Get { key: k, back } => {
+                let lsn = snapshot.saturating_sub(back);
+                let got = tree.get(&key(k), lsn, ghost).await.unwrap();
+                let expect = model.get(&key(k), lsn);
+                if got.map(<[u8]>::to_vec) != expect {
+                    mismatches.push(format!(
+                        "{:?} at {}: got {:?}, expect {:?}",
+                        op, lsn, got, expect
+                    ));
+                }
+            }
+            Op::Scan { start, end, back } => {
+                let lsn = snapshot.saturating_sub(back);
+                let (start, end) = (key(start), end.map(key).unwrap_or_default());
+                let got = scan(tree, &start, &end, lsn).await;
+                let expect = model.scan(&start, &end, lsn);
+                if got != expect {
+                    mismatches.push(format!(
+                        "{:?} at {}: got {:?}, expect {:?}",
+                        op, lsn, got, expect
+                    ));
+                }
+            }
+            Op::Consolidate { key: k } => tree.consolidate(&key(k), ghost).await.unwrap(),
+            Op::Checkpoint => tree.checkpoint(ghost).await.unwrap(),
+        }
+    }
+    mismatches
+}
+
+fn run(seed: u64, small_cache: bool, batches: &[Vec<Task>]) -> Result<(), String> {
+    let env = SimEnv::new(seed);
+    let opts = Options {
+        data_node_size: 256,
+        data_delta_length: 3,
+        index_node_entries: 4,
+        // Small caches evict nodes, so that operations load pages and yield in between.
+        cache_size: if small_cache { 4096 } else { usize::MAX },
+        ..Default::default()
+    };
+    env.block_on(async {
+        let env_ref: Arc<dyn Env> = Arc::new(env.clone());
+        let tree = BTree::open_with_env(env_ref, "/db", opts).await.unwrap();
+        let mut model = Model::default();
+        let mut next_lsn = 1;
+        for batch in batches {
+            let snapshot = next_lsn - 1;
+            // Assigns the LSNs of the writes before the batch runs in any order.
+            let lsns: Vec<Vec<u64>> = batch
+                .iter()
+                .map(|task| {
+                    let num_writes = task
+                        .iter()
+                        .filter(|(_, op)| matches!(op, Op::Put { .. } | Op::Delete { .. }))
+                        .count() as u64;
+                    next_lsn += num_writes;
+                    (next_lsn - num_writes..next_lsn).collect()
+                })
+                .collect();
+            let tasks = batch
+                .iter()
+                .zip(&lsns)
+                .map(|(task, lsns)| run_task(&tree, &env, &model, task, lsns, snapshot));
+            let mismatches: Vec<_> = join_all(tasks).await.into_iter().flatten().collect();
+            if !mismatches.is_empty() {
+                return Err(mismatches.join("\n"));
+            }
+
+            for (task, lsns) in batch.iter().zip(&lsns) {
+                let mut lsns = lsns.iter();
+                for (_, op) in task {
+                    let k = match *op {
+                        Op::Put { key: k, .. } | Op::Delete { key: k } => k,
+                        _ => continue,
+                    };
+                    let lsn = *lsns.next().unwrap();
+                    let version = match *op {
+                        Op::Put { len, .. } => Some(value(lsn, len)),
+                        _ => None,
+                    };
+                    model
+                        .versions
+                        .entry(key(k))
+                        .or_default()
+                        .insert(lsn, version);
+                }
+            }
+            let got = scan(&tree, &[], &[], u64::MAX).await;
+            let expect = model.scan(&[], &[], u64::MAX);
+            if got != expect {
+                return Err(format!(
+                    "scan after batch: got {:?}, expect {:?}",
+                    got, expect
+                ));
+            }
+        }
+        let report = tree.verify().await.unwrap();
+        if !report.is_ok() {
+            return Err(format!("tree is corrupted: {:?}", report.problems));
+        }
+        Ok(())
+    })
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    let key = || 0..NUM_KEYS;
+    prop_oneof![
+        8 => (key(), 0..48usize).prop_map(|(key, len)| Op::Put { key, len }),
+        2 => key().prop_map(|key| Op::Delete { key }),
+        4 => (key(), 0..4u64).prop_map(|(key, back)| Op::Get { key, back }),
+        2 => (key(), proptest::option::of(key()), 0..4u64)
+            .prop_map(|(start, end, back)| Op::Scan { start, end, back }),
+        1 => key().prop_map(|key| Op::Consolidate { key }),
+        1 => Just(Op::Checkpoint),
+    ]
+}
+
+fn batches() -> impl Strategy<Value = Vec<Vec<Task>>> {
+    let task = proptest::collection::vec((0..8u64, op()), 1..24);
+    proptest::collection::vec(proptest::collection::vec(task, 1..4), 1..8)
+}
+
+proptest! {
+    #[test]
+    fn model(seed: u64, small_cache: bool, batches in batches()) {
+        run(seed, small_cache, &batches).map_err(TestCaseError::fail)?;
+    }
+}
@@ -0,0 +1,159 @@
+//! The NUMA topology of the machine, for `Options::numa_aware`.
+//!
+//! The topology is read from sysfs on Linux. Other systems are treated as a single node, and so
+//! are machines whose nodes can't be read.
+
+pub use imp::bind;
+
+/// The NUMA nodes of the CPUs.
+#[derive(Clone, Debug)]
+pub struct Topology {
+    num_nodes: usize,
+    // The node of each CPU.
+    cpu_nodes: Vec<usize>,
+}
+
+impl Default for Topology {
+    /// Returns the topology of a single node.
+    fn default() -> Self {
+        Self {
+            num_nodes: 1,
+            cpu_nodes: Vec::new(),
+        }
+    }
+}
+
+impl Topology {
+    /// Reads the topology of the machine, or returns a single node if it can't be read.
+    pub fn detect() -> Self {
+        imp::detect().unwrap_or_default()
+    }
+
+    pub fn num_nodes(&self) -> usize {
+        self.num_nodes
+    }
+
+    /// Returns the node of the CPU that the current thread runs on.
+    pub fn current_node(&self) -> usize {
+        if self.num_nodes == 1 {
+            return 0;
+        }
+        imp::current_cpu()
+            .and_then(|cpu| self.cpu_nodes.get(cpu).copied())
+            .unwrap_or(0)
+    }
+}
+
+/// Parses a list of ranges in the format of sysfs, like `0-3,8`.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_list(list: &str) -> Option<Vec<usize>> {
+    let mut values = Vec::new();
+    for range in list.trim().split(',').filter(|range| !range.is_empty()) {
+        let (start, end): (usize, usize) = match range.split_once('-') {
+            Some((start, end)) => (start.parse().ok()?, end.parse().ok()?),
+            None => {
+                let value = range.parse().ok()?;
+                (value, value)
+            }
+        };
+        values.extend(start..=end);
+    }
+    Some(values)
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use std::fs;
+
+    use super::{parse_list, Topology};
+
+    const NODE_DIR: &str = "/sys/devices/system/node";
+
+    // From `linux/mempolicy.h`.
+    const MPOL_PREFERRED: libc::c_long = 1;
+    const MPOL_MF_MOVE: libc::c_long = 1 << 1;
+
+    pub fn detect() -> Option<Topology> {
+        let nodes = parse_list(&fs::read_to_string(format!("{}/online", NODE_DIR)).ok()?)?;
+        if nodes.len() <= 1 {
+            return None;
+        }
+        let mut cpu_nodes = Vec::new();
+        for &node in &nodes {
+            let path = format!("{}/node{}/cpulist", NODE_DIR, node);
+            for cpu in parse_list(&fs::read_to_string(path).ok()?)? {
+                if cpu_nodes.len() <= cpu {
+                    cpu_nodes.resize(cpu + 1, 0);
+                }
+                cpu_nodes[cpu] = node;
+            }
+        }
+        Some(Topology {
+            num_nodes: nodes.iter().max().unwrap() + 1,
+            cpu_nodes,
+        })
+    }
+
+    pub fn current_cpu() -> Option<usize> {
+        let cpu = unsafe { libc::sched_getcpu() };
+        usize::try_from(cpu).ok()
+    }
+
+    /// Prefers `node` for the memory in `ptr..ptr + len`, and moves the pages that are already
+    /// there. This is a hint, so failures are ignored.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be aligned to the page size of the system, and the range must be allocated.
+    pub unsafe fn bind(ptr: *mut u8, len: usize, node: usize) {
+        if node >= 64 {
+            return;
+        }
+        let mask: u64 = 1 << node;
+        // The kernel takes the number of bits in the mask plus one.
+        libc::syscall(
+            libc::SYS_mbind,
+            ptr,
+            len,
+            MPOL_PREFERRED,
+            &mask as *const u64,
+            65 as libc::c_ulong,
+            MPOL_MF_MOVE,
+        );
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    use super::Topology;
+
+    pub fn detect() -> Option<Topology> {
+        None
+    }
+
+    pub fn current_cpu() -> Option<usize> {
+        None
+    }
+
+    pub unsafe fn bind(_: *mut u8, _: usize, _: usize) {}
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_lists() {
+        assert_eq!(parse_list("0\n"), Some(vec![0]));
+        assert_eq!(parse_list("0-3,8,10-11"), Some(vec![0, 1, 2, 3, 8, 10, 11]));
+        assert_eq!(parse_list(""), Some(vec![]));
+        assert_eq!(parse_list("0-a"), None);
+    }
+
+    #[test]
+    fn detect() {
+        let topology = Topology::detect();
+        assert!(topology.num_nodes() >= 1);
+        assert!(topology.current_node() < topology.num_nodes());
+    }
+}
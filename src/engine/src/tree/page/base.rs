@@ -1,9 +1,15 @@
-use std::{alloc::Layout, ptr::NonNull};
+use std::{alloc::Layout, ops::Range, ptr::NonNull};
 
-// Page header: ver (6B) | len (1B) | tag (1B) | next (8B) | content_size (4B) |
+// Page header: ver (6B) | len (1B) | tag (1B) | next (8B) | content_size (4B) | chain_size (4B) |
 const PAGE_ALIGNMENT: usize = 8;
-const PAGE_HEADER_SIZE: usize = 20;
+pub(super) const PAGE_HEADER_SIZE: usize = 24;
+const PAGE_VERSION_OFFSET: usize = 0;
 const PAGE_VERSION_SIZE: usize = 6;
+const PAGE_LEN_OFFSET: usize = 6;
+const PAGE_TAG_OFFSET: usize = 7;
+const PAGE_NEXT_OFFSET: usize = 8;
+const PAGE_CONTENT_SIZE_OFFSET: usize = 16;
+const PAGE_CHAIN_SIZE_OFFSET: usize = 20;
 
 /// A non-null pointer to a page.
 #[derive(Copy, Clone, Debug)]
@@ -24,81 +30,85 @@ impl PagePtr {
         self.0.as_ptr()
     }
 
-    unsafe fn ver_ptr(self) -> *mut u8 {
-        self.as_raw()
-    }
-
-    unsafe fn len_ptr(self) -> *mut u8 {
-        self.as_raw().add(PAGE_VERSION_SIZE)
-    }
-
-    unsafe fn tag_ptr(self) -> *mut u8 {
-        self.as_raw().add(PAGE_VERSION_SIZE + 1)
-    }
-
-    unsafe fn next_ptr(self) -> *mut u64 {
-        (self.as_raw() as *mut u64).add(1)
+    /// Reads `N` bytes of the header at `offset`.
+    ///
+    /// Header fields are read and written as bytes, so that a header can be read from any buffer,
+    /// aligned or not.
+    unsafe fn read_header<const N: usize>(self, offset: usize) -> [u8; N] {
+        (self.as_raw().add(offset) as *const [u8; N]).read()
     }
 
-    unsafe fn content_size_ptr(self) -> *mut u32 {
-        (self.as_raw() as *mut u32).add(4)
+    unsafe fn write_header<const N: usize>(self, offset: usize, bytes: [u8; N]) {
+        (self.as_raw().add(offset) as *mut [u8; N]).write(bytes)
     }
 
     /// Returns the page version.
     pub fn ver(&self) -> PageVer {
-        unsafe {
-            let mut ver = 0u64;
-            let ver_ptr = &mut ver as *mut u64 as *mut u8;
-            ver_ptr.copy_from_nonoverlapping(self.ver_ptr(), PAGE_VERSION_SIZE);
-            PageVer(u64::from_le(ver))
-        }
+        let mut ver = [0; 8];
+        ver[..PAGE_VERSION_SIZE].copy_from_slice(&unsafe {
+            self.read_header::<PAGE_VERSION_SIZE>(PAGE_VERSION_OFFSET)
+        });
+        PageVer(u64::from_le_bytes(ver))
     }
 
     pub fn set_ver(&mut self, ver: PageVer) {
-        unsafe {
-            let ver = ver.0.to_le();
-            let ver_ptr = &ver as *const u64 as *const u8;
-            ver_ptr.copy_to_nonoverlapping(self.ver_ptr(), PAGE_VERSION_SIZE);
-        }
+        let mut bytes = [0; PAGE_VERSION_SIZE];
+        bytes.copy_from_slice(&ver.0.to_le_bytes()[..PAGE_VERSION_SIZE]);
+        unsafe { self.write_header(PAGE_VERSION_OFFSET, bytes) };
     }
 
     /// Returns the length of the chain.
     pub fn len(&self) -> u8 {
-        unsafe { self.len_ptr().read() }
+        unsafe { self.read_header::<1>(PAGE_LEN_OFFSET)[0] }
     }
 
     pub fn set_len(&mut self, len: u8) {
-        unsafe {
-            self.len_ptr().write(len);
-        }
+        unsafe { self.write_header(PAGE_LEN_OFFSET, [len]) };
     }
 
     /// Returns the address of the next page in the chain.
     pub fn next(&self) -> u64 {
-        unsafe { self.next_ptr().read().to_le() }
+        u64::from_le_bytes(unsafe { self.read_header(PAGE_NEXT_OFFSET) })
     }
 
     pub fn set_next(&mut self, next: u64) {
-        unsafe {
-            self.next_ptr().write(next.to_le());
-        }
+        unsafe { self.write_header(PAGE_NEXT_OFFSET, next.to_le_bytes()) };
     }
 
     fn tag(&self) -> PageTag {
-        unsafe { self.tag_ptr().read().into() }
+        unsafe { self.read_header::<1>(PAGE_TAG_OFFSET)[0] }.into()
     }
 
     fn set_tag(&mut self, tag: PageTag) {
-        unsafe {
-            self.tag_ptr().write(tag.into());
-        }
+        unsafe { self.write_header(PAGE_TAG_OFFSET, [tag.into()]) };
     }
 
     /// Returns the page kind.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the kind is invalid. Pages from disk are checked with `try_kind` when they are
+    /// decoded, so this only happens to pages that are corrupted in memory.
     pub fn kind(&self) -> PageKind {
         self.tag().kind()
     }
 
+    /// Returns the page kind, or `None` if the kind is invalid.
+    pub fn try_kind(&self) -> Option<PageKind> {
+        PageKind::try_new(self.raw_kind())
+    }
+
+    /// Returns the page kind as is, which may be unknown to this version.
+    pub fn raw_kind(&self) -> u8 {
+        self.tag().0 & PAGE_KIND_MASK
+    }
+
+    /// Sets a kind that may be unknown to this version, to test how such pages are handled.
+    #[cfg(test)]
+    pub fn set_raw_kind(&mut self, kind: u8) {
+        self.set_tag(PageTag((self.tag().0 & !PAGE_KIND_MASK) | kind));
+    }
+
     pub fn set_kind(&mut self, kind: PageKind) {
         self.set_tag(self.tag().with_kind(kind));
     }
@@ -112,6 +122,15 @@ impl PagePtr {
         self.set_tag(self.tag().with_index(is_index));
     }
 
+    /// Returns true if the page stores the sort prefixes of its keys.
+    pub fn has_sort_prefixes(&self) -> bool {
+        self.tag().has_sort_prefixes()
+    }
+
+    pub fn set_sort_prefixes(&mut self, has_sort_prefixes: bool) {
+        self.set_tag(self.tag().with_sort_prefixes(has_sort_prefixes));
+    }
+
     /// Sets the page header as default.
     pub fn set_default(&mut self) {
         unsafe { self.as_raw().write_bytes(0, PAGE_HEADER_SIZE) };
@@ -134,13 +153,22 @@ impl PagePtr {
 
     /// Returns the page content size in bytes.
     pub fn content_size(&self) -> u32 {
-        unsafe { self.content_size_ptr().read().to_le() }
+        u32::from_le_bytes(unsafe { self.read_header(PAGE_CONTENT_SIZE_OFFSET) })
     }
 
     fn set_content_size(&mut self, size: u32) {
-        unsafe {
-            self.content_size_ptr().write(size.to_le());
-        }
+        unsafe { self.write_header(PAGE_CONTENT_SIZE_OFFSET, size.to_le_bytes()) };
+    }
+
+    /// Returns the size of the pages of the chain from this page on, in bytes.
+    ///
+    /// The size is accumulated when a page is chained, and saturates at `u32::MAX`.
+    pub fn chain_size(&self) -> u32 {
+        u32::from_le_bytes(unsafe { self.read_header(PAGE_CHAIN_SIZE_OFFSET) })
+    }
+
+    pub fn set_chain_size(&mut self, size: u32) {
+        unsafe { self.write_header(PAGE_CHAIN_SIZE_OFFSET, size.to_le_bytes()) };
     }
 }
 
@@ -156,6 +184,8 @@ pub struct PageVer(u64);
 const PAGE_VERSION_MAX: u64 = (1 << 48) - 1;
 
 impl PageVer {
+    pub const MAX: u64 = PAGE_VERSION_MAX;
+
     pub const fn new(ver: u64) -> Self {
         assert!(ver <= PAGE_VERSION_MAX);
         Self(ver)
@@ -175,7 +205,9 @@ impl From<PageVer> for u64 {
 #[derive(Copy, Clone, Debug, Default)]
 struct PageTag(u8);
 
-const PAGE_KIND_MASK: u8 = 0x7F;
+const PAGE_KIND_MASK: u8 = 0x3F;
+const PAGE_INDEX_BIT: u8 = 0x80;
+const PAGE_SORT_PREFIXES_BIT: u8 = 0x40;
 
 impl PageTag {
     const fn kind(self) -> PageKind {
@@ -183,22 +215,30 @@ impl PageTag {
     }
 
     const fn with_kind(self, kind: PageKind) -> Self {
-        Self(self.index() | kind as u8)
-    }
-
-    const fn index(self) -> u8 {
-        self.0 & !PAGE_KIND_MASK
+        Self((self.0 & !PAGE_KIND_MASK) | kind as u8)
     }
 
     const fn is_index(self) -> bool {
-        self.index() != 0
+        self.0 & PAGE_INDEX_BIT != 0
     }
 
     const fn with_index(self, is_index: bool) -> Self {
-        if is_index {
-            Self(self.0 | !PAGE_KIND_MASK)
+        self.with_bit(PAGE_INDEX_BIT, is_index)
+    }
+
+    const fn has_sort_prefixes(self) -> bool {
+        self.0 & PAGE_SORT_PREFIXES_BIT != 0
+    }
+
+    const fn with_sort_prefixes(self, has_sort_prefixes: bool) -> Self {
+        self.with_bit(PAGE_SORT_PREFIXES_BIT, has_sort_prefixes)
+    }
+
+    const fn with_bit(self, bit: u8, set: bool) -> Self {
+        if set {
+            Self(self.0 | bit)
         } else {
-            Self(self.0 & PAGE_KIND_MASK)
+            Self(self.0 & !bit)
         }
     }
 }
@@ -215,6 +255,17 @@ impl From<PageTag> for u8 {
     }
 }
 
+/// The kind of a page, which is stored in six bits of the page tag.
+///
+/// Kinds are assigned in ranges, so that new kinds keep their values across versions:
+///
+/// - `CHAIN_KINDS` are the pages in the chains of nodes, which change the entries or the range of
+///   the pages below them.
+/// - `BLOB_KINDS` are the pages that are referenced by entries instead of chains.
+/// - `EXTENSION_KINDS` are kept for kinds that fit neither range.
+///
+/// The `reserved` kinds are planned but not supported yet. A page of a kind that this version
+/// doesn't know is returned as `TypedPageRef::Unknown`, and is never valid on disk.
 #[repr(u8)]
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum PageKind {
@@ -222,12 +273,36 @@ pub enum PageKind {
     Split = 1,
 }
 
+/// The page kinds reserved for future pages.
+#[allow(dead_code)]
+pub mod reserved {
+    /// A delta that merges the node into its left sibling.
+    pub const MERGE: u8 = 2;
+    /// A delta that removes the node after it is merged.
+    pub const REMOVE: u8 = 3;
+    /// A delta that deletes a range of keys.
+    pub const RANGE_TOMBSTONE: u8 = 4;
+    /// A page that stores a large value out of its leaf.
+    pub const BLOB: u8 = 0x10;
+}
+
 impl PageKind {
+    pub const CHAIN_KINDS: Range<u8> = 0x00..0x10;
+    pub const BLOB_KINDS: Range<u8> = 0x10..0x20;
+    pub const EXTENSION_KINDS: Range<u8> = 0x20..PAGE_KIND_MASK + 1;
+
     const fn new(kind: u8) -> Self {
+        match Self::try_new(kind) {
+            Some(kind) => kind,
+            None => panic!("invalid page kind"),
+        }
+    }
+
+    const fn try_new(kind: u8) -> Option<Self> {
         match kind {
-            0 => Self::Data,
-            1 => Self::Split,
-            _ => panic!("invalid page kind"),
+            0 => Some(Self::Data),
+            1 => Some(Self::Split),
+            _ => None,
         }
     }
 }
@@ -275,6 +350,7 @@ impl PageBuilder {
             ptr.set_default();
             ptr.set_kind(self.kind);
             ptr.set_content_size(content_size as u32);
+            ptr.set_chain_size(ptr.size().min(u32::MAX as usize) as u32);
             ptr
         })
     }
@@ -282,11 +358,7 @@ impl PageBuilder {
 
 #[cfg(test)]
 pub mod test {
-    use std::alloc::GlobalAlloc;
-
-    use jemallocator::{usable_size, Jemalloc};
-
-    use super::*;
+    use super::{super::super::malloc, *};
 
     pub const ALLOC: TestAlloc = TestAlloc;
 
@@ -297,22 +369,23 @@ pub mod test {
 
         fn alloc(&self, size: usize) -> Result<PagePtr, Self::Error> {
             unsafe {
-                let ptr = Jemalloc.alloc(Self::alloc_layout(size));
+                let ptr = malloc::alloc(Self::alloc_layout(size));
                 PagePtr::new(ptr).ok_or(())
             }
         }
 
         unsafe fn dealloc(&self, page: PagePtr) {
             let ptr = page.as_raw();
-            let size = usable_size(ptr);
-            Jemalloc.dealloc(ptr, Self::alloc_layout(size));
+            let size = malloc::usable_size(ptr);
+            malloc::dealloc(ptr, Self::alloc_layout(size));
         }
     }
 
     #[test]
     fn page_ptr() {
-        let mut buf = [1u8; PAGE_HEADER_SIZE];
-        let mut ptr = unsafe { PagePtr::new(buf.as_mut_ptr()).unwrap() };
+        // The header is unaligned.
+        let mut buf = [1u8; PAGE_HEADER_SIZE + 1];
+        let mut ptr = unsafe { PagePtr::new(buf.as_mut_ptr().add(1)).unwrap() };
         ptr.set_default();
         assert_eq!(ptr.ver(), PageVer(0));
         ptr.set_ver(ptr.ver().next());
@@ -329,8 +402,45 @@ pub mod test {
         assert_eq!(ptr.is_index(), false);
         ptr.set_index(true);
         assert_eq!(ptr.is_index(), true);
+        ptr.set_sort_prefixes(true);
+        assert!(ptr.has_sort_prefixes());
+        assert!(ptr.is_index());
+        assert_eq!(ptr.kind(), PageKind::Split);
         assert_eq!(ptr.content_size(), 0);
         ptr.set_content_size(4);
         assert_eq!(ptr.content_size(), 4);
+        assert_eq!(ptr.chain_size(), 0);
+        ptr.set_chain_size(28);
+        assert_eq!(ptr.chain_size(), 28);
+    }
+
+    #[test]
+    fn page_kinds() {
+        let ranges = [
+            PageKind::CHAIN_KINDS,
+            PageKind::BLOB_KINDS,
+            PageKind::EXTENSION_KINDS,
+        ];
+        for pair in ranges.windows(2) {
+            assert_eq!(pair[0].end, pair[1].start);
+        }
+        assert_eq!(ranges[0].start, 0);
+        assert_eq!(ranges[2].end, PAGE_KIND_MASK + 1);
+        for kind in [PageKind::Data, PageKind::Split] {
+            assert!(PageKind::CHAIN_KINDS.contains(&kind.into()));
+        }
+        for kind in [reserved::MERGE, reserved::REMOVE, reserved::RANGE_TOMBSTONE] {
+            assert!(PageKind::CHAIN_KINDS.contains(&kind));
+        }
+        assert!(PageKind::BLOB_KINDS.contains(&reserved::BLOB));
+
+        let mut buf = [0u8; PAGE_HEADER_SIZE];
+        let mut ptr = unsafe { PagePtr::new(buf.as_mut_ptr()).unwrap() };
+        ptr.set_default();
+        ptr.set_index(true);
+        ptr.set_raw_kind(reserved::BLOB);
+        assert_eq!(ptr.try_kind(), None);
+        assert_eq!(ptr.raw_kind(), reserved::BLOB);
+        assert!(ptr.is_index());
     }
 }
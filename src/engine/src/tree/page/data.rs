@@ -1,10 +1,10 @@
 use std::{
-    cmp::{Eq, Ord, Ordering, PartialEq, PartialOrd},
+    cmp::{Eq, Ordering, PartialEq},
     mem::size_of,
     ops::Range,
 };
 
-use super::{BufReader, BufWriter, PageVer};
+use super::{BufReader, BufWriter, Comparator, PageVer};
 
 pub trait Encodable {
     /// Returns the size to encode this object.
@@ -23,8 +23,26 @@ pub trait Decodable {
     ///
     /// # Safety
     ///
-    /// The `BufReader` must be initialized with enough data to decode such an object.
+    /// The `BufReader` must be initialized with enough data to decode such an object, or be
+    /// bounds-checked, in which case malformed data marks the reader as corrupted.
     unsafe fn decode_from(r: &mut BufReader) -> Self;
+
+    /// Returns a prefix of this object as a key, or `None` if the object doesn't have one.
+    ///
+    /// Prefixes must be consistent with the order of keys: if `a < b`, then
+    /// `a.sort_prefix() <= b.sort_prefix()`. Pages store the prefixes of their keys, so that
+    /// searches can compare prefixes before decoding keys.
+    fn sort_prefix(&self) -> Option<u64> {
+        None
+    }
+}
+
+/// Returns the first eight bytes of `raw` as a big-endian integer, padded with zeros.
+fn raw_sort_prefix(raw: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    let len = raw.len().min(buf.len());
+    buf[..len].copy_from_slice(&raw[..len]);
+    u64::from_be_bytes(buf)
 }
 
 impl Encodable for u64 {
@@ -41,6 +59,10 @@ impl Decodable for u64 {
     unsafe fn decode_from(r: &mut BufReader) -> Self {
         r.get_u64()
     }
+
+    fn sort_prefix(&self) -> Option<u64> {
+        Some(*self)
+    }
 }
 
 impl Encodable for &[u8] {
@@ -57,6 +79,45 @@ impl Decodable for &[u8] {
     unsafe fn decode_from(r: &mut BufReader) -> Self {
         r.get_length_prefixed_slice()
     }
+
+    fn sort_prefix(&self) -> Option<u64> {
+        Some(raw_sort_prefix(self))
+    }
+}
+
+/// A key that is ordered by a `Comparator`.
+pub trait Comparable {
+    /// Compares this key with `other` in the order of `cmp`.
+    fn compare_with(&self, other: &Self, cmp: &dyn Comparator) -> Ordering;
+}
+
+/// Orders integers naturally, regardless of the comparator.
+impl Comparable for u64 {
+    fn compare_with(&self, other: &Self, _: &dyn Comparator) -> Ordering {
+        self.cmp(other)
+    }
+}
+
+impl Comparable for &[u8] {
+    fn compare_with(&self, other: &Self, cmp: &dyn Comparator) -> Ordering {
+        // The empty key is the smallest one, which comparators don't need to handle.
+        match (self.is_empty(), other.is_empty()) {
+            (false, false) => cmp.compare(self, other),
+            (a, b) => b.cmp(&a),
+        }
+    }
+}
+
+/// A key that is routed to nodes by its raw bytes.
+pub trait RawKey {
+    /// Returns the raw bytes of the key.
+    fn as_raw(&self) -> &[u8];
+}
+
+impl RawKey for &[u8] {
+    fn as_raw(&self) -> &[u8] {
+        self
+    }
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -71,6 +132,12 @@ impl<'a> Key<'a> {
     }
 }
 
+impl RawKey for Key<'_> {
+    fn as_raw(&self) -> &[u8] {
+        self.raw
+    }
+}
+
 impl Eq for Key<'_> {}
 
 impl PartialEq for Key<'_> {
@@ -79,21 +146,16 @@ impl PartialEq for Key<'_> {
     }
 }
 
-impl Ord for Key<'_> {
-    fn cmp(&self, other: &Self) -> Ordering {
-        match self.raw.cmp(other.raw) {
+/// Orders keys by their raw bytes, and then by their LSNs from the newest to the oldest.
+impl Comparable for Key<'_> {
+    fn compare_with(&self, other: &Self, cmp: &dyn Comparator) -> Ordering {
+        match self.raw.compare_with(&other.raw, cmp) {
             Ordering::Equal => other.lsn.cmp(&self.lsn),
             o => o,
         }
     }
 }
 
-impl PartialOrd for Key<'_> {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
-    }
-}
-
 impl Encodable for Key<'_> {
     fn encode_size(&self) -> usize {
         BufWriter::length_prefixed_slice_size(self.raw) + size_of::<u64>()
@@ -111,6 +173,10 @@ impl Decodable for Key<'_> {
         let lsn = r.get_u64();
         Self { raw, lsn }
     }
+
+    fn sort_prefix(&self) -> Option<u64> {
+        Some(raw_sort_prefix(self.raw))
+    }
 }
 
 #[repr(u8)]
@@ -120,12 +186,12 @@ enum ValueKind {
     Delete = 1,
 }
 
-impl From<u8> for ValueKind {
-    fn from(kind: u8) -> Self {
+impl ValueKind {
+    const fn new(kind: u8) -> Option<Self> {
         match kind {
-            0 => Self::Put,
-            1 => Self::Delete,
-            _ => panic!("invalid data kind"),
+            0 => Some(Self::Put),
+            1 => Some(Self::Delete),
+            _ => None,
         }
     }
 }
@@ -157,13 +223,16 @@ impl Encodable for Value<'_> {
 
 impl Decodable for Value<'_> {
     unsafe fn decode_from(r: &mut BufReader) -> Self {
-        let kind = ValueKind::from(r.get_u8());
-        match kind {
-            ValueKind::Put => {
+        match ValueKind::new(r.get_u8()) {
+            Some(ValueKind::Put) => {
                 let value = r.get_length_prefixed_slice();
                 Self::Put(value)
             }
-            ValueKind::Delete => Self::Delete,
+            Some(ValueKind::Delete) => Self::Delete,
+            None => {
+                r.set_corrupted();
+                Self::Delete
+            }
         }
     }
 }
@@ -208,7 +277,11 @@ impl Encodable for Index {
 impl Decodable for Index {
     unsafe fn decode_from(r: &mut BufReader) -> Self {
         let id = r.get_u64();
-        let ver = r.get_u64();
+        let mut ver = r.get_u64();
+        if ver > PageVer::MAX {
+            r.set_corrupted();
+            ver = 0;
+        }
         Self::new(id, PageVer::new(ver))
     }
 }
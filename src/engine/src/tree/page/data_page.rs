@@ -8,37 +8,79 @@ use std::{
 
 use super::*;
 
+/// The largest content size of pages with two-byte offsets.
+const MAX_COMPACT_CONTENT_SIZE: usize = u16::MAX as usize;
+
 /// A builder to create data pages.
+///
+/// A data page consists of an array of offsets to its entries, an optional array of the sort
+/// prefixes of its keys, and then the entries. Offsets take two bytes each if the page content is
+/// no larger than 64KiB, or four bytes otherwise, so that small pages with many entries don't
+/// spend much on offsets. Sort prefixes take eight bytes each, and are stored if all keys have
+/// them, so that searches can narrow the range of entries without decoding keys.
 pub struct DataPageBuilder {
     base: PageBuilder,
-    offsets_size: usize,
+    num_entries: usize,
     payload_size: usize,
+    sort_prefixes: bool,
 }
 
 impl Default for DataPageBuilder {
     fn default() -> Self {
         Self {
             base: PageBuilder::new(PageKind::Data),
-            offsets_size: 0,
+            num_entries: 0,
             payload_size: 0,
+            sort_prefixes: true,
         }
     }
 }
 
-// TODO: Optimizes the page layout with
+// TODO: Optimizes the page layout further with
 // https://cseweb.ucsd.edu//~csjgwang/pubs/ICDE17_BwTree.pdf
 impl DataPageBuilder {
+    /// Sets whether to store the sort prefixes of keys, which is only valid if keys are ordered
+    /// bytewise. Prefixes are stored by default if all keys have them.
+    pub fn sort_prefixes(mut self, enabled: bool) -> Self {
+        self.sort_prefixes = enabled;
+        self
+    }
+
     fn add<K, V>(&mut self, key: &K, value: &V)
     where
-        K: Encodable,
+        K: Encodable + Decodable,
         V: Encodable,
     {
-        self.offsets_size += size_of::<u32>();
+        self.num_entries += 1;
         self.payload_size += key.encode_size() + value.encode_size();
+        self.sort_prefixes &= key.sort_prefix().is_some();
+    }
+
+    fn has_sort_prefixes(&self) -> bool {
+        self.sort_prefixes && self.num_entries > 0
+    }
+
+    /// Returns the size of the offsets and the sort prefixes.
+    fn index_size(&self, offset_size: usize) -> usize {
+        let prefix_size = if self.has_sort_prefixes() {
+            size_of::<u64>()
+        } else {
+            0
+        };
+        self.num_entries * (offset_size + prefix_size)
+    }
+
+    fn is_compact(&self) -> bool {
+        self.index_size(size_of::<u16>()) + self.payload_size <= MAX_COMPACT_CONTENT_SIZE
     }
 
     fn size(&self) -> usize {
-        self.offsets_size + self.payload_size
+        let offset_size = if self.is_compact() {
+            size_of::<u16>()
+        } else {
+            size_of::<u32>()
+        };
+        self.index_size(offset_size) + self.payload_size
     }
 
     /// Builds an empty data page.
@@ -55,7 +97,7 @@ impl DataPageBuilder {
     where
         A: PageAlloc,
         I: RewindableIter,
-        I::Key: Encodable,
+        I::Key: Encodable + Decodable,
         I::Value: Encodable,
     {
         iter.rewind();
@@ -76,19 +118,36 @@ impl DataPageBuilder {
 
 pub struct DataPageBuf {
     ptr: PagePtr,
-    offsets: *mut u32,
+    offsets: *mut u8,
+    compact: bool,
+    // The array of sort prefixes, or null if the page doesn't have them.
+    prefixes: *mut u64,
     content: BufWriter,
     current: usize,
 }
 
 impl DataPageBuf {
     unsafe fn new(mut ptr: PagePtr, builder: DataPageBuilder) -> Self {
-        let offsets = ptr.content_mut() as *mut u32;
+        let offsets = ptr.content_mut();
+        let compact = builder.is_compact();
+        let offset_size = if compact {
+            size_of::<u16>()
+        } else {
+            size_of::<u32>()
+        };
+        let prefixes = if builder.has_sort_prefixes() {
+            ptr.set_sort_prefixes(true);
+            offsets.add(builder.num_entries * offset_size) as *mut u64
+        } else {
+            std::ptr::null_mut()
+        };
         let mut content = BufWriter::new(ptr.content_mut());
-        content.skip(builder.offsets_size);
+        content.skip(builder.index_size(offset_size));
         Self {
             ptr,
             offsets,
+            compact,
+            prefixes,
             content,
             current: 0,
         }
@@ -96,11 +155,23 @@ impl DataPageBuf {
 
     unsafe fn add<K, V>(&mut self, key: &K, value: &V)
     where
-        K: Encodable,
+        K: Encodable + Decodable,
         V: Encodable,
     {
-        let offset = self.content.pos() as u32;
-        self.offsets.add(self.current).write(offset.to_le());
+        let offset = self.content.pos();
+        if self.compact {
+            let offsets = self.offsets as *mut u16;
+            offsets.add(self.current).write((offset as u16).to_le());
+        } else {
+            let offsets = self.offsets as *mut u32;
+            offsets.add(self.current).write((offset as u32).to_le());
+        }
+        if !self.prefixes.is_null() {
+            let prefix = key.sort_prefix().unwrap();
+            self.prefixes
+                .add(self.current)
+                .write_unaligned(prefix.to_le());
+        }
         self.current += 1;
         key.encode_to(&mut self.content);
         value.encode_to(&mut self.content);
@@ -112,7 +183,7 @@ impl DataPageBuf {
 
     pub fn as_ref<'a, K, V>(&self) -> DataPageRef<'a, K, V>
     where
-        K: Decodable + Ord,
+        K: Decodable + Comparable,
         V: Decodable,
     {
         unsafe { DataPageRef::new(self.ptr) }
@@ -133,34 +204,153 @@ impl DerefMut for DataPageBuf {
     }
 }
 
+/// The offsets of the entries in a data page.
+#[derive(Copy, Clone)]
+enum Offsets<'a> {
+    Compact(&'a [u16]),
+    Full(&'a [u32]),
+}
+
+impl<'a> Offsets<'a> {
+    /// Returns the offsets of the page and a pointer to the sort prefixes right after them.
+    unsafe fn new(base: PagePtr) -> (Self, *const u64) {
+        let content_size = base.content_size() as usize;
+        let prefix_size = if base.has_sort_prefixes() {
+            size_of::<u64>()
+        } else {
+            0
+        };
+        // The first entry follows the offsets and the sort prefixes.
+        let (offsets, offset_size) = if content_size <= MAX_COMPACT_CONTENT_SIZE {
+            let ptr = base.content() as *const u16;
+            let len = if content_size == 0 {
+                0
+            } else {
+                u16::from_le(ptr.read()) as usize / (size_of::<u16>() + prefix_size)
+            };
+            (
+                Self::Compact(slice::from_raw_parts(ptr, len)),
+                size_of::<u16>(),
+            )
+        } else {
+            let ptr = base.content() as *const u32;
+            let len = u32::from_le(ptr.read()) as usize / (size_of::<u32>() + prefix_size);
+            (
+                Self::Full(slice::from_raw_parts(ptr, len)),
+                size_of::<u32>(),
+            )
+        };
+        let prefixes = base.content().add(offsets.len() * offset_size) as *const u64;
+        (offsets, prefixes)
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Self::Compact(offsets) => offsets.len(),
+            Self::Full(offsets) => offsets.len(),
+        }
+    }
+
+    fn get(&self, index: usize) -> Option<u32> {
+        match self {
+            Self::Compact(offsets) => offsets.get(index).map(|&o| u16::from_le(o) as u32),
+            Self::Full(offsets) => offsets.get(index).map(|&o| u32::from_le(o)),
+        }
+    }
+}
+
+/// The number of entries below which a search counts the smaller prefixes in one pass instead of
+/// bisecting further. The pass has no branches, so it is vectorized.
+const PREFIX_SCAN_THRESHOLD: usize = 16;
+
 /// An immutable reference to a data page.
 pub struct DataPageRef<'a, K, V> {
     base: PagePtr,
-    offsets: &'a [u32],
+    offsets: Offsets<'a>,
+    // The array of sort prefixes, or null if the page doesn't have them.
+    prefixes: *const u64,
     _mark: PhantomData<(K, V)>,
 }
 
 impl<'a, K, V> DataPageRef<'a, K, V>
 where
-    K: Decodable + Ord,
+    K: Decodable + Comparable,
     V: Decodable,
 {
     pub unsafe fn new(base: PagePtr) -> Self {
-        let offsets_ptr = base.content() as *const u32;
-        let offsets_len = if base.content_size() == 0 {
-            0
+        let (offsets, prefixes) = Offsets::new(base);
+        let prefixes = if base.has_sort_prefixes() {
+            prefixes
         } else {
-            let offset = offsets_ptr.read();
-            offset as usize / size_of::<u32>()
+            std::ptr::null()
         };
-        let offsets = slice::from_raw_parts(offsets_ptr, offsets_len);
         Self {
             base,
             offsets,
+            prefixes,
             _mark: PhantomData,
         }
     }
 
+    /// Creates a reference to a page with bounds-checked decoding, which returns `None` if the
+    /// page or any of its entries is malformed.
+    ///
+    /// Pages from disk are checked once when they are loaded, and then decoded unchecked.
+    ///
+    /// # Safety
+    ///
+    /// `base` must point to an aligned page of `base.size()` bytes.
+    pub unsafe fn new_checked(base: PagePtr) -> Option<Self> {
+        if base.try_kind() != Some(PageKind::Data) {
+            return None;
+        }
+        let content_size = base.content_size() as usize;
+        if content_size > 0 {
+            let (offset_size, first) = if content_size <= MAX_COMPACT_CONTENT_SIZE {
+                if content_size < size_of::<u16>() {
+                    return None;
+                }
+                let first = u16::from_le((base.content() as *const u16).read());
+                (size_of::<u16>(), first as usize)
+            } else {
+                let first = u32::from_le((base.content() as *const u32).read());
+                (size_of::<u32>(), first as usize)
+            };
+            let prefix_size = if base.has_sort_prefixes() {
+                size_of::<u64>()
+            } else {
+                0
+            };
+            // The first entry follows the offsets and the sort prefixes.
+            if first == 0 || first % (offset_size + prefix_size) != 0 || first > content_size {
+                return None;
+            }
+        }
+
+        let page = Self::new(base);
+        let mut last = 0;
+        for i in 0..page.len() {
+            let offset = page.offsets.get(i).unwrap() as usize;
+            if offset < last || offset > content_size {
+                return None;
+            }
+            last = offset;
+        }
+        for i in 0..page.len() {
+            let entry = page.raw_entry(i).unwrap();
+            let mut buf = BufReader::with_limit(entry.as_ptr(), entry.len());
+            let key = K::decode_from(&mut buf);
+            V::decode_from(&mut buf);
+            if buf.is_corrupted() || buf.pos() != entry.len() {
+                return None;
+            }
+            if !page.prefixes.is_null() && key.sort_prefix() != Some(page.sort_prefix(i)) {
+                return None;
+            }
+        }
+        Some(page)
+    }
+
     /// Returns the number of entries in the page.
     pub fn len(&self) -> usize {
         self.offsets.len()
@@ -168,7 +358,7 @@ where
 
     /// Returns the entry at the give position.
     pub fn get(&self, index: usize) -> Option<(K, V)> {
-        if let Some(&offset) = self.offsets.get(index) {
+        if let Some(offset) = self.offsets.get(index) {
             unsafe {
                 let ptr = self.content_at(offset);
                 let mut buf = BufReader::new(ptr);
@@ -181,17 +371,32 @@ where
         }
     }
 
-    /// Returns the first entry that is no less than `target`.
-    pub fn seek(&self, target: &K) -> Option<(K, V)> {
-        self.get(self.rank(target))
+    /// Returns the encoded bytes of the entry at the given position.
+    pub(super) fn raw_entry(&self, index: usize) -> Option<&'a [u8]> {
+        let start = self.offsets.get(index)? as usize;
+        let end = match self.offsets.get(index + 1) {
+            Some(end) => end as usize,
+            None => self.base.content_size() as usize,
+        };
+        unsafe {
+            Some(slice::from_raw_parts(
+                self.content_at(start as u32),
+                end - start,
+            ))
+        }
     }
 
-    /// Returns the first entry that is no greater than `target`.
-    pub fn seek_back(&self, target: &K) -> Option<(K, V)> {
-        let index = self.rank(target);
+    /// Returns the first entry that is no less than `target` in the order of `cmp`.
+    pub fn seek(&self, target: &K, cmp: &dyn Comparator) -> Option<(K, V)> {
+        self.get(self.rank(target, cmp))
+    }
+
+    /// Returns the first entry that is no greater than `target` in the order of `cmp`.
+    pub fn seek_back(&self, target: &K, cmp: &dyn Comparator) -> Option<(K, V)> {
+        let index = self.rank(target, cmp);
         for i in (0..=index).rev() {
             if let Some((key, value)) = self.get(i) {
-                if &key <= target {
+                if key.compare_with(target, cmp) != Ordering::Greater {
                     return Some((key, value));
                 }
             }
@@ -199,22 +404,35 @@ where
         None
     }
 
+    /// Returns the first entry that is greater than `target` in the order of `cmp`.
+    pub fn seek_next(&self, target: &K, cmp: &dyn Comparator) -> Option<(K, V)> {
+        let index = self.rank(target, cmp);
+        match self.get(index) {
+            Some((key, _)) if key.compare_with(target, cmp) == Ordering::Equal => {
+                self.get(index + 1)
+            }
+            next => next,
+        }
+    }
+
     /// Returns an iterator over the entries in the page.
     pub fn iter(&self) -> DataPageIter<'a, K, V> {
         DataPageIter::new(self.clone())
     }
 
-    fn rank(&self, target: &K) -> usize {
-        let mut left = 0;
-        let mut right = self.len();
+    fn rank(&self, target: &K, cmp: &dyn Comparator) -> usize {
+        let (mut left, mut right) = match target.sort_prefix() {
+            Some(prefix) if !self.prefixes.is_null() => self.prefix_range(prefix),
+            _ => (0, self.len()),
+        };
         while left < right {
             let mid = (left + right) / 2;
             let key = unsafe {
-                let ptr = self.content_at(self.offsets[mid]);
+                let ptr = self.content_at(self.offsets.get(mid).unwrap());
                 let mut buf = BufReader::new(ptr);
                 K::decode_from(&mut buf)
             };
-            match key.cmp(target) {
+            match key.compare_with(target, cmp) {
                 Ordering::Less => left = mid + 1,
                 Ordering::Greater => right = mid,
                 Ordering::Equal => return mid,
@@ -223,9 +441,50 @@ where
         left
     }
 
+    fn sort_prefix(&self, index: usize) -> u64 {
+        unsafe { u64::from_le(self.prefixes.add(index).read_unaligned()) }
+    }
+
+    /// Returns the range of entries whose sort prefixes equal `prefix`.
+    ///
+    /// Keys before the range are smaller than any key with `prefix`, and keys after the range are
+    /// larger, so the rank of a key with `prefix` is within the range, inclusive.
+    fn prefix_range(&self, prefix: u64) -> (usize, usize) {
+        // Finds the first entry with a prefix no less than `prefix`.
+        let mut left = 0;
+        let mut right = self.len();
+        while right - left > PREFIX_SCAN_THRESHOLD {
+            let mid = (left + right) / 2;
+            if self.sort_prefix(mid) < prefix {
+                left = mid + 1;
+            } else {
+                right = mid;
+            }
+        }
+        let start = left
+            + (left..right)
+                .map(|i| (self.sort_prefix(i) < prefix) as usize)
+                .sum::<usize>();
+        // Finds the first entry with a prefix greater than `prefix`.
+        let mut left = start;
+        let mut right = self.len();
+        while right - left > PREFIX_SCAN_THRESHOLD {
+            let mid = (left + right) / 2;
+            if self.sort_prefix(mid) <= prefix {
+                left = mid + 1;
+            } else {
+                right = mid;
+            }
+        }
+        let end = left
+            + (left..right)
+                .map(|i| (self.sort_prefix(i) <= prefix) as usize)
+                .sum::<usize>();
+        (start, end)
+    }
+
     fn content_at(&self, offset: u32) -> *const u8 {
-        let offset = offset.to_le() as usize;
-        unsafe { self.base.content().add(offset) }
+        unsafe { self.base.content().add(offset as usize) }
     }
 }
 
@@ -234,6 +493,7 @@ impl<'a, K, V> Clone for DataPageRef<'a, K, V> {
         Self {
             base: self.base,
             offsets: self.offsets,
+            prefixes: self.prefixes,
             _mark: PhantomData,
         }
     }
@@ -269,7 +529,7 @@ where
 
 impl<'a, K, V> ForwardIter for DataPageIter<'a, K, V>
 where
-    K: Decodable + Ord,
+    K: Decodable + Comparable,
     V: Decodable,
 {
     type Key = K;
@@ -290,18 +550,18 @@ where
 
 impl<'a, K, V> SeekableIter for DataPageIter<'a, K, V>
 where
-    K: Decodable + Ord,
+    K: Decodable + Comparable,
     V: Decodable,
 {
-    fn seek(&mut self, target: &K) {
-        self.next = self.page.rank(target);
+    fn seek(&mut self, target: &K, cmp: &dyn Comparator) {
+        self.next = self.page.rank(target, cmp);
         self.last = None;
     }
 }
 
 impl<'a, K, V> RewindableIter for DataPageIter<'a, K, V>
 where
-    K: Decodable + Ord,
+    K: Decodable + Comparable,
     V: Decodable,
 {
     fn rewind(&mut self) {
@@ -310,9 +570,25 @@ where
     }
 }
 
+impl<'a, K, V> IntoIterator for DataPageIter<'a, K, V>
+where
+    K: Decodable + Comparable + Clone,
+    V: Decodable + Clone,
+{
+    type Item = (K, V);
+    type IntoIter = StdIter<Self>;
+
+    fn into_iter(self) -> StdIter<Self> {
+        StdIter::new(self)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::{base::test::ALLOC, *};
+    use crate::tree::BytewiseComparator;
+
+    const CMP: &BytewiseComparator = &BytewiseComparator;
 
     #[test]
     fn data_page() {
@@ -326,12 +602,15 @@ mod test {
         assert_eq!(page.kind(), PageKind::Data);
         assert_eq!(page.is_index(), false);
 
-        assert_eq!(page.seek(&0), Some((1, 0)));
-        assert_eq!(page.seek_back(&0), None);
-        assert_eq!(page.seek(&3), Some((4, 0)));
-        assert_eq!(page.seek_back(&3), Some((2, 0)));
-        assert_eq!(page.seek(&9), None);
-        assert_eq!(page.seek_back(&9), Some((8, 0)));
+        assert_eq!(page.seek(&0, CMP), Some((1, 0)));
+        assert_eq!(page.seek_back(&0, CMP), None);
+        assert_eq!(page.seek(&3, CMP), Some((4, 0)));
+        assert_eq!(page.seek_back(&3, CMP), Some((2, 0)));
+        assert_eq!(page.seek(&9, CMP), None);
+        assert_eq!(page.seek_back(&9, CMP), Some((8, 0)));
+        assert_eq!(page.seek_next(&0, CMP), Some((1, 0)));
+        assert_eq!(page.seek_next(&4, CMP), Some((7, 0)));
+        assert_eq!(page.seek_next(&8, CMP), None);
 
         let mut iter = page.iter();
         assert_eq!(iter.last(), None);
@@ -342,5 +621,112 @@ mod test {
             }
             iter.rewind();
         }
+        let entries: Vec<_> = page.iter().into_iter().take(3).collect();
+        assert_eq!(entries, data[..3]);
+    }
+
+    #[test]
+    fn offsets() {
+        // Small pages have two-byte offsets.
+        let data: Vec<_> = (0..16u64).map(|i| (i, i)).collect();
+        let mut iter = SliceIter::from(data.as_slice());
+        let page = DataPageBuilder::default()
+            .build_from_iter(&ALLOC, &mut iter)
+            .unwrap();
+        assert_eq!(page.content_size(), 16 * (2 + 8 + 16));
+
+        // Large pages have four-byte offsets.
+        let data: Vec<_> = (0..8192u64).map(|i| (i, i)).collect();
+        let mut iter = SliceIter::from(data.as_slice());
+        let page = DataPageBuilder::default()
+            .build_from_iter(&ALLOC, &mut iter)
+            .unwrap();
+        assert_eq!(page.content_size(), 8192 * (4 + 8 + 16));
+        let page = page.as_ref::<u64, u64>();
+        assert_eq!(page.len(), data.len());
+        for (i, item) in data.iter().enumerate() {
+            assert_eq!(page.get(i).as_ref(), Some(item));
+            assert_eq!(page.seek(&item.0, CMP).as_ref(), Some(item));
+        }
+
+        // Empty pages have no offsets.
+        let page = DataPageBuilder::default().build(&ALLOC).unwrap();
+        assert_eq!(page.as_ref::<u64, u64>().len(), 0);
+    }
+
+    #[test]
+    fn sort_prefixes() {
+        // Keys with shared and distinct prefixes, and keys shorter than a prefix.
+        let mut keys: Vec<Vec<u8>> = vec![b"".to_vec(), b"a".to_vec(), b"a\0".to_vec()];
+        for i in 0..64u32 {
+            keys.push(format!("https://example.com/{:04}", i).into_bytes());
+            keys.push(format!("https://{:04}.com", i).into_bytes());
+        }
+        keys.sort();
+        keys.dedup();
+        let data: Vec<_> = keys
+            .iter()
+            .enumerate()
+            .map(|(i, k)| (Key::new(k, i as u64), i as u64))
+            .collect();
+        let mut iter = SliceIter::from(data.as_slice());
+        let page = DataPageBuilder::default()
+            .build_from_iter(&ALLOC, &mut iter)
+            .unwrap();
+        assert!(page.has_sort_prefixes());
+        let page = page.as_ref::<Key, u64>();
+        for (i, &(key, value)) in data.iter().enumerate() {
+            assert_eq!(page.rank(&key, CMP), i);
+            let (k, v) = page.seek(&key, CMP).unwrap();
+            assert_eq!((k.raw, v), (key.raw, value));
+            // A larger LSN sorts before the key, and a smaller one after.
+            assert_eq!(page.rank(&Key::new(key.raw, key.lsn + 1), CMP), i);
+            assert_eq!(
+                page.rank(&Key::new(key.raw, 0), CMP),
+                i + (key.lsn > 0) as usize
+            );
+        }
+        assert_eq!(
+            page.rank(&Key::new(b"https://example.com/", 0), CMP),
+            3 + 64
+        );
+        assert_eq!(page.rank(&Key::new(b"z", 0), CMP), data.len());
+    }
+
+    struct Reverse;
+
+    impl Comparator for Reverse {
+        fn name(&self) -> String {
+            "test.Reverse".to_owned()
+        }
+
+        fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+            b.cmp(a)
+        }
+    }
+
+    #[test]
+    fn comparator() {
+        let keys: Vec<_> = (0..64u32).rev().map(|i| i.to_be_bytes()).collect();
+        let data: Vec<_> = keys.iter().map(|k| (k.as_slice(), 0u64)).collect();
+        let mut iter = SliceIter::from(data.as_slice());
+        let page = DataPageBuilder::default()
+            .sort_prefixes(false)
+            .build_from_iter(&ALLOC, &mut iter)
+            .unwrap();
+        let page = page.as_ref::<&[u8], u64>();
+        assert!(!page.has_sort_prefixes());
+        for (i, &(key, _)) in data.iter().enumerate() {
+            assert_eq!(page.rank(&key, &Reverse), i);
+        }
+        let key = 10u32.to_be_bytes();
+        let next = 9u32.to_be_bytes();
+        assert_eq!(
+            page.seek_next(&key.as_slice(), &Reverse),
+            Some((next.as_slice(), 0))
+        );
+        // The empty key is the smallest one under any order.
+        assert_eq!(page.seek_back(&[].as_slice(), &Reverse), None);
+        assert_eq!(page.seek(&[].as_slice(), &Reverse), page.get(0));
     }
 }
@@ -0,0 +1,327 @@
+use std::{mem::size_of, slice};
+
+use super::{base::PAGE_HEADER_SIZE, *};
+
+/// The size of the page header in images, which leaves out the chain size at the end of the page
+/// header, since chains are rebuilt when pages are loaded. The next page is kept, which is the
+/// disk address of the page below a delta, or null.
+const IMAGE_HEADER_SIZE: usize = 20;
+
+/// Encodes a data page into an image to store on disk.
+///
+/// With a positive `restart_interval`, keys are delta encoded: every `restart_interval` entries
+/// start with a full key, and the keys in between only store the bytes that differ from the
+/// previous key. This saves much space for keys with long common prefixes, at the cost of
+/// rebuilding the page when it is loaded. With zero `restart_interval`, the image is the page as
+/// is.
+///
+/// The image is laid out as:
+///
+/// `restart_interval (4B) | header | content`, if `restart_interval` is zero, or
+///
+/// `restart_interval (4B) | header | num_entries (4B) | entries`, where an entry is
+/// `shared (4B) | unshared (4B) | key suffix | rest_size (4B) | rest`, and the rest is the part of
+/// the entry after the key.
+///
+/// Keys are the length-prefixed slices at the start of entries, which is the case for all
+/// entries of the tree.
+pub fn encode_page_image(page: PagePtr, restart_interval: u32) -> Vec<u8> {
+    assert_eq!(page.kind(), PageKind::Data);
+    let page_size = page.size();
+    let raw = unsafe { slice::from_raw_parts(page.as_raw(), page_size) };
+    let mut buf = Vec::with_capacity(4 + page_size);
+    buf.extend_from_slice(&restart_interval.to_le_bytes());
+    buf.extend_from_slice(&raw[..IMAGE_HEADER_SIZE]);
+    if restart_interval == 0 {
+        buf.extend_from_slice(&raw[PAGE_HEADER_SIZE..]);
+        return buf;
+    }
+
+    let page = unsafe { DataPageRef::<&[u8], &[u8]>::new(page) };
+    buf.extend_from_slice(&(page.len() as u32).to_le_bytes());
+    let mut last_key: &[u8] = &[];
+    for i in 0..page.len() {
+        let entry = page.raw_entry(i).unwrap();
+        let (key, rest) = split_entry(entry).expect("entry starts with a key");
+        let shared = if i % restart_interval as usize == 0 {
+            0
+        } else {
+            shared_prefix_len(last_key, key)
+        };
+        let unshared = &key[shared..];
+        buf.extend_from_slice(&(shared as u32).to_le_bytes());
+        buf.extend_from_slice(&(unshared.len() as u32).to_le_bytes());
+        buf.extend_from_slice(unshared);
+        buf.extend_from_slice(&(rest.len() as u32).to_le_bytes());
+        buf.extend_from_slice(rest);
+        last_key = key;
+    }
+    buf
+}
+
+/// Decodes a page image into a page allocated from `alloc`.
+///
+/// Returns `Ok(None)` if the image is malformed. The entries of the page are decoded with bounds
+/// checks, so that a decoded page is safe to read unchecked, whatever the image is.
+pub fn decode_page_image<A>(image: &[u8], alloc: &A) -> Result<Option<PagePtr>, A::Error>
+where
+    A: PageAlloc,
+{
+    let mut decoder = Decoder(image);
+    let restart_interval = match decoder.get_u32() {
+        Some(interval) => interval,
+        None => return Ok(None),
+    };
+    // Copies the header to an aligned buffer to read it.
+    let mut header_buf = [0u64; (PAGE_HEADER_SIZE + 7) / size_of::<u64>()];
+    let header = match decoder.0.get(..IMAGE_HEADER_SIZE) {
+        Some(header) => unsafe {
+            let ptr = header_buf.as_mut_ptr() as *mut u8;
+            ptr.copy_from_nonoverlapping(header.as_ptr(), IMAGE_HEADER_SIZE);
+            PagePtr::new(ptr).unwrap()
+        },
+        None => return Ok(None),
+    };
+    if restart_interval == 0 {
+        let raw = decoder.0;
+        if IMAGE_HEADER_SIZE + header.content_size() as usize != raw.len() {
+            return Ok(None);
+        }
+        let mut page = alloc.alloc(header.size())?;
+        unsafe {
+            page.as_raw()
+                .copy_from_nonoverlapping(raw.as_ptr(), IMAGE_HEADER_SIZE);
+            page.content_mut().copy_from_nonoverlapping(
+                raw[IMAGE_HEADER_SIZE..].as_ptr(),
+                raw.len() - IMAGE_HEADER_SIZE,
+            );
+        }
+        page.set_chain_size(page.size().min(u32::MAX as usize) as u32);
+        return Ok(check_page(page, alloc));
+    }
+    if header.try_kind() != Some(PageKind::Data) {
+        return Ok(None);
+    }
+
+    decoder.get_bytes(IMAGE_HEADER_SIZE);
+    let entries = match decode_entries(&mut decoder) {
+        Some(entries) if decoder.0.is_empty() => entries,
+        _ => return Ok(None),
+    };
+    let entries: Vec<_> = entries
+        .into_iter()
+        .map(|(key, rest)| {
+            let key = ImageKey {
+                raw: key,
+                sort_prefix: header.has_sort_prefixes(),
+            };
+            (key, ImageRest(rest))
+        })
+        .collect();
+    let mut iter = SliceIter::from(entries.as_slice());
+    let mut page = DataPageBuilder::default().build_from_iter(alloc, &mut iter)?;
+    page.set_ver(header.ver());
+    page.set_len(header.len());
+    page.set_next(header.next());
+    page.set_index(header.is_index());
+    Ok(check_page(page.as_ptr(), alloc))
+}
+
+/// Returns the page if it is well-formed, or deallocates it otherwise.
+fn check_page<A: PageAlloc>(page: PagePtr, alloc: &A) -> Option<PagePtr> {
+    let valid = unsafe {
+        if page.is_index() {
+            DataPageRef::<&[u8], Index>::new_checked(page).is_some()
+        } else {
+            DataPageRef::<Key, Value>::new_checked(page).is_some()
+        }
+    };
+    if valid {
+        Some(page)
+    } else {
+        unsafe { alloc.dealloc(page) };
+        None
+    }
+}
+
+fn decode_entries<'a>(decoder: &mut Decoder<'a>) -> Option<Vec<(Vec<u8>, &'a [u8])>> {
+    let num_entries = decoder.get_u32()? as usize;
+    let mut entries: Vec<(Vec<u8>, &[u8])> = Vec::with_capacity(num_entries.min(decoder.0.len()));
+    for _ in 0..num_entries {
+        let shared = decoder.get_u32()? as usize;
+        let unshared = decoder.get_u32()? as usize;
+        let suffix = decoder.get_bytes(unshared)?;
+        let mut key = match entries.last() {
+            Some((last, _)) if shared <= last.len() => last[..shared].to_vec(),
+            None if shared == 0 => Vec::new(),
+            _ => return None,
+        };
+        key.extend_from_slice(suffix);
+        let rest_size = decoder.get_u32()? as usize;
+        let rest = decoder.get_bytes(rest_size)?;
+        entries.push((key, rest));
+    }
+    Some(entries)
+}
+
+/// Splits an entry into its key and the rest.
+fn split_entry(entry: &[u8]) -> Option<(&[u8], &[u8])> {
+    let mut decoder = Decoder(entry);
+    let len = decoder.get_u32()? as usize;
+    let key = decoder.get_bytes(len)?;
+    Some((key, decoder.0))
+}
+
+fn shared_prefix_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
+
+struct Decoder<'a>(&'a [u8]);
+
+impl<'a> Decoder<'a> {
+    fn get_bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (bytes, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(bytes)
+    }
+
+    fn get_u32(&mut self) -> Option<u32> {
+        self.get_bytes(4)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+    }
+}
+
+/// A key decoded from an image, which is encoded as a length-prefixed slice.
+struct ImageKey {
+    raw: Vec<u8>,
+    sort_prefix: bool,
+}
+
+impl Encodable for ImageKey {
+    fn encode_size(&self) -> usize {
+        BufWriter::length_prefixed_slice_size(&self.raw)
+    }
+
+    unsafe fn encode_to(&self, w: &mut BufWriter) {
+        w.put_length_prefixed_slice(&self.raw);
+    }
+}
+
+impl Decodable for ImageKey {
+    unsafe fn decode_from(r: &mut BufReader) -> Self {
+        Self {
+            raw: r.get_length_prefixed_slice().to_vec(),
+            sort_prefix: true,
+        }
+    }
+
+    fn sort_prefix(&self) -> Option<u64> {
+        if self.sort_prefix {
+            self.raw.as_slice().sort_prefix()
+        } else {
+            None
+        }
+    }
+}
+
+/// The rest of an entry decoded from an image, which is encoded as is.
+struct ImageRest<'a>(&'a [u8]);
+
+impl Encodable for ImageRest<'_> {
+    fn encode_size(&self) -> usize {
+        self.0.len()
+    }
+
+    unsafe fn encode_to(&self, w: &mut BufWriter) {
+        w.put_slice(self.0);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{base::test::ALLOC, *};
+
+    fn page_bytes(page: PagePtr) -> &'static [u8] {
+        unsafe { slice::from_raw_parts(page.as_raw(), page.size()) }
+    }
+
+    fn leaf_and_index_pages() -> [PagePtr; 2] {
+        let keys: Vec<Vec<u8>> = (0..100u32)
+            .map(|i| format!("https://example.com/a/rather/long/path/to/{:04}", i).into_bytes())
+            .collect();
+        let data: Vec<_> = keys
+            .iter()
+            .enumerate()
+            .map(|(i, k)| (Key::new(k, i as u64), Value::Put(b"value")))
+            .collect();
+        let mut iter = SliceIter::from(data.as_slice());
+        let mut leaf = DataPageBuilder::default()
+            .build_from_iter(&ALLOC, &mut iter)
+            .unwrap();
+        leaf.set_ver(PageVer::new(3));
+        leaf.set_len(1);
+
+        let data: Vec<_> = keys
+            .iter()
+            .enumerate()
+            .map(|(i, k)| (k.as_slice(), Index::new(i as u64, PageVer::new(1))))
+            .collect();
+        let mut iter = SliceIter::from(data.as_slice());
+        let mut index = DataPageBuilder::default()
+            .build_from_iter(&ALLOC, &mut iter)
+            .unwrap();
+        index.set_index(true);
+        [leaf.as_ptr(), index.as_ptr()]
+    }
+
+    #[test]
+    fn encode_and_decode() {
+        for page in leaf_and_index_pages() {
+            let raw = encode_page_image(page, 0);
+            let full = encode_page_image(page, 1);
+            let delta = encode_page_image(page, 16);
+            assert!(delta.len() * 2 < full.len());
+            for image in [raw, full, delta] {
+                let decoded = decode_page_image(&image, &ALLOC).unwrap().unwrap();
+                assert_eq!(page_bytes(decoded), page_bytes(page));
+                for len in 0..image.len() {
+                    assert!(decode_page_image(&image[..len], &ALLOC).unwrap().is_none());
+                }
+            }
+        }
+
+        let empty = DataPageBuilder::default().build(&ALLOC).unwrap().as_ptr();
+        let image = encode_page_image(empty, 16);
+        let decoded = decode_page_image(&image, &ALLOC).unwrap().unwrap();
+        assert_eq!(page_bytes(decoded), page_bytes(empty));
+    }
+
+    #[test]
+    fn malformed_images() {
+        // Images with any byte changed either fail to decode or decode to readable pages.
+        for page in leaf_and_index_pages() {
+            for restart_interval in [0, 16] {
+                let image = encode_page_image(page, restart_interval);
+                // Miri is too slow to check every byte.
+                for i in (0..image.len()).step_by(if cfg!(miri) { 13 } else { 1 }) {
+                    for byte in [0, 1, 0x80, 0xFF] {
+                        let mut image = image.clone();
+                        image[i] ^= byte;
+                        if let Some(decoded) = decode_page_image(&image, &ALLOC).unwrap() {
+                            if decoded.is_index() {
+                                let page = unsafe { DataPageRef::<&[u8], Index>::new(decoded) };
+                                assert!((0..page.len()).all(|i| page.get(i).is_some()));
+                            } else {
+                                let page = unsafe { DataPageRef::<Key, Value>::new(decoded) };
+                                assert!((0..page.len()).all(|i| page.get(i).is_some()));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
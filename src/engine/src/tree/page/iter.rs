@@ -1,11 +1,14 @@
 use std::{
-    cmp::{Ord, Ordering},
+    cmp::Ordering,
     collections::BinaryHeap,
     fmt::Debug,
     ops::{Deref, DerefMut},
     slice,
+    sync::Arc,
 };
 
+use super::{Comparable, Comparator};
+
 pub trait ForwardIter {
     type Key;
     type Value;
@@ -18,8 +21,8 @@ pub trait ForwardIter {
 }
 
 pub trait SeekableIter: ForwardIter {
-    /// Positions the next entry at or after the target.
-    fn seek(&mut self, target: &Self::Key);
+    /// Positions the next entry at or after the target in the order of `cmp`.
+    fn seek(&mut self, target: &Self::Key, cmp: &dyn Comparator);
 }
 
 pub trait RewindableIter: ForwardIter {
@@ -44,6 +47,29 @@ where
     }
 }
 
+/// A wrapper that turns a `ForwardIter` into an `Iterator` of owned entries, so that it can be
+/// used with the adapters of `Iterator`, like `collect`, `zip`, and `take`.
+pub struct StdIter<I>(I);
+
+impl<I> StdIter<I> {
+    pub fn new(iter: I) -> Self {
+        Self(iter)
+    }
+}
+
+impl<I> Iterator for StdIter<I>
+where
+    I: ForwardIter,
+    I::Key: Clone,
+    I::Value: Clone,
+{
+    type Item = (I::Key, I::Value);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().cloned()
+    }
+}
+
 /// A wrapper that turns a slice into a `SeekableIter` and `RewindableIter`.
 pub struct SliceIter<'a, K, V> {
     data: &'a [(K, V)],
@@ -77,10 +103,13 @@ impl<'a, K, V> ForwardIter for SliceIter<'a, K, V> {
 
 impl<'a, K, V> SeekableIter for SliceIter<'a, K, V>
 where
-    K: Ord,
+    K: Comparable,
 {
-    fn seek(&mut self, target: &K) {
-        let index = match self.data.binary_search_by(|(key, _)| key.cmp(target)) {
+    fn seek(&mut self, target: &K, cmp: &dyn Comparator) {
+        let index = match self
+            .data
+            .binary_search_by(|(key, _)| key.compare_with(target, cmp))
+        {
             Ok(i) => i,
             Err(i) => i,
         };
@@ -159,33 +188,40 @@ impl<K, V> From<Option<(K, V)>> for OptionIter<K, V> {
 }
 
 /// A wrapper to sorts iterators by their last entries in reverse order.
-struct ReverseIter<I>(I);
+///
+/// Iterators with equal entries are sorted by their ranks, so that the one with the lower rank
+/// comes first.
+struct ReverseIter<I> {
+    iter: I,
+    rank: usize,
+    cmp: Arc<dyn Comparator>,
+}
 
 impl<I> Deref for ReverseIter<I> {
     type Target = I;
 
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.iter
     }
 }
 
 impl<I> DerefMut for ReverseIter<I> {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+        &mut self.iter
     }
 }
 
 impl<I> Eq for ReverseIter<I>
 where
     I: ForwardIter,
-    I::Key: Ord,
+    I::Key: Comparable,
 {
 }
 
 impl<I> PartialEq for ReverseIter<I>
 where
     I: ForwardIter,
-    I::Key: Ord,
+    I::Key: Comparable,
 {
     fn eq(&self, other: &Self) -> bool {
         self.cmp(other) == Ordering::Equal
@@ -195,11 +231,14 @@ where
 impl<I> Ord for ReverseIter<I>
 where
     I: ForwardIter,
-    I::Key: Ord,
+    I::Key: Comparable,
 {
     fn cmp(&self, other: &Self) -> Ordering {
         match (self.last(), other.last()) {
-            (Some(a), Some(b)) => b.0.cmp(&a.0),
+            (Some(a), Some(b)) => {
+                b.0.compare_with(&a.0, self.cmp.as_ref())
+                    .then_with(|| other.rank.cmp(&self.rank))
+            }
             (Some(_), None) => Ordering::Greater,
             (None, Some(_)) => Ordering::Less,
             (None, None) => Ordering::Equal,
@@ -210,32 +249,51 @@ where
 impl<I> PartialOrd for ReverseIter<I>
 where
     I: ForwardIter,
-    I::Key: Ord,
+    I::Key: Comparable,
 {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         Some(self.cmp(other))
     }
 }
 
-/// A iterator that merges entries from multiple iterators in ascending order.
+/// A iterator that merges entries from multiple iterators in the ascending order of a
+/// `Comparator`.
+///
+/// Equal entries from different iterators are returned in the order of the ranks of the
+/// iterators, from the lowest to the highest, or only the one from the iterator with the lowest
+/// rank if the iterator is built with `MergingIterBuilder::dedup`. Consolidations rely on this to
+/// keep the newest of equal entries, so chains rank their newer pages lower.
 pub struct MergingIter<I>
 where
     I: ForwardIter,
-    I::Key: Ord,
+    I::Key: Comparable,
 {
     heap: BinaryHeap<ReverseIter<I>>,
     children: Vec<ReverseIter<I>>,
+    dedup: bool,
 }
 
 impl<I> MergingIter<I>
 where
     I: ForwardIter,
-    I::Key: Ord,
+    I::Key: Comparable,
 {
-    fn new(children: Vec<ReverseIter<I>>) -> Self {
+    fn new(children: Vec<ReverseIter<I>>, dedup: bool) -> Self {
         Self {
             heap: BinaryHeap::default(),
             children,
+            dedup,
+        }
+    }
+
+    /// Advances the iterators whose last entries are equal to `key`.
+    fn skip_equal(&mut self, key: &I::Key) {
+        while let Some(mut iter) = self.heap.peek_mut() {
+            let equal = matches!(iter.last(), Some((k, _)) if k.compare_with(key, iter.cmp.as_ref()).is_eq());
+            if !equal {
+                break;
+            }
+            iter.next();
         }
     }
 
@@ -271,7 +329,7 @@ where
 impl<I> ForwardIter for MergingIter<I>
 where
     I: ForwardIter,
-    I::Key: Ord,
+    I::Key: Comparable,
 {
     type Key = I::Key;
     type Value = I::Value;
@@ -282,6 +340,11 @@ where
 
     fn next(&mut self) -> Option<&(Self::Key, Self::Value)> {
         if let Some(mut iter) = self.heap.pop() {
+            if self.dedup {
+                if let Some((key, _)) = iter.last() {
+                    self.skip_equal(key);
+                }
+            }
             iter.next();
             self.heap.push(iter);
         } else {
@@ -294,53 +357,88 @@ where
 impl<I> SeekableIter for MergingIter<I>
 where
     I: SeekableIter,
-    I::Key: Ord,
+    I::Key: Comparable,
 {
-    fn seek(&mut self, target: &Self::Key) {
-        self.reset(|iter| iter.seek(target));
+    fn seek(&mut self, target: &Self::Key, cmp: &dyn Comparator) {
+        self.reset(|iter| iter.seek(target, cmp));
     }
 }
 
 impl<I> RewindableIter for MergingIter<I>
 where
     I: RewindableIter,
-    I::Key: Ord,
+    I::Key: Comparable,
 {
     fn rewind(&mut self) {
         self.reset(|iter| iter.rewind());
     }
 }
 
+impl<I> IntoIterator for MergingIter<I>
+where
+    I: ForwardIter,
+    I::Key: Comparable + Clone,
+    I::Value: Clone,
+{
+    type Item = (I::Key, I::Value);
+    type IntoIter = StdIter<Self>;
+
+    fn into_iter(self) -> StdIter<Self> {
+        StdIter::new(self)
+    }
+}
+
 /// A builder to create `MergingIter`.
 pub struct MergingIterBuilder<I> {
     children: Vec<ReverseIter<I>>,
+    cmp: Arc<dyn Comparator>,
+    dedup: bool,
 }
 
-impl<I> Default for MergingIterBuilder<I> {
-    fn default() -> Self {
+impl<I> MergingIterBuilder<I>
+where
+    I: ForwardIter,
+    I::Key: Comparable,
+{
+    pub fn new(cmp: Arc<dyn Comparator>) -> Self {
         Self {
             children: Vec::new(),
+            cmp,
+            dedup: false,
         }
     }
-}
 
-impl<I> MergingIterBuilder<I>
-where
-    I: ForwardIter,
-    I::Key: Ord,
-{
+    /// Returns only the entry with the lowest rank of equal entries.
+    pub fn dedup(mut self, dedup: bool) -> Self {
+        self.dedup = dedup;
+        self
+    }
+
+    /// Adds a child, which is ranked by the number of children added before it.
     pub fn add(&mut self, child: I) {
-        self.children.push(ReverseIter(child));
+        let rank = self.children.len();
+        self.add_with_rank(child, rank);
+    }
+
+    /// Adds a child with an explicit rank, which should be different from the ranks of the other
+    /// children, or the order of their equal entries is unspecified.
+    pub fn add_with_rank(&mut self, child: I, rank: usize) {
+        self.children.push(ReverseIter {
+            iter: child,
+            rank,
+            cmp: self.cmp.clone(),
+        });
     }
 
     pub fn build(self) -> MergingIter<I> {
-        MergingIter::new(self.children)
+        MergingIter::new(self.children, self.dedup)
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::tree::BytewiseComparator;
 
     #[test]
     fn slice_iter() {
@@ -387,7 +485,7 @@ mod test {
             (8, 0),
         ];
 
-        let mut merger = MergingIterBuilder::default();
+        let mut merger = MergingIterBuilder::new(Arc::new(BytewiseComparator));
         for item in data.iter() {
             merger.add(SliceIter::from(item));
         }
@@ -405,15 +503,63 @@ mod test {
         }
 
         // Tests seek()
-        iter.seek(&0);
+        iter.seek(&0, &BytewiseComparator);
         assert_eq!(iter.next(), Some(&(1, 0)));
-        iter.seek(&9);
+        iter.seek(&9, &BytewiseComparator);
         assert_eq!(iter.next(), None);
-        iter.seek(&1);
+        iter.seek(&1, &BytewiseComparator);
         assert_eq!(iter.next(), Some(&(1, 0)));
-        iter.seek(&3);
+        iter.seek(&3, &BytewiseComparator);
         assert_eq!(iter.next(), Some(&(3, 0)));
-        iter.seek(&5);
+        iter.seek(&5, &BytewiseComparator);
         assert_eq!(iter.next(), Some(&(7, 0)));
     }
+
+    #[test]
+    fn merging_iter_with_equal_keys() {
+        let data = [[(1, 1), (2, 1)], [(1, 2), (3, 2)], [(1, 3), (2, 3)]];
+        let sorted_data = [(1, 1), (1, 2), (1, 3), (2, 1), (2, 3), (3, 2)];
+
+        let mut merger = MergingIterBuilder::new(Arc::new(BytewiseComparator));
+        for item in data.iter() {
+            merger.add(SliceIter::from(item));
+        }
+        let mut iter = merger.build();
+        for item in sorted_data.iter() {
+            assert_eq!(iter.next(), Some(item));
+        }
+        assert_eq!(iter.next(), None);
+
+        // Tests the adapters of `Iterator`.
+        iter.rewind();
+        let entries: Vec<_> = iter.into_iter().collect();
+        assert_eq!(entries, sorted_data);
+    }
+
+    #[test]
+    fn merging_iter_with_ranks() {
+        let data = [[(1, 1), (2, 1)], [(1, 2), (3, 2)], [(1, 3), (2, 3)]];
+
+        // Equal entries follow the ranks, not the order that the children are added.
+        let mut merger = MergingIterBuilder::new(Arc::new(BytewiseComparator));
+        for (item, rank) in data.iter().zip([2, 0, 1]) {
+            merger.add_with_rank(SliceIter::from(item), rank);
+        }
+        let entries: Vec<_> = merger.build().into_iter().collect();
+        assert_eq!(entries, [(1, 2), (1, 3), (1, 1), (2, 3), (2, 1), (3, 2)]);
+
+        let mut merger = MergingIterBuilder::new(Arc::new(BytewiseComparator)).dedup(true);
+        for (item, rank) in data.iter().zip([2, 0, 1]) {
+            merger.add_with_rank(SliceIter::from(item), rank);
+        }
+        let mut iter = merger.build();
+        for _ in 0..2 {
+            let mut entries = Vec::new();
+            while let Some(&entry) = iter.next() {
+                entries.push(entry);
+            }
+            assert_eq!(entries, [(1, 2), (2, 3), (3, 2)]);
+            iter.rewind();
+        }
+    }
 }
@@ -1,20 +1,39 @@
+//! Pages and their encodings.
+//!
+//! Pages are read and written through raw pointers, so the tests of this module also run under
+//! Miri:
+//!
+//! ```text
+//! MIRIFLAGS="-Zmiri-ignore-leaks" cargo miri test -p photondb-engine --no-default-features --lib tree::page
+//! ```
+//!
+//! Without default features, test pages are allocated by the system allocator, since Miri can't
+//! call into jemalloc. The tests don't free their pages, so leaks are ignored.
+
+use super::Comparator;
+
 mod base;
+#[cfg(test)]
+pub use base::reserved;
 pub use base::{PageAlloc, PageBuilder, PageKind, PagePtr, PageVer};
 
 mod iter;
 pub use iter::{
     ForwardIter, MergingIter, MergingIterBuilder, OptionIter, PrintableIter, RewindableIter,
-    SeekableIter, SliceIter,
+    SeekableIter, SliceIter, StdIter,
 };
 
 mod util;
 use util::{BufReader, BufWriter};
 
 mod data;
-pub use data::{Decodable, Encodable, Index, Key, Value};
+pub use data::{Comparable, Decodable, Encodable, Index, Key, RawKey, Value};
 
 mod data_page;
-pub use data_page::{DataPageBuilder, DataPageIter, DataPageRef};
+pub use data_page::{DataPageBuf, DataPageBuilder, DataPageIter, DataPageRef};
+
+mod image;
+pub use image::{decode_page_image, encode_page_image};
 
 mod split_page;
 pub use split_page::{SplitPageBuilder, SplitPageRef};
@@ -1,14 +1,16 @@
-use super::{DataPageRef, Decodable, PageKind, PagePtr, SplitPageRef};
+use super::{Comparable, DataPageRef, Decodable, PageKind, PagePtr, SplitPageRef};
 
 /// A page reference with a specific type.
 pub enum TypedPageRef<'a, K, V> {
     Data(DataPageRef<'a, K, V>),
     Split(SplitPageRef<'a>),
+    /// A page of a kind that this version doesn't know, whose kind is `PagePtr::raw_kind`.
+    Unknown,
 }
 
 impl<'a, K, V> TypedPageRef<'a, K, V>
 where
-    K: Decodable + Ord,
+    K: Decodable + Comparable,
     V: Decodable,
 {
     /// Creates a typed reference from a `PagePtr`.
@@ -17,9 +19,10 @@ where
     ///
     /// This function is unsafe because it does not check that the page is of the correct type.
     pub unsafe fn cast(base: PagePtr) -> Self {
-        match base.kind() {
-            PageKind::Data => Self::Data(DataPageRef::new(base)),
-            PageKind::Split => Self::Split(SplitPageRef::new(base)),
+        match base.try_kind() {
+            Some(PageKind::Data) => Self::Data(DataPageRef::new(base)),
+            Some(PageKind::Split) => Self::Split(SplitPageRef::new(base)),
+            None => Self::Unknown,
         }
     }
 }
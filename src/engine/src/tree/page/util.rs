@@ -1,24 +1,44 @@
 use std::{mem::size_of, slice};
 
 /// An unsafe, little-endian buffer reader.
+///
+/// A reader created with `with_limit` checks bounds: reads beyond the limit return zeros or empty
+/// slices and mark the reader as corrupted, so that malformed data can be decoded without reading
+/// out of bounds.
 pub struct BufReader {
     ptr: *const u8,
     pos: usize,
+    /// The number of bytes that can be read, or `usize::MAX` if the reader is unchecked.
+    limit: usize,
+    corrupted: bool,
 }
 
 macro_rules! get_int {
     ($name:ident, $t:ty) => {
         pub unsafe fn $name(&mut self) -> $t {
+            if !self.check(size_of::<$t>()) {
+                return 0;
+            }
             let ptr = self.ptr.add(self.pos) as *const $t;
             self.pos += size_of::<$t>();
-            ptr.read().to_le()
+            <$t>::from_le(ptr.read_unaligned())
         }
     };
 }
 
 impl BufReader {
     pub const fn new(ptr: *const u8) -> Self {
-        Self { ptr, pos: 0 }
+        Self::with_limit(ptr, usize::MAX)
+    }
+
+    /// Creates a bounds-checked reader of the `limit` bytes at `ptr`.
+    pub const fn with_limit(ptr: *const u8, limit: usize) -> Self {
+        Self {
+            ptr,
+            pos: 0,
+            limit,
+            corrupted: false,
+        }
     }
 
     pub const fn pos(&self) -> usize {
@@ -29,11 +49,39 @@ impl BufReader {
         self.pos += n;
     }
 
+    /// Returns true if the reader has read beyond its limit or decoded invalid data.
+    pub const fn is_corrupted(&self) -> bool {
+        self.corrupted
+    }
+
+    /// Marks the data as corrupted.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the reader is unchecked, which must only read valid data.
+    pub fn set_corrupted(&mut self) {
+        assert!(self.limit != usize::MAX, "invalid data to decode");
+        self.corrupted = true;
+    }
+
+    /// Returns true if `n` more bytes can be read.
+    fn check(&mut self, n: usize) -> bool {
+        if n <= self.limit - self.pos {
+            return true;
+        }
+        self.pos = self.limit;
+        self.corrupted = true;
+        false
+    }
+
     get_int!(get_u8, u8);
     get_int!(get_u32, u32);
     get_int!(get_u64, u64);
 
     pub unsafe fn get_slice<'a>(&mut self, len: usize) -> &'a [u8] {
+        if !self.check(len) {
+            return &[];
+        }
         let ptr = self.ptr.add(self.pos);
         self.pos += len;
         slice::from_raw_parts(ptr, len)
@@ -54,8 +102,9 @@ pub struct BufWriter {
 macro_rules! put_int {
     ($name:ident, $t:ty) => {
         pub unsafe fn $name(&mut self, v: $t) {
+            // Entries are packed, so integers in them are unaligned.
             let ptr = self.ptr.add(self.pos) as *mut $t;
-            ptr.write(v.to_le());
+            ptr.write_unaligned(v.to_le());
             self.pos += size_of::<$t>();
         }
     };
@@ -79,7 +128,7 @@ impl BufWriter {
     put_int!(put_u64, u64);
 
     pub unsafe fn put_slice(&mut self, slice: &[u8]) {
-        let ptr = self.ptr.add(self.pos) as *mut u8;
+        let ptr = self.ptr.add(self.pos);
         ptr.copy_from(slice.as_ptr(), slice.len());
         self.pos += slice.len();
     }
@@ -1,17 +1,19 @@
 use std::{
-    alloc::GlobalAlloc,
+    collections::{HashMap, HashSet, VecDeque},
+    fmt,
     sync::{
-        atomic::{AtomicUsize, Ordering},
-        Arc,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
     },
 };
 
-use jemallocator::{usable_size, Jemalloc};
-
 use super::{
+    malloc,
     page::{PageAlloc, PagePtr, PageVer},
     pagestore::PageInfo,
-    Error, Result,
+    shard::{FreeLists, ShardedCounter},
+    slab::SlabAlloc,
+    AllocStats, CacheStats, Error, EvictionPolicy, Result,
 };
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
@@ -41,6 +43,7 @@ impl From<PageAddr> for u64 {
     }
 }
 
+#[derive(Copy, Clone)]
 pub enum PageView {
     Mem(PagePtr),
     Disk(PageInfo, u64),
@@ -68,6 +71,14 @@ impl PageView {
         }
     }
 
+    /// Returns the size of the pages of the chain in memory, which is zero for a page on disk.
+    pub fn chain_size(&self) -> usize {
+        match self {
+            Self::Mem(page) => page.chain_size() as usize,
+            Self::Disk(..) => 0,
+        }
+    }
+
     pub fn as_addr(&self) -> PageAddr {
         match *self {
             Self::Mem(page) => PageAddr::Mem(page.into()),
@@ -82,35 +93,446 @@ impl From<PagePtr> for PageView {
     }
 }
 
+/// The kind of operations that allocate pages.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum AllocKind {
+    PutDelta = 0,
+    Consolidation = 1,
+    Split = 2,
+    SwapIn = 3,
+}
+
+/// The tiers of the cache that clean nodes are admitted to.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CacheTier {
+    /// Nodes in the hot tier are evicted by the eviction policy.
+    Hot,
+    /// Nodes in the cold tier are evicted first, in the order they are admitted, unless they are
+    /// accessed again, in which case they are promoted to the hot tier.
+    Cold,
+}
+
+/// A cache budget shared by the tables of a process, see `Options::shared_cache`.
+///
+/// Each table evicts its own nodes while the tables that share the budget are over it in total,
+/// so tables that stop operating keep their nodes until other tables make room for themselves.
 #[derive(Clone)]
+pub struct SharedCache {
+    inner: Arc<SharedCacheInner>,
+}
+
+struct SharedCacheInner {
+    capacity: usize,
+    size: ShardedCounter,
+    evicted_size: AtomicUsize,
+}
+
+impl SharedCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            inner: Arc::new(SharedCacheInner {
+                capacity,
+                size: ShardedCounter::default(),
+                evicted_size: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.inner.capacity
+    }
+
+    /// Returns the size of memory allocated by the tables that share the budget, excluding the
+    /// nodes that are evicted but not freed yet.
+    pub fn usage(&self) -> usize {
+        let inner = &self.inner;
+        inner
+            .size
+            .get()
+            .saturating_sub(inner.evicted_size.load(Ordering::Relaxed))
+    }
+}
+
+impl fmt::Debug for SharedCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedCache")
+            .field("capacity", &self.capacity())
+            .field("usage", &self.usage())
+            .finish()
+    }
+}
+
+/// The share of a cache in a `SharedCache`, which is given back when the cache and its clones are
+/// dropped, including the ones held by deferred frees.
+struct Share {
+    shared: SharedCache,
+    size: Arc<ShardedCounter>,
+    evicted_size: Arc<AtomicUsize>,
+}
+
+impl Drop for Share {
+    fn drop(&mut self) {
+        let inner = &self.shared.inner;
+        inner.size.sub(self.size.get());
+        let evicted_size = self.evicted_size.load(Ordering::Relaxed);
+        inner
+            .evicted_size
+            .fetch_sub(evicted_size, Ordering::Relaxed);
+    }
+}
+
+#[derive(Clone, Default)]
 pub struct PageCache {
-    size: Arc<AtomicUsize>,
+    // The counters that every allocation updates are sharded, so that concurrent writers don't
+    // contend on them.
+    size: Arc<ShardedCounter>,
+    alloc_bytes: Arc<[ShardedCounter; 4]>,
+    // The nodes that can be evicted, or `None` if eviction is disabled.
+    evictor: Option<Arc<Mutex<Evictor>>>,
+    // The size of the nodes that are evicted but not freed yet.
+    evicted_size: Arc<AtomicUsize>,
+    num_evictions: Arc<AtomicU64>,
+    num_promotions: Arc<AtomicU64>,
+    // Allocates small pages if it is set, otherwise all pages are allocated by `malloc`.
+    slabs: Option<Arc<SlabAlloc>>,
+    // The small pages freed recently, which are reused before `malloc` if `slabs` is not set.
+    free_lists: Arc<FreeLists>,
+    // The budget that the cache shares with other caches, if any.
+    share: Option<Arc<Share>>,
+}
+
+/// The nodes that can be evicted, which are unchanged since they are loaded from or written to
+/// disk.
+///
+/// A node is dropped when its first page is deallocated, which happens under the same lock, so a
+/// page of the evictor is never freed while the node is evicted.
+struct Evictor {
+    // The nodes in the hot tier.
+    policy: Box<dyn EvictionPolicy>,
+    // The nodes in the cold tier in the order they are admitted, which may contain dropped nodes.
+    cold: VecDeque<u64>,
+    cold_nodes: HashSet<u64>,
+    // The first page of each node and the disk address of the node.
+    nodes: HashMap<u64, (u64, u64)>,
+    // The nodes of the pages in `nodes`.
+    pages: HashMap<u64, u64>,
+    // The first pages of the evicted nodes that are not freed yet, with the size of the nodes.
+    evicted: HashMap<u64, usize>,
+}
+
+impl Evictor {
+    fn admit_cold(&mut self, id: u64) {
+        if self.cold_nodes.insert(id) {
+            self.cold.push_back(id);
+            if self.cold.len() > self.cold_nodes.len() * 2 {
+                let mut seen = HashSet::with_capacity(self.cold_nodes.len());
+                let cold_nodes = &self.cold_nodes;
+                self.cold
+                    .retain(|id| cold_nodes.contains(id) && seen.insert(*id));
+            }
+        }
+    }
+
+    fn next_victim(&mut self) -> Option<u64> {
+        while let Some(id) = self.cold.pop_front() {
+            if self.cold_nodes.remove(&id) {
+                return Some(id);
+            }
+        }
+        self.policy.evict()
+    }
 }
 
-impl Default for PageCache {
-    fn default() -> Self {
+impl PageCache {
+    /// Creates a cache that evicts nodes with `policy`.
+    pub fn with_policy(policy: Box<dyn EvictionPolicy>) -> Self {
+        let evictor = Evictor {
+            policy,
+            cold: VecDeque::new(),
+            cold_nodes: HashSet::new(),
+            nodes: HashMap::new(),
+            pages: HashMap::new(),
+            evicted: HashMap::new(),
+        };
         Self {
-            size: Arc::new(AtomicUsize::new(0)),
+            evictor: Some(Arc::new(Mutex::new(evictor))),
+            ..Default::default()
         }
     }
+
+    /// Allocates the pages up to `max_size` from slabs, see `SlabAlloc::with_max_size`.
+    /// The slabs are NUMA-aware if `numa_aware` is set, see `SlabAlloc`.
+    pub fn with_slabs(self, max_size: usize, numa_aware: bool) -> Self {
+        let slabs = if numa_aware {
+            SlabAlloc::numa_aware(max_size)
+        } else {
+            SlabAlloc::with_max_size(max_size)
+        };
+        Self {
+            slabs: Some(Arc::new(slabs)),
+            ..self
+        }
+    }
+
+    /// Accounts the size of the cache to `shared` as well.
+    pub fn with_shared(self, shared: SharedCache) -> Self {
+        let share = Share {
+            shared,
+            size: self.size.clone(),
+            evicted_size: self.evicted_size.clone(),
+        };
+        Self {
+            share: Some(Arc::new(share)),
+            ..self
+        }
+    }
+
+    /// Returns true if the caches that share the budget with this one are over it in total.
+    pub fn is_shared_over_budget(&self) -> bool {
+        match &self.share {
+            Some(share) => share.shared.usage() > share.shared.capacity(),
+            None => false,
+        }
+    }
+
+    /// Records that the node `id` with the first page `page` is the same as the one at `addr` on
+    /// disk, and admits it to `tier`, so that it can be evicted.
+    ///
+    /// The page must not be freed until this returns.
+    pub fn insert_clean(&self, id: u64, page: PagePtr, addr: u64, tier: CacheTier) {
+        if let Some(evictor) = &self.evictor {
+            let mut evictor = evictor.lock().unwrap();
+            let page = u64::from(page);
+            if let Some((old, _)) = evictor.nodes.insert(id, (page, addr)) {
+                evictor.pages.remove(&old);
+            }
+            evictor.pages.insert(page, id);
+            match tier {
+                CacheTier::Hot => {
+                    evictor.cold_nodes.remove(&id);
+                    evictor.policy.insert(id);
+                }
+                CacheTier::Cold => {
+                    evictor.policy.remove(id);
+                    evictor.admit_cold(id);
+                }
+            }
+        }
+    }
+
+    /// Returns the first page that the node `id` has when it is recorded clean, with the disk
+    /// address of the node then, if the page is not freed since.
+    ///
+    /// The page may have other pages chained on top of it now.
+    pub fn clean_page(&self, id: u64) -> Option<(u64, u64)> {
+        let evictor = self.evictor.as_ref()?.lock().unwrap();
+        evictor.nodes.get(&id).copied()
+    }
+
+    /// Records an access to the node `id`, which promotes the node to the hot tier if it is in the
+    /// cold tier.
+    pub fn access(&self, id: u64) {
+        if let Some(evictor) = &self.evictor {
+            let mut evictor = evictor.lock().unwrap();
+            if evictor.cold_nodes.remove(&id) {
+                evictor.policy.insert(id);
+                self.num_promotions.fetch_add(1, Ordering::Relaxed);
+            } else {
+                evictor.policy.access(id);
+            }
+        }
+    }
+
+    /// Evicts the oldest node in the cold tier, or a node in the hot tier chosen by the policy, and
+    /// returns its first page.
+    ///
+    /// `f` is called with the id, the first page, and the disk address of a candidate. It should
+    /// replace the first page with the disk address if the node is unchanged, and returns false if
+    /// the node has changed, in which case the next candidate is tried. The first page is valid
+    /// during the call.
+    pub fn evict<F>(&self, mut f: F) -> Option<PagePtr>
+    where
+        F: FnMut(u64, PagePtr, u64) -> bool,
+    {
+        let mut evictor = self.evictor.as_ref()?.lock().unwrap();
+        while let Some(id) = evictor.next_victim() {
+            if let Some((page, addr)) = evictor.nodes.remove(&id) {
+                evictor.pages.remove(&page);
+                let page = unsafe { PagePtr::new(page as *mut u8).unwrap() };
+                if f(id, page, addr) {
+                    let size = self.chain_size(page);
+                    evictor.evicted.insert(page.into(), size);
+                    self.add_evicted_size(size);
+                    self.num_evictions.fetch_add(1, Ordering::Relaxed);
+                    return Some(page);
+                }
+            }
+        }
+        None
+    }
+
+    /// Forgets a page that is about to be freed.
+    fn forget(&self, page: PagePtr) {
+        if let Some(evictor) = &self.evictor {
+            let mut evictor = evictor.lock().unwrap();
+            let page = u64::from(page);
+            if let Some(size) = evictor.evicted.remove(&page) {
+                self.sub_evicted_size(size);
+            }
+            if let Some(id) = evictor.pages.remove(&page) {
+                evictor.nodes.remove(&id);
+                evictor.policy.remove(id);
+                evictor.cold_nodes.remove(&id);
+            }
+        }
+    }
+
+    /// Returns true if the page is allocated on another NUMA node than the one that the current
+    /// thread runs on, which is only known for the pages of NUMA-aware slabs.
+    pub fn is_remote(&self, page: PagePtr) -> bool {
+        match &self.slabs {
+            Some(slabs) => slabs.is_remote(page),
+            None => false,
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            size: self.size() as u64,
+            num_evictions: self.num_evictions.load(Ordering::Relaxed),
+            num_promotions: self.num_promotions.load(Ordering::Relaxed),
+            slab_size: self.slabs.as_ref().map_or(0, |slabs| slabs.size()) as u64,
+            free_list_size: self.free_lists.size() as u64,
+        }
+    }
+
+    /// Returns the size of memory allocated by the cache.
+    pub fn size(&self) -> usize {
+        self.size.get()
+    }
+
+    /// Returns the size of memory allocated by the cache, excluding the nodes that are evicted but
+    /// not freed yet.
+    pub fn resident_size(&self) -> usize {
+        self.size()
+            .saturating_sub(self.evicted_size.load(Ordering::Relaxed))
+    }
+
+    /// Returns an allocator that accounts the allocated bytes to `kind`.
+    pub fn with_kind(&self, kind: AllocKind) -> KindAlloc<'_> {
+        KindAlloc { cache: self, kind }
+    }
+
+    pub fn alloc_stats(&self) -> AllocStats {
+        let bytes = |kind: AllocKind| self.alloc_bytes[kind as usize].get() as u64;
+        AllocStats {
+            put_delta_bytes: bytes(AllocKind::PutDelta),
+            consolidation_bytes: bytes(AllocKind::Consolidation),
+            split_bytes: bytes(AllocKind::Split),
+            swapin_bytes: bytes(AllocKind::SwapIn),
+        }
+    }
+
+    /// Allocates a page and returns it with the usable size of the allocation.
+    fn alloc_with_size(&self, size: usize) -> Result<(PagePtr, usize)> {
+        let page = match &self.slabs {
+            Some(slabs) => slabs.alloc(size)?,
+            None => match self.free_lists.pop(size) {
+                Some(page) => page,
+                None => unsafe {
+                    let ptr = malloc::alloc(Self::alloc_layout(size));
+                    PagePtr::new(ptr).ok_or(Error::Alloc)?
+                },
+            },
+        };
+        let size = self.usable_size(page);
+        self.size.add(size);
+        if let Some(share) = &self.share {
+            share.shared.inner.size.add(size);
+        }
+        Ok((page, size))
+    }
+
+    fn add_evicted_size(&self, size: usize) {
+        self.evicted_size.fetch_add(size, Ordering::Relaxed);
+        if let Some(share) = &self.share {
+            let inner = &share.shared.inner;
+            inner.evicted_size.fetch_add(size, Ordering::Relaxed);
+        }
+    }
+
+    fn sub_evicted_size(&self, size: usize) {
+        self.evicted_size.fetch_sub(size, Ordering::Relaxed);
+        if let Some(share) = &self.share {
+            let inner = &share.shared.inner;
+            inner.evicted_size.fetch_sub(size, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the usable size of the allocation of a page.
+    fn usable_size(&self, page: PagePtr) -> usize {
+        match &self.slabs {
+            Some(slabs) => slabs.usable_size(page),
+            None => unsafe { malloc::usable_size(page.as_raw()) },
+        }
+    }
+
+    /// Returns the allocated size of the pages in the chain that starts from `page`.
+    fn chain_size(&self, page: PagePtr) -> usize {
+        let mut size = 0;
+        let mut next = Some(page);
+        while let Some(page) = next {
+            size += self.usable_size(page);
+            next = match page.next().into() {
+                PageAddr::Mem(addr) => unsafe { PagePtr::new(addr as *mut u8) },
+                PageAddr::Disk(_) => None,
+            };
+        }
+        size
+    }
 }
 
 unsafe impl PageAlloc for PageCache {
     type Error = Error;
 
     fn alloc(&self, size: usize) -> Result<PagePtr> {
-        unsafe {
-            let ptr = Jemalloc.alloc(Self::alloc_layout(size));
-            let size = usable_size(ptr);
-            self.size.fetch_add(size, Ordering::Relaxed);
-            PagePtr::new(ptr).ok_or(Error::Alloc)
+        self.alloc_with_size(size).map(|(page, _)| page)
+    }
+
+    unsafe fn dealloc(&self, page: PagePtr) {
+        self.forget(page);
+        let size = self.usable_size(page);
+        self.size.sub(size);
+        if let Some(share) = &self.share {
+            share.shared.inner.size.sub(size);
         }
+        match &self.slabs {
+            Some(slabs) => slabs.dealloc(page),
+            None => {
+                if !self.free_lists.push(page, size) {
+                    malloc::dealloc(page.as_raw(), Self::alloc_layout(size));
+                }
+            }
+        }
+    }
+}
+
+/// An allocator that accounts the allocated bytes of the cache to some kind of operations.
+pub struct KindAlloc<'a> {
+    cache: &'a PageCache,
+    kind: AllocKind,
+}
+
+unsafe impl PageAlloc for KindAlloc<'_> {
+    type Error = Error;
+
+    fn alloc(&self, size: usize) -> Result<PagePtr> {
+        let (page, size) = self.cache.alloc_with_size(size)?;
+        self.cache.alloc_bytes[self.kind as usize].add(size);
+        Ok(page)
     }
 
     unsafe fn dealloc(&self, page: PagePtr) {
-        let ptr = page.as_raw();
-        let size = usable_size(ptr);
-        self.size.fetch_sub(size, Ordering::Relaxed);
-        Jemalloc.dealloc(ptr, Self::alloc_layout(size));
+        self.cache.dealloc(page);
     }
 }
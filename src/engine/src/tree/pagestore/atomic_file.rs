@@ -0,0 +1,330 @@
+use std::{
+    ffi::OsString,
+    io,
+    path::{Path, PathBuf},
+};
+
+use crate::env::{Env, SequentialWriter};
+
+/// The suffix of temporary files.
+pub const TMP_SUFFIX: &str = ".tmp";
+
+/// A file that is created atomically.
+///
+/// The content is written to a temporary file first. Once the content is synced, the temporary
+/// file is renamed to the path, and then the directory is synced. A crash at any step leaves either
+/// no file or the complete file at the path, and maybe a temporary file, which is removed by
+/// `remove_tmp_files` on recovery.
+pub struct AtomicFile {
+    path: PathBuf,
+    tmp_path: PathBuf,
+}
+
+impl AtomicFile {
+    pub fn new(path: PathBuf) -> Self {
+        let mut tmp_path = OsString::from(&path);
+        tmp_path.push(TMP_SUFFIX);
+        Self {
+            path,
+            tmp_path: tmp_path.into(),
+        }
+    }
+
+    /// Returns the path of the temporary file, which is valid until the file is committed.
+    pub fn tmp_path(&self) -> &Path {
+        &self.tmp_path
+    }
+
+    /// Opens the temporary file for writes.
+    pub async fn open(&self, env: &dyn Env) -> io::Result<Box<dyn SequentialWriter>> {
+        env.open_sequential_writer(&self.tmp_path).await
+    }
+
+    /// Installs the temporary file at the path.
+    ///
+    /// The content of the temporary file must be synced. The writer of the temporary file can still
+    /// append to the file after this.
+    pub async fn commit(self, env: &dyn Env) -> io::Result<()> {
+        env.rename(&self.tmp_path, &self.path).await?;
+        if let Some(dir) = self.path.parent() {
+            env.sync_dir(dir).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Creates a file with `buf` atomically, replacing the file if it exists.
+pub async fn write_file(env: &dyn Env, path: &Path, buf: &[u8]) -> io::Result<()> {
+    let file = AtomicFile::new(path.to_owned());
+    let mut writer = file.open(env).await?;
+    writer.write(buf).await?;
+    writer.sync_data().await?;
+    drop(writer);
+    file.commit(env).await
+}
+
+/// Copies a file atomically.
+pub async fn copy_file(env: &dyn Env, from: &Path, to: &Path) -> io::Result<()> {
+    copy_file_across(env, from, env, to).await
+}
+
+/// Copies a file in `from_env` to `to_env` atomically.
+pub async fn copy_file_across(
+    from_env: &dyn Env,
+    from: &Path,
+    to_env: &dyn Env,
+    to: &Path,
+) -> io::Result<()> {
+    /// The size of the buffer to copy files.
+    const BUFFER_SIZE: u64 = 1 << 20;
+
+    let size = from_env.file_size(from).await?;
+    let reader = from_env.open_positional_reader(from).await?;
+    let file = AtomicFile::new(to.to_owned());
+    let mut writer = file.open(to_env).await?;
+    let mut buf = vec![0; size.min(BUFFER_SIZE) as usize];
+    let mut offset = 0;
+    while offset < size {
+        let len = (size - offset).min(BUFFER_SIZE) as usize;
+        reader.read_exact_at(&mut buf[..len], offset).await?;
+        writer.write(&buf[..len]).await?;
+        offset += len as u64;
+    }
+    writer.sync_data().await?;
+    drop(writer);
+    file.commit(to_env).await
+}
+
+/// Removes the temporary files left by crashes in `dir`.
+pub async fn remove_tmp_files(env: &dyn Env, dir: &Path) -> io::Result<()> {
+    for path in env.read_dir(dir).await? {
+        let name = path.file_name().and_then(|name| name.to_str());
+        if matches!(name, Some(name) if name.ends_with(TMP_SUFFIX)) {
+            env.remove_file(&path).await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub use fault::{FaultEnv, FaultOp};
+
+#[cfg(test)]
+mod fault {
+    use std::{
+        io,
+        path::{Path, PathBuf},
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use crate::env::{BoxFuture, Env, FileLock, PositionalReader, SequentialWriter};
+
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+    pub enum FaultOp {
+        Write,
+        SyncData,
+        Rename,
+        SyncDir,
+    }
+
+    /// An `Env` that simulates a crash at the n-th operation of a kind.
+    ///
+    /// The operation and all the file operations after it fail, so nothing is changed after the
+    /// crash.
+    #[derive(Clone)]
+    pub struct FaultEnv {
+        env: Arc<dyn Env>,
+        state: Arc<Mutex<State>>,
+    }
+
+    struct State {
+        op: FaultOp,
+        countdown: usize,
+        crashed: bool,
+    }
+
+    impl FaultEnv {
+        pub fn new(env: Arc<dyn Env>, op: FaultOp, n: usize) -> Self {
+            let state = State {
+                op,
+                countdown: n,
+                crashed: false,
+            };
+            Self {
+                env,
+                state: Arc::new(Mutex::new(state)),
+            }
+        }
+
+        pub fn crashed(&self) -> bool {
+            self.state.lock().unwrap().crashed
+        }
+
+        fn check(&self, op: Option<FaultOp>) -> io::Result<()> {
+            let mut state = self.state.lock().unwrap();
+            if !state.crashed && op == Some(state.op) {
+                if state.countdown == 0 {
+                    state.crashed = true;
+                } else {
+                    state.countdown -= 1;
+                }
+            }
+            if state.crashed {
+                // Any error works, since nothing is done after a crash.
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+            Ok(())
+        }
+    }
+
+    impl Env for FaultEnv {
+        fn spawn(&self, task: BoxFuture<'static, ()>) {
+            self.env.spawn(task)
+        }
+
+        fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+            self.env.sleep(duration)
+        }
+
+        fn open_sequential_writer<'a>(
+            &'a self,
+            path: &'a Path,
+        ) -> BoxFuture<'a, io::Result<Box<dyn SequentialWriter>>> {
+            Box::pin(async move {
+                self.check(None)?;
+                let file = self.env.open_sequential_writer(path).await?;
+                let writer = FaultWriter {
+                    file,
+                    env: self.clone(),
+                };
+                Ok(Box::new(writer) as Box<dyn SequentialWriter>)
+            })
+        }
+
+        fn open_positional_reader<'a>(
+            &'a self,
+            path: &'a Path,
+        ) -> BoxFuture<'a, io::Result<Box<dyn PositionalReader>>> {
+            self.env.open_positional_reader(path)
+        }
+
+        fn file_size<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<u64>> {
+            self.env.file_size(path)
+        }
+
+        fn create_dir_all<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+            Box::pin(async move {
+                self.check(None)?;
+                self.env.create_dir_all(path).await
+            })
+        }
+
+        fn read_dir<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<Vec<PathBuf>>> {
+            self.env.read_dir(path)
+        }
+
+        fn rename<'a>(&'a self, from: &'a Path, to: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+            Box::pin(async move {
+                self.check(Some(FaultOp::Rename))?;
+                self.env.rename(from, to).await
+            })
+        }
+
+        fn remove_file<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+            Box::pin(async move {
+                self.check(None)?;
+                self.env.remove_file(path).await
+            })
+        }
+
+        fn sync_dir<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+            Box::pin(async move {
+                self.check(Some(FaultOp::SyncDir))?;
+                self.env.sync_dir(path).await
+            })
+        }
+
+        fn lock_file<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<Box<dyn FileLock>>> {
+            self.env.lock_file(path)
+        }
+    }
+
+    struct FaultWriter {
+        file: Box<dyn SequentialWriter>,
+        env: FaultEnv,
+    }
+
+    impl SequentialWriter for FaultWriter {
+        fn write<'a>(&'a mut self, buf: &'a [u8]) -> BoxFuture<'a, io::Result<()>> {
+            Box::pin(async move {
+                if let Err(err) = self.env.check(Some(FaultOp::Write)) {
+                    // A torn write.
+                    let _ = self.file.write(&buf[..buf.len() / 2]).await;
+                    return Err(err);
+                }
+                self.file.write(buf).await
+            })
+        }
+
+        fn sync_data(&mut self) -> BoxFuture<'_, io::Result<()>> {
+            Box::pin(async move {
+                self.env.check(Some(FaultOp::SyncData))?;
+                self.file.sync_data().await
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::env::TokioEnv;
+
+    #[tokio::test]
+    async fn crash_at_each_step() {
+        let env: Arc<dyn Env> = Arc::new(TokioEnv::current());
+        for op in [
+            FaultOp::Write,
+            FaultOp::SyncData,
+            FaultOp::Rename,
+            FaultOp::SyncDir,
+        ] {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("file");
+            write_file(env.as_ref(), &path, b"old").await.unwrap();
+
+            let fault_env = FaultEnv::new(env.clone(), op, 0);
+            assert!(write_file(&fault_env, &path, b"new").await.is_err());
+            assert!(fault_env.crashed());
+
+            // The file is either the old one or the new one, but never a partial one.
+            let content = std::fs::read(&path).unwrap();
+            if matches!(op, FaultOp::SyncDir) {
+                assert_eq!(content, b"new");
+            } else {
+                assert_eq!(content, b"old");
+            }
+            remove_tmp_files(env.as_ref(), dir.path()).await.unwrap();
+            let names: Vec<_> = std::fs::read_dir(dir.path())
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name())
+                .collect();
+            assert_eq!(names, vec!["file"]);
+        }
+    }
+
+    #[tokio::test]
+    async fn copy() {
+        let env = TokioEnv::current();
+        let dir = tempfile::tempdir().unwrap();
+        let from = dir.path().join("from");
+        let to = dir.path().join("to");
+        let content: Vec<u8> = (0..3 << 20).map(|i| i as u8).collect();
+        std::fs::write(&from, &content).unwrap();
+        copy_file(&env, &from, &to).await.unwrap();
+        assert_eq!(std::fs::read(&to).unwrap(), content);
+    }
+}
@@ -1,54 +1,561 @@
-use std::io::Result;
+use super::{check_format_version, PageInfo, RunId, FORMAT_VERSION};
+use crate::{
+    env::{PositionalReader, SequentialWriter},
+    tree::{page::PageVer, Corruption, Error, Result},
+};
 
-use super::env::{RandomRead, SequentialWrite};
+const PAGE_FILE_SUFFIX: &str = "page";
+const PAGE_FILE_MAGIC: u64 = 0x5048_4f54_4f4e_5632;
+/// The magic number of page files before format version 8, whose footers have no version.
+const LEGACY_PAGE_FILE_MAGIC: u64 = 0x5048_4f54_4f4e_5046;
+const LEGACY_FORMAT_VERSION: u32 = 7;
 
-struct BlockHandle {
+/// Returns the name of a page file.
+///
+/// The name embeds the short form of the run id, so that files from different stores are told
+/// apart by name.
+pub fn page_file_name(file_id: u64, run_id: RunId) -> String {
+    format!("{:06}.{}.{}", file_id, run_id.short(), PAGE_FILE_SUFFIX)
+}
+
+/// Parses the file id and the short run id from the name of a page file.
+///
+/// Returns `None` if the name is not a page file name.
+pub fn parse_page_file_name(name: &str) -> Option<(u64, &str)> {
+    let mut parts = name.split('.');
+    let file_id = parts.next()?.parse().ok()?;
+    let run_id = parts.next()?;
+    if parts.next()? != PAGE_FILE_SUFFIX || parts.next().is_some() {
+        return None;
+    }
+    Some((file_id, run_id))
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct BlockHandle {
     pub offset: u64,
     pub size: u64,
 }
 
+impl BlockHandle {
+    const ENCODED_SIZE: usize = 16;
+
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.offset.to_le_bytes());
+        buf.extend_from_slice(&self.size.to_le_bytes());
+    }
+
+    fn decode_from(buf: &[u8]) -> Self {
+        Self {
+            offset: u64::from_le_bytes(buf[0..8].try_into().unwrap()),
+            size: u64::from_le_bytes(buf[8..16].try_into().unwrap()),
+        }
+    }
+}
+
+/// The location and the information of a page in a page file.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PageHandle {
+    pub id: u64,
+    pub info: PageInfo,
+    pub block: BlockHandle,
+    /// The CRC32 of the page image, which is verified when the page is read.
+    pub checksum: u32,
+}
+
+impl PageHandle {
+    const ENCODED_SIZE: usize = 8 + 8 + 1 + 1 + BlockHandle::ENCODED_SIZE + 4;
+
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.id.to_le_bytes());
+        buf.extend_from_slice(&u64::from(self.info.ver).to_le_bytes());
+        buf.push(self.info.len);
+        buf.push(self.info.is_index as u8);
+        self.block.encode_to(buf);
+        buf.extend_from_slice(&self.checksum.to_le_bytes());
+    }
+
+    fn decode_from(buf: &[u8]) -> Result<Self> {
+        let ver = u64::from_le_bytes(buf[8..16].try_into().unwrap());
+        if ver > PageVer::MAX {
+            return Err(Error::Corrupted(
+                format!("page handle has version {}", ver).into(),
+            ));
+        }
+        Ok(Self {
+            id: u64::from_le_bytes(buf[0..8].try_into().unwrap()),
+            info: PageInfo {
+                ver: PageVer::new(ver),
+                len: buf[16],
+                is_index: buf[17] != 0,
+            },
+            block: BlockHandle::decode_from(&buf[18..34]),
+            checksum: u32::from_le_bytes(buf[34..38].try_into().unwrap()),
+        })
+    }
+}
+
+/// The footer of a page file, encoded as:
+///
+/// `meta_handle (16B) | index_handle (16B) | run_id (16B) | file_id (8B) | format_version (4B) |
+/// magic_number (8B)`
+///
+/// Legacy footers have no `format_version`, and end with `LEGACY_PAGE_FILE_MAGIC`.
+#[derive(Copy, Clone)]
 struct PageFileFooter {
-    pub meta_handle: BlockHandle,
-    pub index_handle: BlockHandle,
-    pub magic_number: u64,
+    meta_handle: BlockHandle,
+    index_handle: BlockHandle,
+    run_id: RunId,
+    file_id: u64,
+    format_version: u32,
 }
 
 impl PageFileFooter {
-    const ENCODED_SIZE: usize = 40;
+    const ENCODED_SIZE: usize = Self::LEGACY_ENCODED_SIZE + 4;
+    const LEGACY_ENCODED_SIZE: usize = BlockHandle::ENCODED_SIZE * 2 + 16 + 8 + 8;
+
+    fn encoded_size(&self) -> usize {
+        if self.format_version == LEGACY_FORMAT_VERSION {
+            Self::LEGACY_ENCODED_SIZE
+        } else {
+            Self::ENCODED_SIZE
+        }
+    }
+
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        self.meta_handle.encode_to(buf);
+        self.index_handle.encode_to(buf);
+        buf.extend_from_slice(self.run_id.as_bytes());
+        buf.extend_from_slice(&self.file_id.to_le_bytes());
+        if self.format_version == LEGACY_FORMAT_VERSION {
+            buf.extend_from_slice(&LEGACY_PAGE_FILE_MAGIC.to_le_bytes());
+        } else {
+            buf.extend_from_slice(&self.format_version.to_le_bytes());
+            buf.extend_from_slice(&PAGE_FILE_MAGIC.to_le_bytes());
+        }
+    }
+
+    /// Decodes the footer at the end of `buf`, which is at most `ENCODED_SIZE` bytes.
+    fn decode_from(buf: &[u8]) -> Result<Self> {
+        let magic_number = match buf.len().checked_sub(8) {
+            Some(pos) => u64::from_le_bytes(buf[pos..].try_into().unwrap()),
+            None => 0,
+        };
+        let (buf, format_version) = match magic_number {
+            PAGE_FILE_MAGIC if buf.len() == Self::ENCODED_SIZE => {
+                let version = &buf[Self::LEGACY_ENCODED_SIZE - 8..Self::ENCODED_SIZE - 8];
+                (buf, u32::from_le_bytes(version.try_into().unwrap()))
+            }
+            LEGACY_PAGE_FILE_MAGIC if buf.len() >= Self::LEGACY_ENCODED_SIZE => (
+                &buf[buf.len() - Self::LEGACY_ENCODED_SIZE..],
+                LEGACY_FORMAT_VERSION,
+            ),
+            _ => {
+                return Err(Error::Corrupted(
+                    format!(
+                        "page file has magic number {:#x}, expected {:#x}",
+                        magic_number, PAGE_FILE_MAGIC
+                    )
+                    .into(),
+                ))
+            }
+        };
+        check_format_version(format_version, &"page file")?;
+        let (meta, buf) = buf.split_at(BlockHandle::ENCODED_SIZE);
+        let (index, buf) = buf.split_at(BlockHandle::ENCODED_SIZE);
+        let (run_id, buf) = buf.split_at(16);
+        Ok(Self {
+            meta_handle: BlockHandle::decode_from(meta),
+            index_handle: BlockHandle::decode_from(index),
+            run_id: RunId::from_bytes(run_id.try_into().unwrap()),
+            file_id: u64::from_le_bytes(buf[..8].try_into().unwrap()),
+            format_version,
+        })
+    }
 }
 
-struct PageFileReader<R> {
+/// Reads pages from a page file.
+pub struct PageFileReader<R> {
     file: R,
+    file_size: u64,
+    footer: PageFileFooter,
 }
 
-impl<R: RandomRead> PageFileReader<R> {
-    pub fn open(file: R, file_size: u64) -> Result<Self> {
-        todo!()
+impl<R: PositionalReader> PageFileReader<R> {
+    /// Opens a page file and validates its footer.
+    ///
+    /// Files of older format versions are opened too, so that they can be migrated.
+    pub async fn open(file: R, file_size: u64) -> Result<Self> {
+        let footer_size = PageFileFooter::LEGACY_ENCODED_SIZE as u64;
+        if file_size < footer_size {
+            return Err(Error::Corrupted(
+                format!(
+                    "page file size {} is smaller than the footer size {}",
+                    file_size, footer_size
+                )
+                .into(),
+            ));
+        }
+        let footer_size = file_size.min(PageFileFooter::ENCODED_SIZE as u64);
+        let mut buf = vec![0; footer_size as usize];
+        file.read_exact_at(&mut buf, file_size - footer_size)
+            .await?;
+        let footer = PageFileFooter::decode_from(&buf)?;
+        Ok(Self {
+            file,
+            file_size,
+            footer,
+        })
+    }
+
+    /// Returns the run id of the store that wrote this file.
+    pub fn run_id(&self) -> RunId {
+        self.footer.run_id
+    }
+
+    pub fn file_id(&self) -> u64 {
+        self.footer.file_id
+    }
+
+    pub fn file_size(&self) -> u64 {
+        self.file_size
+    }
+
+    pub fn format_version(&self) -> u32 {
+        self.footer.format_version
+    }
+
+    /// Copies this file to `writer` with a footer of `format_version`, and syncs it.
+    ///
+    /// The footer is the only part of a page file that differs between the supported versions, so
+    /// the pages and their addresses are kept. Migrations upgrade page files with this.
+    pub async fn copy_with_footer(
+        &self,
+        writer: &mut dyn SequentialWriter,
+        format_version: u32,
+    ) -> Result<()> {
+        /// The size of the buffer to copy files.
+        const BUFFER_SIZE: u64 = 1 << 20;
+
+        let size = self.file_size - self.footer.encoded_size() as u64;
+        let mut buf = vec![0; size.min(BUFFER_SIZE) as usize];
+        let mut offset = 0;
+        while offset < size {
+            let len = (size - offset).min(BUFFER_SIZE) as usize;
+            self.file.read_exact_at(&mut buf[..len], offset).await?;
+            writer.write(&buf[..len]).await?;
+            offset += len as u64;
+        }
+        let footer = PageFileFooter {
+            format_version,
+            ..self.footer
+        };
+        buf.clear();
+        footer.encode_to(&mut buf);
+        writer.write(&buf).await?;
+        writer.sync_data().await?;
+        Ok(())
     }
 
-    pub fn read_page(&mut self) -> Result<Vec<u8>> {
-        todo!()
+    /// Returns the handles of the pages in this file.
+    pub async fn read_index(&self) -> Result<Vec<PageHandle>> {
+        let buf = self.read_block(self.footer.index_handle).await?;
+        buf.chunks_exact(PageHandle::ENCODED_SIZE)
+            .map(PageHandle::decode_from)
+            .collect()
+    }
+
+    /// Returns the addresses of the pages made obsolete by this file.
+    pub async fn read_obsolete_pages(&self) -> Result<Vec<u64>> {
+        let buf = self.read_block(self.footer.meta_handle).await?;
+        let addrs = buf
+            .chunks_exact(8)
+            .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()))
+            .collect();
+        Ok(addrs)
+    }
+
+    pub async fn read_page(&self, handle: &PageHandle) -> Result<Vec<u8>> {
+        let buf = self.read_block(handle.block).await?;
+        self.check_page(handle, &buf)?;
+        Ok(buf)
+    }
+
+    /// Reads pages that are stored back to back in the order of `handles` with one read.
+    pub async fn read_pages(&self, handles: &[PageHandle]) -> Result<Vec<Vec<u8>>> {
+        let offset = match handles.first() {
+            Some(first) => first.block.offset,
+            None => return Ok(Vec::new()),
+        };
+        let mut size = 0u64;
+        for handle in handles {
+            // Handles are read from disk, so a damaged file may have gaps or overlaps between them.
+            let end = offset.checked_add(size);
+            if end != Some(handle.block.offset) {
+                let message = format!(
+                    "page {} at offset {} of page file {} doesn't follow the page that ends at {:?}",
+                    handle.id,
+                    handle.block.offset,
+                    page_file_name(self.file_id(), self.run_id()),
+                    end
+                );
+                let corruption = Corruption::new(message)
+                    .with_file(self.file_id(), handle.block.offset)
+                    .with_page(handle.id);
+                return Err(Error::Corrupted(corruption));
+            }
+            size = size.saturating_add(handle.block.size);
+        }
+        let buf = self.read_block(BlockHandle { offset, size }).await?;
+        let mut images = Vec::with_capacity(handles.len());
+        let mut start = 0;
+        for handle in handles {
+            let image = &buf[start..start + handle.block.size as usize];
+            self.check_page(handle, image)?;
+            images.push(image.to_vec());
+            start += handle.block.size as usize;
+        }
+        Ok(images)
+    }
+
+    /// Reads a page into `buf`, which must have the same size as the page.
+    pub async fn read_page_into(&self, handle: &PageHandle, buf: &mut [u8]) -> Result<()> {
+        self.check_block(handle.block)?;
+        assert_eq!(buf.len() as u64, handle.block.size);
+        self.file.read_exact_at(buf, handle.block.offset).await?;
+        self.check_page(handle, buf)
+    }
+
+    fn check_page(&self, handle: &PageHandle, buf: &[u8]) -> Result<()> {
+        let checksum = crc32fast::hash(buf);
+        if checksum != handle.checksum {
+            let message = format!(
+                "page {} at offset {} of page file {} has checksum {:#x}, expected {:#x}",
+                handle.id,
+                handle.block.offset,
+                page_file_name(self.file_id(), self.run_id()),
+                checksum,
+                handle.checksum
+            );
+            let corruption = Corruption::new(message)
+                .with_file(self.file_id(), handle.block.offset)
+                .with_page(handle.id)
+                .with_checksum(handle.checksum, checksum);
+            return Err(Error::Corrupted(corruption));
+        }
+        Ok(())
+    }
+
+    async fn read_block(&self, handle: BlockHandle) -> Result<Vec<u8>> {
+        self.check_block(handle)?;
+        let mut buf = vec![0; handle.size as usize];
+        self.file.read_exact_at(&mut buf, handle.offset).await?;
+        Ok(buf)
+    }
+
+    fn check_block(&self, handle: BlockHandle) -> Result<()> {
+        let end = handle.offset.checked_add(handle.size);
+        if !matches!(end, Some(end) if end <= self.file_size) {
+            let message = format!(
+                "block {:?} is out of the page file size {}",
+                handle, self.file_size
+            );
+            let corruption = Corruption::new(message).with_file(self.file_id(), handle.offset);
+            return Err(Error::Corrupted(corruption));
+        }
+        Ok(())
     }
 }
 
-struct PageFileWriter<W> {
+/// Writes pages to a page file.
+pub struct PageFileWriter<W> {
     file: W,
+    offset: u64,
+    run_id: RunId,
+    file_id: u64,
+    pages: Vec<PageHandle>,
+    obsolete_pages: Vec<u64>,
 }
 
-impl<W: SequentialWrite> PageFileWriter<W> {
-    pub fn open(file: W) -> Result<Self> {
-        todo!()
+impl<W: SequentialWriter> PageFileWriter<W> {
+    pub fn new(file: W, run_id: RunId, file_id: u64) -> Self {
+        Self {
+            file,
+            offset: 0,
+            run_id,
+            file_id,
+            pages: Vec::new(),
+            obsolete_pages: Vec::new(),
+        }
+    }
+
+    /// Appends a page to the file and returns its handle.
+    pub async fn add_page(&mut self, id: u64, info: PageInfo, page: &[u8]) -> Result<PageHandle> {
+        let block = self.write_block(page).await?;
+        let handle = PageHandle {
+            id,
+            info,
+            block,
+            checksum: crc32fast::hash(page),
+        };
+        self.pages.push(handle);
+        Ok(handle)
     }
 
-    pub fn add_page(&mut self) {}
+    /// Records a page address that is made obsolete by this file.
+    pub fn add_obsolete_page(&mut self, addr: u64) {
+        self.obsolete_pages.push(addr);
+    }
 
-    pub fn add_obsolete_page(&mut self) {}
+    /// Writes the index, the metadata and the footer, and syncs the file.
+    ///
+    /// Returns the size of the file.
+    pub async fn finish(self) -> Result<u64> {
+        let (file_size, _) = self.finish_with_footer().await?;
+        Ok(file_size)
+    }
+
+    /// Finishes the file like `finish`, and returns a reader of the file with `file`.
+    ///
+    /// The reader is built from what is written, so the file is not read back.
+    pub async fn finish_into_reader<R>(self, file: R) -> Result<PageFileReader<R>> {
+        let (file_size, footer) = self.finish_with_footer().await?;
+        Ok(PageFileReader {
+            file,
+            file_size,
+            footer,
+        })
+    }
+
+    async fn finish_with_footer(mut self) -> Result<(u64, PageFileFooter)> {
+        let mut buf = Vec::with_capacity(self.pages.len() * PageHandle::ENCODED_SIZE);
+        for page in &self.pages {
+            page.encode_to(&mut buf);
+        }
+        let index_handle = self.write_block(&buf).await?;
+
+        buf.clear();
+        for addr in &self.obsolete_pages {
+            buf.extend_from_slice(&addr.to_le_bytes());
+        }
+        let meta_handle = self.write_block(&buf).await?;
+
+        buf.clear();
+        let footer = PageFileFooter {
+            meta_handle,
+            index_handle,
+            run_id: self.run_id,
+            file_id: self.file_id,
+            format_version: FORMAT_VERSION,
+        };
+        footer.encode_to(&mut buf);
+        self.write_block(&buf).await?;
+        self.file.sync_data().await?;
+        Ok((self.offset, footer))
+    }
+
+    async fn write_block(&mut self, buf: &[u8]) -> Result<BlockHandle> {
+        self.file.write(buf).await?;
+        let handle = BlockHandle {
+            offset: self.offset,
+            size: buf.len() as u64,
+        };
+        self.offset += handle.size;
+        Ok(handle)
+    }
 }
 
-struct PageFile {}
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::env::{Env, TokioEnv};
+
+    #[test]
+    fn file_name() {
+        let run_id = RunId::random();
+        let name = page_file_name(7, run_id);
+        assert_eq!(
+            parse_page_file_name(&name),
+            Some((7, run_id.short().as_str()))
+        );
+        assert_eq!(parse_page_file_name("MANIFEST"), None);
+        assert_eq!(parse_page_file_name("000007.abc.page.tmp"), None);
+    }
+
+    #[tokio::test]
+    async fn write_and_read() {
+        let env = TokioEnv::current();
+        let dir = tempfile::tempdir().unwrap();
+        let run_id = RunId::random();
+        let path = dir.path().join(page_file_name(1, run_id));
+
+        let file = env.open_sequential_writer(&path).await.unwrap();
+        let mut writer = PageFileWriter::new(file, run_id, 1);
+        let mut handles = Vec::new();
+        for i in 0..4u64 {
+            let page = vec![i as u8; i as usize + 1];
+            let info = PageInfo {
+                ver: PageVer::new(i),
+                len: i as u8,
+                is_index: i % 2 == 0,
+            };
+            handles.push(writer.add_page(i, info, &page).await.unwrap());
+        }
+        writer.add_obsolete_page(42);
+        let size = writer.finish().await.unwrap();
+        assert_eq!(env.file_size(&path).await.unwrap(), size);
+
+        let file = env.open_positional_reader(&path).await.unwrap();
+        let reader = PageFileReader::open(file, size).await.unwrap();
+        assert_eq!(reader.run_id(), run_id);
+        assert_eq!(reader.file_id(), 1);
+        assert_eq!(reader.read_index().await.unwrap(), handles);
+        assert_eq!(reader.read_obsolete_pages().await.unwrap(), vec![42]);
+        for (i, handle) in handles.iter().enumerate() {
+            let page = reader.read_page(handle).await.unwrap();
+            assert_eq!(page, vec![i as u8; i + 1]);
+        }
+        let pages = reader.read_pages(&handles[1..]).await.unwrap();
+        assert_eq!(pages, vec![vec![1; 2], vec![2; 3], vec![3; 4]]);
+
+        // A page that doesn't match its checksum is corrupted.
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[handles[3].block.offset as usize] ^= 1;
+        std::fs::write(&path, bytes).unwrap();
+        let file = env.open_positional_reader(&path).await.unwrap();
+        let reader = PageFileReader::open(file, size).await.unwrap();
+        assert!(reader.read_page(&handles[2]).await.is_ok());
+        assert!(reader.read_pages(&handles[..3]).await.is_ok());
+        assert!(reader.read_pages(&handles[2..]).await.is_err());
+        match reader.read_page(&handles[3]).await {
+            Err(Error::Corrupted(corruption)) => {
+                assert_eq!(corruption.file_id, Some(reader.file_id()));
+                assert_eq!(corruption.offset, Some(handles[3].block.offset));
+                assert_eq!(corruption.page_id, Some(handles[3].id));
+                assert_eq!(corruption.expected_checksum, Some(handles[3].checksum));
+                assert_ne!(corruption.actual_checksum, corruption.expected_checksum);
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
 
-impl PageFile {
-    pub fn read_page(&self, index: usize) -> Result<Vec<u8>> {
-        todo!()
+        // Pages that don't follow one another are corrupted, and so are pages past the end of a
+        // truncated file.
+        match reader.read_pages(&[handles[0], handles[2]]).await {
+            Err(Error::Corrupted(corruption)) => {
+                assert_eq!(corruption.file_id, Some(reader.file_id()));
+                assert_eq!(corruption.offset, Some(handles[2].block.offset));
+                assert_eq!(corruption.page_id, Some(handles[2].id));
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+        let mut past_end = handles[3];
+        past_end.block.size = size;
+        match reader.read_pages(&[handles[2], past_end]).await {
+            Err(Error::Corrupted(corruption)) => {
+                assert_eq!(corruption.file_id, Some(reader.file_id()));
+                assert_eq!(corruption.offset, Some(handles[2].block.offset));
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
     }
 }
@@ -0,0 +1,248 @@
+use std::{
+    collections::BTreeMap,
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use super::{parse_page_file_name, TMP_SUFFIX};
+use crate::{
+    env::{BoxFuture, Env, FileLock, PositionalReader, SequentialWriter},
+    tree::{FileIoStats, IoCounters, IoOp, IoStats, OpIoStats},
+};
+
+/// Collects the I/O statistics of a store by file and operation.
+#[derive(Default)]
+pub struct IoRecorder {
+    // Reads are done by recoveries until the store is opened, and by swap-ins after that.
+    recovering: AtomicBool,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    ops: BTreeMap<IoOp, IoCounters>,
+    files: BTreeMap<(String, IoOp), IoCounters>,
+}
+
+impl IoRecorder {
+    pub fn new() -> Self {
+        Self {
+            recovering: AtomicBool::new(true),
+            ..Default::default()
+        }
+    }
+
+    /// Marks that the store is opened.
+    pub fn finish_recovery(&self) {
+        self.recovering.store(false, Ordering::Release);
+    }
+
+    pub fn stats(&self) -> IoStats {
+        let inner = self.inner.lock().unwrap();
+        let ops = inner
+            .ops
+            .iter()
+            .map(|(&op, io)| OpIoStats { op, io: io.clone() })
+            .collect();
+        let files = inner
+            .files
+            .iter()
+            .map(|((file, op), io)| FileIoStats {
+                file: file.clone(),
+                op: *op,
+                io: io.clone(),
+            })
+            .collect();
+        IoStats { ops, files }
+    }
+
+    fn read_op(&self) -> IoOp {
+        if self.recovering.load(Ordering::Acquire) {
+            IoOp::Recovery
+        } else {
+            IoOp::SwapIn
+        }
+    }
+
+    fn record(&self, file: &str, op: IoOp, elapsed: Duration, f: impl Fn(&mut IoCounters)) {
+        let micros = elapsed.as_micros() as u64;
+        let update = |io: &mut IoCounters| {
+            f(io);
+            io.latency.record(micros);
+        };
+        let mut inner = self.inner.lock().unwrap();
+        update(inner.ops.entry(op).or_default());
+        update(inner.files.entry((file.to_owned(), op)).or_default());
+    }
+
+    fn remove_file(&self, file: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.files.retain(|(name, _), _| name != file);
+    }
+}
+
+/// Returns the name of the file at `path`.
+///
+/// Temporary files are named after the files they are installed as.
+fn file_name(path: &Path) -> String {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    name.strip_suffix(TMP_SUFFIX).unwrap_or(&name).to_owned()
+}
+
+/// An `Env` that records the I/O on the files it opens.
+pub struct RecordingEnv {
+    env: Arc<dyn Env>,
+    recorder: Arc<IoRecorder>,
+}
+
+impl RecordingEnv {
+    pub fn new(env: Arc<dyn Env>, recorder: Arc<IoRecorder>) -> Self {
+        Self { env, recorder }
+    }
+}
+
+impl Env for RecordingEnv {
+    fn spawn(&self, task: BoxFuture<'static, ()>) {
+        self.env.spawn(task)
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        self.env.sleep(duration)
+    }
+
+    fn open_sequential_writer<'a>(
+        &'a self,
+        path: &'a Path,
+    ) -> BoxFuture<'a, io::Result<Box<dyn SequentialWriter>>> {
+        Box::pin(async move {
+            let file = self.env.open_sequential_writer(path).await?;
+            let name = file_name(path);
+            // Writes to files other than page files are manifest updates.
+            let op = if parse_page_file_name(&name).is_some() {
+                IoOp::Flush
+            } else {
+                IoOp::Manifest
+            };
+            let writer = RecordingWriter {
+                file,
+                name,
+                op,
+                recorder: self.recorder.clone(),
+            };
+            Ok(Box::new(writer) as Box<dyn SequentialWriter>)
+        })
+    }
+
+    fn open_positional_reader<'a>(
+        &'a self,
+        path: &'a Path,
+    ) -> BoxFuture<'a, io::Result<Box<dyn PositionalReader>>> {
+        Box::pin(async move {
+            let file = self.env.open_positional_reader(path).await?;
+            let reader = RecordingReader {
+                file,
+                name: file_name(path),
+                recorder: self.recorder.clone(),
+            };
+            Ok(Box::new(reader) as Box<dyn PositionalReader>)
+        })
+    }
+
+    fn file_size<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<u64>> {
+        self.env.file_size(path)
+    }
+
+    fn create_dir_all<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+        self.env.create_dir_all(path)
+    }
+
+    fn read_dir<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<Vec<PathBuf>>> {
+        self.env.read_dir(path)
+    }
+
+    fn rename<'a>(&'a self, from: &'a Path, to: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+        self.env.rename(from, to)
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            self.env.remove_file(path).await?;
+            self.recorder.remove_file(&file_name(path));
+            Ok(())
+        })
+    }
+
+    fn sync_dir<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+        self.env.sync_dir(path)
+    }
+
+    fn lock_file<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<Box<dyn FileLock>>> {
+        self.env.lock_file(path)
+    }
+}
+
+struct RecordingWriter {
+    file: Box<dyn SequentialWriter>,
+    name: String,
+    op: IoOp,
+    recorder: Arc<IoRecorder>,
+}
+
+impl SequentialWriter for RecordingWriter {
+    fn write<'a>(&'a mut self, buf: &'a [u8]) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let start = Instant::now();
+            self.file.write(buf).await?;
+            self.recorder
+                .record(&self.name, self.op, start.elapsed(), |io| {
+                    io.num_writes += 1;
+                    io.write_bytes += buf.len() as u64;
+                });
+            Ok(())
+        })
+    }
+
+    fn sync_data(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async move {
+            let start = Instant::now();
+            self.file.sync_data().await?;
+            self.recorder
+                .record(&self.name, self.op, start.elapsed(), |_| {});
+            Ok(())
+        })
+    }
+}
+
+struct RecordingReader {
+    file: Box<dyn PositionalReader>,
+    name: String,
+    recorder: Arc<IoRecorder>,
+}
+
+impl PositionalReader for RecordingReader {
+    fn read_exact_at<'a>(
+        &'a self,
+        buf: &'a mut [u8],
+        offset: u64,
+    ) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let len = buf.len() as u64;
+            let start = Instant::now();
+            self.file.read_exact_at(buf, offset).await?;
+            let op = self.recorder.read_op();
+            self.recorder.record(&self.name, op, start.elapsed(), |io| {
+                io.num_reads += 1;
+                io.read_bytes += len;
+            });
+            Ok(())
+        })
+    }
+}
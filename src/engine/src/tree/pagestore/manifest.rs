@@ -0,0 +1,571 @@
+use std::{
+    fmt,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use uuid::Uuid;
+
+use super::{check_format_version, write_file, AtomicFile, FORMAT_VERSION};
+use crate::{
+    env::{Env, SequentialWriter},
+    tree::{Error, Result},
+};
+
+const CURRENT_NAME: &str = "CURRENT";
+const MANIFEST_PREFIX: &str = "MANIFEST-";
+/// A record is framed as `len (4B) | crc32 (4B) | payload (len B)`.
+const RECORD_HEADER_SIZE: usize = 8;
+/// The size above which a manifest file is rotated.
+const MAX_MANIFEST_FILE_SIZE: u64 = 4 << 20;
+/// The format version that adds `Manifest::page_bases`.
+const PAGE_BASES_VERSION: u32 = 9;
+
+/// A random id assigned to a store when it is created.
+///
+/// The id is recorded in the manifest and embedded in the names and footers of the files of the
+/// store, so that files from different stores can not be mixed up.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct RunId(Uuid);
+
+impl RunId {
+    pub fn random() -> Self {
+        Self(Uuid::new_v4())
+    }
+
+    pub fn from_bytes(bytes: [u8; 16]) -> Self {
+        Self(Uuid::from_bytes(bytes))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 16] {
+        self.0.as_bytes()
+    }
+
+    /// Returns the short form of the id used in file names.
+    pub fn short(&self) -> String {
+        let mut s = self.0.simple().to_string();
+        s.truncate(8);
+        s
+    }
+}
+
+impl fmt::Display for RunId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// The manifest of a store, which is the source of truth of the store on open.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Manifest {
+    /// The format version of the store, which is older than `FORMAT_VERSION` until the store is
+    /// migrated.
+    pub format_version: u32,
+    pub run_id: RunId,
+    /// The ids of the page files that belong to the store, with the largest LSN that each file may
+    /// contain.
+    ///
+    /// Other page files in the directory are left by interrupted writes.
+    pub files: Vec<(u64, u64)>,
+    /// The id of the root page.
+    pub root_id: u64,
+    /// The next page id to allocate.
+    pub next_page_id: u64,
+    /// The page table of the last checkpoint, which maps page ids to disk addresses.
+    pub page_table: Vec<(u64, u64)>,
+    /// Named counters of the owner of the store, which are kept across restarts.
+    pub counters: Vec<(String, u64)>,
+    /// The ids of the page files in `files` that are in the cold tier.
+    pub cold_files: Vec<u64>,
+    /// The disk addresses of the delta pages that the page table reaches, with the addresses of
+    /// the pages below them, so that the files of the whole chains are kept.
+    ///
+    /// It is always empty before format version 9.
+    pub page_bases: Vec<(u64, u64)>,
+}
+
+impl Manifest {
+    pub fn new() -> Self {
+        Self {
+            format_version: FORMAT_VERSION,
+            run_id: RunId::random(),
+            files: Vec::new(),
+            root_id: 0,
+            next_page_id: 0,
+            page_table: Vec::new(),
+            counters: Vec::new(),
+            cold_files: Vec::new(),
+            page_bases: Vec::new(),
+        }
+    }
+
+    /// Encodes the manifest as:
+    ///
+    /// `format_version (4B) | run_id (16B) | root_id (8B) | next_page_id (8B) |
+    /// num_files (8B) | (file_id, max_lsn) (16B) * num_files | num_pages (8B) |
+    /// (id, addr) (16B) * num_pages | num_counters (8B) |
+    /// (name_len (8B) | name | value (8B)) * num_counters | num_cold_files (8B) |
+    /// file_id (8B) * num_cold_files | num_page_bases (8B) | (addr, base) (16B) * num_page_bases`
+    ///
+    /// Versions before 9 end at the cold files.
+    fn encode(&self) -> Vec<u8> {
+        let counters_size: usize = self.counters.iter().map(|(name, _)| 16 + name.len()).sum();
+        let size = 4
+            + 16
+            + 8 * 6
+            + self.files.len() * 16
+            + self.page_table.len() * 16
+            + counters_size
+            + self.cold_files.len() * 8
+            + self.page_bases.len() * 16;
+        let mut buf = Vec::with_capacity(size);
+        buf.extend_from_slice(&self.format_version.to_le_bytes());
+        buf.extend_from_slice(self.run_id.as_bytes());
+        buf.extend_from_slice(&self.root_id.to_le_bytes());
+        buf.extend_from_slice(&self.next_page_id.to_le_bytes());
+        buf.extend_from_slice(&(self.files.len() as u64).to_le_bytes());
+        for (file_id, max_lsn) in &self.files {
+            buf.extend_from_slice(&file_id.to_le_bytes());
+            buf.extend_from_slice(&max_lsn.to_le_bytes());
+        }
+        buf.extend_from_slice(&(self.page_table.len() as u64).to_le_bytes());
+        for (id, addr) in &self.page_table {
+            buf.extend_from_slice(&id.to_le_bytes());
+            buf.extend_from_slice(&addr.to_le_bytes());
+        }
+        buf.extend_from_slice(&(self.counters.len() as u64).to_le_bytes());
+        for (name, value) in &self.counters {
+            buf.extend_from_slice(&(name.len() as u64).to_le_bytes());
+            buf.extend_from_slice(name.as_bytes());
+            buf.extend_from_slice(&value.to_le_bytes());
+        }
+        buf.extend_from_slice(&(self.cold_files.len() as u64).to_le_bytes());
+        for file_id in &self.cold_files {
+            buf.extend_from_slice(&file_id.to_le_bytes());
+        }
+        if self.format_version >= PAGE_BASES_VERSION {
+            buf.extend_from_slice(&(self.page_bases.len() as u64).to_le_bytes());
+            for (addr, base) in &self.page_bases {
+                buf.extend_from_slice(&addr.to_le_bytes());
+                buf.extend_from_slice(&base.to_le_bytes());
+            }
+        }
+        buf
+    }
+
+    fn decode(buf: &[u8]) -> Result<Self> {
+        let corrupted =
+            || Error::Corrupted(format!("manifest record has size {}", buf.len()).into());
+        let mut decoder = Decoder(buf);
+        let format_version = decoder.get_u32().ok_or_else(corrupted)?;
+        check_format_version(format_version, &"manifest")?;
+        let run_id = decoder.get_bytes(16).ok_or_else(corrupted)?;
+        let run_id = RunId::from_bytes(run_id.try_into().unwrap());
+        let root_id = decoder.get_u64().ok_or_else(corrupted)?;
+        let next_page_id = decoder.get_u64().ok_or_else(corrupted)?;
+        let num_files = decoder.get_len(16).ok_or_else(corrupted)?;
+        let files = (0..num_files)
+            .map(|_| (decoder.get_u64().unwrap(), decoder.get_u64().unwrap()))
+            .collect();
+        let num_pages = decoder.get_len(16).ok_or_else(corrupted)?;
+        let page_table: Vec<_> = (0..num_pages)
+            .map(|_| (decoder.get_u64().unwrap(), decoder.get_u64().unwrap()))
+            .collect();
+        let num_counters = decoder.get_len(16).ok_or_else(corrupted)?;
+        let mut counters = Vec::with_capacity(num_counters);
+        for _ in 0..num_counters {
+            let name_len = decoder.get_len(1).ok_or_else(corrupted)?;
+            let name = decoder.get_bytes(name_len).unwrap();
+            let name = String::from_utf8(name.to_vec()).map_err(|_| corrupted())?;
+            let value = decoder.get_u64().ok_or_else(corrupted)?;
+            counters.push((name, value));
+        }
+        let num_cold_files = decoder.get_len(8).ok_or_else(corrupted)?;
+        let cold_files = (0..num_cold_files)
+            .map(|_| decoder.get_u64().unwrap())
+            .collect();
+        let page_bases = if format_version >= PAGE_BASES_VERSION {
+            let num_page_bases = decoder.get_len(16).ok_or_else(corrupted)?;
+            (0..num_page_bases)
+                .map(|_| (decoder.get_u64().unwrap(), decoder.get_u64().unwrap()))
+                .collect()
+        } else {
+            Vec::new()
+        };
+        if !decoder.0.is_empty() {
+            return Err(corrupted());
+        }
+        if let Some((id, _)) = page_table.iter().find(|(id, _)| *id >= next_page_id) {
+            return Err(Error::Corrupted(
+                format!(
+                    "manifest has page {} beyond the next page id {}",
+                    id, next_page_id
+                )
+                .into(),
+            ));
+        }
+        Ok(Self {
+            format_version,
+            run_id,
+            files,
+            root_id,
+            next_page_id,
+            page_table,
+            counters,
+            cold_files,
+            page_bases,
+        })
+    }
+}
+
+struct Decoder<'a>(&'a [u8]);
+
+impl<'a> Decoder<'a> {
+    fn get_bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (bytes, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(bytes)
+    }
+
+    fn get_u32(&mut self) -> Option<u32> {
+        self.get_bytes(4)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+    }
+
+    fn get_u64(&mut self) -> Option<u64> {
+        self.get_bytes(8)
+            .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+    }
+
+    /// Reads the length of an array, and checks that the array with `item_size` fits in the rest.
+    fn get_len(&mut self, item_size: usize) -> Option<usize> {
+        let len = self.get_u64()?;
+        let size = len.checked_mul(item_size as u64)?;
+        if size > self.0.len() as u64 {
+            return None;
+        }
+        Some(len as usize)
+    }
+}
+
+/// A log of manifest records.
+///
+/// `CURRENT` names the manifest file in use, which holds a sequence of checksummed records, and
+/// the last complete record is the current manifest. A torn record at the end of the file is
+/// ignored, so a crash during an update leaves the previous manifest in effect.
+///
+/// The log is rotated to a new file on open and when it grows too large. The new file starts with
+/// a full record, and is installed by atomically replacing `CURRENT`.
+pub struct ManifestFile {
+    env: Arc<dyn Env>,
+    dir: PathBuf,
+    file_num: u64,
+    file: Box<dyn SequentialWriter>,
+    file_size: u64,
+    current: Manifest,
+}
+
+impl ManifestFile {
+    /// Opens the manifest log in `dir`, creating a new manifest if it doesn't exist.
+    pub async fn open(env: Arc<dyn Env>, dir: &Path) -> Result<(Self, Manifest)> {
+        let (file_num, manifest) = match read_current(env.as_ref(), dir).await? {
+            Some(file_num) => {
+                let path = manifest_path(dir, file_num);
+                let manifest = read_manifest(env.as_ref(), &path).await?;
+                (file_num, manifest)
+            }
+            None => (0, Manifest::new()),
+        };
+        let file = Self::create(env, dir, file_num + 1, &manifest).await?;
+        Ok((file, manifest))
+    }
+
+    /// Reads the current manifest in `dir` without changing any files, or returns `None` if there
+    /// is no manifest.
+    pub async fn read(env: &dyn Env, dir: &Path) -> Result<Option<Manifest>> {
+        match read_current(env, dir).await? {
+            Some(file_num) => {
+                let manifest = read_manifest(env, &manifest_path(dir, file_num)).await?;
+                Ok(Some(manifest))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Replaces the manifest in `dir` with `manifest`, even if the current one is unreadable.
+    pub async fn rebuild(env: Arc<dyn Env>, dir: &Path, manifest: &Manifest) -> Result<()> {
+        let mut file_num = 0;
+        for path in env.read_dir(dir).await? {
+            let name = path.file_name().and_then(|name| name.to_str());
+            if let Some(num) = name.and_then(parse_manifest_name) {
+                file_num = file_num.max(num);
+            }
+        }
+        Self::create(env, dir, file_num + 1, manifest).await?;
+        Ok(())
+    }
+
+    pub fn file_num(&self) -> u64 {
+        self.file_num
+    }
+
+    /// Returns the last recorded manifest.
+    pub fn current(&self) -> &Manifest {
+        &self.current
+    }
+
+    /// Records a new version of the manifest.
+    pub async fn record(&mut self, manifest: &Manifest) -> Result<()> {
+        if self.file_size >= MAX_MANIFEST_FILE_SIZE {
+            let env = self.env.clone();
+            *self = Self::create(env, &self.dir, self.file_num + 1, manifest).await?;
+            return Ok(());
+        }
+        let record = encode_record(&manifest.encode());
+        self.file.write(&record).await?;
+        self.file.sync_data().await?;
+        self.file_size += record.len() as u64;
+        self.current = manifest.clone();
+        Ok(())
+    }
+
+    /// Creates a manifest file with `manifest` and installs it as the current one.
+    async fn create(
+        env: Arc<dyn Env>,
+        dir: &Path,
+        file_num: u64,
+        manifest: &Manifest,
+    ) -> Result<Self> {
+        let record = encode_record(&manifest.encode());
+        // The file is appended to after it is installed.
+        let atomic_file = AtomicFile::new(manifest_path(dir, file_num));
+        let mut file = atomic_file.open(env.as_ref()).await?;
+        file.write(&record).await?;
+        file.sync_data().await?;
+        atomic_file.commit(env.as_ref()).await?;
+
+        fail_point!("manifest_before_current");
+        let content = format!("{}\n", manifest_name(file_num));
+        write_file(env.as_ref(), &dir.join(CURRENT_NAME), content.as_bytes()).await?;
+        fail_point!("manifest_after_current");
+
+        // Removes the obsolete manifest files, including the ones left by previous crashes.
+        for path in env.read_dir(dir).await? {
+            let name = path.file_name().and_then(|name| name.to_str());
+            match name.and_then(parse_manifest_name) {
+                Some(num) if num != file_num => env.remove_file(&path).await?,
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            env,
+            dir: dir.to_owned(),
+            file_num,
+            file,
+            file_size: record.len() as u64,
+            current: manifest.clone(),
+        })
+    }
+}
+
+fn manifest_name(file_num: u64) -> String {
+    format!("{}{:06}", MANIFEST_PREFIX, file_num)
+}
+
+fn manifest_path(dir: &Path, file_num: u64) -> PathBuf {
+    dir.join(manifest_name(file_num))
+}
+
+fn parse_manifest_name(name: &str) -> Option<u64> {
+    name.strip_prefix(MANIFEST_PREFIX)?.parse().ok()
+}
+
+fn encode_record(payload: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(RECORD_HEADER_SIZE + payload.len());
+    buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&crc32fast::hash(payload).to_le_bytes());
+    buf.extend_from_slice(payload);
+    buf
+}
+
+async fn read_file(env: &dyn Env, path: &Path) -> std::io::Result<Vec<u8>> {
+    let file = env.open_positional_reader(path).await?;
+    let size = env.file_size(path).await?;
+    let mut buf = vec![0; size as usize];
+    file.read_exact_at(&mut buf, 0).await?;
+    Ok(buf)
+}
+
+/// Returns the number of the current manifest file, or `None` if there is no `CURRENT`.
+async fn read_current(env: &dyn Env, dir: &Path) -> Result<Option<u64>> {
+    let buf = match read_file(env, &dir.join(CURRENT_NAME)).await {
+        Ok(buf) => buf,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let file_num = std::str::from_utf8(&buf)
+        .ok()
+        .and_then(|s| s.strip_suffix('\n'))
+        .and_then(parse_manifest_name);
+    match file_num {
+        Some(file_num) => Ok(Some(file_num)),
+        None => Err(Error::Corrupted(
+            format!(
+                "{} has invalid content {:?}",
+                CURRENT_NAME,
+                String::from_utf8_lossy(&buf)
+            )
+            .into(),
+        )),
+    }
+}
+
+/// Reads the last complete record of a manifest file.
+async fn read_manifest(env: &dyn Env, path: &Path) -> Result<Manifest> {
+    let buf = read_file(env, path).await?;
+    decode_manifest_file(path, &buf)
+}
+
+/// Decodes the last complete record of the content of a manifest file at `path`.
+pub fn decode_manifest_file(path: &Path, buf: &[u8]) -> Result<Manifest> {
+    let mut last = None;
+    let mut rest = buf;
+    while rest.len() >= RECORD_HEADER_SIZE {
+        let len = u32::from_le_bytes(rest[0..4].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(rest[4..8].try_into().unwrap());
+        let rest_len = rest.len() - RECORD_HEADER_SIZE;
+        if len > rest_len {
+            // The last record is torn.
+            break;
+        }
+        let payload = &rest[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + len];
+        if crc32fast::hash(payload) != crc {
+            if len == rest_len {
+                // The last record is torn.
+                break;
+            }
+            return Err(Error::Corrupted(
+                format!(
+                    "manifest {} has a checksum mismatch at offset {}",
+                    path.display(),
+                    buf.len() - rest.len()
+                )
+                .into(),
+            ));
+        }
+        last = Some(payload);
+        rest = &rest[RECORD_HEADER_SIZE + len..];
+    }
+    match last {
+        Some(payload) => Manifest::decode(payload),
+        None => Err(Error::Corrupted(
+            format!("manifest {} has no complete record", path.display()).into(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs::OpenOptions, io::Write};
+
+    use super::*;
+    use crate::env::TokioEnv;
+
+    fn append(path: &Path, buf: &[u8]) {
+        let mut file = OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(buf).unwrap();
+    }
+
+    #[tokio::test]
+    async fn open_and_rotate() {
+        let env: Arc<dyn Env> = Arc::new(TokioEnv::current());
+        let dir = tempfile::tempdir().unwrap();
+        let (mut file, manifest) = ManifestFile::open(env.clone(), dir.path()).await.unwrap();
+        assert_eq!(file.file_num(), 1);
+        let updated = Manifest {
+            files: vec![(3, 10), (4, 20)],
+            root_id: 0,
+            next_page_id: 2,
+            page_table: vec![(0, 1), (1, 2)],
+            ..Manifest::new()
+        };
+        file.record(&updated).await.unwrap();
+        drop(file);
+
+        let (file, reopened) = ManifestFile::open(env.clone(), dir.path()).await.unwrap();
+        assert_ne!(reopened, manifest);
+        assert_eq!(reopened, updated);
+        assert_eq!(file.file_num(), 2);
+        assert!(!manifest_path(dir.path(), 1).exists());
+    }
+
+    #[tokio::test]
+    async fn torn_record() {
+        let env: Arc<dyn Env> = Arc::new(TokioEnv::current());
+        let dir = tempfile::tempdir().unwrap();
+        let (file, manifest) = ManifestFile::open(env.clone(), dir.path()).await.unwrap();
+        let path = manifest_path(dir.path(), file.file_num());
+        drop(file);
+
+        // A torn header and a torn payload are both ignored.
+        append(&path, &[1, 2, 3]);
+        let (file, reopened) = ManifestFile::open(env.clone(), dir.path()).await.unwrap();
+        assert_eq!(reopened, manifest);
+        let path = manifest_path(dir.path(), file.file_num());
+        drop(file);
+        let mut record = encode_record(&Manifest::new().encode());
+        record.truncate(record.len() - 1);
+        append(&path, &record);
+        let (file, reopened) = ManifestFile::open(env.clone(), dir.path()).await.unwrap();
+        assert_eq!(reopened, manifest);
+        let path = manifest_path(dir.path(), file.file_num());
+        drop(file);
+
+        // A corrupted record followed by other records is not.
+        let mut record = encode_record(&Manifest::new().encode());
+        record[RECORD_HEADER_SIZE] ^= 1;
+        append(&path, &record);
+        append(&path, &encode_record(&Manifest::new().encode()));
+        let err = ManifestFile::open(env.clone(), dir.path())
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, Error::Corrupted(_)));
+    }
+
+    #[test]
+    fn encode_and_decode() {
+        let manifest = Manifest {
+            files: vec![(1, 10), (2, 20), (3, 30)],
+            root_id: 5,
+            next_page_id: 9,
+            page_table: vec![(5, 1 << 40), (8, 2 << 40)],
+            counters: vec![("a".to_owned(), 1), ("".to_owned(), 2)],
+            cold_files: vec![2],
+            page_bases: vec![(8 << 40, 2 << 40)],
+            ..Manifest::new()
+        };
+        let buf = manifest.encode();
+        assert_eq!(Manifest::decode(&buf).unwrap(), manifest);
+        for len in 0..buf.len() {
+            assert!(Manifest::decode(&buf[..len]).is_err());
+        }
+
+        // Older versions are decoded for migrations, and newer versions are refused.
+        let older = Manifest {
+            format_version: FORMAT_VERSION - 1,
+            page_bases: Vec::new(),
+            ..manifest
+        };
+        let mut buf = older.encode();
+        assert_eq!(Manifest::decode(&buf).unwrap(), older);
+        buf[0..4].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        let err = Manifest::decode(&buf).unwrap_err();
+        assert!(matches!(err, Error::NotSupported(_)));
+    }
+}
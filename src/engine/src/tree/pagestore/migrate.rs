@@ -0,0 +1,157 @@
+use std::{path::Path, sync::Arc};
+
+use super::{
+    lock_dir, page_file_name, remove_tmp_files, AtomicFile, Manifest, ManifestFile, PageFileReader,
+    PageStore, FORMAT_VERSION,
+};
+use crate::{
+    env::Env,
+    tree::{Error, Options, Result},
+};
+
+impl PageStore {
+    /// Migrates the store in `path`, which must not be opened, to the current format version in
+    /// place, and returns the version that it is migrated from.
+    ///
+    /// Each step upgrades the store by one version, and records the new version in the manifest
+    /// when it is done, so an interrupted migration is resumed by the next one. `opts` must be the
+    /// options that the store is opened with, to find the page files in `Options::cold_tier`.
+    pub async fn migrate(env: Arc<dyn Env>, path: &Path, opts: &Options) -> Result<u32> {
+        let _lock = lock_dir(env.as_ref(), path).await?;
+        if ManifestFile::read(env.as_ref(), path).await?.is_none() {
+            return Err(Error::Corrupted(
+                format!("{} has no store to migrate", path.display()).into(),
+            ));
+        }
+        remove_tmp_files(env.as_ref(), path).await?;
+        if let Some(tier) = &opts.cold_tier {
+            remove_tmp_files(tier.env.as_ref(), &tier.path).await?;
+        }
+        let (mut manifest_file, mut manifest) = ManifestFile::open(env.clone(), path).await?;
+        let from = manifest.format_version;
+        while manifest.format_version < FORMAT_VERSION {
+            match manifest.format_version {
+                7 => upgrade_page_file_footers(env.as_ref(), path, opts, &manifest).await?,
+                // Version 9 adds the bases of delta pages to the manifest, and stores of version
+                // 8 have no delta pages, so recording the new version is enough.
+                8 => {}
+                version => unreachable!("format version {} has no migration", version),
+            }
+            manifest.format_version += 1;
+            manifest_file.record(&manifest).await?;
+        }
+        Ok(from)
+    }
+}
+
+/// Rewrites the page files of `manifest` with footers of the next format version.
+///
+/// Version 8 adds the format version to the footers of page files.
+async fn upgrade_page_file_footers(
+    env: &dyn Env,
+    path: &Path,
+    opts: &Options,
+    manifest: &Manifest,
+) -> Result<()> {
+    let version = manifest.format_version + 1;
+    for &(file_id, _) in &manifest.files {
+        let (env, dir) = if manifest.cold_files.contains(&file_id) {
+            match &opts.cold_tier {
+                Some(tier) => (tier.env.as_ref(), tier.path.as_path()),
+                None => {
+                    return Err(Error::Corrupted(
+                        format!(
+                    "manifest has page file {} in the cold tier, but no cold tier is configured",
+                    file_id
+                )
+                        .into(),
+                    ))
+                }
+            }
+        } else {
+            (env, path)
+        };
+        let file_path = dir.join(page_file_name(file_id, manifest.run_id));
+        let file_size = env.file_size(&file_path).await?;
+        let file = env.open_positional_reader(&file_path).await?;
+        let reader = PageFileReader::open(file, file_size).await?;
+        if reader.format_version() >= version {
+            // The file is upgraded by an interrupted migration.
+            continue;
+        }
+        let file = AtomicFile::new(file_path);
+        let mut writer = file.open(env).await?;
+        reader.copy_with_footer(writer.as_mut(), version).await?;
+        drop(writer);
+        file.commit(env).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{env::TokioEnv, tree::Table};
+
+    /// Rewrites the store in `path` as if it is written with `version`.
+    async fn set_format_version(env: Arc<dyn Env>, path: &Path, version: u32) {
+        let mut manifest = ManifestFile::read(env.as_ref(), path)
+            .await
+            .unwrap()
+            .unwrap();
+        for &(file_id, _) in &manifest.files {
+            let file_path = path.join(page_file_name(file_id, manifest.run_id));
+            let file_size = env.file_size(&file_path).await.unwrap();
+            let file = env.open_positional_reader(&file_path).await.unwrap();
+            let reader = PageFileReader::open(file, file_size).await.unwrap();
+            let file = AtomicFile::new(file_path);
+            let mut writer = file.open(env.as_ref()).await.unwrap();
+            reader
+                .copy_with_footer(writer.as_mut(), version)
+                .await
+                .unwrap();
+            drop(writer);
+            file.commit(env.as_ref()).await.unwrap();
+        }
+        manifest.format_version = version;
+        ManifestFile::rebuild(env, path, &manifest).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn migrate() {
+        let env: Arc<dyn Env> = Arc::new(TokioEnv::current());
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path();
+        let opts = Options {
+            data_node_size: 64,
+            ..Default::default()
+        };
+        let table = Table::open(path, opts.clone()).await.unwrap();
+        for i in 0..64u64 {
+            let buf = i.to_be_bytes();
+            table.put(&buf, i, &buf).await.unwrap();
+        }
+        table.close().await.unwrap();
+
+        set_format_version(env.clone(), path, 7).await;
+        let err = Table::open(path, opts.clone()).await.err().unwrap();
+        assert!(matches!(err, Error::NotSupported(_)));
+        let from = PageStore::migrate(env.clone(), path, &opts).await.unwrap();
+        assert_eq!(from, 7);
+        let from = PageStore::migrate(env.clone(), path, &opts).await.unwrap();
+        assert_eq!(from, FORMAT_VERSION);
+        let table = Table::open(path, opts.clone()).await.unwrap();
+        for i in 0..64u64 {
+            let buf = i.to_be_bytes();
+            assert_eq!(table.get(&buf, i).await.unwrap(), Some(buf.to_vec()));
+        }
+        table.close().await.unwrap();
+
+        // Newer versions are refused.
+        set_format_version(env.clone(), path, FORMAT_VERSION + 1).await;
+        let err = Table::open(path, opts.clone()).await.err().unwrap();
+        assert!(matches!(err, Error::NotSupported(_)));
+        let err = PageStore::migrate(env, path, &opts).await.unwrap_err();
+        assert!(matches!(err, Error::NotSupported(_)));
+    }
+}
@@ -1,2 +1,58 @@
+#[allow(dead_code)]
+mod file;
+pub use file::{page_file_name, parse_page_file_name, PageFileReader, PageFileWriter, PageHandle};
+
+#[allow(dead_code)]
+mod manifest;
+#[cfg(feature = "fuzzing")]
+pub use manifest::decode_manifest_file;
+pub use manifest::{Manifest, ManifestFile, RunId};
+
+#[allow(dead_code)]
 mod store;
-pub use store::{PageInfo, PageStore};
+use store::{corruption_at, disk_addr, file_id_of, lock_dir, offset_of};
+pub use store::{dealloc_chain, PageInfo, PageStore};
+
+mod repair;
+
+mod migrate;
+
+mod atomic_file;
+use atomic_file::{
+    copy_file, copy_file_across, remove_tmp_files, write_file, AtomicFile, TMP_SUFFIX,
+};
+#[cfg(test)]
+use atomic_file::{FaultEnv, FaultOp};
+
+mod io_stats;
+use io_stats::{IoRecorder, RecordingEnv};
+
+use crate::tree::{Error, Result};
+
+/// The version of the format of manifests and page files.
+///
+/// Bump it on incompatible changes, including changes of the page layout, along with a migration
+/// from the previous version in `migrate.rs`.
+const FORMAT_VERSION: u32 = 9;
+/// The oldest format version that can be migrated.
+const MIN_FORMAT_VERSION: u32 = 7;
+
+/// Checks the format version of a file that the store reads.
+///
+/// Newer versions are refused, since they may be written by a newer release in ways that this one
+/// can't read back.
+fn check_format_version(version: u32, what: &dyn std::fmt::Display) -> Result<()> {
+    if version > FORMAT_VERSION {
+        return Err(Error::NotSupported(format!(
+            "{} has format version {}, newer than the supported version {}",
+            what, version, FORMAT_VERSION
+        )));
+    }
+    if version < MIN_FORMAT_VERSION {
+        return Err(Error::NotSupported(format!(
+            "{} has format version {}, older than the oldest supported version {}",
+            what, version, MIN_FORMAT_VERSION
+        )));
+    }
+    Ok(())
+}
@@ -0,0 +1,422 @@
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use super::{
+    corruption_at, disk_addr, file_id_of, lock_dir, offset_of, page_file_name,
+    parse_page_file_name, remove_tmp_files, AtomicFile, Manifest, ManifestFile, PageFileReader,
+    PageFileWriter, PageHandle, PageInfo, PageStore, RunId, FORMAT_VERSION,
+};
+use crate::{
+    env::{Env, PositionalReader},
+    tree::{
+        page::{
+            decode_page_image, encode_page_image, DataPageBuilder, DataPageRef, Index, Key,
+            MergingIterBuilder, PageAlloc, PagePtr, SliceIter, Value,
+        },
+        pagecache::PageAddr,
+        Comparator, Error, Options, RepairReport, Result,
+    },
+};
+
+type PageFile = PageFileReader<Box<dyn PositionalReader>>;
+
+/// A page file found by a repair.
+struct ScannedFile {
+    reader: PageFile,
+    handles: HashMap<u64, PageHandle>,
+}
+
+/// A leaf salvaged by a repair, with the start of its key range.
+struct Leaf {
+    page: PagePtr,
+    low: Vec<u8>,
+    file_id: u64,
+}
+
+impl PageStore {
+    /// Repairs the store in `path`, which must not be opened, by salvaging the pages that are still
+    /// readable.
+    ///
+    /// The nodes in the page table of the manifest are read, or the latest page of each node in
+    /// the page files if the manifest is unreadable. Leaves that are intact are rewritten to a new
+    /// page file with a new root of `root_id` over them, and recorded in a new manifest, while
+    /// index nodes are rebuilt, and unreadable leaves are dropped. The old page files are removed
+    /// when the store is opened again.
+    ///
+    /// Returns what is salvaged and what is lost, with the key ranges of the dropped leaves if
+    /// they are known.
+    pub async fn repair<A>(
+        env: Arc<dyn Env>,
+        path: &Path,
+        opts: &Options,
+        root_id: u64,
+        alloc: &A,
+    ) -> Result<RepairReport>
+    where
+        A: PageAlloc<Error = Error>,
+    {
+        let _lock = lock_dir(env.as_ref(), path).await?;
+        remove_tmp_files(env.as_ref(), path).await?;
+        let mut report = RepairReport::default();
+        let manifest = match ManifestFile::read(env.as_ref(), path).await {
+            Ok(manifest) => manifest,
+            Err(err) => {
+                report.lost.push(format!("manifest is unreadable: {}", err));
+                None
+            }
+        };
+
+        let mut dirs = vec![(env.clone(), path.to_owned())];
+        if let Some(tier) = &opts.cold_tier {
+            dirs.push((tier.env.clone(), tier.path.clone()));
+        }
+        let run_id = manifest.as_ref().map(|manifest| manifest.run_id);
+        let (run_id, files) = scan_page_files(&dirs, run_id, &mut report).await?;
+
+        // The nodes to salvage and their disk addresses.
+        let page_table: BTreeMap<u64, u64> = match &manifest {
+            Some(manifest) => manifest.page_table.iter().copied().collect(),
+            None => {
+                let mut page_table = BTreeMap::new();
+                let mut file_ids: Vec<_> = files.keys().copied().collect();
+                file_ids.sort_unstable();
+                for file_id in file_ids {
+                    for (&offset, handle) in &files[&file_id].handles {
+                        let addr = disk_addr(file_id, offset);
+                        page_table.insert(handle.id, addr);
+                    }
+                }
+                page_table
+            }
+        };
+        let mut pages = HashMap::new();
+        let mut unreadable = BTreeMap::new();
+        for (&id, &addr) in &page_table {
+            match load_page(&files, addr, alloc, &opts.comparator).await {
+                Ok(page) => {
+                    pages.insert(id, page);
+                }
+                Err(err) => {
+                    unreadable.insert(id, err);
+                }
+            }
+        }
+
+        // Walks the readable part of the tree to find the key ranges of the nodes.
+        let root = manifest
+            .as_ref()
+            .map_or(root_id, |manifest| manifest.root_id);
+        let mut ranges = HashMap::new();
+        let mut stack = vec![(root, Vec::new(), Vec::new())];
+        while let Some((id, low, high)) = stack.pop() {
+            match pages.get(&id) {
+                Some(page) if page.is_index() => {
+                    let page = unsafe { DataPageRef::<&[u8], Index>::new(*page) };
+                    for i in 0..page.len() {
+                        let (key, index) = page.get(i).unwrap();
+                        let child_high = match page.get(i + 1) {
+                            Some((next, _)) => next.to_vec(),
+                            None => high.clone(),
+                        };
+                        stack.push((index.id, key.to_vec(), child_high));
+                    }
+                }
+                _ => {
+                    ranges.insert(id, (low, high));
+                }
+            }
+        }
+        for (id, err) in unreadable {
+            let msg = match ranges.get(&id) {
+                Some((low, high)) => format!(
+                    "node {} covering keys in [{}, {}) is dropped: {}",
+                    id,
+                    low.escape_ascii(),
+                    high.escape_ascii(),
+                    err
+                ),
+                None => format!("node {} covering unknown keys is dropped: {}", id, err),
+            };
+            report.lost.push(msg);
+        }
+
+        let cmp = opts.comparator.as_ref();
+        let mut leaves = Vec::new();
+        for (&id, &page) in &pages {
+            if page.is_index() {
+                continue;
+            }
+            let data = unsafe { DataPageRef::<Key, Value>::new(page) };
+            let low = match (ranges.get(&id), data.get(0)) {
+                (Some((low, _)), _) => low.clone(),
+                (None, Some((key, _))) => key.raw.to_vec(),
+                // Empty leaves out of the tree have nothing to salvage.
+                (None, None) => continue,
+            };
+            let file_id = file_id_of(page_table[&id]);
+            leaves.push(Leaf { page, low, file_id });
+        }
+        // Leaves of the same range are stale versions of each other without a manifest, and the
+        // one in the latest file wins.
+        leaves.sort_by(|a, b| match cmp.compare(&a.low, &b.low) {
+            Ordering::Equal => b.file_id.cmp(&a.file_id),
+            o => o,
+        });
+        leaves.dedup_by(|stale, leaf| cmp.compare(&stale.low, &leaf.low).is_eq());
+        if let Some(first) = leaves.first_mut() {
+            // The first leaf covers all the keys below the others.
+            first.low.clear();
+        }
+
+        // Rewrites the leaves with only the keys in their new ranges.
+        let mut rebuilt = Vec::with_capacity(leaves.len() + 1);
+        let mut root_entries = Vec::with_capacity(leaves.len());
+        let mut max_lsn = manifest
+            .as_ref()
+            .and_then(|manifest| manifest.files.iter().map(|&(_, lsn)| lsn).max())
+            .unwrap_or(0);
+        let builder = || DataPageBuilder::default().sort_prefixes(cmp.is_bytewise());
+        for (i, leaf) in leaves.iter().enumerate() {
+            let high = leaves.get(i + 1).map(|next| next.low.as_slice());
+            let data = unsafe { DataPageRef::<Key, Value>::new(leaf.page) };
+            let entries: Vec<_> = (0..data.len())
+                .map(|i| data.get(i).unwrap())
+                .filter(|(key, _)| {
+                    cmp.compare(key.raw, &leaf.low).is_ge()
+                        && !matches!(high, Some(high) if cmp.compare(key.raw, high).is_ge())
+                })
+                .collect();
+            report.num_leaves += 1;
+            report.num_entries += entries.len() as u64;
+            max_lsn = entries
+                .iter()
+                .fold(max_lsn, |lsn, (key, _)| lsn.max(key.lsn));
+            let mut iter = SliceIter::from(entries.as_slice());
+            let mut page = builder().build_from_iter(alloc, &mut iter)?;
+            page.set_ver(leaf.page.ver());
+            let id = root_id + 1 + i as u64;
+            root_entries.push((leaf.low.as_slice(), Index::new(id, page.ver())));
+            rebuilt.push((id, page.as_ptr()));
+        }
+        if root_entries.is_empty() {
+            let mut page = builder().build(alloc)?;
+            let id = root_id + 1;
+            root_entries.push((&[], Index::new(id, page.ver())));
+            rebuilt.push((id, page.as_ptr()));
+        }
+        let mut iter = SliceIter::from(root_entries.as_slice());
+        let mut root = builder().build_from_iter(alloc, &mut iter)?;
+        root.set_index(true);
+        rebuilt.push((root_id, root.as_ptr()));
+
+        let next_file_id = files.keys().max().map_or(1, |id| id + 1);
+        let next_file_id = manifest
+            .as_ref()
+            .and_then(|manifest| manifest.files.iter().map(|&(id, _)| id + 1).max())
+            .map_or(next_file_id, |id| id.max(next_file_id));
+        let result = write_page_file(
+            env.as_ref(),
+            path,
+            run_id,
+            next_file_id,
+            &rebuilt,
+            opts.page_restart_interval,
+        )
+        .await;
+        for (_, page) in rebuilt {
+            unsafe { alloc.dealloc(page) };
+        }
+        for (_, page) in pages {
+            unsafe { alloc.dealloc(page) };
+        }
+        let mut page_table = result?;
+        page_table.sort_unstable();
+        let repaired = Manifest {
+            format_version: FORMAT_VERSION,
+            run_id,
+            files: vec![(next_file_id, max_lsn)],
+            root_id,
+            next_page_id: root_id + page_table.len() as u64,
+            page_table,
+            counters: manifest
+                .map(|manifest| manifest.counters)
+                .unwrap_or_default(),
+            cold_files: Vec::new(),
+            page_bases: Vec::new(),
+        };
+        ManifestFile::rebuild(env, path, &repaired).await?;
+        Ok(report)
+    }
+}
+
+/// Opens the readable page files of the store in `dirs`, and returns them by file id with the run
+/// id of the store.
+///
+/// Without `run_id` from the manifest, the store is the run with the most page files.
+async fn scan_page_files(
+    dirs: &[(Arc<dyn Env>, PathBuf)],
+    run_id: Option<RunId>,
+    report: &mut RepairReport,
+) -> Result<(RunId, HashMap<u64, ScannedFile>)> {
+    let mut files = Vec::new();
+    for (env, dir) in dirs {
+        for path in env.read_dir(dir).await? {
+            let name = path.file_name().and_then(|name| name.to_str());
+            let short_run_id = match name.and_then(parse_page_file_name) {
+                Some((_, short_run_id)) => short_run_id.to_owned(),
+                None => continue,
+            };
+            if matches!(run_id, Some(run_id) if run_id.short() != short_run_id) {
+                continue;
+            }
+            match open_page_file(env.as_ref(), &path).await {
+                Ok(file) => files.push(file),
+                Err(err) => {
+                    report
+                        .lost
+                        .push(format!("page file {} is dropped: {}", path.display(), err))
+                }
+            }
+        }
+    }
+    let run_id = match run_id {
+        Some(run_id) => run_id,
+        None => {
+            let mut counts = HashMap::new();
+            for file in &files {
+                *counts.entry(file.reader.run_id()).or_insert(0) += 1;
+            }
+            match counts.into_iter().max_by_key(|&(_, count)| count) {
+                Some((run_id, _)) => run_id,
+                None => return Err(Error::Corrupted("no store to repair".into())),
+            }
+        }
+    };
+    let files = files
+        .into_iter()
+        .filter(|file| file.reader.run_id() == run_id)
+        .map(|file| (file.reader.file_id(), file))
+        .collect();
+    Ok((run_id, files))
+}
+
+async fn open_page_file(env: &dyn Env, path: &Path) -> Result<ScannedFile> {
+    let file = env.open_positional_reader(path).await?;
+    let file_size = env.file_size(path).await?;
+    let reader = PageFileReader::open(file, file_size).await?;
+    let handles = reader
+        .read_index()
+        .await?
+        .into_iter()
+        .map(|handle| (handle.block.offset, handle))
+        .collect();
+    Ok(ScannedFile { reader, handles })
+}
+
+/// Loads and validates the page at `addr`, which is consolidated with the pages below it if it is
+/// a delta.
+async fn load_page<A>(
+    files: &HashMap<u64, ScannedFile>,
+    addr: u64,
+    alloc: &A,
+    cmp: &Arc<dyn Comparator>,
+) -> Result<PagePtr>
+where
+    A: PageAlloc<Error = Error>,
+{
+    let head = load_image(files, addr, alloc).await?;
+    if head.len() == 0 {
+        return Ok(head);
+    }
+    // The chain is loaded from the newest page to the oldest.
+    let mut pages = vec![head];
+    let mut result = Ok(());
+    while let PageAddr::Disk(base) = PageAddr::from(pages.last().unwrap().next()) {
+        match load_image(files, base, alloc).await {
+            // Lengths decrease along the chain, so that a malformed chain can't loop forever.
+            Ok(page) if pages.last().unwrap().len().checked_sub(1) == Some(page.len()) => {
+                pages.push(page)
+            }
+            Ok(page) => {
+                let message = format!("page at {} has a mismatched length {}", base, page.len());
+                unsafe { alloc.dealloc(page) };
+                result = Err(Error::Corrupted(corruption_at(base, message)));
+                break;
+            }
+            Err(err) => {
+                result = Err(err);
+                break;
+            }
+        }
+    }
+    let result = result.and_then(|()| {
+        let mut merger = MergingIterBuilder::new(cmp.clone()).dedup(true);
+        for &page in &pages {
+            merger.add(unsafe { DataPageRef::<Key, Value>::new(page) }.iter());
+        }
+        let mut iter = merger.build();
+        let mut page = DataPageBuilder::default().build_from_iter(alloc, &mut iter)?;
+        page.set_ver(head.ver());
+        Ok(page.as_ptr())
+    });
+    for page in pages {
+        unsafe { alloc.dealloc(page) };
+    }
+    result
+}
+
+/// Loads and validates the image at `addr` as a single page.
+async fn load_image<A>(files: &HashMap<u64, ScannedFile>, addr: u64, alloc: &A) -> Result<PagePtr>
+where
+    A: PageAlloc<Error = Error>,
+{
+    let file_id = file_id_of(addr);
+    let file = files.get(&file_id).ok_or_else(|| {
+        let message = format!("page file {} is lost", file_id);
+        Error::Corrupted(corruption_at(addr, message))
+    })?;
+    let handle = file.handles.get(&offset_of(addr)).ok_or_else(|| {
+        let message = format!("page at {} is not in its page file", addr);
+        Error::Corrupted(corruption_at(addr, message))
+    })?;
+    let image = file.reader.read_page(handle).await?;
+    let page = decode_page_image(&image, alloc)?.ok_or_else(|| {
+        let message = format!("page at {} has a malformed image", addr);
+        Error::Corrupted(corruption_at(addr, message).with_page(handle.id))
+    })?;
+    if PageInfo::from(page) != handle.info {
+        unsafe { alloc.dealloc(page) };
+        let message = format!("page at {} does not match its handle {:?}", addr, handle);
+        return Err(Error::Corrupted(
+            corruption_at(addr, message).with_page(handle.id),
+        ));
+    }
+    Ok(page)
+}
+
+/// Writes `pages` to a new page file, and returns their ids and disk addresses.
+async fn write_page_file(
+    env: &dyn Env,
+    dir: &Path,
+    run_id: RunId,
+    file_id: u64,
+    pages: &[(u64, PagePtr)],
+    restart_interval: u32,
+) -> Result<Vec<(u64, u64)>> {
+    let atomic_file = AtomicFile::new(dir.join(page_file_name(file_id, run_id)));
+    let file = atomic_file.open(env).await?;
+    let mut writer = PageFileWriter::new(file, run_id, file_id);
+    let mut page_table = Vec::with_capacity(pages.len());
+    for &(id, page) in pages {
+        let image = encode_page_image(page, restart_interval);
+        let handle = writer.add_page(id, page.into(), &image).await?;
+        page_table.push((id, disk_addr(file_id, handle.block.offset)));
+    }
+    writer.finish().await?;
+    atomic_file.commit(env).await?;
+    Ok(page_table)
+}
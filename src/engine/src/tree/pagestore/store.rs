@@ -1,35 +1,1342 @@
-use crate::tree::{
-    page::{PagePtr, PageVer},
-    Options, Result,
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Instant,
 };
 
+use futures::future::try_join_all;
+use tokio::sync::Mutex as AsyncMutex;
+
+use super::{
+    copy_file, copy_file_across, page_file_name, parse_page_file_name, remove_tmp_files,
+    AtomicFile, IoRecorder, Manifest, ManifestFile, PageFileReader, PageFileWriter, PageHandle,
+    RecordingEnv, RunId, FORMAT_VERSION,
+};
+use crate::{
+    env::{Env, FileLock, PositionalReader},
+    tree::{
+        page::{decode_page_image, encode_page_image, PageAlloc, PagePtr, PageVer},
+        pagecache::PageAddr,
+        Corruption, Error, IoStats, ManifestInfo, Options, PageFileInfo, Result, TierStats,
+        TieringPolicy,
+    },
+};
+
+/// The name of the file that a writable store locks in its directory.
+const LOCK_NAME: &str = "LOCK";
+
+/// The number of bits of the file offset in a disk address.
+///
+/// A disk address consists of the id of a page file and the offset of a page in the file.
+const FILE_OFFSET_BITS: u32 = 40;
+
+pub(super) fn disk_addr(file_id: u64, offset: u64) -> u64 {
+    (file_id << FILE_OFFSET_BITS) | offset
+}
+
+pub(super) fn file_id_of(addr: u64) -> u64 {
+    addr >> FILE_OFFSET_BITS
+}
+
+pub(super) fn offset_of(addr: u64) -> u64 {
+    addr & ((1 << FILE_OFFSET_BITS) - 1)
+}
+
+/// Returns the context of a corruption of the page at `addr`.
+pub(super) fn corruption_at(addr: u64, message: String) -> Corruption {
+    Corruption::new(message).with_file(file_id_of(addr), offset_of(addr))
+}
+
+/// Decodes the image of the page at `addr` into a page allocated from `alloc`, and checks it
+/// against its handle.
+fn decode_image<A>(addr: u64, handle: &PageHandle, image: &[u8], alloc: &A) -> Result<PagePtr>
+where
+    A: PageAlloc<Error = Error>,
+{
+    let page = match decode_page_image(image, alloc)? {
+        Some(page) => page,
+        None => {
+            let message = format!("page at {} has a malformed image", addr);
+            return Err(Error::Corrupted(
+                corruption_at(addr, message).with_page(handle.id),
+            ));
+        }
+    };
+    if PageInfo::from(page) != handle.info {
+        unsafe { alloc.dealloc(page) };
+        let message = format!("page at {} does not match its handle {:?}", addr, handle);
+        return Err(Error::Corrupted(
+            corruption_at(addr, message).with_page(handle.id),
+        ));
+    }
+    Ok(page)
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct PageInfo {
     pub ver: PageVer,
     pub len: u8,
     pub is_index: bool,
 }
 
-pub struct PageStore {}
+impl From<PagePtr> for PageInfo {
+    fn from(page: PagePtr) -> Self {
+        Self {
+            ver: page.ver(),
+            len: page.len(),
+            is_index: page.is_index(),
+        }
+    }
+}
+
+type PageFile = PageFileReader<Box<dyn PositionalReader>>;
+// Pages that follow one another in a file, with their addresses.
+type PageRun = (Arc<PageFile>, Vec<(u64, PageHandle)>);
+
+pub struct PageStore {
+    env: Arc<dyn Env>,
+    // The env without I/O statistics, which backups use to not mix with the I/O of the store.
+    raw_env: Arc<dyn Env>,
+    path: PathBuf,
+    run_id: RunId,
+    restart_interval: u32,
+    // The manifest when the store is opened.
+    recovered: Manifest,
+    files: Mutex<PageFiles>,
+    // The manifest log, or `None` if the store is read-only.
+    manifest_file: AsyncMutex<Option<ManifestFile>>,
+    io_recorder: Arc<IoRecorder>,
+    cold: Option<ColdStore>,
+    // The lock of the store directory, or `None` if the store is read-only.
+    _lock: Option<Box<dyn FileLock>>,
+}
+
+/// The cold tier of `Options::cold_tier`.
+struct ColdStore {
+    env: Arc<dyn Env>,
+    raw_env: Arc<dyn Env>,
+    path: PathBuf,
+    policy: TieringPolicy,
+}
+
+#[derive(Default)]
+struct PageFiles {
+    next_file_id: u64,
+    readers: HashMap<u64, Arc<PageFile>>,
+    // The largest LSN that each file may contain.
+    max_lsns: HashMap<u64, u64>,
+    pages: HashMap<u64, PageHandle>,
+    // The disk addresses of delta pages and the pages below them.
+    bases: HashMap<u64, u64>,
+    // The files in the cold tier.
+    cold: HashSet<u64>,
+    // The last time that each file is read or written. Files that are in the cold tier when the
+    // store is opened have no last read until they are read.
+    last_reads: HashMap<u64, Option<Instant>>,
+    num_demotions: u64,
+    num_promotions: u64,
+}
+
+impl PageFiles {
+    fn insert(&mut self, reader: PageFile, handles: Vec<PageHandle>, max_lsn: u64, cold: bool) {
+        let file_id = reader.file_id();
+        self.max_lsns.insert(file_id, max_lsn);
+        if cold {
+            self.cold.insert(file_id);
+            self.last_reads.insert(file_id, None);
+        } else {
+            self.last_reads.insert(file_id, Some(Instant::now()));
+        }
+        for handle in handles {
+            let addr = disk_addr(file_id, handle.block.offset);
+            self.pages.insert(addr, handle);
+        }
+        self.readers.insert(file_id, Arc::new(reader));
+        self.next_file_id = self.next_file_id.max(file_id + 1);
+    }
+
+    /// Replaces the reader of a file that is moved to the other tier.
+    fn move_file(&mut self, reader: PageFile, cold: bool) {
+        let file_id = reader.file_id();
+        self.readers.insert(file_id, Arc::new(reader));
+        if cold {
+            self.cold.insert(file_id);
+            self.num_demotions += 1;
+        } else {
+            self.cold.remove(&file_id);
+            self.num_promotions += 1;
+        }
+    }
+
+    /// Removes the files that have no pages in `live_files`, and returns their ids and whether
+    /// they are in the cold tier.
+    fn retain(&mut self, live_files: &HashSet<u64>) -> Vec<(u64, bool)> {
+        let obsolete: Vec<u64> = self
+            .readers
+            .keys()
+            .filter(|id| !live_files.contains(id))
+            .copied()
+            .collect();
+        for id in &obsolete {
+            self.readers.remove(id);
+            self.max_lsns.remove(id);
+            self.last_reads.remove(id);
+        }
+        self.pages
+            .retain(|addr, _| live_files.contains(&file_id_of(*addr)));
+        self.bases
+            .retain(|addr, _| live_files.contains(&file_id_of(*addr)));
+        obsolete
+            .into_iter()
+            .map(|id| (id, self.cold.remove(&id)))
+            .collect()
+    }
+}
+
+impl PageStore {
+    /// Opens a store in `path`, creating it if it doesn't exist.
+    ///
+    /// The store consists of the page files in the manifest, and other page files are removed.
+    /// Returns an error if some files in `path` or the directory of `Options::cold_tier` belong to
+    /// a different store, `Error::Busy` if the store is opened by another writer, or
+    /// `Error::NotSupported` if the store has another format version.
+    ///
+    /// With `Options::read_only`, the store must exist, and no files are changed. The store can't
+    /// be written then, and it keeps reading the page files of the manifest when it is opened.
+    /// Read-only stores don't take the lock of the directory, so they can be opened along with a
+    /// writer.
+    pub async fn open(env: Arc<dyn Env>, path: &Path, opts: Options) -> Result<Self> {
+        let read_only = opts.read_only;
+        let io_recorder = Arc::new(IoRecorder::new());
+        let raw_env = env;
+        let env: Arc<dyn Env> = Arc::new(RecordingEnv::new(raw_env.clone(), io_recorder.clone()));
+        let lock = if read_only {
+            None
+        } else {
+            env.create_dir_all(path).await?;
+            let lock = lock_dir(env.as_ref(), path).await?;
+            remove_tmp_files(env.as_ref(), path).await?;
+            Some(lock)
+        };
+        let cold = match opts.cold_tier {
+            Some(tier) => {
+                let env: Arc<dyn Env> =
+                    Arc::new(RecordingEnv::new(tier.env.clone(), io_recorder.clone()));
+                if !read_only {
+                    env.create_dir_all(&tier.path).await?;
+                    remove_tmp_files(env.as_ref(), &tier.path).await?;
+                }
+                Some(ColdStore {
+                    env,
+                    raw_env: tier.env,
+                    path: tier.path,
+                    policy: tier.policy,
+                })
+            }
+            None => None,
+        };
+        let (manifest_file, manifest) = if read_only {
+            match ManifestFile::read(env.as_ref(), path).await? {
+                Some(manifest) => (None, manifest),
+                None => {
+                    return Err(Error::Corrupted(
+                        format!("{} has no store to open in read-only mode", path.display()).into(),
+                    ))
+                }
+            }
+        } else {
+            let (manifest_file, manifest) = ManifestFile::open(env.clone(), path).await?;
+            (Some(manifest_file), manifest)
+        };
+        if manifest.format_version < FORMAT_VERSION {
+            return Err(Error::NotSupported(format!(
+                "{} has format version {}, which must be migrated to {} with `PageStore::migrate`",
+                path.display(),
+                manifest.format_version,
+                FORMAT_VERSION
+            )));
+        }
+        let mut files = PageFiles {
+            next_file_id: 1,
+            bases: manifest.page_bases.iter().copied().collect(),
+            ..Default::default()
+        };
+        let max_lsns: HashMap<u64, u64> = manifest.files.iter().copied().collect();
+        let cold_files: HashSet<u64> = manifest.cold_files.iter().copied().collect();
+        let hot_files = manifest
+            .files
+            .iter()
+            .map(|(id, _)| *id)
+            .filter(|id| !cold_files.contains(id))
+            .collect();
+        for (reader, handles) in
+            load_page_files(env.as_ref(), path, manifest.run_id, hot_files, read_only).await?
+        {
+            let max_lsn = max_lsns[&reader.file_id()];
+            files.insert(reader, handles, max_lsn, false);
+        }
+        match &cold {
+            Some(cold) => {
+                let (env, dir) = (cold.env.as_ref(), &cold.path);
+                for (reader, handles) in
+                    load_page_files(env, dir, manifest.run_id, cold_files, read_only).await?
+                {
+                    let max_lsn = max_lsns[&reader.file_id()];
+                    files.insert(reader, handles, max_lsn, true);
+                }
+            }
+            None if !cold_files.is_empty() => {
+                return Err(Error::Corrupted(
+                    format!(
+                    "manifest has {} page files in the cold tier, but no cold tier is configured",
+                    cold_files.len()
+                )
+                    .into(),
+                ))
+            }
+            None => {}
+        }
+        io_recorder.finish_recovery();
+        Ok(Self {
+            env,
+            raw_env,
+            path: path.to_owned(),
+            run_id: manifest.run_id,
+            restart_interval: opts.page_restart_interval,
+            recovered: manifest,
+            files: Mutex::new(files),
+            manifest_file: AsyncMutex::new(manifest_file),
+            io_recorder,
+            cold,
+            _lock: lock,
+        })
+    }
+
+    pub fn run_id(&self) -> RunId {
+        self.run_id
+    }
+
+    /// Returns the manifest when the store is opened, which records the last checkpoint to recover
+    /// from.
+    pub fn recovered(&self) -> &Manifest {
+        &self.recovered
+    }
+
+    pub fn io_stats(&self) -> IoStats {
+        self.io_recorder.stats()
+    }
+
+    pub fn tier_stats(&self) -> TierStats {
+        let files = self.files.lock().unwrap();
+        TierStats {
+            num_cold_files: files.cold.len() as u64,
+            num_demotions: files.num_demotions,
+            num_promotions: files.num_promotions,
+        }
+    }
+
+    /// Returns the manifest of the last checkpoint.
+    pub async fn manifest(&self) -> Manifest {
+        match &*self.manifest_file.lock().await {
+            Some(file) => file.current().clone(),
+            None => self.recovered.clone(),
+        }
+    }
+
+    pub async fn manifest_info(&self) -> ManifestInfo {
+        let manifest = self.manifest().await;
+        ManifestInfo {
+            run_id: manifest.run_id.to_string(),
+            files: manifest.files,
+            cold_files: manifest.cold_files,
+            root_id: manifest.root_id,
+            next_page_id: manifest.next_page_id,
+            page_table: manifest
+                .page_table
+                .into_iter()
+                .map(|(id, addr)| (id, file_id_of(addr), offset_of(addr)))
+                .collect(),
+            counters: manifest.counters,
+        }
+    }
+
+    /// Returns the space usage of the page files, in the order of their ids.
+    pub async fn file_infos(&self) -> Vec<PageFileInfo> {
+        let manifest = self.manifest().await;
+        let live: HashSet<u64> = manifest
+            .page_table
+            .iter()
+            .map(|&(_, addr)| addr)
+            .chain(manifest.page_bases.iter().map(|&(_, base)| base))
+            .collect();
+        let files = self.files.lock().unwrap();
+        let mut infos: HashMap<u64, PageFileInfo> = files
+            .readers
+            .iter()
+            .map(|(&file_id, reader)| {
+                let info = PageFileInfo {
+                    file_id,
+                    cold: files.cold.contains(&file_id),
+                    file_size: reader.file_size(),
+                    num_pages: 0,
+                    live_pages: 0,
+                    live_bytes: 0,
+                };
+                (file_id, info)
+            })
+            .collect();
+        for (&addr, handle) in &files.pages {
+            if let Some(info) = infos.get_mut(&file_id_of(addr)) {
+                info.num_pages += 1;
+                if live.contains(&addr) {
+                    info.live_pages += 1;
+                    info.live_bytes += handle.block.size;
+                }
+            }
+        }
+        let mut infos: Vec<_> = infos.into_values().collect();
+        infos.sort_unstable_by_key(|info| info.file_id);
+        infos
+    }
+
+    /// Returns the env, the env without I/O statistics, and the directory of the page files in a
+    /// tier.
+    fn tier(&self, cold: bool) -> (&dyn Env, &dyn Env, &Path) {
+        match &self.cold {
+            Some(tier) if cold => (tier.env.as_ref(), tier.raw_env.as_ref(), &tier.path),
+            _ => (self.env.as_ref(), self.raw_env.as_ref(), &self.path),
+        }
+    }
+
+    pub fn page_info(&self, addr: u64) -> Option<PageInfo> {
+        let files = self.files.lock().unwrap();
+        files.pages.get(&addr).map(|handle| handle.info)
+    }
+
+    /// Returns the size of the image of the page at `addr`.
+    pub fn page_size(&self, addr: u64) -> Option<u64> {
+        let files = self.files.lock().unwrap();
+        files.pages.get(&addr).map(|handle| handle.block.size)
+    }
+
+    /// Loads a page into memory allocated from `alloc`.
+    ///
+    /// A delta page is loaded with the pages below it on disk, and returned as the first page of
+    /// the rebuilt chain, which must be freed with `dealloc_chain`. The pages of the chain are read
+    /// concurrently.
+    ///
+    /// Returns `None` if the page doesn't exist, which can happen if the page is released by a
+    /// checkpoint after the address is read.
+    pub async fn load_page<A>(&self, addr: u64, alloc: &A) -> Result<Option<PagePtr>>
+    where
+        A: PageAlloc<Error = Error>,
+    {
+        let images = match self.read_chain(addr).await? {
+            Some(images) => images,
+            None => return Ok(None),
+        };
+        let mut pages: Vec<PagePtr> = Vec::with_capacity(images.len());
+        for (i, (page_addr, handle, image)) in images.iter().enumerate() {
+            let page = match decode_image(*page_addr, handle, image, alloc) {
+                Ok(page) => page,
+                Err(err) => {
+                    for page in pages {
+                        unsafe { alloc.dealloc(page) };
+                    }
+                    return Err(err);
+                }
+            };
+            pages.push(page);
+            // The chain is found by the recorded bases, which must match the pages.
+            let next = images.get(i + 1).map(|(base, ..)| *base);
+            let linked = match PageAddr::from(page.next()) {
+                PageAddr::Disk(base) => Some(base) == next,
+                PageAddr::Mem(_) => next.is_none(),
+            };
+            if !linked {
+                for page in pages {
+                    unsafe { alloc.dealloc(page) };
+                }
+                let message = format!(
+                    "page at {} has next page {:#x}, but {:?} is recorded below it",
+                    page_addr,
+                    page.next(),
+                    next
+                );
+                return Err(Error::Corrupted(corruption_at(*page_addr, message)));
+            }
+        }
+        for i in 1..pages.len() {
+            let next = pages[i];
+            pages[i - 1].set_next(PageAddr::Mem(next.into()).into());
+        }
+        // The chain sizes are counted from the bottom of the chain.
+        let mut chain_size = 0u64;
+        for page in pages.iter_mut().rev() {
+            chain_size += page.size() as u64;
+            page.set_chain_size(chain_size.min(u32::MAX as u64) as u32);
+        }
+        Ok(Some(pages[0]))
+    }
+
+    /// Reads the images of the page at `addr` and the pages below it.
+    ///
+    /// The chain is found by the recorded bases, and each run of pages that follow one another in
+    /// a file is read with one read, concurrently with the other runs.
+    ///
+    /// Returns `None` if the page doesn't exist.
+    async fn read_chain(&self, addr: u64) -> Result<Option<Vec<(u64, PageHandle, Vec<u8>)>>> {
+        let runs = {
+            let mut files = self.files.lock().unwrap();
+            let mut runs: Vec<PageRun> = Vec::new();
+            let mut above: Option<(u64, PageHandle)> = None;
+            let mut next = Some(addr);
+            while let Some(page_addr) = next {
+                let handle = match (files.pages.get(&page_addr), above) {
+                    (Some(handle), _) => *handle,
+                    (None, None) => return Ok(None),
+                    // Bases are kept as long as their deltas, so both are released together
+                    // unless the store is corrupted.
+                    (None, Some((above_addr, _))) => {
+                        let message = format!(
+                            "page at {} is chained to page {}, which doesn't exist",
+                            above_addr, page_addr
+                        );
+                        return Err(Error::Corrupted(corruption_at(page_addr, message)));
+                    }
+                };
+                let file_id = file_id_of(page_addr);
+                match above {
+                    // Lengths are read from disk, so they are checked without overflows. They
+                    // decrease along the chain, so that it ends on malformed bases.
+                    Some((_, above)) if above.info.len.checked_sub(1) != Some(handle.info.len) => {
+                        let message = format!(
+                            "page at {} has length {}, but the delta above it has length {}",
+                            page_addr, handle.info.len, above.info.len
+                        );
+                        return Err(Error::Corrupted(corruption_at(page_addr, message)));
+                    }
+                    Some((above_addr, above))
+                        if file_id_of(above_addr) == file_id
+                            && above.block.offset.checked_add(above.block.size)
+                                == Some(handle.block.offset) =>
+                    {
+                        runs.last_mut().unwrap().1.push((page_addr, handle));
+                    }
+                    _ => {
+                        files.last_reads.insert(file_id, Some(Instant::now()));
+                        let reader = files.readers[&file_id].clone();
+                        runs.push((reader, vec![(page_addr, handle)]));
+                    }
+                }
+                above = Some((page_addr, handle));
+                next = files.bases.get(&page_addr).copied();
+            }
+            runs
+        };
+        let images = try_join_all(runs.iter().map(|(reader, run)| {
+            let handles: Vec<PageHandle> = run.iter().map(|(_, handle)| *handle).collect();
+            async move { reader.read_pages(&handles).await }
+        }))
+        .await?;
+        Ok(Some(
+            runs.into_iter()
+                .zip(images)
+                .flat_map(|((_, run), images)| run.into_iter().zip(images))
+                .map(|((addr, handle), image)| (addr, handle, image))
+                .collect(),
+        ))
+    }
+
+    /// Writes the images of pages to a new page file and returns their disk addresses.
+    ///
+    /// The pages must contain no entries after `max_lsn`, and be either the only pages of their
+    /// chains, or deltas whose next pages are the disk addresses of the pages below them, see
+    /// `FlushPolicy`. The pages below are kept by checkpoints as long as the deltas are, and
+    /// `load_page` reads the whole chain back.
+    pub async fn write_pages(&self, pages: &[(u64, PagePtr)], max_lsn: u64) -> Result<Vec<u64>> {
+        let file_id = {
+            let mut files = self.files.lock().unwrap();
+            files.next_file_id += 1;
+            files.next_file_id - 1
+        };
+        let atomic_file = AtomicFile::new(self.path.join(page_file_name(file_id, self.run_id)));
+        let file = atomic_file.open(self.env.as_ref()).await?;
+        let mut writer = PageFileWriter::new(file, self.run_id, file_id);
+        let mut handles = Vec::with_capacity(pages.len());
+        for &(id, page) in pages {
+            let image = encode_page_image(page, self.restart_interval);
+            handles.push(writer.add_page(id, page.into(), &image).await?);
+        }
+        let file = self
+            .env
+            .open_positional_reader(atomic_file.tmp_path())
+            .await?;
+        let reader = writer.finish_into_reader(file).await?;
+        fail_point!("page_file_before_commit");
+        atomic_file.commit(self.env.as_ref()).await?;
+        let mut files = self.files.lock().unwrap();
+        let addrs: Vec<u64> = handles
+            .iter()
+            .map(|handle| disk_addr(file_id, handle.block.offset))
+            .collect();
+        files.insert(reader, handles, max_lsn, false);
+        for (&(_, page), &addr) in pages.iter().zip(&addrs) {
+            if let PageAddr::Disk(base) = PageAddr::from(page.next()) {
+                files.bases.insert(addr, base);
+            }
+        }
+        Ok(addrs)
+    }
+
+    /// Records a checkpoint with `counters` in the manifest, and then removes the page files that
+    /// are not referenced by the page table anymore.
+    ///
+    /// With `Options::cold_tier`, the page files that are cold by the tiering policy are moved to
+    /// the cold tier, and the cold ones that are read recently are moved back, as part of the
+    /// checkpoint. The addresses of pages don't change with their tiers.
+    ///
+    /// Checkpoints must not run concurrently.
+    pub async fn checkpoint(
+        &self,
+        root_id: u64,
+        next_page_id: u64,
+        page_table: Vec<(u64, u64)>,
+        counters: Vec<(String, u64)>,
+    ) -> Result<()> {
+        let (live_files, page_bases, mut files, cold_files, moves) = {
+            let page_files = self.files.lock().unwrap();
+            // Delta pages keep the pages below them.
+            let mut page_bases = Vec::new();
+            for &(_, addr) in &page_table {
+                let mut addr = addr;
+                while let Some(&base) = page_files.bases.get(&addr) {
+                    page_bases.push((addr, base));
+                    addr = base;
+                }
+            }
+            page_bases.sort_unstable();
+            page_bases.dedup();
+            let live_files: HashSet<u64> = page_table
+                .iter()
+                .map(|(_, addr)| file_id_of(*addr))
+                .chain(page_bases.iter().map(|(_, base)| file_id_of(*base)))
+                .collect();
+            let files: Vec<(u64, u64)> = live_files
+                .iter()
+                .map(|id| (*id, page_files.max_lsns[id]))
+                .collect();
+            let cold_files = match &self.cold {
+                Some(cold) => {
+                    let last_reads = live_files
+                        .iter()
+                        .map(|id| (*id, page_files.last_reads[id]))
+                        .collect();
+                    cold.policy.cold_files(&last_reads, Instant::now())
+                }
+                None => HashSet::new(),
+            };
+            // The files that change tiers, and whether they are moved to the cold tier.
+            let moves: Vec<(u64, bool)> = live_files
+                .iter()
+                .filter(|id| cold_files.contains(id) != page_files.cold.contains(id))
+                .map(|id| (*id, cold_files.contains(id)))
+                .collect();
+            (live_files, page_bases, files, cold_files, moves)
+        };
+        // The files are copied to their new tiers before the manifest refers to them there.
+        for &(file_id, cold) in &moves {
+            let name = page_file_name(file_id, self.run_id);
+            let (_, from_env, from_dir) = self.tier(!cold);
+            let (_, to_env, to_dir) = self.tier(cold);
+            copy_file_across(from_env, &from_dir.join(&name), to_env, &to_dir.join(&name)).await?;
+        }
+        files.sort_unstable();
+        let mut cold_files: Vec<u64> = cold_files.into_iter().collect();
+        cold_files.sort_unstable();
+        let manifest = Manifest {
+            format_version: FORMAT_VERSION,
+            run_id: self.run_id,
+            files,
+            root_id,
+            next_page_id,
+            page_table,
+            counters,
+            cold_files,
+            page_bases,
+        };
+        fail_point!("checkpoint_before_manifest");
+        match self.manifest_file.lock().await.as_mut() {
+            Some(manifest_file) => manifest_file.record(&manifest).await?,
+            None => return Err(Error::ReadOnly),
+        }
+        fail_point!("checkpoint_after_manifest");
+
+        let mut readers = Vec::with_capacity(moves.len());
+        for &(file_id, cold) in &moves {
+            let (env, _, dir) = self.tier(cold);
+            let path = dir.join(page_file_name(file_id, self.run_id));
+            let file = env.open_positional_reader(&path).await?;
+            let file_size = env.file_size(&path).await?;
+            readers.push((PageFileReader::open(file, file_size).await?, cold));
+        }
+        // Readers that hold an obsolete address see a missing page and retry with the new one.
+        let obsolete = {
+            let mut page_files = self.files.lock().unwrap();
+            for (reader, cold) in readers {
+                page_files.move_file(reader, cold);
+            }
+            page_files.retain(&live_files)
+        };
+        for (file_id, cold) in obsolete {
+            let (env, _, dir) = self.tier(cold);
+            env.remove_file(&dir.join(page_file_name(file_id, self.run_id)))
+                .await?;
+        }
+        // The old copies of the moved files are removed without the I/O statistics of the files.
+        for (file_id, cold) in moves {
+            let (_, env, dir) = self.tier(!cold);
+            env.remove_file(&dir.join(page_file_name(file_id, self.run_id)))
+                .await?;
+        }
+        Ok(())
+    }
+}
 
-#[allow(dead_code)]
 impl PageStore {
-    pub async fn open(_opts: Options) -> Result<Self> {
-        Ok(Self {})
+    /// Exports the current manifest and the page files that may contain entries at or after
+    /// `since_lsn` to `dir`, and returns the largest LSN that the exported store may contain.
+    ///
+    /// With `since_lsn` 0, the backup is a full copy of the store, which can be opened as is.
+    /// Otherwise, it is an incremental backup that is applied to a copy of the store with
+    /// `apply_incremental`, where the copy is backed up with a `since_lsn` no greater than the
+    /// returned LSN of its backup.
+    ///
+    /// The page files in the cold tier are copied to `dir` as well, so a backup is self-contained.
+    ///
+    /// Backups must not run concurrently with checkpoints.
+    pub async fn backup(&self, dir: &Path, since_lsn: u64) -> Result<u64> {
+        let env = self.raw_env.as_ref();
+        env.create_dir_all(dir).await?;
+        if ManifestFile::read(env, dir).await?.is_some() {
+            return Err(Error::Corrupted(
+                format!(
+                    "backup directory {} already contains a store",
+                    dir.display()
+                )
+                .into(),
+            ));
+        }
+        let mut manifest = match self.manifest_file.lock().await.as_ref() {
+            Some(manifest_file) => manifest_file.current().clone(),
+            None => self.recovered.clone(),
+        };
+        let mut max_lsn = since_lsn;
+        for &(file_id, file_max_lsn) in &manifest.files {
+            max_lsn = max_lsn.max(file_max_lsn);
+            if file_max_lsn >= since_lsn {
+                let name = page_file_name(file_id, self.run_id);
+                let (_, from_env, from_dir) = self.tier(manifest.cold_files.contains(&file_id));
+                copy_file_across(from_env, &from_dir.join(&name), env, &dir.join(&name)).await?;
+            }
+        }
+        manifest.cold_files.clear();
+        let (mut manifest_file, _) = ManifestFile::open(self.raw_env.clone(), dir).await?;
+        manifest_file.record(&manifest).await?;
+        Ok(max_lsn)
+    }
+
+    /// Applies an incremental backup in `backup_dir` to the store in `path`, which must not be
+    /// opened.
+    ///
+    /// The manifest of the store is replaced only if all its page files are present, so a failed
+    /// application leaves the store as it was. The page files that are in the cold tier of the
+    /// store and not in the backup stay in the cold tier.
+    pub async fn apply_incremental(
+        env: Arc<dyn Env>,
+        path: &Path,
+        backup_dir: &Path,
+    ) -> Result<()> {
+        let mut backup = match ManifestFile::read(env.as_ref(), backup_dir).await? {
+            Some(manifest) => manifest,
+            None => {
+                return Err(Error::Corrupted(
+                    format!("backup {} has no manifest", backup_dir.display()).into(),
+                ))
+            }
+        };
+        let current = match ManifestFile::read(env.as_ref(), path).await? {
+            Some(manifest) => manifest,
+            None => {
+                return Err(Error::Corrupted(
+                    format!("{} has no store to apply the backup to", path.display()).into(),
+                ))
+            }
+        };
+        if backup.run_id != current.run_id {
+            return Err(Error::Corrupted(
+                format!(
+                    "backup has run {}, but the store has run {}",
+                    backup.run_id, current.run_id
+                )
+                .into(),
+            ));
+        }
+        let mut cold_files = Vec::new();
+        for &(file_id, _) in &backup.files {
+            let name = page_file_name(file_id, backup.run_id);
+            let from = backup_dir.join(&name);
+            let to = path.join(&name);
+            if env.file_size(&to).await.is_ok() {
+                continue;
+            }
+            if env.file_size(&from).await.is_err() {
+                if current.cold_files.contains(&file_id) {
+                    cold_files.push(file_id);
+                    continue;
+                }
+                return Err(Error::Corrupted(
+                    format!("page file {} is in neither the store nor the backup", name).into(),
+                ));
+            }
+            copy_file(env.as_ref(), &from, &to).await?;
+        }
+        backup.cold_files = cold_files;
+        let (mut manifest_file, _) = ManifestFile::open(env, path).await?;
+        manifest_file.record(&backup).await?;
+        Ok(())
+    }
+}
+
+/// Frees a page returned by `PageStore::load_page`, with the pages below it.
+///
+/// # Safety
+///
+/// The pages must be allocated from `alloc` and not shared.
+pub unsafe fn dealloc_chain<A: PageAlloc>(alloc: &A, page: PagePtr) {
+    let mut page = Some(page);
+    while let Some(p) = page {
+        page = match PageAddr::from(p.next()) {
+            PageAddr::Mem(next) => PagePtr::new(next as *mut u8),
+            PageAddr::Disk(_) => None,
+        };
+        alloc.dealloc(p);
     }
+}
 
-    pub fn page_info(&self, _addr: u64) -> Option<PageInfo> {
-        todo!()
+/// Locks the directory of a store for a writer, or returns `Error::Busy` if it is locked.
+pub(super) async fn lock_dir(env: &dyn Env, dir: &Path) -> Result<Box<dyn FileLock>> {
+    env.lock_file(&dir.join(LOCK_NAME)).await.map_err(|err| {
+        if err.kind() == io::ErrorKind::WouldBlock {
+            Error::Busy(format!("{} is opened by another writer", dir.display()))
+        } else {
+            err.into()
+        }
+    })
+}
+
+/// Loads the page files `file_ids` of `run_id` in `dir`, and removes the other page files of the
+/// store unless `read_only`.
+///
+/// Returns an error if some page files in `dir` belong to another store, or some page files of
+/// `file_ids` are missing.
+async fn load_page_files(
+    env: &dyn Env,
+    dir: &Path,
+    run_id: RunId,
+    file_ids: HashSet<u64>,
+    read_only: bool,
+) -> Result<Vec<(PageFile, Vec<PageHandle>)>> {
+    let short_run_id = run_id.short();
+    let mut missing = file_ids;
+    let mut files = Vec::new();
+    let mut obsolete = Vec::new();
+    for path in env.read_dir(dir).await? {
+        let name = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) => name,
+            None => continue,
+        };
+        let (file_id, file_run_id) = match parse_page_file_name(name) {
+            Some(parsed) => parsed,
+            None => continue,
+        };
+        if file_run_id != short_run_id {
+            return Err(Error::Corrupted(
+                format!(
+                    "page file {} belongs to run {}, but the manifest has run {}",
+                    path.display(),
+                    file_run_id,
+                    run_id
+                )
+                .into(),
+            ));
+        }
+        if !missing.remove(&file_id) {
+            // The file is written by an interrupted checkpoint or not removed after one, or by the
+            // writer of a read-only store after it is opened.
+            if !read_only {
+                obsolete.push(path);
+            }
+            continue;
+        }
+        let file = env.open_positional_reader(&path).await?;
+        let file_size = env.file_size(&path).await?;
+        let reader = PageFileReader::open(file, file_size).await?;
+        if reader.run_id() != run_id || reader.file_id() != file_id {
+            return Err(Error::Corrupted(format!(
+                "page file {} has run {} and file id {} in its footer, expected run {} and file id {}",
+                path.display(),
+                reader.run_id(),
+                reader.file_id(),
+                run_id,
+                file_id
+            ).into()));
+        }
+        let handles = reader.read_index().await?;
+        files.push((reader, handles));
+    }
+    if let Some(file_id) = missing.into_iter().min() {
+        return Err(Error::Corrupted(
+            format!(
+                "page file {} in the manifest is missing",
+                page_file_name(file_id, run_id)
+            )
+            .into(),
+        ));
+    }
+    for path in obsolete {
+        env.remove_file(&path).await?;
     }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
 
-    pub async fn load_page(&self, _addr: u64) -> Result<PagePtr> {
-        todo!()
+    use super::{
+        super::{FaultEnv, FaultOp, TMP_SUFFIX},
+        *,
+    };
+    use crate::{
+        env::TokioEnv,
+        tree::{
+            page::{DataPageBuilder, Key, OptionIter, Value},
+            pagecache::PageCache,
+            ColdTier, IoOp,
+        },
+    };
+
+    async fn write_page_file(env: &dyn Env, dir: &Path, name: &str, run_id: RunId, file_id: u64) {
+        let file = env.open_sequential_writer(&dir.join(name)).await.unwrap();
+        let mut writer = PageFileWriter::new(file, run_id, file_id);
+        let info = PageInfo {
+            ver: PageVer::default(),
+            len: 0,
+            is_index: false,
+        };
+        writer.add_page(1, info, b"page").await.unwrap();
+        writer.finish().await.unwrap();
     }
 
-    pub fn acquire_page(&self) -> u64 {
-        todo!()
+    #[tokio::test]
+    async fn checkpoint() {
+        let env: Arc<dyn Env> = Arc::new(TokioEnv::current());
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path();
+        let cache = PageCache::default();
+        let store = PageStore::open(env.clone(), path, Options::default())
+            .await
+            .unwrap();
+        assert_eq!(store.recovered().page_table, vec![]);
+
+        let mut iter = OptionIter::from((Key::new(&[1], 1), Value::Put(&[2])));
+        let mut page = DataPageBuilder::default()
+            .build_from_iter(&cache, &mut iter)
+            .unwrap();
+        page.set_ver(PageVer::new(3));
+        let page = page.as_ptr();
+        let addrs = store.write_pages(&[(7, page), (8, page)], 1).await.unwrap();
+        store
+            .checkpoint(7, 9, vec![(7, addrs[0]), (8, addrs[1])], vec![])
+            .await
+            .unwrap();
+        assert_eq!(store.page_info(addrs[0]), Some(PageInfo::from(page)));
+        let loaded = store.load_page(addrs[1], &cache).await.unwrap().unwrap();
+        let size = page.size();
+        unsafe {
+            let expect = std::slice::from_raw_parts(page.as_raw(), size);
+            assert_eq!(std::slice::from_raw_parts(loaded.as_raw(), size), expect);
+            cache.dealloc(loaded);
+        }
+
+        // The first file is removed once no page references it.
+        let new_addrs = store.write_pages(&[(8, page)], 2).await.unwrap();
+        store
+            .checkpoint(8, 9, vec![(8, new_addrs[0])], vec![])
+            .await
+            .unwrap();
+        assert_eq!(store.page_info(addrs[0]), None);
+        assert!(store.load_page(addrs[1], &cache).await.unwrap().is_none());
+        let first_file = path.join(page_file_name(file_id_of(addrs[0]), store.run_id()));
+        assert!(!first_file.exists());
+        drop(store);
+
+        let store = PageStore::open(env.clone(), path, Options::default())
+            .await
+            .unwrap();
+        let manifest = store.recovered();
+        assert_eq!(manifest.files, vec![(file_id_of(new_addrs[0]), 2)]);
+        assert_eq!(manifest.root_id, 8);
+        assert_eq!(manifest.next_page_id, 9);
+        assert_eq!(manifest.page_table, vec![(8, new_addrs[0])]);
+        assert_eq!(store.page_info(new_addrs[0]), Some(PageInfo::from(page)));
+        unsafe { cache.dealloc(page) };
     }
 
-    pub fn release_page(&self, _addr: u64) {
-        todo!()
+    #[tokio::test]
+    async fn delta_chains() {
+        let env: Arc<dyn Env> = Arc::new(TokioEnv::current());
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path();
+        let cache = PageCache::default();
+        let store = PageStore::open(env.clone(), path, Options::default())
+            .await
+            .unwrap();
+        // Writes a base and two deltas over it in three files.
+        let mut addr = None;
+        let mut pages = Vec::new();
+        for i in 0..3u8 {
+            let buf = [i];
+            let mut iter = OptionIter::from((Key::new(&buf, i as u64), Value::Put(&buf)));
+            let mut page = DataPageBuilder::default()
+                .build_from_iter(&cache, &mut iter)
+                .unwrap();
+            page.set_len(i);
+            if let Some(addr) = addr {
+                page.set_next(PageAddr::Disk(addr).into());
+            }
+            let page = page.as_ptr();
+            addr = Some(store.write_pages(&[(1, page)], 1).await.unwrap()[0]);
+            pages.push(page);
+        }
+        let addr = addr.unwrap();
+        store
+            .checkpoint(1, 2, vec![(1, addr)], vec![])
+            .await
+            .unwrap();
+        // The pages below the last delta are kept in place.
+        assert_eq!(store.file_infos().await.len(), 3);
+        drop(store);
+
+        let store = PageStore::open(env, path, Options::default())
+            .await
+            .unwrap();
+        let num_reads = |store: &PageStore| {
+            let stats = store.io_stats();
+            let op = stats.ops.iter().find(|op| op.op == IoOp::SwapIn);
+            op.map_or(0, |op| op.io.num_reads)
+        };
+        let reads = num_reads(&store);
+        // Each page is in its own file, and read with its own read.
+        let head = store.load_page(addr, &cache).await.unwrap().unwrap();
+        assert_eq!(num_reads(&store), reads + 3);
+        let mut page = Some(head);
+        for &expect in pages.iter().rev() {
+            let loaded = page.unwrap();
+            assert_eq!(loaded.len(), expect.len());
+            let content = |page: PagePtr| unsafe {
+                std::slice::from_raw_parts(page.content(), page.content_size() as usize)
+            };
+            assert_eq!(content(loaded), content(expect));
+            page = match PageAddr::from(loaded.next()) {
+                PageAddr::Mem(ptr) => unsafe { PagePtr::new(ptr as *mut u8) },
+                PageAddr::Disk(_) => panic!("page is not loaded"),
+            };
+        }
+        assert!(page.is_none());
+        unsafe { dealloc_chain(&cache, head) };
+        for page in pages {
+            unsafe { cache.dealloc(page) };
+        }
+    }
+
+    #[tokio::test]
+    async fn malformed_chains() {
+        let env: Arc<dyn Env> = Arc::new(TokioEnv::current());
+        let dir = tempfile::tempdir().unwrap();
+        let cache = PageCache::default();
+        let store = PageStore::open(env, dir.path(), Options::default())
+            .await
+            .unwrap();
+        let mut base = DataPageBuilder::default().build(&cache).unwrap();
+        base.set_len(u8::MAX);
+        let base = base.as_ptr();
+        let base_addr = store.write_pages(&[(1, base)], 1).await.unwrap()[0];
+        for len in [0, 1, u8::MAX] {
+            let mut delta = DataPageBuilder::default().build(&cache).unwrap();
+            delta.set_len(len);
+            delta.set_next(PageAddr::Disk(base_addr).into());
+            let delta = delta.as_ptr();
+            let addr = store.write_pages(&[(1, delta)], 1).await.unwrap()[0];
+            let err = store.load_page(addr, &cache).await.err().unwrap();
+            assert!(matches!(err, Error::Corrupted(_)));
+            unsafe { cache.dealloc(delta) };
+        }
+        unsafe { cache.dealloc(base) };
+    }
+
+    #[tokio::test]
+    async fn crash_during_checkpoint() {
+        let env: Arc<dyn Env> = Arc::new(TokioEnv::current());
+        let opts = Options::default();
+        let cache = PageCache::default();
+        let page = DataPageBuilder::default().build(&cache).unwrap().as_ptr();
+        for op in [
+            FaultOp::Write,
+            FaultOp::SyncData,
+            FaultOp::Rename,
+            FaultOp::SyncDir,
+        ] {
+            for n in 0.. {
+                let dir = tempfile::tempdir().unwrap();
+                let path = dir.path();
+                let store = PageStore::open(env.clone(), path, opts.clone())
+                    .await
+                    .unwrap();
+                let addrs = store.write_pages(&[(0, page)], 1).await.unwrap();
+                store
+                    .checkpoint(0, 1, vec![(0, addrs[0])], vec![])
+                    .await
+                    .unwrap();
+                drop(store);
+
+                // Crashes at the n-th `op` of reopening the store and taking a checkpoint.
+                let fault_env = FaultEnv::new(env.clone(), op, n);
+                let result = async {
+                    let store =
+                        PageStore::open(Arc::new(fault_env.clone()), path, opts.clone()).await?;
+                    let addrs = store.write_pages(&[(0, page)], 2).await?;
+                    store.checkpoint(0, 1, vec![(0, addrs[0])], vec![]).await?;
+                    Ok::<_, Error>(addrs[0])
+                }
+                .await;
+                if !fault_env.crashed() {
+                    result.unwrap();
+                    break;
+                }
+                assert!(result.is_err());
+
+                // The store recovers to one of the checkpoints, without temporary files.
+                let store = PageStore::open(env.clone(), path, opts.clone())
+                    .await
+                    .unwrap();
+                let manifest = store.recovered();
+                let (_, addr) = manifest.page_table[0];
+                assert!(addr == addrs[0] || manifest.files[0].1 == 2);
+                assert!(store.load_page(addr, &cache).await.unwrap().is_some());
+                for entry in std::fs::read_dir(path).unwrap() {
+                    let name = entry.unwrap().file_name();
+                    assert!(!name.to_str().unwrap().ends_with(TMP_SUFFIX));
+                }
+            }
+        }
+        unsafe { cache.dealloc(page) };
+    }
+
+    #[tokio::test]
+    async fn obsolete_and_missing_files() {
+        let env: Arc<dyn Env> = Arc::new(TokioEnv::current());
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path();
+        let opts = Options::default();
+        let cache = PageCache::default();
+
+        let store = PageStore::open(env.clone(), path, opts.clone())
+            .await
+            .unwrap();
+        let run_id = store.run_id();
+        let page = DataPageBuilder::default().build(&cache).unwrap().as_ptr();
+        let addrs = store.write_pages(&[(0, page)], 0).await.unwrap();
+        store
+            .checkpoint(0, 1, vec![(0, addrs[0])], vec![])
+            .await
+            .unwrap();
+        drop(store);
+        unsafe { cache.dealloc(page) };
+
+        // A file that is not in the manifest is removed, even if it is torn.
+        let obsolete = path.join(page_file_name(9, run_id));
+        std::fs::write(&obsolete, b"torn").unwrap();
+        let store = PageStore::open(env.clone(), path, opts.clone())
+            .await
+            .unwrap();
+        assert!(!obsolete.exists());
+        assert!(store.page_info(addrs[0]).is_some());
+        drop(store);
+
+        // A file in the manifest must exist.
+        let name = page_file_name(file_id_of(addrs[0]), run_id);
+        std::fs::remove_file(path.join(name)).unwrap();
+        let err = PageStore::open(env.clone(), path, opts)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, Error::Corrupted(_)));
+    }
+
+    #[tokio::test]
+    async fn lock_directory() {
+        let env: Arc<dyn Env> = Arc::new(TokioEnv::current());
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path();
+        let opts = Options::default();
+
+        let store = PageStore::open(env.clone(), path, opts.clone())
+            .await
+            .unwrap();
+        let err = PageStore::open(env.clone(), path, opts.clone())
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, Error::Busy(_)));
+
+        // Read-only stores don't take the lock.
+        let read_only = Options {
+            read_only: true,
+            ..opts.clone()
+        };
+        let reader = PageStore::open(env.clone(), path, read_only).await.unwrap();
+
+        // The lock is released when the store is dropped.
+        drop(store);
+        PageStore::open(env.clone(), path, opts).await.unwrap();
+        drop(reader);
+    }
+
+    #[tokio::test]
+    async fn reject_foreign_files() {
+        let env: Arc<dyn Env> = Arc::new(TokioEnv::current());
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path();
+        let opts = Options::default();
+        let cache = PageCache::default();
+
+        let store = PageStore::open(env.clone(), path, opts.clone())
+            .await
+            .unwrap();
+        let run_id = store.run_id();
+        let page = DataPageBuilder::default().build(&cache).unwrap().as_ptr();
+        let addrs = store.write_pages(&[(0, page)], 0).await.unwrap();
+        store
+            .checkpoint(0, 1, vec![(0, addrs[0])], vec![])
+            .await
+            .unwrap();
+        drop(store);
+        unsafe { cache.dealloc(page) };
+        // Reopening keeps the run id and accepts its own files.
+        let store = PageStore::open(env.clone(), path, opts.clone())
+            .await
+            .unwrap();
+        assert_eq!(store.run_id(), run_id);
+        drop(store);
+
+        // A file from another store is detected by its name.
+        let other_run_id = RunId::random();
+        let name = page_file_name(2, other_run_id);
+        write_page_file(env.as_ref(), path, &name, other_run_id, 2).await;
+        let err = PageStore::open(env.clone(), path, opts.clone())
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, Error::Corrupted(_)));
+        std::fs::remove_file(path.join(&name)).unwrap();
+
+        // A renamed file from another store is detected by its footer.
+        let file_id = file_id_of(addrs[0]);
+        let name = page_file_name(file_id, run_id);
+        write_page_file(env.as_ref(), path, &name, other_run_id, file_id).await;
+        let err = PageStore::open(env.clone(), path, opts)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, Error::Corrupted(_)));
+    }
+
+    #[tokio::test]
+    async fn tiering() {
+        let env: Arc<dyn Env> = Arc::new(TokioEnv::current());
+        let dir = tempfile::tempdir().unwrap();
+        let cold_dir = tempfile::tempdir().unwrap();
+        let path = dir.path();
+        let cache = PageCache::default();
+        let opts = |policy| Options {
+            cold_tier: Some(ColdTier {
+                env: env.clone(),
+                path: cold_dir.path().to_owned(),
+                policy,
+            }),
+            ..Default::default()
+        };
+
+        // Every file is cold right after it is written.
+        let store = PageStore::open(env.clone(), path, opts(TieringPolicy::Age(Duration::ZERO)))
+            .await
+            .unwrap();
+        let run_id = store.run_id();
+        let page = DataPageBuilder::default().build(&cache).unwrap().as_ptr();
+        let addrs = store.write_pages(&[(0, page)], 1).await.unwrap();
+        unsafe { cache.dealloc(page) };
+        let name = page_file_name(file_id_of(addrs[0]), run_id);
+        store
+            .checkpoint(0, 1, vec![(0, addrs[0])], vec![])
+            .await
+            .unwrap();
+        assert!(!path.join(&name).exists());
+        assert!(cold_dir.path().join(&name).exists());
+        let stats = store.tier_stats();
+        assert_eq!((stats.num_cold_files, stats.num_demotions), (1, 1));
+        let loaded = store.load_page(addrs[0], &cache).await.unwrap().unwrap();
+        unsafe { cache.dealloc(loaded) };
+        drop(store);
+
+        // The tiers of the files are recovered, and the files must be in their tiers.
+        let err = PageStore::open(env.clone(), path, Options::default())
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, Error::Corrupted(_)));
+        let long_age = TieringPolicy::Age(Duration::from_secs(3600));
+        let store = PageStore::open(env.clone(), path, opts(long_age))
+            .await
+            .unwrap();
+        assert_eq!(store.recovered().cold_files, vec![file_id_of(addrs[0])]);
+
+        // A cold file stays cold until it is read, and then it is moved back.
+        store
+            .checkpoint(0, 1, vec![(0, addrs[0])], vec![])
+            .await
+            .unwrap();
+        assert_eq!(store.tier_stats().num_cold_files, 1);
+        let loaded = store.load_page(addrs[0], &cache).await.unwrap().unwrap();
+        unsafe { cache.dealloc(loaded) };
+        store
+            .checkpoint(0, 1, vec![(0, addrs[0])], vec![])
+            .await
+            .unwrap();
+        assert!(path.join(&name).exists());
+        assert!(!cold_dir.path().join(&name).exists());
+        let stats = store.tier_stats();
+        assert_eq!((stats.num_cold_files, stats.num_promotions), (0, 1));
+        let loaded = store.load_page(addrs[0], &cache).await.unwrap().unwrap();
+        unsafe { cache.dealloc(loaded) };
+        drop(store);
+
+        let store = PageStore::open(env.clone(), path, Options::default())
+            .await
+            .unwrap();
+        assert!(store.page_info(addrs[0]).is_some());
     }
 }
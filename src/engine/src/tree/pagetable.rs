@@ -1,10 +1,19 @@
+#[cfg(not(loom))]
+use std::sync::{
+    atomic::{AtomicPtr, AtomicU64, Ordering},
+    Arc,
+};
 use std::{
-    mem::MaybeUninit,
+    alloc::{dealloc, Layout},
+    collections::HashSet,
     ptr::null_mut,
-    sync::{
-        atomic::{AtomicPtr, AtomicU64, Ordering},
-        Arc,
-    },
+    sync::Mutex,
+};
+
+#[cfg(loom)]
+use loom::sync::{
+    atomic::{AtomicPtr, AtomicU64, Ordering},
+    Arc,
 };
 
 use super::Guard;
@@ -15,6 +24,27 @@ pub struct PageTable {
 }
 
 impl PageTable {
+    /// Creates a table with the given entries, where `next` is the next id to allocate.
+    ///
+    /// Ids below `next` that are not in `entries` are free to allocate.
+    pub fn with_entries(entries: &[(u64, u64)], next: u64) -> Self {
+        let table = Self::default();
+        let mut used = vec![false; next as usize];
+        for &(id, addr) in entries {
+            assert!(id < next, "id {} is not below the next id {}", id, next);
+            table.set(id, addr);
+            used[id as usize] = true;
+        }
+        table.inner.next.store(next, Ordering::Relaxed);
+        // Frees in reverse order so that smaller ids are allocated first.
+        for id in (0..next).rev() {
+            if !used[id as usize] {
+                table.inner.dealloc(id);
+            }
+        }
+        table
+    }
+
     pub fn get(&self, id: u64) -> u64 {
         self.inner.index(id).load(Ordering::Acquire)
     }
@@ -29,60 +59,129 @@ impl PageTable {
             .compare_exchange(old, new, Ordering::AcqRel, Ordering::Acquire)
     }
 
+    /// Returns the next id to allocate after the free ones.
+    pub fn next_id(&self) -> u64 {
+        self.inner.next.load(Ordering::Relaxed)
+    }
+
+    /// Returns the size of the segments that are allocated.
+    pub fn memory_size(&self) -> usize {
+        self.inner
+            .segments
+            .iter()
+            .enumerate()
+            .filter(|(_, segment)| !segment.load(Ordering::Acquire).is_null())
+            .map(|(i, _)| segment_layout(i).size())
+            .sum()
+    }
+
     pub fn alloc(&self, _: &Guard) -> Option<u64> {
         self.inner.alloc()
     }
 
+    /// Returns the id to the free list after all the current guards are dropped, so that threads
+    /// that may still see the id never observe its reuse.
     pub fn dealloc(&self, id: u64, guard: &Guard) {
+        self.inner.retired.lock().unwrap().insert(id);
         let inner = self.inner.clone();
         guard.defer(move || {
             inner.dealloc(id);
+            inner.retired.lock().unwrap().remove(&id);
         })
     }
+
+    /// Returns the ids in use with their entries, excluding the free ids and the ones that are
+    /// deallocated but not returned to the free list yet.
+    ///
+    /// Ids must not be allocated or deallocated concurrently, except by the deferred returns of
+    /// `dealloc`.
+    pub fn entries(&self) -> Vec<(u64, u64)> {
+        // Deferred returns push the ids to the free list before they remove them from the retired
+        // ones, so every id that is not in use is in one of them while the lock is held.
+        let retired = self.inner.retired.lock().unwrap();
+        let mut free = HashSet::new();
+        let mut id = self.inner.free.load(Ordering::Acquire);
+        while id != NIL {
+            free.insert(id);
+            id = self.get(id);
+        }
+        (0..self.next_id())
+            .filter(|id| !free.contains(id) && !retired.contains(id))
+            .map(|id| (id, self.get(id)))
+            .collect()
+    }
 }
 
+/// A segmented array of page addresses that grows on demand.
+///
+/// Segment `i` holds `BASE_LEN << i` entries, so a handful of segments cover the whole id space,
+/// and the table only takes memory proportional to the largest id in use. Segments are installed
+/// lazily with a CAS and never move, so reads are lock-free.
 struct Inner {
-    // Level 0: [0, L0_MAX)
-    l0: Box<L0<L0_LEN>>,
-    // Level 1: [L0_MAX, L1_MAX)
-    l1: Box<L1<L1_LEN>>,
-    // Level 2: [L1_MAX, L2_MAX)
-    l2: Box<L2<L2_LEN>>,
+    segments: [AtomicPtr<AtomicU64>; NUM_SEGMENTS],
     // The next id to allocate.
     next: AtomicU64,
     // The head of the free list.
     // The list uses epoch-based reclaimation to prevent the ABA problem.
     free: AtomicU64,
+    // The ids that are deallocated but not returned to the free list yet, whose entries may still
+    // refer to pages that are about to be freed.
+    retired: Mutex<HashSet<u64>>,
 }
 
 impl Default for Inner {
     fn default() -> Self {
         Self {
-            l0: Box::default(),
-            l1: Box::default(),
-            l2: Box::default(),
+            segments: [(); NUM_SEGMENTS].map(|_| AtomicPtr::default()),
             next: AtomicU64::new(0),
-            free: AtomicU64::new(L2_MAX),
+            free: AtomicU64::new(NIL),
+            retired: Mutex::default(),
+        }
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        for (i, segment) in self.segments.iter().enumerate() {
+            let ptr = segment.load(Ordering::Acquire);
+            if !ptr.is_null() {
+                unsafe { dealloc_segment(ptr, segment_layout(i)) };
+            }
         }
     }
 }
 
 impl Inner {
     fn index(&self, index: u64) -> &AtomicU64 {
-        if index < L0_MAX {
-            self.l0.index(index)
-        } else if index < L1_MAX {
-            self.l1.index(index - L0_MAX)
-        } else if index < L2_MAX {
-            self.l2.index(index - L1_MAX)
-        } else {
-            unreachable!()
+        let (i, j) = locate(index);
+        let mut ptr = self.segments[i].load(Ordering::Acquire);
+        if ptr.is_null() {
+            ptr = self.install_or_acquire_segment(i);
+        }
+        unsafe { &*ptr.add(j) }
+    }
+
+    #[cold]
+    fn install_or_acquire_segment(&self, index: usize) -> *mut AtomicU64 {
+        let layout = segment_layout(index);
+        let segment = alloc_segment(layout);
+        match self.segments[index].compare_exchange(
+            null_mut(),
+            segment,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => segment,
+            Err(current) => {
+                unsafe { dealloc_segment(segment, layout) };
+                current
+            }
         }
     }
 
     fn alloc(&self) -> Option<u64> {
         let mut id = self.free.load(Ordering::Acquire);
-        while id != L2_MAX {
+        while id != NIL {
             let next = self.index(id).load(Ordering::Acquire);
             match self
                 .free
@@ -92,13 +191,13 @@ impl Inner {
                 Err(actual) => id = actual,
             }
         }
-        if id == L2_MAX {
+        if id == NIL {
             id = self.next.load(Ordering::Relaxed);
-            if id < L2_MAX {
+            if id < MAX_ID {
                 id = self.next.fetch_add(1, Ordering::Relaxed);
             }
         }
-        if id < L2_MAX {
+        if id < MAX_ID {
             Some(id)
         } else {
             None
@@ -120,87 +219,64 @@ impl Inner {
     }
 }
 
-struct L0<const N: usize>([AtomicU64; N]);
+// Loom models tables with tiny segments, so that they grow with a few ids.
+#[cfg(not(loom))]
+const BASE_BITS: u32 = 10;
+#[cfg(loom)]
+const BASE_BITS: u32 = 1;
+const BASE_LEN: u64 = 1 << BASE_BITS;
+const NUM_SEGMENTS: usize = (u64::BITS - BASE_BITS) as usize;
+// Ids are offset by `BASE_LEN` to locate segments, so the largest ones are not usable.
+const MAX_ID: u64 = u64::MAX - BASE_LEN;
+// The end of the free list.
+const NIL: u64 = u64::MAX;
 
-impl<const N: usize> Default for L0<N> {
-    fn default() -> Self {
-        Self(unsafe { MaybeUninit::zeroed().assume_init() })
-    }
+/// Returns the segment and the offset in the segment of an id.
+fn locate(id: u64) -> (usize, usize) {
+    let x = id + BASE_LEN;
+    let bit = u64::BITS - 1 - x.leading_zeros();
+    let segment = (bit - BASE_BITS) as usize;
+    let offset = (x - (1 << bit)) as usize;
+    (segment, offset)
 }
 
-impl<const N: usize> L0<N> {
-    fn index(&self, index: u64) -> &AtomicU64 {
-        &self.0[index as usize]
-    }
+fn segment_layout(index: usize) -> Layout {
+    Layout::array::<AtomicU64>((BASE_LEN as usize) << index).unwrap()
 }
 
-macro_rules! define_level {
-    ($level:ident, $child:ty, $fanout:expr) => {
-        struct $level<const N: usize>([AtomicPtr<$child>; N]);
-
-        impl<const N: usize> Default for $level<N> {
-            fn default() -> Self {
-                Self(unsafe { MaybeUninit::zeroed().assume_init() })
-            }
-        }
-
-        impl<const N: usize> Drop for $level<N> {
-            fn drop(&mut self) {
-                for child in &self.0 {
-                    let ptr = child.load(Ordering::Acquire);
-                    if !ptr.is_null() {
-                        unsafe {
-                            Box::from_raw(ptr);
-                        }
-                    }
-                }
-            }
-        }
+/// Allocates a segment of zeros.
+#[cfg(not(loom))]
+fn alloc_segment(layout: Layout) -> *mut AtomicU64 {
+    use std::alloc::{alloc_zeroed, handle_alloc_error};
 
-        impl<const N: usize> $level<N> {
-            fn index(&self, index: u64) -> &AtomicU64 {
-                let i = index / $fanout;
-                let j = index % $fanout;
-                let p = self.0[i as usize].load(Ordering::Relaxed);
-                let child = unsafe {
-                    p.as_ref()
-                        .unwrap_or_else(|| self.install_or_acquire_child(i as usize))
-                };
-                child.index(j)
-            }
-
-            #[cold]
-            fn install_or_acquire_child(&self, index: usize) -> &$child {
-                let mut child = Box::into_raw(Box::default());
-                if let Err(current) = self.0[index].compare_exchange(
-                    null_mut(),
-                    child,
-                    Ordering::AcqRel,
-                    Ordering::Acquire,
-                ) {
-                    unsafe {
-                        Box::from_raw(child);
-                    }
-                    child = current;
-                }
-                unsafe { &*child }
-            }
-        }
-    };
+    let segment = unsafe { alloc_zeroed(layout) as *mut AtomicU64 };
+    if segment.is_null() {
+        handle_alloc_error(layout);
+    }
+    segment
 }
 
-const FANOUT: usize = 1 << 16;
-const L0_LEN: usize = FANOUT;
-const L1_LEN: usize = FANOUT - 1;
-const L2_LEN: usize = FANOUT - 1;
-const L0_MAX: u64 = FANOUT as u64;
-const L1_MAX: u64 = L0_MAX * L0_MAX;
-const L2_MAX: u64 = L1_MAX * L0_MAX;
+/// Allocates a segment of zeros, which are constructed one by one, since loom tracks atomics.
+#[cfg(loom)]
+fn alloc_segment(layout: Layout) -> *mut AtomicU64 {
+    let len = layout.size() / std::mem::size_of::<AtomicU64>();
+    let segment: Box<[AtomicU64]> = (0..len).map(|_| AtomicU64::new(0)).collect();
+    Box::into_raw(segment) as *mut AtomicU64
+}
 
-define_level!(L1, L0<FANOUT>, L0_MAX);
-define_level!(L2, L1<FANOUT>, L1_MAX);
+/// Deallocates a segment from `alloc_segment`.
+unsafe fn dealloc_segment(segment: *mut AtomicU64, layout: Layout) {
+    if cfg!(loom) {
+        let len = layout.size() / std::mem::size_of::<AtomicU64>();
+        drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(
+            segment, len,
+        )));
+    } else {
+        dealloc(segment as *mut u8, layout);
+    }
+}
 
-#[cfg(test)]
+#[cfg(all(test, not(loom)))]
 mod test {
     use crossbeam_epoch::unprotected;
 
@@ -218,12 +294,186 @@ mod test {
         assert_eq!(table.alloc(guard), Some(0));
     }
 
+    #[test]
+    fn with_entries() {
+        let guard = unsafe { unprotected() };
+        let table = PageTable::with_entries(&[(0, 10), (2, 12), (BASE_LEN, 13)], BASE_LEN + 2);
+        assert_eq!(table.get(0), 10);
+        assert_eq!(table.get(2), 12);
+        assert_eq!(table.get(BASE_LEN), 13);
+        assert_eq!(table.alloc(guard), Some(1));
+        assert_eq!(table.alloc(guard), Some(3));
+        for id in 4..BASE_LEN {
+            assert_eq!(table.alloc(guard), Some(id));
+        }
+        assert_eq!(table.alloc(guard), Some(BASE_LEN + 1));
+        assert_eq!(table.alloc(guard), Some(BASE_LEN + 2));
+        assert_eq!(table.next_id(), BASE_LEN + 3);
+    }
+
+    #[test]
+    fn entries() {
+        let table = PageTable::with_entries(&[(0, 10), (2, 12), (3, 13)], 5);
+        assert_eq!(table.entries(), vec![(0, 10), (2, 12), (3, 13)]);
+        // Deallocated ids are excluded before and after they are returned to the free list.
+        let guard = crossbeam_epoch::pin();
+        table.dealloc(2, &guard);
+        assert_eq!(table.entries(), vec![(0, 10), (3, 13)]);
+        drop(guard);
+        for _ in 0..1024 {
+            crossbeam_epoch::pin().flush();
+            if table.inner.retired.lock().unwrap().is_empty() {
+                break;
+            }
+        }
+        assert!(table.inner.retired.lock().unwrap().is_empty());
+        assert_eq!(table.entries(), vec![(0, 10), (3, 13)]);
+    }
+
+    #[test]
+    fn dealloc_after_epoch() {
+        let table = PageTable::default();
+        let reader = crossbeam_epoch::pin();
+        let id = table.alloc(&reader).unwrap();
+        {
+            let guard = crossbeam_epoch::pin();
+            table.dealloc(id, &guard);
+            guard.flush();
+        }
+        // The id is not reused while the reader may still see it.
+        for _ in 0..128 {
+            crossbeam_epoch::pin().flush();
+            assert_ne!(table.alloc(&reader), Some(id));
+        }
+        drop(reader);
+        let mut reused = false;
+        for _ in 0..1024 {
+            let guard = crossbeam_epoch::pin();
+            guard.flush();
+            if table.alloc(&guard) == Some(id) {
+                reused = true;
+                break;
+            }
+        }
+        assert!(reused);
+    }
+
     #[test]
     fn index() {
         let table = PageTable::default();
-        for i in [0, L0_MAX - 1, L0_MAX, L1_MAX - 1, L1_MAX, L2_MAX - 1] {
+        for i in [
+            0,
+            BASE_LEN - 1,
+            BASE_LEN,
+            BASE_LEN * 3,
+            BASE_LEN * 3 + 1,
+            1 << 24,
+        ] {
             table.set(i, i);
             assert_eq!(table.get(i), i);
         }
     }
+
+    #[test]
+    fn locate() {
+        assert_eq!(super::locate(0), (0, 0));
+        assert_eq!(super::locate(BASE_LEN - 1), (0, BASE_LEN as usize - 1));
+        assert_eq!(super::locate(BASE_LEN), (1, 0));
+        assert_eq!(
+            super::locate(BASE_LEN * 3 - 1),
+            (1, BASE_LEN as usize * 2 - 1)
+        );
+        assert_eq!(super::locate(BASE_LEN * 3), (2, 0));
+        assert_eq!(super::locate(MAX_ID - 1).0, NUM_SEGMENTS - 1);
+    }
+
+    #[test]
+    fn grow() {
+        const N: u64 = BASE_LEN * 16;
+        let table = PageTable::default();
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let table = table.clone();
+                std::thread::spawn(move || {
+                    let guard = unsafe { unprotected() };
+                    for _ in 0..N / 4 {
+                        let id = table.alloc(guard).unwrap();
+                        table.set(id, id + 1);
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        for id in 0..N {
+            assert_eq!(table.get(id), id + 1);
+        }
+    }
+}
+
+/// Model checks of the table with loom, run with
+/// `RUSTFLAGS="--cfg loom" cargo test -p photondb-engine --release --lib loom`.
+#[cfg(all(test, loom))]
+mod loom_test {
+    use crossbeam_epoch::unprotected;
+    use loom::thread;
+
+    use super::*;
+
+    #[test]
+    fn concurrent_alloc() {
+        loom::model(|| {
+            // Ids 0 and 1 are free, and 2 is the next one.
+            let table = PageTable::with_entries(&[], 2);
+            let handles: Vec<_> = (0..2)
+                .map(|_| {
+                    let table = table.clone();
+                    thread::spawn(move || table.alloc(unsafe { unprotected() }).unwrap())
+                })
+                .collect();
+            let mut ids: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+            ids.sort_unstable();
+            assert_eq!(ids, [0, 1]);
+        });
+    }
+
+    #[test]
+    fn alloc_and_dealloc() {
+        loom::model(|| {
+            let guard = unsafe { unprotected() };
+            // Id 0 is in use, and 1 is free.
+            let table = PageTable::with_entries(&[(0, 10)], 2);
+            let dealloc = {
+                let table = table.clone();
+                thread::spawn(move || table.dealloc(0, unsafe { unprotected() }))
+            };
+            let id = table.alloc(guard).unwrap();
+            dealloc.join().unwrap();
+            // All ids are allocated once, whichever order the free list is changed in.
+            let mut ids = vec![id, table.alloc(guard).unwrap(), table.alloc(guard).unwrap()];
+            ids.sort_unstable();
+            assert_eq!(ids, [0, 1, 2]);
+        });
+    }
+
+    #[test]
+    fn concurrent_grow() {
+        loom::model(|| {
+            let table = PageTable::default();
+            // The ids are in the same segment, which is installed by either thread.
+            let handles: Vec<_> = [BASE_LEN, BASE_LEN + 1]
+                .into_iter()
+                .map(|id| {
+                    let table = table.clone();
+                    thread::spawn(move || table.set(id, id + 1))
+                })
+                .collect();
+            for handle in handles {
+                handle.join().unwrap();
+            }
+            assert_eq!(table.get(BASE_LEN), BASE_LEN + 1);
+            assert_eq!(table.get(BASE_LEN + 1), BASE_LEN + 2);
+        });
+    }
 }
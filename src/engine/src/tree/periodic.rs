@@ -0,0 +1,93 @@
+use std::{
+    sync::{Arc, Weak},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use futures::{
+    channel::oneshot,
+    executor,
+    future::{self, Either},
+};
+
+use super::{BTree, Ghost};
+use crate::env::Env;
+
+/// The shortest wait between two periodic checkpoints, so that a zero interval doesn't spin.
+const MIN_WAIT: Duration = Duration::from_millis(1);
+
+/// Checkpoints a tree in the background at the interval of `Options::checkpoint_interval` or
+/// `SyncMode::Every`, so that writes are durable within the interval even if no more writes come.
+///
+/// Checkpoints hold pinned ghosts across awaits, so they can't run in the `Send` tasks of
+/// `Env::spawn`. They run on a thread of their own instead, which still sleeps and does I/O through
+/// the `Env` of the tree. The thread only holds the tree during a checkpoint, and exits when it is
+/// stopped or the tree is dropped.
+pub struct PeriodicCheckpoints {
+    stop: Option<oneshot::Sender<()>>,
+    done: Option<oneshot::Receiver<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl PeriodicCheckpoints {
+    /// Starts the periodic checkpoints of `tree`, or returns `None` if they are disabled.
+    pub fn start(tree: &Arc<BTree>) -> Option<Self> {
+        let interval = tree.periodic_interval()?;
+        let env = tree.env().clone();
+        let tree = Arc::downgrade(tree);
+        let (stop, stopped) = oneshot::channel();
+        let (done_tx, done) = oneshot::channel();
+        let thread = thread::Builder::new()
+            .name("photondb-checkpoint".to_owned())
+            .spawn(move || {
+                executor::block_on(run(tree, env, interval, stopped));
+                let _ = done_tx.send(());
+            })
+            .expect("failed to spawn thread");
+        Some(Self {
+            stop: Some(stop),
+            done: Some(done),
+            thread: Some(thread),
+        })
+    }
+
+    /// Stops the checkpoints, and waits for the running one to finish.
+    pub async fn stop(mut self) {
+        self.stop.take();
+        if let Some(done) = self.done.take() {
+            let _ = done.await;
+        }
+    }
+}
+
+impl Drop for PeriodicCheckpoints {
+    /// Stops the checkpoints, and blocks until the running one finishes, so that the tree is
+    /// released when this returns.
+    fn drop(&mut self) {
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+async fn run(
+    tree: Weak<BTree>,
+    env: Arc<dyn Env>,
+    interval: Duration,
+    mut stopped: oneshot::Receiver<()>,
+) {
+    let mut wait = interval;
+    loop {
+        // A dropped sender stops the checkpoints too.
+        let sleep = env.sleep(wait.max(MIN_WAIT));
+        if let Either::Right(_) = future::select(sleep, &mut stopped).await {
+            break;
+        }
+        let tree = match tree.upgrade() {
+            Some(tree) => tree,
+            None => break,
+        };
+        wait = tree.periodic_checkpoint(interval, &Ghost::pin()).await;
+    }
+}
@@ -0,0 +1,105 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Limits the rate of writes when checkpoints or consolidations fall behind them.
+///
+/// The tree falls behind if the keys and values written since the last checkpoint exceed
+/// `max_dirty_bytes`, or if a write leaves more than `max_delta_backlog` deltas on its leaf because
+/// the leaf can't be consolidated. Writes in either case are delayed to `bytes_per_sec`, so that
+/// bursts of writes don't grow the memory without bound.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct WriteRateLimit {
+    /// The bytes of keys and values per second that writes are limited to.
+    pub bytes_per_sec: u64,
+    /// The bytes of keys and values written since the last checkpoint.
+    pub max_dirty_bytes: u64,
+    /// The number of deltas on a leaf, which should be larger than `Options::data_delta_length`.
+    pub max_delta_backlog: u8,
+}
+
+/// A token bucket that delays writes to `WriteRateLimit::bytes_per_sec`.
+///
+/// The bucket holds up to one second of tokens. A write takes its tokens even if the bucket doesn't
+/// have enough, and waits until the debt is paid off, so that writes are delayed in the order that
+/// they take tokens.
+pub struct RateLimiter {
+    limit: WriteRateLimit,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(limit: WriteRateLimit) -> Self {
+        Self::with_start(limit, Instant::now())
+    }
+
+    fn with_start(limit: WriteRateLimit, start: Instant) -> Self {
+        let bucket = Bucket {
+            tokens: limit.bytes_per_sec as f64,
+            last_refill: start,
+        };
+        Self {
+            limit,
+            bucket: Mutex::new(bucket),
+        }
+    }
+
+    pub fn limit(&self) -> &WriteRateLimit {
+        &self.limit
+    }
+
+    /// Takes `bytes` tokens and returns how long the write should wait for them.
+    pub fn acquire(&self, bytes: u64) -> Duration {
+        self.acquire_at(bytes, Instant::now())
+    }
+
+    fn acquire_at(&self, bytes: u64, now: Instant) -> Duration {
+        let rate = self.limit.bytes_per_sec.max(1) as f64;
+        let mut bucket = self.bucket.lock().unwrap();
+        let elapsed = now.saturating_duration_since(bucket.last_refill);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * rate).min(rate);
+        bucket.last_refill = bucket.last_refill.max(now);
+        bucket.tokens -= bytes as f64;
+        if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / rate)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn acquire() {
+        let limit = WriteRateLimit {
+            bytes_per_sec: 1000,
+            max_dirty_bytes: 0,
+            max_delta_backlog: 0,
+        };
+        let start = Instant::now();
+        let limiter = RateLimiter::with_start(limit, start);
+        let acquire_millis = |bytes, now| {
+            let delay: Duration = limiter.acquire_at(bytes, now);
+            (delay.as_secs_f64() * 1000.0).round() as u64
+        };
+        // A full bucket allows a burst of one second.
+        assert_eq!(acquire_millis(1000, start), 0);
+        assert_eq!(acquire_millis(500, start), 500);
+        assert_eq!(acquire_millis(500, start), 1000);
+        // The debt is paid off over time.
+        assert_eq!(acquire_millis(100, start + Duration::from_secs(1)), 100);
+        // The bucket doesn't hold more than one second of tokens.
+        let now = start + Duration::from_secs(10);
+        assert_eq!(acquire_millis(1000, now), 0);
+        assert_eq!(acquire_millis(1, now), 1);
+    }
+}
@@ -0,0 +1,241 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::broadcast;
+
+use super::{Error, Result};
+
+/// A committed write of a table.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Change {
+    pub key: Vec<u8>,
+    pub lsn: u64,
+    /// The value of a put, or `None` for a delete.
+    pub value: Option<Vec<u8>>,
+}
+
+/// Changes that a replica applies together with `Table::apply_batch_with_lsn`.
+///
+/// The LSN of a batch increases from batch to batch within the epoch of the batch, like the
+/// position of the batch in the stream of the primary. Batches from `ChangeStream::next_batch`
+/// carry the stream epoch and the stream LSN of their last change, so that a replica can resume
+/// the stream from its `Table::applied_epoch` and `Table::applied_lsn` with
+/// `Table::subscribe_from`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ChangeBatch {
+    pub epoch: u64,
+    pub lsn: u64,
+    pub changes: Vec<Change>,
+}
+
+/// A change with its position in the stream of the table.
+struct StreamChange {
+    lsn: u64,
+    change: Change,
+}
+
+/// A stream of the changes committed to a table after the stream is subscribed.
+///
+/// Changes of different keys may be out of LSN order if they are written concurrently. A follower
+/// that applies all of them ends up with the same entries as the table regardless of the order,
+/// since entries are versioned by their LSNs.
+///
+/// Each change also has a stream LSN, which is its position in the stream. Stream LSNs increase by
+/// one from change to change within the stream epoch of the table. The changes since the last
+/// checkpoint may be lost in a crash, so the stream starts over in a new random epoch each time
+/// the table is opened, and positions of an earlier epoch can't be resumed from.
+pub struct ChangeStream {
+    // The changes before the stream is subscribed, which are returned first.
+    replay: VecDeque<Arc<StreamChange>>,
+    rx: broadcast::Receiver<Arc<StreamChange>>,
+    // The number of changes skipped after the changes of the last batch, which is returned as an
+    // error by the next call.
+    lagged: Option<u64>,
+    epoch: u64,
+    // The stream LSN of the last returned change.
+    lsn: u64,
+}
+
+impl ChangeStream {
+    /// Returns the next change, or `None` if the table is closed.
+    ///
+    /// Returns `Error::Lagged` if the stream falls behind the table by more than
+    /// `Options::replication_buffer_size` changes, in which case the skipped changes are lost and
+    /// the follower must be rebuilt.
+    pub async fn next(&mut self) -> Result<Option<Change>> {
+        if let Some(skipped) = self.lagged.take() {
+            return Err(Error::Lagged { skipped });
+        }
+        let change = match self.replay.pop_front() {
+            Some(change) => change,
+            None => match self.rx.recv().await {
+                Ok(change) => change,
+                Err(broadcast::error::RecvError::Closed) => return Ok(None),
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    return Err(Error::Lagged { skipped })
+                }
+            },
+        };
+        Ok(Some(self.take(change)))
+    }
+
+    /// Returns the changes that are ready, or waits for the next one, as a batch of the stream
+    /// epoch whose LSN is the stream LSN of its last change. Returns `None` if the table is closed.
+    ///
+    /// Errors are the same as `next`, and the changes before an error are returned first.
+    pub async fn next_batch(&mut self) -> Result<Option<ChangeBatch>> {
+        let mut changes = match self.next().await? {
+            Some(change) => vec![change],
+            None => return Ok(None),
+        };
+        while let Some(change) = self.replay.pop_front() {
+            changes.push(self.take(change));
+        }
+        loop {
+            match self.rx.try_recv() {
+                Ok(change) => changes.push(self.take(change)),
+                Err(broadcast::error::TryRecvError::Lagged(skipped)) => {
+                    self.lagged = Some(skipped);
+                    break;
+                }
+                Err(_) => break,
+            }
+        }
+        Ok(Some(ChangeBatch {
+            epoch: self.epoch,
+            lsn: self.lsn,
+            changes,
+        }))
+    }
+
+    /// Returns the stream epoch of the table.
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Returns the stream LSN of the last returned change, or the one that the stream is
+    /// subscribed from if there is none.
+    pub fn lsn(&self) -> u64 {
+        self.lsn
+    }
+
+    fn take(&mut self, change: Arc<StreamChange>) -> Change {
+        self.lsn = change.lsn;
+        Arc::try_unwrap(change)
+            .map(|change| change.change)
+            .unwrap_or_else(|change| change.change.clone())
+    }
+}
+
+/// Publishes the committed changes of a tree to its subscribers.
+pub(super) struct ChangePublisher {
+    capacity: usize,
+    epoch: u64,
+    state: Mutex<PublisherState>,
+}
+
+struct PublisherState {
+    tx: broadcast::Sender<Arc<StreamChange>>,
+    // The stream LSN of the last change.
+    lsn: u64,
+    // The last changes, which are kept once a stream is subscribed, so that streams can resume
+    // from them.
+    history: Option<VecDeque<Arc<StreamChange>>>,
+}
+
+impl ChangePublisher {
+    /// Creates a publisher whose stream starts in `epoch`.
+    pub(super) fn new(capacity: usize, epoch: u64) -> Self {
+        let capacity = capacity.max(1);
+        let (tx, _) = broadcast::channel(capacity);
+        Self {
+            capacity,
+            epoch,
+            state: Mutex::new(PublisherState {
+                tx,
+                lsn: 0,
+                history: None,
+            }),
+        }
+    }
+
+    pub(super) fn subscribe(&self) -> ChangeStream {
+        let mut state = self.state.lock().unwrap();
+        let lsn = state.lsn;
+        self.stream_from(&mut state, lsn).unwrap()
+    }
+
+    /// Subscribes to the changes after the stream LSN `lsn` of `epoch`.
+    ///
+    /// Returns `Error::Lagged` if some of the changes are not kept anymore, and
+    /// `Error::InvalidArgument` if `epoch` is not the current epoch or `lsn` is after the last
+    /// change.
+    pub(super) fn subscribe_from(&self, epoch: u64, lsn: u64) -> Result<ChangeStream> {
+        if epoch != self.epoch {
+            return Err(Error::InvalidArgument(format!(
+                "stream position {} of epoch {:#x} is from before the table was reopened in epoch \
+                 {:#x}, and the changes after it may be lost",
+                lsn, epoch, self.epoch
+            )));
+        }
+        let mut state = self.state.lock().unwrap();
+        self.stream_from(&mut state, lsn)
+    }
+
+    fn stream_from(&self, state: &mut PublisherState, lsn: u64) -> Result<ChangeStream> {
+        if lsn > state.lsn {
+            return Err(Error::InvalidArgument(format!(
+                "stream LSN {} is after the last change at {}",
+                lsn, state.lsn
+            )));
+        }
+        let capacity = self.capacity;
+        let history = state
+            .history
+            .get_or_insert_with(|| VecDeque::with_capacity(capacity));
+        let first = history.front().map_or(state.lsn + 1, |change| change.lsn);
+        if lsn + 1 < first {
+            return Err(Error::Lagged {
+                skipped: first - lsn - 1,
+            });
+        }
+        let replay = history
+            .iter()
+            .filter(|change| change.lsn > lsn)
+            .cloned()
+            .collect();
+        Ok(ChangeStream {
+            replay,
+            rx: state.tx.subscribe(),
+            lagged: None,
+            epoch: self.epoch,
+            lsn,
+        })
+    }
+
+    /// Publishes a change, and keeps it for the streams subscribed later if there are any.
+    pub(super) fn publish(&self, key: &[u8], lsn: u64, value: Option<&[u8]>) {
+        let mut state = self.state.lock().unwrap();
+        state.lsn += 1;
+        if state.history.is_none() {
+            return;
+        }
+        let change = Arc::new(StreamChange {
+            lsn: state.lsn,
+            change: Change {
+                key: key.to_vec(),
+                lsn,
+                value: value.map(|v| v.to_vec()),
+            },
+        });
+        let history = state.history.as_mut().unwrap();
+        if history.len() == self.capacity {
+            history.pop_front();
+        }
+        history.push_back(change.clone());
+        // The subscribers may be dropped in the meantime, which is fine.
+        let _ = state.tx.send(change);
+    }
+}
@@ -0,0 +1,166 @@
+use std::{
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::Instant,
+};
+
+use tokio::sync::Notify;
+
+use super::StallStats;
+
+/// The kind of work that takes precedence over stalled writes.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Work {
+    SwapIn = 0,
+    Read = 1,
+}
+
+/// A scheduler that prevents stalled writes from starving reads.
+///
+/// Swap-ins and reads hold a `WorkGuard` while they run. A stalled write waits for the swap-ins and
+/// then the reads that are pending when it stalls, so reads never queue behind write retries, and
+/// a write only waits for a bounded amount of work.
+#[derive(Default)]
+pub struct Scheduler {
+    queues: [Queue; 2],
+    stalled: AtomicUsize,
+    counters: StallCounters,
+}
+
+impl Scheduler {
+    /// Marks the beginning of some work, which ends when the returned guard is dropped.
+    pub fn begin(&self, work: Work) -> WorkGuard<'_> {
+        let queue = &self.queues[work as usize];
+        queue.begun.fetch_add(1, Ordering::SeqCst);
+        if self.stalled.load(Ordering::SeqCst) > 0 {
+            let counter = match work {
+                Work::SwapIn => &self.counters.num_swapins,
+                Work::Read => &self.counters.num_reads,
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+        WorkGuard { sched: self, queue }
+    }
+
+    /// Stalls a write until the pending swap-ins and reads finish.
+    pub async fn stall(&self) {
+        self.stalled.fetch_add(1, Ordering::SeqCst);
+        let start = Instant::now();
+        self.queues[Work::SwapIn as usize].drain().await;
+        let swapin_end = Instant::now();
+        self.queues[Work::Read as usize].drain().await;
+        let read_end = Instant::now();
+        self.stalled.fetch_sub(1, Ordering::SeqCst);
+
+        let counters = &self.counters;
+        let swapin_micros = (swapin_end - start).as_micros() as u64;
+        let read_micros = (read_end - swapin_end).as_micros() as u64;
+        counters.num_stalls.fetch_add(1, Ordering::Relaxed);
+        counters
+            .stall_micros
+            .fetch_add(swapin_micros + read_micros, Ordering::Relaxed);
+        counters
+            .yield_to_swapin_micros
+            .fetch_add(swapin_micros, Ordering::Relaxed);
+        counters
+            .yield_to_read_micros
+            .fetch_add(read_micros, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> StallStats {
+        let counters = &self.counters;
+        StallStats {
+            num_stalls: counters.num_stalls.load(Ordering::Relaxed),
+            stall_micros: counters.stall_micros.load(Ordering::Relaxed),
+            yield_to_swapin_micros: counters.yield_to_swapin_micros.load(Ordering::Relaxed),
+            yield_to_read_micros: counters.yield_to_read_micros.load(Ordering::Relaxed),
+            num_swapins_during_stall: counters.num_swapins.load(Ordering::Relaxed),
+            num_reads_during_stall: counters.num_reads.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A guard that ends some work when dropped.
+pub struct WorkGuard<'a> {
+    sched: &'a Scheduler,
+    queue: &'a Queue,
+}
+
+impl Drop for WorkGuard<'_> {
+    fn drop(&mut self) {
+        self.queue.done.fetch_add(1, Ordering::SeqCst);
+        if self.sched.stalled.load(Ordering::SeqCst) > 0 {
+            self.queue.notify.notify_waiters();
+        }
+    }
+}
+
+#[derive(Default)]
+struct Queue {
+    begun: AtomicU64,
+    done: AtomicU64,
+    notify: Notify,
+}
+
+impl Queue {
+    /// Waits until as much work as pending at this point has been done.
+    async fn drain(&self) {
+        let target = self.begun.load(Ordering::SeqCst);
+        loop {
+            let notified = self.notify.notified();
+            if self.done.load(Ordering::SeqCst) >= target {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+#[derive(Default)]
+struct StallCounters {
+    num_stalls: AtomicU64,
+    stall_micros: AtomicU64,
+    yield_to_swapin_micros: AtomicU64,
+    yield_to_read_micros: AtomicU64,
+    num_swapins: AtomicU64,
+    num_reads: AtomicU64,
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use tokio::time::timeout;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn stall() {
+        let sched = Scheduler::default();
+        // A stall without pending work returns immediately.
+        sched.stall().await;
+
+        let read = sched.begin(Work::Read);
+        let swapin = sched.begin(Work::SwapIn);
+        let stall = sched.stall();
+        tokio::pin!(stall);
+        let wait = Duration::from_millis(10);
+        assert!(timeout(wait, &mut stall).await.is_err());
+        drop(swapin);
+        assert!(timeout(wait, &mut stall).await.is_err());
+        // Work that begins after the stall is not waited.
+        let _late_read = sched.begin(Work::Read);
+        drop(read);
+        stall.await;
+
+        let stats = sched.stats();
+        assert_eq!(stats.num_stalls, 2);
+        assert_eq!(stats.num_reads_during_stall, 1);
+        assert_eq!(stats.num_swapins_during_stall, 0);
+        assert!(stats.yield_to_swapin_micros >= 10_000);
+        assert!(stats.yield_to_read_micros >= 10_000);
+        assert_eq!(
+            stats.stall_micros,
+            stats.yield_to_swapin_micros + stats.yield_to_read_micros
+        );
+    }
+}
@@ -0,0 +1,246 @@
+//! Allocation state of the cache that is sharded by threads.
+//!
+//! Every page allocation and deallocation updates the size of the cache, so a single counter
+//! bounces between the cores of concurrent writers. Threads are assigned to shards in a
+//! round-robin manner instead, and each shard lives on its own cache line.
+
+use std::{
+    cell::Cell,
+    sync::{
+        atomic::{AtomicIsize, AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+use super::{
+    malloc,
+    page::{PageAlloc, PagePtr},
+    pagecache::PageCache,
+};
+
+const NUM_SHARDS: usize = 16;
+
+static NEXT_SHARD: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static SHARD: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+/// Returns the shard of the current thread.
+fn current_shard() -> usize {
+    SHARD.with(|shard| match shard.get() {
+        Some(i) => i,
+        None => {
+            let i = NEXT_SHARD.fetch_add(1, Ordering::Relaxed) % NUM_SHARDS;
+            shard.set(Some(i));
+            i
+        }
+    })
+}
+
+/// Aligns a value to its own cache line, so that shards don't share lines.
+#[derive(Default)]
+#[repr(align(128))]
+struct Padded<T>(T);
+
+/// A counter that is sharded by threads.
+///
+/// Shards go negative on subtraction, since pages are often freed on other threads than the ones
+/// that allocate them, and the sum of the shards is the value of the counter.
+#[derive(Default)]
+pub struct ShardedCounter {
+    shards: [Padded<AtomicIsize>; NUM_SHARDS],
+}
+
+impl ShardedCounter {
+    pub fn add(&self, n: usize) {
+        self.shards[current_shard()]
+            .0
+            .fetch_add(n as isize, Ordering::Relaxed);
+    }
+
+    pub fn sub(&self, n: usize) {
+        self.shards[current_shard()]
+            .0
+            .fetch_sub(n as isize, Ordering::Relaxed);
+    }
+
+    /// Returns the sum of the shards.
+    ///
+    /// The shards are not read at once, so the sum may miss an addition on one shard but see the
+    /// matching subtraction on another. Such sums are clamped at zero instead of wrapping around
+    /// to a huge value.
+    pub fn get(&self) -> usize {
+        let sum = self.shards.iter().fold(0isize, |sum, shard| {
+            sum.wrapping_add(shard.0.load(Ordering::Relaxed))
+        });
+        sum.max(0) as usize
+    }
+}
+
+/// The smallest size class of the free lists.
+const MIN_CLASS_SIZE: usize = 64;
+
+/// The number of size classes, which are the powers of two from `MIN_CLASS_SIZE` on and cover
+/// the common sizes of delta pages.
+const NUM_CLASSES: usize = 5;
+
+/// The maximum number of pages kept in each size class of a shard.
+const MAX_CLASS_PAGES: usize = 16;
+
+/// Free lists of small pages allocated by `malloc`, which are sharded by threads.
+///
+/// Delta pages are allocated and freed at a high rate, so a freed page is kept in the free list of
+/// the current thread instead, and reused by the next allocation of the same class on that thread
+/// without calling the allocator. A page goes to the largest class that its usable size covers,
+/// and each list is bounded, so the pages kept are a small, fixed amount of memory.
+#[derive(Default)]
+pub struct FreeLists {
+    shards: [Padded<Mutex<[Vec<usize>; NUM_CLASSES]>>; NUM_SHARDS],
+    size: ShardedCounter,
+}
+
+impl FreeLists {
+    /// Returns the size of the pages in the lists.
+    pub fn size(&self) -> usize {
+        self.size.get()
+    }
+
+    /// Takes a page of at least `size` bytes from the lists of the current thread.
+    pub fn pop(&self, size: usize) -> Option<PagePtr> {
+        let class =
+            (MIN_CLASS_SIZE.max(size).next_power_of_two() / MIN_CLASS_SIZE).trailing_zeros();
+        let class = class as usize;
+        if class >= NUM_CLASSES {
+            return None;
+        }
+        let ptr = self.shards[current_shard()].0.lock().unwrap()[class].pop()?;
+        let page = unsafe { PagePtr::new(ptr as *mut u8) }?;
+        self.size.sub(unsafe { malloc::usable_size(page.as_raw()) });
+        Some(page)
+    }
+
+    /// Puts a page with `usable_size` into the lists of the current thread, and returns false if
+    /// the page doesn't fit, in which case it should be freed.
+    pub fn push(&self, page: PagePtr, usable_size: usize) -> bool {
+        if usable_size < MIN_CLASS_SIZE {
+            return false;
+        }
+        // The largest class that the page covers.
+        let class = (usize::BITS - 1 - (usable_size / MIN_CLASS_SIZE).leading_zeros()) as usize;
+        if class >= NUM_CLASSES {
+            return false;
+        }
+        let mut lists = self.shards[current_shard()].0.lock().unwrap();
+        if lists[class].len() >= MAX_CLASS_PAGES {
+            return false;
+        }
+        lists[class].push(page.as_raw() as usize);
+        self.size.add(usable_size);
+        true
+    }
+}
+
+impl Drop for FreeLists {
+    fn drop(&mut self) {
+        for shard in &mut self.shards {
+            for list in shard.0.get_mut().unwrap() {
+                for &ptr in list.iter() {
+                    unsafe {
+                        let size = malloc::usable_size(ptr as *const u8);
+                        malloc::dealloc(ptr as *mut u8, PageCache::alloc_layout(size));
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::Arc, thread};
+
+    use super::*;
+
+    #[test]
+    fn sharded_counter() {
+        let counter = Arc::new(ShardedCounter::default());
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let counter = counter.clone();
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        counter.add(3);
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(counter.get(), 12000);
+        // Subtractions on other threads go negative in their shards.
+        counter.sub(12000);
+        assert_eq!(counter.get(), 0);
+    }
+
+    #[test]
+    fn sharded_counter_never_wraps() {
+        let counter = Arc::new(ShardedCounter::default());
+        let done = Arc::new(AtomicUsize::new(0));
+        // Sizes are added on one thread and subtracted on another, like pages that are allocated
+        // and freed on different threads.
+        let mut handles = Vec::new();
+        for _ in 0..2 {
+            let (tx, rx) = std::sync::mpsc::sync_channel(1);
+            let producer = counter.clone();
+            handles.push(thread::spawn(move || {
+                for _ in 0..10000 {
+                    producer.add(4096);
+                    tx.send(()).unwrap();
+                }
+            }));
+            let consumer = counter.clone();
+            let done = done.clone();
+            handles.push(thread::spawn(move || {
+                while rx.recv().is_ok() {
+                    consumer.sub(4096);
+                }
+                done.fetch_add(1, Ordering::Relaxed);
+            }));
+        }
+        while done.load(Ordering::Relaxed) < 2 {
+            assert!(counter.get() < isize::MAX as usize);
+        }
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(counter.get(), 0);
+    }
+
+    #[test]
+    fn free_lists() {
+        let lists = FreeLists::default();
+        let cache = PageCache::default();
+        let page = cache.alloc(100).unwrap();
+        let usable_size = unsafe { malloc::usable_size(page.as_raw()) };
+        assert!(lists.push(page, usable_size));
+        assert_eq!(lists.size(), usable_size);
+        // The page is in the class of 64 bytes.
+        assert!(lists.pop(128).is_none());
+        assert_eq!(lists.pop(50).unwrap().as_raw(), page.as_raw());
+        assert_eq!(lists.size(), 0);
+        assert!(lists.pop(50).is_none());
+        unsafe { cache.dealloc(page) };
+
+        for _ in 0..MAX_CLASS_PAGES {
+            let page = cache.alloc(64).unwrap();
+            assert!(lists.push(page, 64));
+        }
+        let page = cache.alloc(64).unwrap();
+        assert!(!lists.push(page, 64));
+        assert!(!lists.push(page, 32));
+        assert!(!lists.push(page, 4096));
+        unsafe { cache.dealloc(page) };
+    }
+}
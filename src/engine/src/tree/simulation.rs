@@ -0,0 +1,238 @@
+//! Simulation tests of recovery.
+//!
+//! Each seed runs concurrent writes and checkpoints on a `SimEnv` that crashes at a random point,
+//! and then checks that the table recovers the state of the last completed checkpoint. Set
+//! `PHOTONDB_SIM_SEEDS` to run more seeds, or `PHOTONDB_SIM_SEED` to run a failed one again.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use futures::future::{join, join_all};
+
+use super::{Options, Table};
+use crate::env::{Env, Rng, SimEnv};
+
+const PATH: &str = "/db";
+const NUM_KEYS: u64 = 64;
+const NUM_WRITERS: u64 = 3;
+const NUM_WRITES: u64 = 32;
+const NUM_CHECKPOINTS: u64 = 3;
+
+/// A write that is attempted on the table.
+#[derive(Clone, Copy)]
+struct Write {
+    lsn: u64,
+    /// Puts the LSN as the value, or deletes the key if false.
+    put: bool,
+    /// The order in which the write completed, or `None` if it failed.
+    done: Option<u64>,
+}
+
+/// The history of the writes of a run.
+#[derive(Default)]
+struct History {
+    writes: Mutex<HashMap<Vec<u8>, Vec<Write>>>,
+    /// Orders the completions of writes and the starts of checkpoints.
+    clock: AtomicU64,
+    /// Writes that complete before this are durable.
+    durable: AtomicU64,
+}
+
+impl History {
+    /// Checks a recovered value of a key, which must come from an attempted write, and not be older
+    /// than the durable writes.
+    fn check(&self, key: &[u8], value: Option<Vec<u8>>) -> Result<(), String> {
+        let writes = self.writes.lock().unwrap();
+        let writes = writes.get(key).map_or(&[][..], Vec::as_slice);
+        let durable = self.durable.load(Ordering::SeqCst);
+        let min_lsn = writes
+            .iter()
+            .filter(|write| matches!(write.done, Some(done) if done < durable))
+            .map(|write| write.lsn)
+            .max();
+        let found = match &value {
+            Some(value) => {
+                let lsn = u64::from_be_bytes(value.as_slice().try_into().unwrap());
+                writes.iter().any(|write| write.put && write.lsn == lsn) && Some(lsn) >= min_lsn
+            }
+            None => {
+                min_lsn.is_none()
+                    || writes
+                        .iter()
+                        .any(|write| !write.put && Some(write.lsn) >= min_lsn)
+            }
+        };
+        if !found {
+            return Err(format!(
+                "key {} recovers {:?}, while the durable LSN is {:?}",
+                key.escape_ascii(),
+                value,
+                min_lsn
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn options(rng: &mut Rng) -> Options {
+    Options {
+        data_node_size: 256,
+        data_delta_length: 4,
+        index_node_entries: 4,
+        // Small caches flush and evict nodes between checkpoints.
+        cache_size: if rng.below(2) == 0 { 4096 } else { usize::MAX },
+        page_restart_interval: rng.below(4) as u32,
+        ..Default::default()
+    }
+}
+
+fn key(i: u64) -> Vec<u8> {
+    format!("key{:03}", i).into_bytes()
+}
+
+async fn open(env: &SimEnv, opts: Options) -> super::Result<Table> {
+    Table::open_with_env(Arc::new(env.clone()), PATH, opts).await
+}
+
+async fn write(table: &Table, env: &SimEnv, history: &History, lsn: &AtomicU64, seed: u64) {
+    let mut rng = Rng(seed);
+    for _ in 0..NUM_WRITES {
+        env.sleep(Duration::from_millis(rng.below(10))).await;
+        let key = key(rng.below(NUM_KEYS));
+        let lsn = lsn.fetch_add(1, Ordering::SeqCst) + 1;
+        let put = rng.below(8) != 0;
+        let write = Write {
+            lsn,
+            put,
+            done: None,
+        };
+        history
+            .writes
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_default()
+            .push(write);
+        let result = if put {
+            table.put(&key, lsn, &lsn.to_be_bytes()).await
+        } else {
+            table.delete(&key, lsn).await
+        };
+        if result.is_err() {
+            return;
+        }
+        let done = history.clock.fetch_add(1, Ordering::SeqCst);
+        let mut writes = history.writes.lock().unwrap();
+        let write = writes.get_mut(&key).unwrap();
+        write
+            .iter_mut()
+            .find(|write| write.lsn == lsn)
+            .unwrap()
+            .done = Some(done);
+    }
+}
+
+async fn checkpoint(table: &Table, env: &SimEnv, history: &History, seed: u64) {
+    let mut rng = Rng(seed);
+    for _ in 0..NUM_CHECKPOINTS {
+        env.sleep(Duration::from_millis(rng.below(200))).await;
+        let start = history.clock.fetch_add(1, Ordering::SeqCst);
+        if table.checkpoint().await.is_err() {
+            return;
+        }
+        history.durable.fetch_max(start, Ordering::SeqCst);
+    }
+}
+
+/// Runs a seed and returns the problem found.
+fn run(seed: u64) -> Result<(), String> {
+    let mut rng = Rng(seed);
+    let opts = options(&mut rng);
+    let env = SimEnv::new(seed);
+    // Some runs end without a crash, which still lose the data after the last checkpoint.
+    if rng.below(4) != 0 {
+        env.crash_after(rng.below(120));
+    }
+    let history = History::default();
+    let lsn = AtomicU64::new(0);
+    env.block_on(async {
+        let table = match open(&env, opts.clone()).await {
+            Ok(table) => table,
+            Err(_) => return,
+        };
+        // The tasks run in the future of `block_on`, since futures of tables are not `Send`.
+        let checkpoint = checkpoint(&table, &env, &history, rng.next());
+        let seeds: Vec<_> = (0..NUM_WRITERS).map(|_| rng.next()).collect();
+        let writers = seeds
+            .into_iter()
+            .map(|seed| write(&table, &env, &history, &lsn, seed));
+        join(join_all(writers), checkpoint).await;
+    });
+    let crashed = env.crashed();
+
+    let env = env.restart();
+    env.block_on(async {
+        let table = open(&env, opts.clone())
+            .await
+            .map_err(|err| format!("recovery fails (crashed: {}): {}", crashed, err))?;
+        let report = table.verify().await.map_err(|err| err.to_string())?;
+        if !report.is_ok() {
+            return Err(format!("recovery is corrupted: {:?}", report.problems));
+        }
+        let next_lsn = lsn.load(Ordering::SeqCst) + 1;
+        for i in 0..NUM_KEYS {
+            let key = key(i);
+            let value = table
+                .get(&key, u64::MAX)
+                .await
+                .map_err(|err| err.to_string())?;
+            history.check(&key, value)?;
+            // The recovered table takes new writes, which are durable after a checkpoint.
+            table
+                .put(&key, next_lsn + i, &i.to_be_bytes())
+                .await
+                .map_err(|err| err.to_string())?;
+        }
+        table.checkpoint().await.map_err(|err| err.to_string())
+    })?;
+
+    let env = env.restart();
+    env.block_on(async {
+        let table = open(&env, opts)
+            .await
+            .map_err(|err| format!("second recovery fails: {}", err))?;
+        for i in 0..NUM_KEYS {
+            let value = table
+                .get(&key(i), u64::MAX)
+                .await
+                .map_err(|err| err.to_string())?;
+            if value != Some(i.to_be_bytes().to_vec()) {
+                return Err(format!("key {} is lost after the second recovery", i));
+            }
+        }
+        Ok(())
+    })
+}
+
+fn env_var(name: &str) -> Option<u64> {
+    std::env::var(name).ok().map(|value| value.parse().unwrap())
+}
+
+#[test]
+fn recovery() {
+    let seeds = match env_var("PHOTONDB_SIM_SEED") {
+        Some(seed) => seed..seed + 1,
+        None => 0..env_var("PHOTONDB_SIM_SEEDS").unwrap_or(1000),
+    };
+    for seed in seeds {
+        if let Err(err) = run(seed) {
+            panic!("seed {} fails: {}", seed, err);
+        }
+    }
+}
@@ -0,0 +1,267 @@
+use std::{
+    alloc::Layout,
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, RwLock,
+    },
+};
+
+use super::{
+    malloc,
+    numa::{self, Topology},
+    page::{PageAlloc, PagePtr},
+    Error, Result,
+};
+
+/// The size of a slab, which is also its alignment.
+const SLAB_SIZE: usize = 256 * 1024;
+
+/// The smallest size class.
+const MIN_CLASS_SIZE: usize = 64;
+
+/// The largest size class by default, which covers the delta pages.
+const DEFAULT_MAX_CLASS_SIZE: usize = 4096;
+
+/// The largest size class that can be configured, so that a slab holds a few pages of each class.
+pub const MAX_CLASS_SIZE: usize = SLAB_SIZE / 4;
+
+/// A page allocator that carves small pages out of large slabs.
+///
+/// Each slab serves one size class, and freed pages are reused by later allocations of the same
+/// class, so that the small delta pages that are allocated and freed at a high rate don't churn
+/// the general-purpose allocator or fragment its heap. Slabs are only freed when the allocator is
+/// dropped.
+///
+/// Size classes are the powers of two up to a maximum size. Larger pages are allocated by
+/// `malloc`.
+///
+/// On machines with multiple NUMA nodes, a NUMA-aware allocator keeps the slabs and free pages
+/// of each node apart, and allocates pages from the slabs of the node that the thread runs on.
+pub struct SlabAlloc {
+    // The size of each class.
+    sizes: Vec<usize>,
+    // The free pages of each size class of each node.
+    classes: Vec<Mutex<Vec<usize>>>,
+    // The size class and the node of each slab by its address.
+    slabs: RwLock<HashMap<usize, (usize, usize)>>,
+    size: AtomicUsize,
+    topology: Topology,
+}
+
+impl Default for SlabAlloc {
+    fn default() -> Self {
+        Self::with_max_size(DEFAULT_MAX_CLASS_SIZE)
+    }
+}
+
+impl SlabAlloc {
+    /// Creates an allocator whose largest size class covers `max_size`, which is at least the
+    /// default one and at most `MAX_CLASS_SIZE`.
+    pub fn with_max_size(max_size: usize) -> Self {
+        Self::new(max_size, Topology::default())
+    }
+
+    /// Creates a NUMA-aware allocator, see `with_max_size` for `max_size`.
+    pub fn numa_aware(max_size: usize) -> Self {
+        Self::new(max_size, Topology::detect())
+    }
+
+    fn new(max_size: usize, topology: Topology) -> Self {
+        let max_size = max_size
+            .clamp(DEFAULT_MAX_CLASS_SIZE, MAX_CLASS_SIZE)
+            .next_power_of_two();
+        let sizes: Vec<_> = (MIN_CLASS_SIZE.trailing_zeros()..=max_size.trailing_zeros())
+            .map(|shift| 1 << shift)
+            .collect();
+        let num_classes = sizes.len() * topology.num_nodes();
+        Self {
+            classes: (0..num_classes).map(|_| Mutex::default()).collect(),
+            sizes,
+            slabs: RwLock::default(),
+            size: AtomicUsize::new(0),
+            topology,
+        }
+    }
+
+    /// Returns the size of memory reserved by slabs.
+    pub fn size(&self) -> usize {
+        self.size.load(Ordering::Relaxed)
+    }
+
+    /// Returns the usable size of a page allocated by this allocator.
+    pub fn usable_size(&self, page: PagePtr) -> usize {
+        match self.class_of(page) {
+            Some((class, _)) => self.sizes[class],
+            None => unsafe { malloc::usable_size(page.as_raw()) },
+        }
+    }
+
+    /// Returns the NUMA node of a page if it is allocated from a slab.
+    pub fn node_of(&self, page: PagePtr) -> Option<usize> {
+        self.class_of(page).map(|(_, node)| node)
+    }
+
+    /// Returns true if the page is allocated on another NUMA node than the one that the current
+    /// thread runs on.
+    pub fn is_remote(&self, page: PagePtr) -> bool {
+        self.topology.num_nodes() > 1
+            && matches!(self.node_of(page), Some(node) if node != self.topology.current_node())
+    }
+
+    /// Returns the size class and the node of a page if it is allocated from a slab.
+    fn class_of(&self, page: PagePtr) -> Option<(usize, usize)> {
+        let slab = page.as_raw() as usize & !(SLAB_SIZE - 1);
+        self.slabs.read().unwrap().get(&slab).copied()
+    }
+
+    fn slab_layout() -> Layout {
+        Layout::from_size_align(SLAB_SIZE, SLAB_SIZE).unwrap()
+    }
+
+    /// Allocates a slab for `class` on `node` and returns its pages.
+    fn alloc_slab(&self, class: usize, node: usize) -> Result<Vec<usize>> {
+        let slab = unsafe { malloc::alloc(Self::slab_layout()) };
+        if slab.is_null() {
+            return Err(Error::Alloc);
+        }
+        if self.topology.num_nodes() > 1 {
+            unsafe { numa::bind(slab, SLAB_SIZE, node) };
+        }
+        let slab = slab as usize;
+        self.slabs.write().unwrap().insert(slab, (class, node));
+        self.size.fetch_add(SLAB_SIZE, Ordering::Relaxed);
+        // Pops pages from the start of the slab first.
+        let size = self.sizes[class];
+        Ok((0..SLAB_SIZE / size)
+            .rev()
+            .map(|i| slab + i * size)
+            .collect())
+    }
+}
+
+unsafe impl PageAlloc for SlabAlloc {
+    type Error = Error;
+
+    fn alloc(&self, size: usize) -> Result<PagePtr> {
+        let class = match self.sizes.iter().position(|&class| size <= class) {
+            Some(class) => class,
+            None => unsafe {
+                let ptr = malloc::alloc(Self::alloc_layout(size));
+                return PagePtr::new(ptr).ok_or(Error::Alloc);
+            },
+        };
+        let node = self.topology.current_node();
+        let mut free = self.classes[node * self.sizes.len() + class]
+            .lock()
+            .unwrap();
+        if free.is_empty() {
+            *free = self.alloc_slab(class, node)?;
+        }
+        let ptr = free.pop().unwrap();
+        Ok(unsafe { PagePtr::new(ptr as *mut u8).unwrap() })
+    }
+
+    unsafe fn dealloc(&self, page: PagePtr) {
+        match self.class_of(page) {
+            // Pages go back to the node that their memory is on.
+            Some((class, node)) => {
+                self.classes[node * self.sizes.len() + class]
+                    .lock()
+                    .unwrap()
+                    .push(page.as_raw() as usize);
+            }
+            None => {
+                let ptr = page.as_raw();
+                malloc::dealloc(ptr, Self::alloc_layout(malloc::usable_size(ptr)));
+            }
+        }
+    }
+}
+
+impl Drop for SlabAlloc {
+    fn drop(&mut self) {
+        for &slab in self.slabs.get_mut().unwrap().keys() {
+            unsafe { malloc::dealloc(slab as *mut u8, Self::slab_layout()) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn alloc_and_dealloc() {
+        let alloc = SlabAlloc::default();
+        let n = SLAB_SIZE / 64 + 1;
+        let pages: Vec<_> = (0..n).map(|_| alloc.alloc(40).unwrap()).collect();
+        let addrs: HashSet<_> = pages.iter().map(|page| page.as_raw() as usize).collect();
+        assert_eq!(addrs.len(), n);
+        assert_eq!(alloc.size(), SLAB_SIZE * 2);
+        for &page in &pages {
+            assert_eq!(alloc.usable_size(page), 64);
+            unsafe { page.as_raw().write_bytes(1, 64) };
+        }
+
+        // Freed pages are reused by the same size class.
+        let page = pages[1];
+        unsafe { alloc.dealloc(page) };
+        assert_eq!(alloc.alloc(64).unwrap().as_raw(), page.as_raw());
+        let other = alloc.alloc(100).unwrap();
+        assert_eq!(alloc.usable_size(other), 128);
+        assert_eq!(alloc.size(), SLAB_SIZE * 3);
+
+        // Large pages are not allocated from slabs.
+        let large = alloc.alloc(SLAB_SIZE).unwrap();
+        assert!(alloc.usable_size(large) >= SLAB_SIZE);
+        assert_eq!(alloc.size(), SLAB_SIZE * 3);
+        unsafe {
+            alloc.dealloc(large);
+            alloc.dealloc(other);
+            for page in pages {
+                alloc.dealloc(page);
+            }
+        }
+    }
+
+    #[test]
+    fn configured_size_classes() {
+        let alloc = SlabAlloc::with_max_size(16 * 1024);
+        let page = alloc.alloc(10 * 1024).unwrap();
+        assert_eq!(alloc.usable_size(page), 16 * 1024);
+        assert_eq!(alloc.size(), SLAB_SIZE);
+        let large = alloc.alloc(16 * 1024 + 1).unwrap();
+        assert_eq!(alloc.size(), SLAB_SIZE);
+
+        // Sizes are rounded up to powers of two, and capped to keep a few pages in a slab.
+        assert_eq!(SlabAlloc::with_max_size(5000).sizes.last(), Some(&8192));
+        assert_eq!(SlabAlloc::with_max_size(0).sizes.last(), Some(&4096));
+        let max = SlabAlloc::with_max_size(usize::MAX);
+        assert_eq!(max.sizes.last(), Some(&MAX_CLASS_SIZE));
+        unsafe {
+            alloc.dealloc(page);
+            alloc.dealloc(large);
+        }
+    }
+
+    #[test]
+    fn numa_aware() {
+        let alloc = SlabAlloc::numa_aware(0);
+        let page = alloc.alloc(40).unwrap();
+        assert_eq!(alloc.node_of(page), Some(alloc.topology.current_node()));
+        assert!(!alloc.is_remote(page));
+        unsafe { alloc.dealloc(page) };
+        assert_eq!(alloc.alloc(40).unwrap().as_raw(), page.as_raw());
+
+        let large = alloc.alloc(SLAB_SIZE).unwrap();
+        assert_eq!(alloc.node_of(large), None);
+        assert!(!alloc.is_remote(large));
+        unsafe {
+            alloc.dealloc(large);
+            alloc.dealloc(page);
+        }
+    }
+}
@@ -0,0 +1,453 @@
+/// Statistics of a tree.
+#[derive(Clone, Debug, Default)]
+pub struct Stats {
+    pub stall: StallStats,
+    pub alloc: AllocStats,
+    pub cache: CacheStats,
+    pub contention: ContentionStats,
+    pub write: WriteStats,
+    pub lifetime: LifetimeStats,
+    pub tier: TierStats,
+    pub jobs: JobStats,
+    pub ghost: GhostStats,
+    /// The number of levels from the root to the leaves, which grows by one each time the root
+    /// splits.
+    pub height: usize,
+}
+
+/// The counters of a single operation, collected with `GetOptions::perf_context` or
+/// `PutOptions::perf_context`.
+///
+/// The counters add up across the operations that they are collected for.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PerfContext {
+    /// The number of nodes visited, including the index nodes on the way to the leaf.
+    pub num_nodes: u64,
+    /// The number of pages visited in the chains of the nodes.
+    pub num_pages: u64,
+    /// The length of the longest chain visited, in pages.
+    pub max_chain_length: u64,
+    /// The number of pages swapped in from disk.
+    pub num_swapins: u64,
+    /// The size of the pages swapped in from disk, as they are in memory.
+    pub swapin_bytes: u64,
+    /// The number of retries on conflicts with other operations.
+    pub num_retries: u64,
+}
+
+/// Statistics about the epochs that protect the pages in memory from being reclaimed.
+///
+/// Pages and node ids that are replaced are reclaimed after all the ghosts that may still see them
+/// are dropped. The statistics cover all the trees in the process, since they share the epochs.
+/// A growing backlog suggests that some ghosts are held for long, like `PinnedValue`s or scans,
+/// and `Table::flush_epoch` may help to reclaim the rest.
+#[derive(Clone, Debug, Default)]
+pub struct GhostStats {
+    /// The number of ghosts that are pinned.
+    pub num_pinned: u64,
+    /// The number of callbacks that reclaim retired pages, which are deferred until the ghosts
+    /// that may see the pages are dropped.
+    pub num_deferred: u64,
+    /// The size of the pages that are retired but not reclaimed yet, in bytes.
+    pub deferred_bytes: u64,
+}
+
+/// Statistics about stalled writes.
+///
+/// Writes are stalled when the cache exceeds its budget. Stalled writes yield to swap-ins first and
+/// then to reads, so the breakdown below shows where the stall time goes.
+#[derive(Clone, Debug, Default)]
+pub struct StallStats {
+    /// The number of stalled writes.
+    pub num_stalls: u64,
+    /// The total time stalled writes spent waiting, in microseconds.
+    pub stall_micros: u64,
+    /// The time stalled writes spent yielding to swap-ins, in microseconds.
+    pub yield_to_swapin_micros: u64,
+    /// The time stalled writes spent yielding to reads, in microseconds.
+    pub yield_to_read_micros: u64,
+    /// The number of swap-ins that started while some writes were stalled.
+    pub num_swapins_during_stall: u64,
+    /// The number of reads that started while some writes were stalled.
+    pub num_reads_during_stall: u64,
+}
+
+/// Statistics about the background jobs that run on behalf of the tree.
+#[derive(Clone, Debug, Default)]
+pub struct JobStats {
+    /// The number of flushes to make room in the cache when nothing can be evicted.
+    pub num_eviction_flushes: u64,
+    /// The number of periodic checkpoints.
+    pub num_checkpoints: u64,
+    /// The number of periodic checkpoints skipped to leave room for eviction flushes.
+    pub num_skipped_checkpoints: u64,
+    /// The total time jobs waited for each other, in microseconds.
+    pub wait_micros: u64,
+}
+
+/// Statistics about the cache.
+#[derive(Clone, Debug, Default)]
+pub struct CacheStats {
+    /// The size of memory allocated by the cache.
+    pub size: u64,
+    /// The number of nodes evicted to disk.
+    pub num_evictions: u64,
+    /// The number of nodes swapped in by scans that are promoted to the hot tier of the cache by a
+    /// second access.
+    pub num_promotions: u64,
+    /// The size of memory reserved by the slabs of `Options::slab_alloc`, which includes the
+    /// free pages of the slabs.
+    pub slab_size: u64,
+    /// The size of the small pages that are freed and kept for reuse by later allocations, which
+    /// is not included in `size`.
+    pub free_list_size: u64,
+}
+
+/// Statistics about page allocations by the kind of operations.
+///
+/// The numbers are the total bytes allocated in the cache, including the pages that are freed
+/// later, so they show which activity drives the memory growth.
+#[derive(Clone, Debug, Default)]
+pub struct AllocStats {
+    /// The bytes allocated for delta pages of puts and deletes.
+    pub put_delta_bytes: u64,
+    /// The bytes allocated for consolidated pages.
+    pub consolidation_bytes: u64,
+    /// The bytes allocated for splits, including the split deltas, the new siblings, and the
+    /// index deltas installed to the parents.
+    pub split_bytes: u64,
+    /// The bytes allocated for pages swapped in from disk.
+    pub swapin_bytes: u64,
+}
+
+/// Statistics about writes.
+#[derive(Clone, Debug, Default)]
+pub struct WriteStats {
+    /// The number of writes whose delta pages exceed `Options::max_delta_size`.
+    ///
+    /// Such writes are consolidated into their leaves right away. Many of them suggest that the
+    /// values are too large for the tree.
+    pub num_oversize_writes: u64,
+    /// The total size of the oversize delta pages in bytes.
+    pub oversize_bytes: u64,
+    /// The number of times that the newest deltas of leaves are merged instead of consolidating
+    /// the leaves, see `Options::data_delta_merge_length`.
+    pub num_delta_merges: u64,
+    /// The bytes of keys and values written since the last checkpoint.
+    pub dirty_bytes: u64,
+    /// The number of writes delayed by `Options::write_rate_limit`.
+    pub num_throttled_writes: u64,
+    /// The total time that writes are delayed, in microseconds.
+    pub throttle_micros: u64,
+}
+
+/// Statistics about the page files in the cold tier of `Options::cold_tier`.
+#[derive(Clone, Debug, Default)]
+pub struct TierStats {
+    /// The number of page files in the cold tier.
+    pub num_cold_files: u64,
+    /// The number of page files moved to the cold tier since the tree is opened.
+    pub num_demotions: u64,
+    /// The number of page files moved back from the cold tier since the tree is opened.
+    pub num_promotions: u64,
+}
+
+/// Cumulative statistics over the lifetime of a tree.
+///
+/// The statistics start from zero when a tree is opened, unless `Options::persist_stats` is set,
+/// in which case they are recorded at checkpoints and continue from the last checkpoint.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LifetimeStats {
+    pub num_puts: u64,
+    pub num_deletes: u64,
+    /// The total size of the keys and values written by puts and deletes.
+    pub write_bytes: u64,
+    /// The total size of the page files written by checkpoints.
+    pub flush_bytes: u64,
+    pub num_checkpoints: u64,
+}
+
+impl LifetimeStats {
+    const COUNTERS: [&'static str; 5] = [
+        "num_puts",
+        "num_deletes",
+        "write_bytes",
+        "flush_bytes",
+        "num_checkpoints",
+    ];
+
+    /// Returns the ratio of the bytes written to disk to the bytes written by users.
+    pub fn write_amplification(&self) -> f64 {
+        if self.write_bytes == 0 {
+            return 0.0;
+        }
+        self.flush_bytes as f64 / self.write_bytes as f64
+    }
+
+    pub(super) fn add(&self, other: &LifetimeStats) -> LifetimeStats {
+        LifetimeStats {
+            num_puts: self.num_puts + other.num_puts,
+            num_deletes: self.num_deletes + other.num_deletes,
+            write_bytes: self.write_bytes + other.write_bytes,
+            flush_bytes: self.flush_bytes + other.flush_bytes,
+            num_checkpoints: self.num_checkpoints + other.num_checkpoints,
+        }
+    }
+
+    fn values(&self) -> [u64; 5] {
+        [
+            self.num_puts,
+            self.num_deletes,
+            self.write_bytes,
+            self.flush_bytes,
+            self.num_checkpoints,
+        ]
+    }
+
+    /// Returns the statistics as named counters to record in the manifest.
+    pub(super) fn to_counters(&self) -> Vec<(String, u64)> {
+        Self::COUNTERS
+            .iter()
+            .zip(self.values())
+            .map(|(name, value)| (name.to_string(), value))
+            .collect()
+    }
+
+    /// Restores the statistics from named counters, where unknown counters are ignored.
+    pub(super) fn from_counters(counters: &[(String, u64)]) -> LifetimeStats {
+        let get = |name: &str| {
+            counters
+                .iter()
+                .find(|(n, _)| n == name)
+                .map_or(0, |(_, value)| *value)
+        };
+        let [num_puts, num_deletes, write_bytes, flush_bytes, num_checkpoints] =
+            Self::COUNTERS.map(get);
+        LifetimeStats {
+            num_puts,
+            num_deletes,
+            write_bytes,
+            flush_bytes,
+            num_checkpoints,
+        }
+    }
+}
+
+/// Statistics about operations that gave up after too many retries.
+#[derive(Clone, Debug, Default)]
+pub struct ContentionStats {
+    /// The number of operations that gave up.
+    pub num_contentions: u64,
+    /// The nodes that operations gave up on, the most contended first.
+    pub hot_nodes: Vec<NodeContention>,
+}
+
+/// The contentions on a node.
+#[derive(Clone, Debug, Default)]
+pub struct NodeContention {
+    pub node_id: u64,
+    /// The number of operations that gave up on the node.
+    pub num_contentions: u64,
+    /// The key of the last operation that gave up on the node.
+    pub last_key: Vec<u8>,
+}
+
+/// The operations of the store that do I/O.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum IoOp {
+    /// Reads files to open the store.
+    Recovery,
+    /// Writes page files for checkpoints.
+    Flush,
+    /// Reads pages from page files.
+    SwapIn,
+    /// Writes the manifest.
+    Manifest,
+}
+
+/// Statistics about the I/O of the store.
+#[derive(Clone, Debug, Default)]
+pub struct IoStats {
+    /// The I/O by operation, including the I/O on files that have been removed.
+    pub ops: Vec<OpIoStats>,
+    /// The I/O on the live files by file and operation.
+    pub files: Vec<FileIoStats>,
+}
+
+/// The I/O of an operation.
+#[derive(Clone, Debug)]
+pub struct OpIoStats {
+    pub op: IoOp,
+    pub io: IoCounters,
+}
+
+/// The I/O of an operation on a file.
+#[derive(Clone, Debug)]
+pub struct FileIoStats {
+    /// The name of the file.
+    pub file: String,
+    pub op: IoOp,
+    pub io: IoCounters,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct IoCounters {
+    pub num_reads: u64,
+    pub read_bytes: u64,
+    pub num_writes: u64,
+    pub write_bytes: u64,
+    /// The latencies of reads, writes, and syncs.
+    pub latency: LatencyHistogram,
+}
+
+/// A histogram of latencies with power-of-two buckets.
+///
+/// Bucket `i` counts the latencies in `[2^(i-1), 2^i)` microseconds, and bucket 0 counts the ones
+/// below 1 microsecond.
+#[derive(Clone, Debug, Default)]
+pub struct LatencyHistogram {
+    pub buckets: Vec<u64>,
+}
+
+impl LatencyHistogram {
+    pub fn record(&mut self, micros: u64) {
+        let i = (u64::BITS - micros.leading_zeros()) as usize;
+        if self.buckets.len() <= i {
+            self.buckets.resize(i + 1, 0);
+        }
+        self.buckets[i] += 1;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// Returns an upper bound of the latency at the given percentile, in microseconds.
+    pub fn percentile(&self, percentile: f64) -> u64 {
+        let target = (self.count() as f64 * percentile / 100.0).ceil() as u64;
+        let mut count = 0;
+        for (i, n) in self.buckets.iter().enumerate() {
+            count += n;
+            if count >= target.max(1) {
+                return if i == 0 {
+                    0
+                } else {
+                    u64::MAX >> (u64::BITS as usize - i)
+                };
+            }
+        }
+        0
+    }
+}
+
+/// The shape of a tree and the space usage of its page files, see `Table::inspect`.
+#[derive(Clone, Debug, Default)]
+pub struct TreeInfo {
+    /// The number of levels from the root to the leaves.
+    pub height: usize,
+    pub num_index_nodes: u64,
+    pub num_leaf_nodes: u64,
+    /// The number of leaves that are on disk.
+    pub num_disk_leaves: u64,
+    /// The number of pages of the nodes in memory, including delta pages.
+    pub num_mem_pages: u64,
+    /// The size of the pages of the nodes in memory, in bytes.
+    pub mem_bytes: u64,
+    /// The size of the largest chain of a node in memory, in bytes.
+    pub max_chain_size: u64,
+    /// The page files of the store, in the order of their ids.
+    pub files: Vec<PageFileInfo>,
+}
+
+/// A breakdown of the memory used by a tree, see `Table::memory_usage`.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryUsage {
+    /// The size of memory allocated by the page cache, including the pages that are freed but not
+    /// reclaimed yet.
+    pub cache_bytes: u64,
+    /// The size of the base pages of the leaves in memory.
+    pub base_bytes: u64,
+    /// The size of the delta and split pages of the leaves in memory, which hold the writes that
+    /// are not consolidated yet.
+    pub delta_bytes: u64,
+    /// The size of the pages of the index nodes in memory.
+    pub index_bytes: u64,
+    /// The size of the page table.
+    pub page_table_bytes: u64,
+    /// The size of the pages that are freed but wait for pinned ghosts to be reclaimed, across all
+    /// the trees of the process, see `GhostStats::deferred_bytes`.
+    pub deferred_bytes: u64,
+    /// The number of background jobs that are running or waiting to run.
+    pub num_pending_jobs: u64,
+}
+
+/// The space usage of a page file.
+#[derive(Clone, Debug)]
+pub struct PageFileInfo {
+    pub file_id: u64,
+    /// Whether the file is in `Options::cold_tier`.
+    pub cold: bool,
+    pub file_size: u64,
+    pub num_pages: u64,
+    /// The number and bytes of the pages in the page table of the last checkpoint. The space of
+    /// other pages is reclaimed when the file is removed.
+    pub live_pages: u64,
+    pub live_bytes: u64,
+}
+
+/// The manifest of the last checkpoint of a table, see `Table::manifest`.
+#[derive(Clone, Debug)]
+pub struct ManifestInfo {
+    pub run_id: String,
+    /// The page files of the table, with the largest LSN that each file may contain.
+    pub files: Vec<(u64, u64)>,
+    /// The ids of the page files in the cold tier.
+    pub cold_files: Vec<u64>,
+    pub root_id: u64,
+    pub next_page_id: u64,
+    /// The page table, which maps page ids to file ids and offsets.
+    pub page_table: Vec<(u64, u64, u64)>,
+    /// The statistics recorded by `Options::persist_stats`.
+    pub counters: Vec<(String, u64)>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn latency_histogram() {
+        let mut hist = LatencyHistogram::default();
+        assert_eq!(hist.percentile(99.0), 0);
+        for micros in [0, 1, 2, 3, 100, 1000] {
+            hist.record(micros);
+        }
+        assert_eq!(hist.buckets, vec![1, 1, 2, 0, 0, 0, 0, 1, 0, 0, 1]);
+        assert_eq!(hist.count(), 6);
+        assert_eq!(hist.percentile(50.0), 3);
+        assert_eq!(hist.percentile(80.0), 127);
+        assert_eq!(hist.percentile(100.0), 1023);
+    }
+
+    #[test]
+    fn lifetime_stats_counters() {
+        let stats = LifetimeStats {
+            num_puts: 1,
+            num_deletes: 2,
+            write_bytes: 30,
+            flush_bytes: 60,
+            num_checkpoints: 5,
+        };
+        let counters = stats.to_counters();
+        assert_eq!(LifetimeStats::from_counters(&counters), stats);
+        assert_eq!(stats.write_amplification(), 2.0);
+        let sum = stats.add(&stats);
+        assert_eq!(sum.num_checkpoints, 10);
+
+        // Unknown counters are ignored, and missing ones are zero.
+        let counters = vec![("num_puts".to_owned(), 3), ("unknown".to_owned(), 4)];
+        let restored = LifetimeStats::from_counters(&counters);
+        assert_eq!(restored.num_puts, 3);
+        assert_eq!(restored.num_deletes, 0);
+    }
+}
@@ -0,0 +1,84 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::env::Env;
+
+/// A cheaper tier of storage, like an object store, where cold page files are migrated to.
+///
+/// Page files are written to the table directory first. At each checkpoint, the files that
+/// `policy` considers cold are moved to `path` in `env`, and the cold files that are read again
+/// are moved back. The tier of each file is recorded in the manifest, so the cold tier must be
+/// configured every time the table is opened once it has files.
+#[derive(Clone, Debug)]
+pub struct ColdTier {
+    pub env: Arc<dyn Env>,
+    /// The directory of the cold page files, which must not be shared with other tables.
+    pub path: PathBuf,
+    pub policy: TieringPolicy,
+}
+
+/// Decides which page files are cold by the last time their pages are read.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TieringPolicy {
+    /// Files that are not read for the duration are cold.
+    Age(Duration),
+    /// The given percentage of the files that are least recently read are cold.
+    Percentage(u8),
+}
+
+impl TieringPolicy {
+    /// Returns the files that should be in the cold tier, given the last time each file is read.
+    ///
+    /// A file without a last read is older than any other file.
+    pub(super) fn cold_files(
+        self,
+        last_reads: &HashMap<u64, Option<Instant>>,
+        now: Instant,
+    ) -> HashSet<u64> {
+        match self {
+            Self::Age(age) => last_reads
+                .iter()
+                .filter(|(_, last_read)| match last_read {
+                    Some(last_read) => now.saturating_duration_since(*last_read) >= age,
+                    None => true,
+                })
+                .map(|(&id, _)| id)
+                .collect(),
+            Self::Percentage(percentage) => {
+                let mut files: Vec<_> = last_reads.iter().map(|(&id, &t)| (t, id)).collect();
+                files.sort_unstable();
+                let num_cold = files.len() * percentage.min(100) as usize / 100;
+                files[..num_cold].iter().map(|&(_, id)| id).collect()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn cold_files() {
+        let now = Instant::now();
+        let mut last_reads: HashMap<u64, Option<Instant>> = (1..=3)
+            .map(|id| (id, Some(now - Duration::from_secs(10 * id))))
+            .collect();
+        last_reads.insert(4, None);
+        let policy = TieringPolicy::Age(Duration::from_secs(25));
+        assert_eq!(policy.cold_files(&last_reads, now), HashSet::from([3, 4]));
+        let policy = TieringPolicy::Percentage(50);
+        assert_eq!(policy.cold_files(&last_reads, now), HashSet::from([3, 4]));
+        let policy = TieringPolicy::Percentage(80);
+        assert_eq!(
+            policy.cold_files(&last_reads, now),
+            HashSet::from([2, 3, 4])
+        );
+        let policy = TieringPolicy::Percentage(0);
+        assert!(policy.cold_files(&last_reads, now).is_empty());
+    }
+}
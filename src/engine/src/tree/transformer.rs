@@ -0,0 +1,65 @@
+use std::fmt;
+
+/// Transforms values when they are read by scans.
+///
+/// A transformer lets applications migrate values written in an old format (e.g. encrypted or
+/// compressed by an older version) gradually: scans return the transformed values, and with
+/// `Options::rewrite_on_consolidation`, consolidations write the transformed values back, so the
+/// old format goes away as nodes are consolidated.
+pub trait ValueTransformer: Send + Sync {
+    /// Returns the transformed value, or `None` if the value doesn't need to change.
+    fn transform(&self, key: &[u8], value: &[u8]) -> Option<Vec<u8>>;
+}
+
+/// Checks that `transformer` behaves as the tree expects on `samples` of keys and values, and
+/// panics if it doesn't.
+///
+/// Transformations must be deterministic, and a transformed value must not change when it is
+/// transformed again, since rewritten values are read by scans as well.
+pub fn check_value_transformer(transformer: &dyn ValueTransformer, samples: &[(&[u8], &[u8])]) {
+    for &(key, value) in samples {
+        let transformed = transformer.transform(key, value);
+        assert_eq!(
+            transformer.transform(key, value),
+            transformed,
+            "transformation of key {:?} is not deterministic",
+            key
+        );
+        if let Some(transformed) = transformed {
+            assert_eq!(
+                transformer.transform(key, &transformed),
+                None,
+                "transformed value of key {:?} changes again",
+                key
+            );
+        }
+    }
+}
+
+impl fmt::Debug for dyn ValueTransformer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ValueTransformer")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct StripPrefix;
+
+    impl ValueTransformer for StripPrefix {
+        fn transform(&self, _: &[u8], value: &[u8]) -> Option<Vec<u8>> {
+            value.strip_prefix(b"v1:").map(|v| v.to_vec())
+        }
+    }
+
+    #[test]
+    fn conformance() {
+        check_value_transformer(&StripPrefix, &[(b"a", b"v1:value"), (b"b", b"value")]);
+        let result = std::panic::catch_unwind(|| {
+            check_value_transformer(&StripPrefix, &[(b"a", b"v1:v1:value")]);
+        });
+        assert!(result.is_err());
+    }
+}
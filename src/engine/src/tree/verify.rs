@@ -0,0 +1,123 @@
+use std::ops::Range;
+
+use super::{
+    page::{Comparable, DataPageRef, Decodable, RawKey},
+    Comparator,
+};
+
+/// The result of `Table::verify`.
+#[derive(Clone, Debug, Default)]
+pub struct VerifyReport {
+    /// The number of nodes reached from the root.
+    pub num_nodes: u64,
+    /// The number of entries in the nodes that are reached.
+    pub num_entries: u64,
+    /// The problems found, which is empty if the table is intact.
+    pub problems: Vec<String>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// The result of `Table::repair`.
+#[derive(Clone, Debug, Default)]
+pub struct RepairReport {
+    /// The number of leaves salvaged.
+    pub num_leaves: u64,
+    /// The number of entries in the salvaged leaves.
+    pub num_entries: u64,
+    /// The files and nodes that are dropped, with the key ranges of the nodes if they are known.
+    pub lost: Vec<String>,
+}
+
+/// Checks that the entries of a page are in order and their keys are in `range`, and returns the
+/// problems found.
+///
+/// An empty end of `range` means that the range is unbounded.
+pub(super) fn check_page<K, V>(
+    page: &DataPageRef<'_, K, V>,
+    range: Range<&[u8]>,
+    cmp: &dyn Comparator,
+) -> Vec<String>
+where
+    K: Decodable + Comparable + RawKey,
+    V: Decodable,
+{
+    let mut problems = Vec::new();
+    let mut last: Option<K> = None;
+    for i in 0..page.len() {
+        let (key, _) = page.get(i).unwrap();
+        let raw = key.as_raw();
+        if let Some(last) = &last {
+            if !last.compare_with(&key, cmp).is_lt() {
+                problems.push(format!(
+                    "entry {} with key {} is out of order",
+                    i,
+                    raw.escape_ascii()
+                ));
+            }
+        }
+        let below = cmp.compare(raw, range.start).is_lt();
+        let above = !range.end.is_empty() && cmp.compare(raw, range.end).is_ge();
+        if below || above {
+            problems.push(format!(
+                "entry {} with key {} is out of the node range [{}, {})",
+                i,
+                raw.escape_ascii(),
+                range.start.escape_ascii(),
+                range.end.escape_ascii()
+            ));
+        }
+        last = Some(key);
+    }
+    problems
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tree::{
+        page::{DataPageBuilder, Key, PageAlloc, SliceIter, Value},
+        pagecache::PageCache,
+        BytewiseComparator,
+    };
+
+    #[test]
+    fn check_page_entries() {
+        let cache = PageCache::default();
+        let cmp = BytewiseComparator;
+        let entries = [
+            (Key::new(b"b", 2), Value::Put(b"v")),
+            (Key::new(b"b", 1), Value::Put(b"v")),
+            (Key::new(b"c", 1), Value::Delete),
+        ];
+        let mut iter = SliceIter::from(&entries);
+        let mut page = DataPageBuilder::default()
+            .build_from_iter(&cache, &mut iter)
+            .unwrap();
+        let page_ref = page.as_ref::<Key, Value>();
+        assert!(check_page(&page_ref, b"a".as_slice()..b"".as_slice(), &cmp).is_empty());
+        assert!(check_page(&page_ref, b"b".as_slice()..b"d".as_slice(), &cmp).is_empty());
+        let problems = check_page(&page_ref, b"bb".as_slice()..b"c".as_slice(), &cmp);
+        assert_eq!(problems.len(), 3);
+        assert!(problems[0].starts_with("entry 0 with key b is out of the node range"));
+        assert!(problems[2].starts_with("entry 2 with key c is out of the node range"));
+        unsafe { cache.dealloc(page.as_ptr()) };
+
+        // Entries of the same key are ordered from the newest to the oldest.
+        let entries = [
+            (Key::new(b"b", 1), Value::Put(b"v")),
+            (Key::new(b"b", 2), Value::Put(b"v")),
+        ];
+        let mut iter = SliceIter::from(&entries);
+        let mut page = DataPageBuilder::default()
+            .build_from_iter(&cache, &mut iter)
+            .unwrap();
+        let problems = check_page(&page.as_ref::<Key, Value>(), &[][..]..&[][..], &cmp);
+        assert_eq!(problems, vec!["entry 1 with key b is out of order"]);
+        unsafe { cache.dealloc(page.as_ptr()) };
+    }
+}
@@ -0,0 +1,156 @@
+//! Crash tests with the fail points of the engine.
+//!
+//! Each test stops an operation at a fail point, drops the table as if the process was killed
+//! there, and checks that the table reopens to a consistent tree. Writes are durable at
+//! checkpoints, so the fail points are on the paths of checkpoints and the splits that they see.
+//!
+//! Run with `cargo test -p photondb-engine --features failpoints --test failpoints`.
+
+#![cfg(feature = "failpoints")]
+
+use std::path::Path;
+
+use fail::FailScenario;
+use photondb_engine::tree::{Options, Table};
+
+const NUM_KEYS: u64 = 256;
+
+fn options() -> Options {
+    Options {
+        data_node_size: 256,
+        data_delta_length: 4,
+        ..Default::default()
+    }
+}
+
+async fn open(path: &Path) -> Table {
+    Table::open(path, options()).await.unwrap()
+}
+
+/// Writes all the keys with `version` as their values.
+async fn write(table: &Table, version: u64) {
+    for i in 0..NUM_KEYS {
+        let lsn = version * NUM_KEYS + i;
+        table
+            .put(&i.to_be_bytes(), lsn, &version.to_be_bytes())
+            .await
+            .unwrap();
+    }
+}
+
+/// Checks that the tree is intact and all the keys have `version` as their values.
+async fn check(table: &Table, version: u64) {
+    let report = table.verify().await.unwrap();
+    assert!(report.is_ok(), "{:?}", report.problems);
+    for i in 0..NUM_KEYS {
+        let value = table.get(&i.to_be_bytes(), u64::MAX).await.unwrap();
+        assert_eq!(value, Some(version.to_be_bytes().to_vec()), "key {}", i);
+    }
+}
+
+#[tokio::test]
+async fn checkpoint() {
+    let scenario = FailScenario::setup();
+    // The fail points and whether the checkpoint is durable if it stops there.
+    let cases = [
+        ("page_file_before_commit", false),
+        ("checkpoint_before_manifest", false),
+        ("checkpoint_after_manifest", true),
+    ];
+    for (name, durable) in cases {
+        let dir = tempfile::tempdir().unwrap();
+        let table = open(dir.path()).await;
+        write(&table, 1).await;
+        table.checkpoint().await.unwrap();
+        write(&table, 2).await;
+        fail::cfg(name, "return").unwrap();
+        assert!(table.checkpoint().await.is_err(), "{}", name);
+        fail::remove(name);
+        drop(table);
+
+        let table = open(dir.path()).await;
+        check(&table, if durable { 2 } else { 1 }).await;
+        // The files left by the stopped checkpoint don't get in the way of the next one.
+        write(&table, 3).await;
+        table.checkpoint().await.unwrap();
+        drop(table);
+        let table = open(dir.path()).await;
+        check(&table, 3).await;
+    }
+    scenario.teardown();
+}
+
+#[tokio::test]
+async fn manifest_swap() {
+    let scenario = FailScenario::setup();
+    // The manifest is rotated to a new file whenever the table is opened.
+    for name in ["manifest_before_current", "manifest_after_current"] {
+        let dir = tempfile::tempdir().unwrap();
+        let table = open(dir.path()).await;
+        write(&table, 1).await;
+        table.checkpoint().await.unwrap();
+        drop(table);
+
+        fail::cfg(name, "return").unwrap();
+        assert!(
+            Table::open(dir.path(), options()).await.is_err(),
+            "{}",
+            name
+        );
+        fail::remove(name);
+        let table = open(dir.path()).await;
+        check(&table, 1).await;
+        drop(table);
+        let manifests = std::fs::read_dir(dir.path())
+            .unwrap()
+            .filter(|entry| {
+                let name = entry.as_ref().unwrap().file_name();
+                name.to_str().unwrap().starts_with("MANIFEST")
+            })
+            .count();
+        assert_eq!(manifests, 1, "{}", name);
+    }
+    scenario.teardown();
+}
+
+#[tokio::test]
+async fn split() {
+    let scenario = FailScenario::setup();
+    let dir = tempfile::tempdir().unwrap();
+    let table = open(dir.path()).await;
+    write(&table, 1).await;
+    table.checkpoint().await.unwrap();
+
+    // Splits are installed on the nodes, but never on their parents.
+    fail::cfg("split_before_reconcile", "return").unwrap();
+    let mut stopped = false;
+    for i in 0..NUM_KEYS {
+        let key = (NUM_KEYS + i).to_be_bytes();
+        if table.put(&key, 2 * NUM_KEYS + i, b"new").await.is_err() {
+            stopped = true;
+            break;
+        }
+    }
+    assert!(stopped);
+    assert!(table.checkpoint().await.is_err());
+    fail::remove("split_before_reconcile");
+    drop(table);
+    let table = open(dir.path()).await;
+    check(&table, 1).await;
+    drop(table);
+
+    // A split that is stopped once is completed by the next operations.
+    let table = open(dir.path()).await;
+    fail::cfg("split_before_reconcile", "1*return").unwrap();
+    write(&table, 2).await;
+    for i in 0..NUM_KEYS {
+        let key = (NUM_KEYS + i).to_be_bytes();
+        let _ = table.put(&key, 3 * NUM_KEYS + i, b"new").await;
+    }
+    fail::remove("split_before_reconcile");
+    table.checkpoint().await.unwrap();
+    drop(table);
+    let table = open(dir.path()).await;
+    check(&table, 2).await;
+    scenario.teardown();
+}
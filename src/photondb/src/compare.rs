@@ -0,0 +1,279 @@
+use std::{cmp::Ordering, sync::Arc};
+
+use crate::{BytewiseComparator, Comparator, Result, Table};
+
+/// Options for [`compare_tables`].
+#[derive(Clone)]
+pub struct CompareOptions {
+    /// The LSN to read both tables at.
+    pub lsn: u64,
+    /// The order of keys in both tables, which must be the one that they are opened with.
+    pub comparator: Arc<dyn Comparator>,
+    /// The number of keys in each range that is checksummed.
+    pub range_keys: usize,
+    /// The maximum number of divergent keys to keep in the report. Divergent keys beyond it are
+    /// only counted.
+    pub max_divergent_keys: usize,
+}
+
+impl Default for CompareOptions {
+    fn default() -> Self {
+        Self {
+            lsn: u64::MAX,
+            comparator: Arc::new(BytewiseComparator),
+            range_keys: 1024,
+            max_divergent_keys: 1024,
+        }
+    }
+}
+
+/// A key whose values differ between two tables. A missing value means that the key is not in
+/// that table.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DivergentKey {
+    pub key: Vec<u8>,
+    pub first: Option<Vec<u8>>,
+    pub second: Option<Vec<u8>>,
+}
+
+/// The checksums of the entries in `start..end` of two tables.
+///
+/// An empty `end` means that the range is unbounded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RangeChecksum {
+    pub start: Vec<u8>,
+    pub end: Vec<u8>,
+    /// The number of entries in each table.
+    pub num_entries: [u64; 2],
+    /// The FNV-1a checksums of the keys and values in each table, which are stable across
+    /// processes.
+    pub checksums: [u64; 2],
+}
+
+impl RangeChecksum {
+    /// Returns true if the range has the same entries in both tables.
+    pub fn matches(&self) -> bool {
+        self.num_entries[0] == self.num_entries[1] && self.checksums[0] == self.checksums[1]
+    }
+}
+
+/// The result of [`compare_tables`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CompareReport {
+    /// The number of keys in either table.
+    pub num_keys: u64,
+    /// The number of keys whose values differ.
+    pub num_divergent_keys: u64,
+    /// The first keys whose values differ, up to `CompareOptions::max_divergent_keys`.
+    pub divergent_keys: Vec<DivergentKey>,
+    /// The checksums of consecutive ranges that cover the compared range.
+    pub ranges: Vec<RangeChecksum>,
+}
+
+impl CompareReport {
+    /// Returns true if both tables have the same entries.
+    pub fn is_ok(&self) -> bool {
+        self.num_divergent_keys == 0
+    }
+}
+
+/// Compares the entries in `start..end` of two tables, such as a replica or a backup and its
+/// source, and reports the keys whose values differ and the checksums of each range of
+/// `CompareOptions::range_keys` keys. An empty `end` means that the range is unbounded.
+///
+/// Both tables are streamed side by side with cursors, so the comparison takes little memory
+/// besides the divergent keys that are kept. Ranges are split at the same keys for both tables,
+/// so the checksums of two comparisons against the same table line up as long as the keys do.
+pub async fn compare_tables(
+    first: &Table,
+    second: &Table,
+    start: &[u8],
+    end: &[u8],
+    opts: &CompareOptions,
+) -> Result<CompareReport> {
+    let mut cursors = [
+        first.cursor(start, end, opts.lsn),
+        second.cursor(start, end, opts.lsn),
+    ];
+    let mut heads = [cursors[0].next().await?, cursors[1].next().await?];
+    let mut report = CompareReport::default();
+    let mut range = RangeBuilder::new(start);
+    loop {
+        let order = match (&heads[0], &heads[1]) {
+            (None, None) => break,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some((a, _)), Some((b, _))) => opts.comparator.compare(a, b),
+        };
+        let (key, values) = match order {
+            Ordering::Less => {
+                let (key, value) = heads[0].take().unwrap();
+                heads[0] = cursors[0].next().await?;
+                (key, [Some(value), None])
+            }
+            Ordering::Greater => {
+                let (key, value) = heads[1].take().unwrap();
+                heads[1] = cursors[1].next().await?;
+                (key, [None, Some(value)])
+            }
+            Ordering::Equal => {
+                let (key, a) = heads[0].take().unwrap();
+                let (_, b) = heads[1].take().unwrap();
+                heads[0] = cursors[0].next().await?;
+                heads[1] = cursors[1].next().await?;
+                (key, [Some(a), Some(b)])
+            }
+        };
+
+        if range.num_keys == opts.range_keys.max(1) {
+            report.ranges.push(range.finish(key.clone()));
+            range = RangeBuilder::new(&key);
+        }
+        range.add(&key, &values);
+        report.num_keys += 1;
+        if values[0] != values[1] {
+            report.num_divergent_keys += 1;
+            if report.divergent_keys.len() < opts.max_divergent_keys {
+                let [first, second] = values;
+                report
+                    .divergent_keys
+                    .push(DivergentKey { key, first, second });
+            }
+        }
+    }
+    report.ranges.push(range.finish(end.to_vec()));
+    Ok(report)
+}
+
+struct RangeBuilder {
+    start: Vec<u8>,
+    num_keys: usize,
+    num_entries: [u64; 2],
+    checksums: [u64; 2],
+}
+
+impl RangeBuilder {
+    fn new(start: &[u8]) -> Self {
+        Self {
+            start: start.to_vec(),
+            num_keys: 0,
+            num_entries: [0; 2],
+            checksums: [FNV_OFFSET_BASIS; 2],
+        }
+    }
+
+    fn add(&mut self, key: &[u8], values: &[Option<Vec<u8>>; 2]) {
+        self.num_keys += 1;
+        for (i, value) in values.iter().enumerate() {
+            if let Some(value) = value {
+                self.num_entries[i] += 1;
+                // The lengths keep the boundaries of keys and values in the checksums.
+                let mut hash = self.checksums[i];
+                for part in [key, value.as_slice()] {
+                    hash = fnv1a(hash, &(part.len() as u64).to_le_bytes());
+                    hash = fnv1a(hash, part);
+                }
+                self.checksums[i] = hash;
+            }
+        }
+    }
+
+    fn finish(self, end: Vec<u8>) -> RangeChecksum {
+        RangeChecksum {
+            start: self.start,
+            end,
+            num_entries: self.num_entries,
+            checksums: self.checksums,
+        }
+    }
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    for &b in bytes {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Options;
+
+    #[tokio::test]
+    async fn compare_replicas() {
+        let dir = tempfile::tempdir().unwrap();
+        let primary = Table::open(dir.path().join("primary"), Options::default())
+            .await
+            .unwrap();
+        let replica = Table::open(dir.path().join("replica"), Options::default())
+            .await
+            .unwrap();
+        for i in 0..100u64 {
+            let buf = i.to_be_bytes();
+            primary.put(&buf, 1, &buf).await.unwrap();
+            replica.put(&buf, 1, &buf).await.unwrap();
+        }
+
+        let opts = CompareOptions {
+            range_keys: 10,
+            ..Default::default()
+        };
+        let report = compare_tables(&primary, &replica, &[], &[], &opts)
+            .await
+            .unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.num_keys, 100);
+        assert_eq!(report.ranges.len(), 10);
+        assert!(report.ranges.iter().all(|r| r.matches()));
+        assert_eq!(report.ranges[1].start, 10u64.to_be_bytes());
+        assert_eq!(report.ranges[1].end, 20u64.to_be_bytes());
+
+        // The replica misses a write, has a stale value, and has an extra key.
+        primary.put(&5u64.to_be_bytes(), 2, b"new").await.unwrap();
+        primary.delete(&42u64.to_be_bytes(), 2).await.unwrap();
+        replica
+            .put(&100u64.to_be_bytes(), 2, b"extra")
+            .await
+            .unwrap();
+        let report = compare_tables(&primary, &replica, &[], &[], &opts)
+            .await
+            .unwrap();
+        assert!(!report.is_ok());
+        assert_eq!(report.num_keys, 101);
+        assert_eq!(
+            report.divergent_keys,
+            vec![
+                DivergentKey {
+                    key: 5u64.to_be_bytes().to_vec(),
+                    first: Some(b"new".to_vec()),
+                    second: Some(5u64.to_be_bytes().to_vec()),
+                },
+                DivergentKey {
+                    key: 42u64.to_be_bytes().to_vec(),
+                    first: None,
+                    second: Some(42u64.to_be_bytes().to_vec()),
+                },
+                DivergentKey {
+                    key: 100u64.to_be_bytes().to_vec(),
+                    first: None,
+                    second: Some(b"extra".to_vec()),
+                },
+            ]
+        );
+        let diverged: Vec<_> = report.ranges.iter().map(|r| !r.matches()).collect();
+        assert_eq!(diverged.iter().filter(|&&d| d).count(), 3);
+        assert!(diverged[0] && diverged[4] && diverged[10]);
+
+        // Reads at the older LSN see the same entries.
+        let opts = CompareOptions { lsn: 1, ..opts };
+        let report = compare_tables(&primary, &replica, &[], &[], &opts)
+            .await
+            .unwrap();
+        assert!(report.is_ok());
+    }
+}
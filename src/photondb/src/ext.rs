@@ -0,0 +1,71 @@
+//! Extension points of PhotonDB.
+//!
+//! Applications customize the engine by implementing the traits here:
+//!
+//! - [`Env`] runs the engine on a different async runtime or file system. [`ThreadPoolEnv`] is a
+//!   built-in implementation, and so is `TokioEnv` with the default `tokio-env` feature. With the
+//!   `object-store` feature, `ObjectStoreEnv` stores tables in services like S3 through an
+//!   `ObjectStore`.
+//! - [`Comparator`] defines the order of keys, see
+//!   [`Options::comparator`](crate::Options::comparator). [`BytewiseComparator`] is the default
+//!   one, and [`TimestampComparator`](crate::TimestampComparator) orders keys that end with
+//!   timestamps.
+//! - [`ValueTransformer`] migrates values as they are read and consolidated, see
+//!   [`Options::value_transformer`](crate::Options::value_transformer).
+//! - [`EventListener`] hooks metrics and logging to the events of a table, see
+//!   [`Options::event_listener`](crate::Options::event_listener).
+//! - [`EvictionPolicy`] decides which nodes to evict from the cache, see
+//!   [`CachePolicy::Custom`](crate::CachePolicy::Custom). [`Clock`](crate::Clock) and
+//!   [`TinyLfu`](crate::TinyLfu) are the built-in policies.
+//!
+//! The [`testkit`] module has conformance tests that implementations should pass, and
+//! [`testkit::SimEnv`] to test applications deterministically with simulated crashes.
+
+#[cfg(feature = "tokio-env")]
+pub use photondb_engine::env::TokioEnv;
+#[cfg(feature = "object-store")]
+pub use photondb_engine::env::{ObjectStore, ObjectStoreEnv};
+pub use photondb_engine::{
+    env::{BoxFuture, Env, FileLock, PositionalReader, SequentialWriter, ThreadPoolEnv},
+    tree::{BytewiseComparator, Comparator, EventListener, EvictionPolicy, ValueTransformer},
+};
+
+/// Conformance tests for implementations of the extension traits.
+///
+/// The checks panic on failures, so that they can be called from the tests of applications.
+pub mod testkit {
+    pub use photondb_engine::{
+        env::{check_env, SimEnv},
+        tree::{
+            check_comparator, check_event_listener, check_eviction_policy, check_value_transformer,
+        },
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{append_timestamp, Clock, TimestampComparator, TinyLfu};
+
+    struct NoopListener;
+
+    impl EventListener for NoopListener {}
+
+    #[tokio::test]
+    async fn testkit() {
+        let dir = tempfile::tempdir().unwrap();
+        testkit::check_env(&TokioEnv::current(), dir.path()).await;
+
+        let keys = [b"a".as_slice(), b"ab", b"b", &[0], &[0xff; 9]];
+        testkit::check_comparator(&BytewiseComparator, &keys);
+        let timestamped: Vec<_> = keys.iter().map(|k| append_timestamp(k, 1)).collect();
+        let timestamped: Vec<_> = timestamped.iter().map(|k| k.as_slice()).collect();
+        testkit::check_comparator(
+            &TimestampComparator::<BytewiseComparator>::default(),
+            &timestamped,
+        );
+        testkit::check_eviction_policy(&mut Clock::default());
+        testkit::check_eviction_policy(&mut TinyLfu::default());
+        testkit::check_event_listener(&NoopListener);
+    }
+}
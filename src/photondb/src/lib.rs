@@ -0,0 +1,60 @@
+//! PhotonDB is a high performance data store.
+//!
+//! The APIs at the top level are async. With the default `tokio-env` feature, `Table::open` runs
+//! a table on the tokio runtime of the current context, and `Table::open_with_env` runs it on any
+//! [`ext::Env`]. The [`sync`] module provides blocking APIs for applications without an async
+//! runtime, and the [`ext`] module gathers the traits to extend the engine.
+//!
+//! A [`Table`] is the entry point of a data store:
+//!
+//! ```no_run
+//! # async fn example() -> photondb::Result<()> {
+//! use photondb::{Options, Table};
+//!
+//! let table = Table::open("/tmp/photondb", Options::default()).await?;
+//! table.put(b"key", 1, b"value").await?;
+//! assert_eq!(table.get(b"key", 1).await?, Some(b"value".to_vec()));
+//! table.delete(b"key", 2).await?;
+//! table
+//!     .scan(b"a", b"z", 2, |key, value| {
+//!         println!("{:?} = {:?}", key, value);
+//!         true
+//!     })
+//!     .await?;
+//! // Writes are durable at checkpoints, and closing a table checkpoints it.
+//! table.checkpoint().await?;
+//! table.close().await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Every key is versioned by the LSN of its write, which the application assigns, and reads see
+//! the versions at or before their LSNs.
+
+pub use photondb_engine::tree::{
+    append_timestamp, split_timestamp, BytewiseComparator, CachePolicy, Change, ChangeBatch,
+    ChangeStream, Clock, ColdTier, Comparator, Corruption, Cursor, DeltaLengthPolicy, Error, Event,
+    EventKind, EventListener, FlushPolicy, GetOptions, GhostStats, IoStats, ManifestInfo,
+    MemoryUsage, Options, PageFileInfo, PageFileWriter, PerfContext, PinnedValue, PutOptions,
+    RepairReport, Result, ScanOptions, SharedCache, Stats, SyncMode, Table, TieringPolicy,
+    TimestampComparator, TinyLfu, TreeInfo, ValueTransformer, VerifyReport, WriteRateLimit,
+    TIMESTAMP_SIZE,
+};
+
+mod multi_get;
+pub use multi_get::{multi_get, GetRequest};
+
+mod compare;
+pub use compare::{compare_tables, CompareOptions, CompareReport, DivergentKey, RangeChecksum};
+
+mod typed;
+pub use typed::{Codec, TypedCursor, TypedTable};
+
+mod write_batch;
+
+mod transaction;
+pub use transaction::{LockTable, Transaction};
+
+pub mod ext;
+
+pub mod sync;
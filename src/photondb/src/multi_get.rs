@@ -0,0 +1,92 @@
+use futures::future::try_join_all;
+
+use crate::{Result, Table};
+
+/// A request to get the value of a key from a table.
+#[derive(Clone, Copy)]
+pub struct GetRequest<'a> {
+    pub table: &'a Table,
+    pub key: &'a [u8],
+    pub lsn: u64,
+}
+
+/// Gets the values of keys across tables in one call, in the order of the requests.
+///
+/// The requests are grouped by table and LSN, and the groups are resolved concurrently. Each table
+/// further groups the keys by leaf.
+pub async fn multi_get(requests: &[GetRequest<'_>]) -> Result<Vec<Option<Vec<u8>>>> {
+    let mut groups: Vec<Group<'_>> = Vec::new();
+    for (i, req) in requests.iter().enumerate() {
+        let group = groups
+            .iter_mut()
+            .find(|g| std::ptr::eq(g.table, req.table) && g.lsn == req.lsn);
+        match group {
+            Some(group) => {
+                group.keys.push(req.key);
+                group.indexes.push(i);
+            }
+            None => groups.push(Group {
+                table: req.table,
+                lsn: req.lsn,
+                keys: vec![req.key],
+                indexes: vec![i],
+            }),
+        }
+    }
+
+    let results = try_join_all(groups.iter().map(|g| g.table.multi_get(&g.keys, g.lsn))).await?;
+    let mut values = vec![None; requests.len()];
+    for (group, group_values) in groups.iter().zip(results) {
+        for (&i, value) in group.indexes.iter().zip(group_values) {
+            values[i] = value;
+        }
+    }
+    Ok(values)
+}
+
+struct Group<'a> {
+    table: &'a Table,
+    lsn: u64,
+    keys: Vec<&'a [u8]>,
+    indexes: Vec<usize>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Options;
+
+    #[tokio::test]
+    async fn multi_get_across_tables() {
+        let dir = tempfile::tempdir().unwrap();
+        let entities = Table::open(dir.path().join("entities"), Options::default())
+            .await
+            .unwrap();
+        let indexes = Table::open(dir.path().join("indexes"), Options::default())
+            .await
+            .unwrap();
+        entities.put(b"1", 1, b"alice").await.unwrap();
+        entities.put(b"2", 2, b"bob").await.unwrap();
+        indexes.put(b"alice", 1, b"1").await.unwrap();
+
+        let req = |table, key, lsn| GetRequest { table, key, lsn };
+        let requests = [
+            req(&entities, b"2".as_slice(), 2),
+            req(&indexes, b"alice".as_slice(), 2),
+            req(&entities, b"1".as_slice(), 2),
+            req(&entities, b"2".as_slice(), 1),
+            req(&indexes, b"bob".as_slice(), 2),
+        ];
+        let values = multi_get(&requests).await.unwrap();
+        assert_eq!(
+            values,
+            vec![
+                Some(b"bob".to_vec()),
+                Some(b"1".to_vec()),
+                Some(b"alice".to_vec()),
+                None,
+                None,
+            ]
+        );
+    }
+}
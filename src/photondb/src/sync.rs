@@ -0,0 +1,505 @@
+//! Blocking APIs that don't require an async runtime.
+//!
+//! The blocking APIs drive the async ones on the calling thread, and do file I/O synchronously.
+
+use std::{
+    future::Future,
+    io::{Read, Write},
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, Wake, Waker},
+    thread::{self, Thread},
+};
+
+use futures::io::AllowStdIo;
+use photondb_engine::env::ThreadPoolEnv;
+
+use crate::{
+    Change, CompareOptions, CompareReport, GetOptions, IoStats, ManifestInfo, MemoryUsage, Options,
+    PinnedValue, PutOptions, RepairReport, Result, ScanOptions, Stats, TreeInfo, VerifyReport,
+};
+
+/// The number of threads to run background tasks.
+const NUM_BACKGROUND_THREADS: usize = 1;
+
+/// A blocking version of [`crate::Table`].
+pub struct Table {
+    table: crate::Table,
+}
+
+impl Table {
+    /// Opens a table in `path`.
+    ///
+    /// The table is created if it doesn't exist.
+    pub fn open(path: impl AsRef<Path>, opts: Options) -> Result<Self> {
+        let env = Arc::new(ThreadPoolEnv::new(NUM_BACKGROUND_THREADS));
+        let table = block_on(crate::Table::open_with_env(env, path, opts))?;
+        Ok(Self { table })
+    }
+
+    pub fn get(&self, key: &[u8], lsn: u64) -> Result<Option<Vec<u8>>> {
+        block_on(self.table.get(key, lsn))
+    }
+
+    /// Gets the value of `key` with the given options.
+    pub fn get_with_options(
+        &self,
+        key: &[u8],
+        lsn: u64,
+        opts: GetOptions<'_>,
+    ) -> Result<Option<Vec<u8>>> {
+        block_on(self.table.get_with_options(key, lsn, opts))
+    }
+
+    /// Returns true if `key` has a value at `lsn`, without copying the value.
+    pub fn contains(&self, key: &[u8], lsn: u64) -> Result<bool> {
+        block_on(self.table.contains(key, lsn))
+    }
+
+    /// Gets the value of `key` as of timestamp `ts`.
+    ///
+    /// See [`crate::Table::get_at`] for details.
+    pub fn get_at(&self, key: &[u8], ts: u64, lsn: u64) -> Result<Option<Vec<u8>>> {
+        block_on(self.table.get_at(key, ts, lsn))
+    }
+
+    /// Gets the value of `key` into `buf`, and returns false if the key is not found.
+    ///
+    /// See [`crate::Table::get_into`] for details.
+    pub fn get_into(&self, key: &[u8], lsn: u64, buf: &mut Vec<u8>) -> Result<bool> {
+        block_on(self.table.get_into(key, lsn, buf))
+    }
+
+    /// Gets the value of `key` without copying it.
+    ///
+    /// See [`crate::Table::get_pinned`] for details.
+    pub fn get_pinned(&self, key: &[u8], lsn: u64) -> Result<Option<PinnedValue>> {
+        block_on(self.table.get_pinned(key, lsn))
+    }
+
+    pub fn put(&self, key: &[u8], lsn: u64, value: &[u8]) -> Result<()> {
+        block_on(self.table.put(key, lsn, value))
+    }
+
+    /// Puts `value` to `key` with the given options.
+    pub fn put_with_options(
+        &self,
+        key: &[u8],
+        lsn: u64,
+        value: &[u8],
+        opts: PutOptions<'_>,
+    ) -> Result<()> {
+        block_on(self.table.put_with_options(key, lsn, value, opts))
+    }
+
+    pub fn delete(&self, key: &[u8], lsn: u64) -> Result<()> {
+        block_on(self.table.delete(key, lsn))
+    }
+
+    /// Puts `value` to `key` at an allocated LSN, and returns the LSN.
+    ///
+    /// See [`crate::Table::next_lsn`] for details.
+    pub fn put_auto(&self, key: &[u8], value: &[u8]) -> Result<u64> {
+        block_on(self.table.put_auto(key, value))
+    }
+
+    /// Deletes `key` at an allocated LSN, and returns the LSN.
+    pub fn delete_auto(&self, key: &[u8]) -> Result<u64> {
+        block_on(self.table.delete_auto(key))
+    }
+
+    /// Allocates an LSN that is larger than all the LSNs allocated or written before.
+    pub fn next_lsn(&self) -> u64 {
+        self.table.next_lsn()
+    }
+
+    /// Returns the largest LSN that is allocated or written.
+    pub fn last_lsn(&self) -> u64 {
+        self.table.last_lsn()
+    }
+
+    /// Calls `f` with the entries in `start..end` that are visible at `lsn`, in key order, until
+    /// `f` returns false. An empty `end` means that the range is unbounded.
+    pub fn scan<F>(&self, start: &[u8], end: &[u8], lsn: u64, f: F) -> Result<()>
+    where
+        F: FnMut(&[u8], &[u8]) -> bool,
+    {
+        block_on(self.table.scan(start, end, lsn, f))
+    }
+
+    /// Scans the entries in `start..end` that are visible at `lsn` with the given options.
+    pub fn scan_with_options<F>(
+        &self,
+        start: &[u8],
+        end: &[u8],
+        lsn: u64,
+        opts: ScanOptions,
+        f: F,
+    ) -> Result<()>
+    where
+        F: FnMut(&[u8], &[u8]) -> bool,
+    {
+        block_on(self.table.scan_with_options(start, end, lsn, opts, f))
+    }
+
+    /// Returns a cursor over the entries in `start..end` that are visible at `lsn`, in key order.
+    ///
+    /// See [`crate::Table::cursor`] for details.
+    pub fn cursor(&self, start: &[u8], end: &[u8], lsn: u64) -> Cursor<'_> {
+        Cursor {
+            cursor: self.table.cursor(start, end, lsn),
+        }
+    }
+
+    /// Returns the approximate size of the entries in `start..end`, without scanning them.
+    pub fn approximate_size(&self, start: &[u8], end: &[u8]) -> Result<u64> {
+        block_on(self.table.approximate_size(start, end))
+    }
+
+    /// Returns the approximate number of entries in `start..end`, without scanning them.
+    pub fn approximate_count(&self, start: &[u8], end: &[u8]) -> Result<u64> {
+        block_on(self.table.approximate_count(start, end))
+    }
+
+    /// Returns the approximate number of keys whose newest versions are puts, without scanning
+    /// them.
+    pub fn len_estimate(&self) -> u64 {
+        self.table.len_estimate()
+    }
+
+    /// Returns about `n` keys sampled from the table, sorted by the comparator.
+    ///
+    /// See [`crate::Table::sample_keys`] for details.
+    pub fn sample_keys(&self, n: usize) -> Result<Vec<Vec<u8>>> {
+        block_on(self.table.sample_keys(n))
+    }
+
+    /// Writes the table to disk, so that it can be recovered when it is opened again.
+    pub fn checkpoint(&self) -> Result<()> {
+        block_on(self.table.checkpoint())
+    }
+
+    /// Checkpoints the table, and returns the durable LSN.
+    ///
+    /// See [`crate::Table::flush`] for details.
+    pub fn flush(&self) -> Result<u64> {
+        block_on(self.table.flush())
+    }
+
+    /// Waits until the writes that complete before the call are durable, and returns the durable
+    /// LSN.
+    ///
+    /// See [`crate::Table::wait_durable`] for details.
+    pub fn wait_durable(&self) -> Result<u64> {
+        block_on(self.table.wait_durable())
+    }
+
+    /// Returns the largest LSN of the writes covered by the last checkpoint.
+    pub fn durable_lsn(&self) -> u64 {
+        self.table.durable_lsn()
+    }
+
+    /// Raises the timestamp before which the history of keys is dropped, and returns the new one.
+    ///
+    /// See [`crate::Table::set_history_ts_low`] for details.
+    pub fn set_history_ts_low(&self, ts: u64) -> u64 {
+        self.table.set_history_ts_low(ts)
+    }
+
+    /// Returns the timestamp before which the history of keys is dropped.
+    pub fn history_ts_low(&self) -> u64 {
+        self.table.history_ts_low()
+    }
+
+    /// Checkpoints the table and closes it.
+    ///
+    /// See [`crate::Table::close`] for details.
+    pub fn close(self) -> Result<()> {
+        block_on(self.table.close())
+    }
+
+    /// Writes the entries in `start..end` that are visible at `lsn` to `writer`, and returns the
+    /// number of entries written.
+    ///
+    /// See [`crate::Table::export`] for details.
+    pub fn export<W: Write>(&self, start: &[u8], end: &[u8], lsn: u64, writer: W) -> Result<u64> {
+        let mut writer = AllowStdIo::new(writer);
+        block_on(self.table.export(start, end, lsn, &mut writer))
+    }
+
+    /// Puts the entries written by `export` to the table, and returns the number of entries.
+    pub fn import<R: Read>(&self, reader: R) -> Result<u64> {
+        let mut reader = AllowStdIo::new(reader);
+        block_on(self.table.import(&mut reader))
+    }
+
+    /// Builds the empty table from `iter`, whose entries are sorted by the comparator, at `lsn`.
+    ///
+    /// See [`crate::Table::build_from_sorted_iter`] for details.
+    pub fn build_from_sorted_iter<I, K, V>(&self, iter: I, lsn: u64) -> Result<()>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        block_on(self.table.build_from_sorted_iter(iter, lsn))
+    }
+
+    /// Links the leaves of a page file written by [`PageFileWriter`] into the table.
+    ///
+    /// See [`crate::Table::ingest`] for details.
+    pub fn ingest(&self, path: impl AsRef<Path>) -> Result<()> {
+        block_on(self.table.ingest(path))
+    }
+
+    /// Backs up the table to `dir`, and returns the largest LSN that the backup may contain.
+    ///
+    /// See [`crate::Table::backup`] for details.
+    pub fn backup(&self, dir: impl AsRef<Path>, since_lsn: u64) -> Result<u64> {
+        block_on(self.table.backup(dir, since_lsn))
+    }
+
+    /// Applies an incremental backup in `backup_dir` to the table in `path`, which must not be
+    /// opened.
+    pub fn apply_incremental(path: impl AsRef<Path>, backup_dir: impl AsRef<Path>) -> Result<()> {
+        let env = Arc::new(ThreadPoolEnv::new(NUM_BACKGROUND_THREADS));
+        block_on(crate::Table::apply_incremental_with_env(
+            env, path, backup_dir,
+        ))
+    }
+
+    /// Subscribes to the changes committed to the table after this call.
+    ///
+    /// See [`crate::Table::subscribe`] for details.
+    pub fn subscribe(&self) -> ChangeStream {
+        ChangeStream {
+            stream: self.table.subscribe(),
+        }
+    }
+
+    /// Applies a change from the `ChangeStream` of another table.
+    pub fn apply(&self, change: &Change) -> Result<()> {
+        block_on(self.table.apply(change))
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.table.stats()
+    }
+
+    /// Tries to reclaim the pages that are retired by all the tables in the process.
+    ///
+    /// See [`crate::Table::flush_epoch`] for details.
+    pub fn flush_epoch(&self) {
+        self.table.flush_epoch()
+    }
+
+    /// Returns the I/O statistics of the table by file and operation.
+    pub fn io_stats(&self) -> IoStats {
+        self.table.io_stats()
+    }
+
+    /// Returns the shape of the tree and the space usage of the page files.
+    ///
+    /// See [`crate::Table::inspect`] for details.
+    pub fn inspect(&self) -> Result<TreeInfo> {
+        block_on(self.table.inspect())
+    }
+
+    /// Returns a breakdown of the memory used by the table.
+    ///
+    /// See [`crate::Table::memory_usage`] for details.
+    pub fn memory_usage(&self) -> Result<MemoryUsage> {
+        block_on(self.table.memory_usage())
+    }
+
+    /// Returns the manifest of the last checkpoint.
+    pub fn manifest(&self) -> ManifestInfo {
+        block_on(self.table.manifest())
+    }
+
+    /// Verifies the integrity of the last checkpoint.
+    ///
+    /// See [`crate::Table::verify`] for details.
+    pub fn verify(&self) -> Result<VerifyReport> {
+        block_on(self.table.verify())
+    }
+
+    /// Repairs the table in `path`, which must not be opened.
+    ///
+    /// See [`crate::Table::repair`] for details.
+    pub fn repair(path: impl AsRef<Path>, opts: Options) -> Result<RepairReport> {
+        let env = Arc::new(ThreadPoolEnv::new(NUM_BACKGROUND_THREADS));
+        block_on(crate::Table::repair_with_env(env, path, opts))
+    }
+}
+
+/// A blocking version of [`crate::PageFileWriter`].
+pub struct PageFileWriter {
+    writer: crate::PageFileWriter,
+}
+
+impl PageFileWriter {
+    /// Creates a page file in `path`, whose entries are written at `lsn`.
+    ///
+    /// `opts` must be the options of the table that the file is ingested into.
+    pub fn create(path: impl AsRef<Path>, lsn: u64, opts: &Options) -> Result<Self> {
+        let env = Arc::new(ThreadPoolEnv::new(NUM_BACKGROUND_THREADS));
+        let writer = block_on(crate::PageFileWriter::create_with_env(env, path, lsn, opts))?;
+        Ok(Self { writer })
+    }
+
+    /// Adds an entry to the file.
+    ///
+    /// See [`crate::PageFileWriter::put`] for details.
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        block_on(self.writer.put(key, value))
+    }
+
+    /// Returns the number of entries added to the file.
+    pub fn num_entries(&self) -> u64 {
+        self.writer.num_entries()
+    }
+
+    /// Finishes the file, and returns its size.
+    pub fn finish(self) -> Result<u64> {
+        block_on(self.writer.finish())
+    }
+}
+
+/// A blocking version of [`crate::Cursor`].
+pub struct Cursor<'a> {
+    cursor: crate::Cursor<'a>,
+}
+
+/// Yields the keys and values of the entries.
+impl<'a> Iterator for Cursor<'a> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        block_on(self.cursor.next()).transpose()
+    }
+}
+
+/// A blocking version of [`crate::ChangeStream`].
+pub struct ChangeStream {
+    stream: crate::ChangeStream,
+}
+
+/// Yields the changes, blocking until there is one, and ends when the table is closed.
+impl Iterator for ChangeStream {
+    type Item = Result<Change>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        block_on(self.stream.next()).transpose()
+    }
+}
+
+/// A blocking version of [`crate::compare_tables`].
+pub fn compare_tables(
+    first: &Table,
+    second: &Table,
+    start: &[u8],
+    end: &[u8],
+    opts: &CompareOptions,
+) -> Result<CompareReport> {
+    block_on(crate::compare_tables(
+        &first.table,
+        &second.table,
+        start,
+        end,
+        opts,
+    ))
+}
+
+/// Runs a future to completion on the current thread.
+fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = Box::pin(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        match Pin::as_mut(&mut future).poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn table() {
+        let dir = tempfile::tempdir().unwrap();
+        let table = Table::open(dir.path(), Options::default()).unwrap();
+        for i in 0..16u64 {
+            let buf = i.to_be_bytes();
+            table.put(&buf, i, &buf).unwrap();
+        }
+        let key = 3u64.to_be_bytes();
+        assert_eq!(table.get(&key, 16).unwrap(), Some(key.to_vec()));
+        assert_eq!(
+            table.get_pinned(&key, 16).unwrap().as_deref(),
+            Some(key.as_slice())
+        );
+        let mut buf = Vec::new();
+        assert!(table.get_into(&key, 16, &mut buf).unwrap());
+        assert_eq!(buf, key);
+        table.delete(&key, 16).unwrap();
+        assert_eq!(table.get(&key, 16).unwrap(), None);
+
+        let mut keys = Vec::new();
+        let end = 6u64.to_be_bytes();
+        table
+            .scan(&[], &end, 16, |k, _| {
+                keys.push(u64::from_be_bytes(k.try_into().unwrap()));
+                true
+            })
+            .unwrap();
+        assert_eq!(keys, vec![0, 1, 2, 4, 5]);
+        let keys: Vec<_> = table
+            .cursor(&[], &end, 16)
+            .map(|entry| u64::from_be_bytes(entry.unwrap().0.try_into().unwrap()))
+            .collect();
+        assert_eq!(keys, vec![0, 1, 2, 4, 5]);
+
+        table.checkpoint().unwrap();
+        assert_eq!(table.durable_lsn(), 16);
+        assert_eq!(table.flush().unwrap(), 16);
+        drop(table);
+        let table = Table::open(dir.path(), Options::default()).unwrap();
+        assert_eq!(table.get(&key, 16).unwrap(), None);
+        let key = 4u64.to_be_bytes();
+        assert_eq!(table.get(&key, 16).unwrap(), Some(key.to_vec()));
+        table.put(&key, 17, &key).unwrap();
+
+        let path = dir.path().join("ingest");
+        let mut writer = PageFileWriter::create(&path, 18, &Options::default()).unwrap();
+        writer.put(&100u64.to_be_bytes(), b"ingested").unwrap();
+        writer.finish().unwrap();
+        table.ingest(&path).unwrap();
+        assert_eq!(
+            table.get(&100u64.to_be_bytes(), 18).unwrap(),
+            Some(b"ingested".to_vec())
+        );
+
+        let mut file = Vec::new();
+        assert_eq!(table.export(&[], &[], 18, &mut file).unwrap(), 16);
+        let other_dir = tempfile::tempdir().unwrap();
+        let other = Table::open(other_dir.path(), Options::default()).unwrap();
+        assert_eq!(other.import(file.as_slice()).unwrap(), 16);
+        assert_eq!(other.get(&key, 17).unwrap(), Some(key.to_vec()));
+        table.close().unwrap();
+        let table = Table::open(dir.path(), Options::default()).unwrap();
+        assert_eq!(table.get(&key, 17).unwrap(), Some(key.to_vec()));
+    }
+}
@@ -0,0 +1,317 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    sync::Mutex,
+    task::{Context, Poll, Waker},
+};
+
+use crate::{write_batch::WriteBatch, Error, Result, Table};
+
+/// A key of a table, identified by `Table::id`.
+type LockKey = (u64, Vec<u8>);
+
+/// An in-memory table of key locks for pessimistic transactions across tables.
+///
+/// A transaction locks the keys that it reads for update or writes, and holds the locks until it
+/// commits or is dropped, so conflicting transactions wait instead of failing at commit. Waits
+/// that would close a cycle fail with `Error::Deadlock`, and the transaction that gets the error
+/// should be dropped to release its locks.
+///
+/// Locks only exclude other transactions of the same lock table. Writes to the tables outside of
+/// transactions are not blocked.
+#[derive(Default)]
+pub struct LockTable {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    next_txn: u64,
+    // The transaction that holds each locked key.
+    owners: HashMap<LockKey, u64>,
+    // The transaction that each waiting transaction waits for.
+    waits_for: HashMap<u64, u64>,
+    // The wakers of the transactions that wait for each key, one per transaction.
+    waiters: HashMap<LockKey, Vec<(u64, Waker)>>,
+}
+
+impl State {
+    /// Returns true if `txn` waiting for `holder` closes a cycle of waits.
+    fn is_deadlock(&self, txn: u64, holder: u64) -> bool {
+        let mut next = Some(holder);
+        while let Some(t) = next {
+            if t == txn {
+                return true;
+            }
+            next = self.waits_for.get(&t).copied();
+        }
+        false
+    }
+}
+
+impl LockTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Begins a transaction.
+    pub fn begin(&self) -> Transaction<'_> {
+        let mut state = self.state.lock().unwrap();
+        state.next_txn += 1;
+        Transaction {
+            locks: self,
+            id: state.next_txn,
+            keys: Vec::new(),
+            batch: WriteBatch::new(),
+        }
+    }
+
+    fn release(&self, keys: &[LockKey]) {
+        let mut state = self.state.lock().unwrap();
+        for key in keys {
+            state.owners.remove(key);
+            for (_, waker) in state.waiters.remove(key).into_iter().flatten() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// A pessimistic transaction, see [`LockTable`].
+///
+/// Writes are buffered until the transaction commits, so reads don't see the writes of their own
+/// transaction.
+pub struct Transaction<'a> {
+    locks: &'a LockTable,
+    id: u64,
+    keys: Vec<LockKey>,
+    batch: WriteBatch<'a>,
+}
+
+impl<'a> Transaction<'a> {
+    /// Locks `key` of `table` until the transaction ends, waiting for the transaction that holds
+    /// it if any.
+    pub async fn lock(&mut self, table: &Table, key: &[u8]) -> Result<()> {
+        let key = (table.id(), key.to_vec());
+        let acquired = Acquire {
+            locks: self.locks,
+            txn: self.id,
+            key: &key,
+        }
+        .await?;
+        if acquired {
+            self.keys.push(key);
+        }
+        Ok(())
+    }
+
+    /// Locks `key` of `table`, and returns its value at `lsn`.
+    pub async fn get_for_update(
+        &mut self,
+        table: &Table,
+        key: &[u8],
+        lsn: u64,
+    ) -> Result<Option<Vec<u8>>> {
+        self.lock(table, key).await?;
+        table.get(key, lsn).await
+    }
+
+    /// Locks `key` of `table`, and buffers a put of it.
+    pub async fn put(&mut self, table: &'a Table, key: &'a [u8], value: &'a [u8]) -> Result<()> {
+        self.lock(table, key).await?;
+        self.batch.put(table, key, value);
+        Ok(())
+    }
+
+    /// Locks `key` of `table`, and buffers a delete of it.
+    pub async fn delete(&mut self, table: &'a Table, key: &'a [u8]) -> Result<()> {
+        self.lock(table, key).await?;
+        self.batch.delete(table, key);
+        Ok(())
+    }
+
+    /// Sets a savepoint of the writes of the transaction.
+    ///
+    /// Savepoints are nested, so that each rollback undoes the writes since the last savepoint
+    /// that is not rolled back yet.
+    pub fn set_savepoint(&mut self) {
+        self.batch.set_savepoint();
+    }
+
+    /// Removes the writes since the last savepoint, and returns false if there is no savepoint.
+    ///
+    /// The locks acquired since the savepoint are still held until the transaction ends.
+    pub fn rollback_to_savepoint(&mut self) -> bool {
+        self.batch.rollback_to_savepoint()
+    }
+
+    /// Applies the writes of the transaction at `lsn`, and releases its locks.
+    ///
+    /// The locks are held until the writes are applied. Reads at older LSNs see none of the
+    /// writes, and reads at `lsn` see all of them once the commit returns. Each table is
+    /// checkpointed on its own, however, so the writes to different tables are not atomic across
+    /// crashes. On errors, some of the writes may be applied.
+    pub async fn commit(self, lsn: u64) -> Result<()> {
+        self.batch.apply(lsn).await
+    }
+}
+
+impl Drop for Transaction<'_> {
+    fn drop(&mut self) {
+        self.locks.release(&self.keys);
+    }
+}
+
+/// A future that acquires a lock, and returns false if the transaction holds it already.
+struct Acquire<'a> {
+    locks: &'a LockTable,
+    txn: u64,
+    key: &'a LockKey,
+}
+
+impl Future for Acquire<'_> {
+    type Output = Result<bool>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.locks.state.lock().unwrap();
+        let holder = match state.owners.get(self.key) {
+            Some(&holder) if holder == self.txn => return Poll::Ready(Ok(false)),
+            Some(&holder) => holder,
+            None => {
+                state.owners.insert(self.key.clone(), self.txn);
+                state.waits_for.remove(&self.txn);
+                return Poll::Ready(Ok(true));
+            }
+        };
+        if state.is_deadlock(self.txn, holder) {
+            state.waits_for.remove(&self.txn);
+            return Poll::Ready(Err(Error::Deadlock(format!(
+                "transaction {} waits for transaction {}, which waits for it",
+                self.txn, holder
+            ))));
+        }
+        state.waits_for.insert(self.txn, holder);
+        let waiters = state.waiters.entry(self.key.clone()).or_default();
+        match waiters.iter_mut().find(|(txn, _)| *txn == self.txn) {
+            // The future is polled again before the key is released.
+            Some((_, waker)) => {
+                if !waker.will_wake(cx.waker()) {
+                    *waker = cx.waker().clone();
+                }
+            }
+            None => waiters.push((self.txn, cx.waker().clone())),
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Acquire<'_> {
+    fn drop(&mut self) {
+        // The wait is cancelled or done.
+        let mut state = self.locks.state.lock().unwrap();
+        state.waits_for.remove(&self.txn);
+        if let Some(waiters) = state.waiters.get_mut(self.key) {
+            waiters.retain(|(txn, _)| *txn != self.txn);
+            if waiters.is_empty() {
+                state.waiters.remove(self.key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::FutureExt;
+
+    use super::*;
+    use crate::Options;
+
+    #[tokio::test]
+    async fn transactions() {
+        let dir = tempfile::tempdir().unwrap();
+        let accounts = Table::open(dir.path(), Options::default()).await.unwrap();
+        accounts.put(b"a", 1, b"10").await.unwrap();
+        let locks = LockTable::new();
+
+        let mut t1 = locks.begin();
+        assert_eq!(
+            t1.get_for_update(&accounts, b"a", 1).await.unwrap(),
+            Some(b"10".to_vec())
+        );
+        t1.put(&accounts, b"a", b"20").await.unwrap();
+
+        // The second transaction waits until the first one commits.
+        let mut t2 = locks.begin();
+        let mut wait = Box::pin(t2.lock(&accounts, b"a"));
+        assert!(wait.as_mut().now_or_never().is_none());
+        t1.commit(2).await.unwrap();
+        wait.await.unwrap();
+        assert_eq!(accounts.get(b"a", 2).await.unwrap(), Some(b"20".to_vec()));
+
+        // Dropping a transaction releases its locks.
+        drop(t2);
+        let mut t3 = locks.begin();
+        t3.lock(&accounts, b"a").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn deadlock() {
+        let dir = tempfile::tempdir().unwrap();
+        let table = Table::open(dir.path(), Options::default()).await.unwrap();
+        let locks = LockTable::new();
+        let mut t1 = locks.begin();
+        let mut t2 = locks.begin();
+        t1.lock(&table, b"a").await.unwrap();
+        t2.lock(&table, b"b").await.unwrap();
+
+        let mut wait = Box::pin(t1.lock(&table, b"b"));
+        assert!(wait.as_mut().now_or_never().is_none());
+        let err = t2.lock(&table, b"a").await.unwrap_err();
+        assert!(matches!(err, Error::Deadlock(_)));
+        drop(t2);
+        wait.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn waiters() {
+        let dir = tempfile::tempdir().unwrap();
+        let table = Table::open(dir.path(), Options::default()).await.unwrap();
+        let locks = LockTable::new();
+        let mut t1 = locks.begin();
+        t1.lock(&table, b"a").await.unwrap();
+
+        // A wait that is polled repeatedly keeps one waker.
+        let mut t2 = locks.begin();
+        let mut wait = Box::pin(t2.lock(&table, b"a"));
+        for _ in 0..4 {
+            assert!(wait.as_mut().now_or_never().is_none());
+        }
+        let num_waiters = |locks: &LockTable| {
+            let state = locks.state.lock().unwrap();
+            state.waiters.values().map(|w| w.len()).sum::<usize>()
+        };
+        assert_eq!(num_waiters(&locks), 1);
+        // A cancelled wait leaves no waker behind.
+        drop(wait);
+        assert_eq!(num_waiters(&locks), 0);
+    }
+
+    #[tokio::test]
+    async fn closed_tables() {
+        let dir = tempfile::tempdir().unwrap();
+        let locks = LockTable::new();
+        let mut t1 = locks.begin();
+        // The locks of a closed table don't apply to the tables opened after it, even if they
+        // reuse its memory.
+        let mut table = Table::open(dir.path().join("1"), Options::default())
+            .await
+            .unwrap();
+        t1.lock(&table, b"a").await.unwrap();
+        table = Table::open(dir.path().join("2"), Options::default())
+            .await
+            .unwrap();
+        let mut t2 = locks.begin();
+        assert!(t2.lock(&table, b"a").now_or_never().is_some());
+    }
+}
@@ -0,0 +1,291 @@
+use std::marker::PhantomData;
+
+use crate::{Cursor, Error, Result, Table};
+
+/// An encoding of a type to bytes.
+///
+/// The built-in codecs preserve the order of values in the order of their bytes, so keys sort
+/// the same way as the values that they encode under the default comparator:
+///
+/// - Integers are big-endian, with the sign bit of signed integers flipped.
+/// - Strings and byte vectors escape `0x00` as `0x00 0xff` and end with `0x00 0x01`, so that a
+///   value sorts before the values that it is a prefix of, even in the middle of a tuple.
+/// - Tuples concatenate the encodings of their elements, so they sort element by element.
+pub trait Codec: Sized {
+    /// Appends the encoding of `self` to `buf`.
+    fn encode_to(&self, buf: &mut Vec<u8>);
+
+    /// Decodes a value from the front of `buf`, and advances `buf` past it.
+    fn decode_from(buf: &mut &[u8]) -> Result<Self>;
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.encode_to(&mut buf);
+        buf
+    }
+
+    /// Decodes a value from the whole `buf`.
+    fn decode(mut buf: &[u8]) -> Result<Self> {
+        let value = Self::decode_from(&mut buf)?;
+        if !buf.is_empty() {
+            return Err(Error::Corrupted(
+                format!("{} trailing bytes after the encoded value", buf.len()).into(),
+            ));
+        }
+        Ok(value)
+    }
+}
+
+fn take<'a>(buf: &mut &'a [u8], n: usize) -> Result<&'a [u8]> {
+    if buf.len() < n {
+        return Err(Error::Corrupted(
+            format!("expected {} bytes, found {}", n, buf.len()).into(),
+        ));
+    }
+    let (head, tail) = buf.split_at(n);
+    *buf = tail;
+    Ok(head)
+}
+
+macro_rules! unsigned_codec {
+    ($($t:ty),*) => {$(
+        impl Codec for $t {
+            fn encode_to(&self, buf: &mut Vec<u8>) {
+                buf.extend_from_slice(&self.to_be_bytes());
+            }
+
+            fn decode_from(buf: &mut &[u8]) -> Result<Self> {
+                let bytes = take(buf, std::mem::size_of::<$t>())?;
+                Ok(<$t>::from_be_bytes(bytes.try_into().unwrap()))
+            }
+        }
+    )*};
+}
+
+macro_rules! signed_codec {
+    ($($t:ty => $u:ty),*) => {$(
+        impl Codec for $t {
+            fn encode_to(&self, buf: &mut Vec<u8>) {
+                // Flipping the sign bit orders negative integers before positive ones.
+                ((*self as $u) ^ (1 << (<$u>::BITS - 1))).encode_to(buf);
+            }
+
+            fn decode_from(buf: &mut &[u8]) -> Result<Self> {
+                let bits = <$u>::decode_from(buf)?;
+                Ok((bits ^ (1 << (<$u>::BITS - 1))) as $t)
+            }
+        }
+    )*};
+}
+
+unsigned_codec!(u8, u16, u32, u64, u128);
+signed_codec!(i8 => u8, i16 => u16, i32 => u32, i64 => u64, i128 => u128);
+
+const ESCAPE: u8 = 0x00;
+const ESCAPED: u8 = 0xff;
+const TERMINATOR: u8 = 0x01;
+
+fn encode_bytes(bytes: &[u8], buf: &mut Vec<u8>) {
+    for &b in bytes {
+        buf.push(b);
+        if b == ESCAPE {
+            buf.push(ESCAPED);
+        }
+    }
+    buf.extend_from_slice(&[ESCAPE, TERMINATOR]);
+}
+
+impl Codec for Vec<u8> {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        encode_bytes(self, buf);
+    }
+
+    fn decode_from(buf: &mut &[u8]) -> Result<Self> {
+        let mut value = Vec::new();
+        loop {
+            match take(buf, 1)?[0] {
+                ESCAPE => match take(buf, 1)?[0] {
+                    ESCAPED => value.push(ESCAPE),
+                    TERMINATOR => return Ok(value),
+                    b => {
+                        return Err(Error::Corrupted(
+                            format!("invalid escaped byte {:#x}", b).into(),
+                        ))
+                    }
+                },
+                b => value.push(b),
+            }
+        }
+    }
+}
+
+impl Codec for String {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        // UTF-8 bytes are in the order of code points.
+        encode_bytes(self.as_bytes(), buf);
+    }
+
+    fn decode_from(buf: &mut &[u8]) -> Result<Self> {
+        let bytes = Vec::<u8>::decode_from(buf)?;
+        String::from_utf8(bytes).map_err(|err| Error::Corrupted(err.to_string().into()))
+    }
+}
+
+macro_rules! tuple_codec {
+    ($($name:ident),+) => {
+        impl<$($name: Codec),+> Codec for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn encode_to(&self, buf: &mut Vec<u8>) {
+                let ($($name,)+) = self;
+                $($name.encode_to(buf);)+
+            }
+
+            fn decode_from(buf: &mut &[u8]) -> Result<Self> {
+                Ok(($($name::decode_from(buf)?,)+))
+            }
+        }
+    };
+}
+
+tuple_codec!(A);
+tuple_codec!(A, B);
+tuple_codec!(A, B, C);
+tuple_codec!(A, B, C, D);
+
+/// A table whose keys and values are encoded with [`Codec`]s.
+///
+/// The key codec must preserve the order of keys under the comparator of the table, which the
+/// built-in codecs do for the default one. Values that fail to decode are returned as
+/// `Error::Corrupted`.
+pub struct TypedTable<K, V> {
+    table: Table,
+    _codecs: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K: Codec, V: Codec> TypedTable<K, V> {
+    pub fn new(table: Table) -> Self {
+        Self {
+            table,
+            _codecs: PhantomData,
+        }
+    }
+
+    /// Returns the underlying table, for the operations that take raw bytes.
+    pub fn inner(&self) -> &Table {
+        &self.table
+    }
+
+    pub fn into_inner(self) -> Table {
+        self.table
+    }
+
+    pub async fn get(&self, key: &K, lsn: u64) -> Result<Option<V>> {
+        let value = self.table.get(&key.encode(), lsn).await?;
+        value.map(|value| V::decode(&value)).transpose()
+    }
+
+    pub async fn put(&self, key: &K, lsn: u64, value: &V) -> Result<()> {
+        self.table.put(&key.encode(), lsn, &value.encode()).await
+    }
+
+    pub async fn delete(&self, key: &K, lsn: u64) -> Result<()> {
+        self.table.delete(&key.encode(), lsn).await
+    }
+
+    /// Returns a cursor over the entries in `start..end` that are visible at `lsn`, in key order.
+    /// A missing bound means that the range is unbounded on that side.
+    pub fn cursor(&self, start: Option<&K>, end: Option<&K>, lsn: u64) -> TypedCursor<'_, K, V> {
+        let start = start.map(Codec::encode).unwrap_or_default();
+        let end = end.map(Codec::encode).unwrap_or_default();
+        TypedCursor {
+            cursor: self.table.cursor(&start, &end, lsn),
+            _codecs: PhantomData,
+        }
+    }
+}
+
+/// A cursor of a [`TypedTable`].
+pub struct TypedCursor<'a, K, V> {
+    cursor: Cursor<'a>,
+    _codecs: PhantomData<fn() -> (K, V)>,
+}
+
+impl<'a, K: Codec, V: Codec> TypedCursor<'a, K, V> {
+    /// Returns the next entry, or `None` at the end of the range.
+    pub async fn next(&mut self) -> Result<Option<(K, V)>> {
+        match self.cursor.next().await? {
+            Some((key, value)) => Ok(Some((K::decode(&key)?, V::decode(&value)?))),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Options;
+
+    fn assert_ordered<T: Codec + PartialEq + std::fmt::Debug>(values: &[T]) {
+        for pair in values.windows(2) {
+            assert!(pair[0].encode() < pair[1].encode(), "{:?}", pair);
+        }
+        for value in values {
+            assert_eq!(&T::decode(&value.encode()).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn codecs() {
+        assert_ordered(&[0u8, 1, 255]);
+        assert_ordered(&[0u64, 1, 256, u64::MAX]);
+        assert_ordered(&[i32::MIN, -256, -1, 0, 1, 256, i32::MAX]);
+        assert_ordered(&[i128::MIN, -1, 0, i128::MAX]);
+        assert_ordered(&["", "\0", "\0\0", "a", "a\0", "a\0b", "ab", "b"].map(String::from));
+        assert_ordered(&[vec![], vec![0], vec![0, 255], vec![1], vec![255]]);
+        assert_ordered(&[
+            (String::from("a"), -1i64),
+            (String::from("a"), 0),
+            (String::from("a\0"), i64::MIN),
+            (String::from("b"), i64::MIN),
+        ]);
+        assert_ordered(&[(1u8, vec![2u8], String::new(), 3u16)]);
+
+        assert!(u32::decode(&[0; 3]).is_err());
+        assert!(u32::decode(&[0; 5]).is_err());
+        assert!(String::decode(b"a").is_err());
+        assert!(String::decode(b"a\0\x02").is_err());
+        assert!(String::decode(b"\xff\0\x01").is_err());
+    }
+
+    #[tokio::test]
+    async fn typed_table() {
+        let dir = tempfile::tempdir().unwrap();
+        let table = Table::open(dir.path(), Options::default()).await.unwrap();
+        let table = TypedTable::<(String, i64), u64>::new(table);
+        for (i, user) in ["bob", "alice", "carol"].iter().enumerate() {
+            for ts in [-1, 0, 1] {
+                let key = (user.to_string(), ts);
+                table.put(&key, 1, &(i as u64)).await.unwrap();
+            }
+        }
+        let key = ("alice".to_string(), -1);
+        assert_eq!(table.get(&key, 1).await.unwrap(), Some(1));
+        table.delete(&key, 2).await.unwrap();
+        assert_eq!(table.get(&key, 2).await.unwrap(), None);
+
+        let start = ("alice".to_string(), i64::MIN);
+        let end = ("bob".to_string(), 1);
+        let mut cursor = table.cursor(Some(&start), Some(&end), 2);
+        let mut keys = Vec::new();
+        while let Some(((user, ts), _)) = cursor.next().await.unwrap() {
+            keys.push((user, ts));
+        }
+        let expected = [("alice", 0), ("alice", 1), ("bob", -1), ("bob", 0)];
+        assert_eq!(keys, expected.map(|(user, ts)| (user.to_string(), ts)));
+
+        // Raw values that don't decode are reported.
+        table.inner().put(b"\0", 3, b"value").await.unwrap();
+        let mut cursor = table.cursor(None, None, 3);
+        assert!(matches!(cursor.next().await, Err(Error::Corrupted(_))));
+    }
+}
@@ -0,0 +1,234 @@
+use std::collections::HashMap;
+
+use futures::future::try_join_all;
+
+use crate::{Result, Table};
+
+/// The buffered writes of a transaction across tables, which are applied at one LSN.
+///
+/// Reads at older LSNs see none of the writes, and reads at the LSN see all of them once `apply`
+/// returns. Each table is checkpointed on its own, so the writes are not atomic across crashes.
+#[derive(Default)]
+pub(crate) struct WriteBatch<'a> {
+    groups: Vec<Group<'a>>,
+    savepoints: Vec<Savepoint>,
+}
+
+/// The sizes of the groups of a batch when a savepoint is set.
+struct Savepoint {
+    group_lens: Vec<usize>,
+}
+
+struct Group<'a> {
+    table: &'a Table,
+    writes: Vec<(&'a [u8], Option<&'a [u8]>)>,
+}
+
+impl<'a> WriteBatch<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a put of `key` to `table`.
+    ///
+    /// The writes of a batch have the same LSN, so only the last write of a key to a table is
+    /// applied if the key is written more than once.
+    pub fn put(&mut self, table: &'a Table, key: &'a [u8], value: &'a [u8]) {
+        self.add(table, key, Some(value));
+    }
+
+    /// Adds a delete of `key` from `table`.
+    ///
+    /// Only the last write of a key to a table is applied, like `put`.
+    pub fn delete(&mut self, table: &'a Table, key: &'a [u8]) {
+        self.add(table, key, None);
+    }
+
+    /// Sets a savepoint, which `rollback_to_savepoint` rolls the batch back to.
+    ///
+    /// Savepoints are nested, so that each rollback undoes the writes since the last savepoint
+    /// that is not rolled back or popped yet.
+    pub fn set_savepoint(&mut self) {
+        self.savepoints.push(Savepoint {
+            group_lens: self.groups.iter().map(|g| g.writes.len()).collect(),
+        });
+    }
+
+    /// Removes the writes since the last savepoint, and the savepoint itself.
+    ///
+    /// Returns false if there is no savepoint, in which case the batch is unchanged.
+    pub fn rollback_to_savepoint(&mut self) -> bool {
+        let savepoint = match self.savepoints.pop() {
+            Some(savepoint) => savepoint,
+            None => return false,
+        };
+        self.groups.truncate(savepoint.group_lens.len());
+        for (group, len) in self.groups.iter_mut().zip(savepoint.group_lens) {
+            group.writes.truncate(len);
+        }
+        true
+    }
+
+    /// Applies the writes at `lsn`.
+    ///
+    /// The writes to each table are applied in order, and the tables are written concurrently. On
+    /// errors, some writes may be applied, and the batch can be applied again at the same LSN.
+    pub async fn apply(&self, lsn: u64) -> Result<()> {
+        try_join_all(self.groups.iter().map(|group| group.apply(lsn))).await?;
+        Ok(())
+    }
+
+    fn add(&mut self, table: &'a Table, key: &'a [u8], value: Option<&'a [u8]>) {
+        let group = self
+            .groups
+            .iter_mut()
+            .find(|g| std::ptr::eq(g.table, table));
+        match group {
+            Some(group) => group.writes.push((key, value)),
+            None => self.groups.push(Group {
+                table,
+                writes: vec![(key, value)],
+            }),
+        }
+    }
+}
+
+impl Group<'_> {
+    async fn apply(&self, lsn: u64) -> Result<()> {
+        // The writes that are overwritten later in the batch are skipped.
+        let last_writes: HashMap<&[u8], usize> = self
+            .writes
+            .iter()
+            .enumerate()
+            .map(|(i, &(key, _))| (key, i))
+            .collect();
+        for (i, &(key, value)) in self.writes.iter().enumerate() {
+            if last_writes[key] != i {
+                continue;
+            }
+            match value {
+                Some(value) => self.table.put(key, lsn, value).await?,
+                None => self.table.delete(key, lsn).await?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Options;
+
+    #[tokio::test]
+    async fn write_batch_across_tables() {
+        let dir = tempfile::tempdir().unwrap();
+        let entities = Table::open(dir.path().join("entities"), Options::default())
+            .await
+            .unwrap();
+        let indexes = Table::open(dir.path().join("indexes"), Options::default())
+            .await
+            .unwrap();
+        entities.put(b"1", 1, b"alice").await.unwrap();
+        indexes.put(b"alice", 1, b"1").await.unwrap();
+
+        // Renames the entity and moves its index entry.
+        let mut batch = WriteBatch::new();
+        batch.put(&entities, b"1", b"bob");
+        batch.delete(&indexes, b"alice");
+        batch.put(&indexes, b"bob", b"1");
+        batch.apply(2).await.unwrap();
+
+        assert_eq!(
+            entities.get(b"1", 1).await.unwrap(),
+            Some(b"alice".to_vec())
+        );
+        assert_eq!(indexes.get(b"alice", 1).await.unwrap(), Some(b"1".to_vec()));
+        assert_eq!(indexes.get(b"bob", 1).await.unwrap(), None);
+        assert_eq!(entities.get(b"1", 2).await.unwrap(), Some(b"bob".to_vec()));
+        assert_eq!(indexes.get(b"alice", 2).await.unwrap(), None);
+        assert_eq!(indexes.get(b"bob", 2).await.unwrap(), Some(b"1".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn duplicate_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let t1 = Table::open(dir.path().join("t1"), Options::default())
+            .await
+            .unwrap();
+        let t2 = Table::open(dir.path().join("t2"), Options::default())
+            .await
+            .unwrap();
+        t1.put(b"c", 1, b"1").await.unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch.put(&t1, b"a", b"1");
+        batch.put(&t2, b"a", b"1");
+        batch.put(&t1, b"a", b"2");
+        batch.delete(&t1, b"b");
+        batch.put(&t1, b"b", b"2");
+        batch.put(&t1, b"c", b"2");
+        batch.delete(&t1, b"c");
+        // A rollback brings back the write that is overwritten after the savepoint.
+        batch.put(&t2, b"b", b"1");
+        batch.set_savepoint();
+        batch.put(&t2, b"b", b"2");
+        assert!(batch.rollback_to_savepoint());
+        batch.apply(2).await.unwrap();
+        drop(batch);
+
+        async fn check(t1: &Table, t2: &Table) {
+            assert_eq!(t1.get(b"a", 2).await.unwrap(), Some(b"2".to_vec()));
+            assert_eq!(t1.get(b"b", 2).await.unwrap(), Some(b"2".to_vec()));
+            assert_eq!(t1.get(b"c", 2).await.unwrap(), None);
+            assert_eq!(t1.get(b"c", 1).await.unwrap(), Some(b"1".to_vec()));
+            assert_eq!(t2.get(b"a", 2).await.unwrap(), Some(b"1".to_vec()));
+            assert_eq!(t2.get(b"b", 2).await.unwrap(), Some(b"1".to_vec()));
+        }
+        check(&t1, &t2).await;
+        // The overwritten writes are not applied.
+        let stats = t1.stats().lifetime;
+        assert_eq!((stats.num_puts, stats.num_deletes), (3, 1));
+
+        // The writes are the same once they are consolidated on disk.
+        t1.close().await.unwrap();
+        t2.close().await.unwrap();
+        let t1 = Table::open(dir.path().join("t1"), Options::default())
+            .await
+            .unwrap();
+        let t2 = Table::open(dir.path().join("t2"), Options::default())
+            .await
+            .unwrap();
+        check(&t1, &t2).await;
+    }
+
+    #[tokio::test]
+    async fn savepoints() {
+        let dir = tempfile::tempdir().unwrap();
+        let t1 = Table::open(dir.path().join("t1"), Options::default())
+            .await
+            .unwrap();
+        let t2 = Table::open(dir.path().join("t2"), Options::default())
+            .await
+            .unwrap();
+
+        let mut batch = WriteBatch::new();
+        assert!(!batch.rollback_to_savepoint());
+        batch.put(&t1, b"a", b"1");
+        batch.set_savepoint();
+        batch.put(&t1, b"b", b"1");
+        batch.set_savepoint();
+        batch.put(&t2, b"c", b"1");
+        // Rolls back the write to `t2`, and then the second write to `t1`.
+        assert!(batch.rollback_to_savepoint());
+        assert!(batch.rollback_to_savepoint());
+        assert!(!batch.rollback_to_savepoint());
+        batch.put(&t2, b"d", b"1");
+        batch.apply(1).await.unwrap();
+
+        assert_eq!(t1.get(b"a", 1).await.unwrap(), Some(b"1".to_vec()));
+        assert_eq!(t1.get(b"b", 1).await.unwrap(), None);
+        assert_eq!(t2.get(b"c", 1).await.unwrap(), None);
+        assert_eq!(t2.get(b"d", 1).await.unwrap(), Some(b"1".to_vec()));
+    }
+}
@@ -0,0 +1,518 @@
+//! Tools to inspect PhotonDB tables offline.
+//!
+//! The `photondb-tools` binary runs a [`Command`] on a table, which is opened with
+//! [`Options::read_only`], so that it doesn't change the table and can run along with the process
+//! that writes it:
+//!
+//! ```text
+//! photondb-tools dump <path> [--start <key>] [--end <key>] [--lsn <lsn>] [--hex]
+//! photondb-tools compare <path> <other> [--start <key>] [--end <key>] [--lsn <lsn>] [--hex]
+//! photondb-tools stats <path>
+//! photondb-tools manifest <path>
+//! photondb-tools verify <path>
+//! photondb-tools repair <path>
+//! ```
+//!
+//! `repair` is the exception, which rewrites a table after `verify` finds problems in it, so the
+//! table must not be opened by any other process.
+//! The tools see the last checkpoint of the table. Tables with a custom comparator or cold tier
+//! can't be opened by the tools, since they are not configured on the command line.
+
+use std::{
+    error::Error,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use photondb::{
+    sync::{compare_tables, Table},
+    CompareOptions, Options,
+};
+
+pub const USAGE: &str = "\
+Usage:
+    photondb-tools dump <path> [--start <key>] [--end <key>] [--lsn <lsn>] [--hex]
+        Prints the entries in [start, end) that are visible at lsn, one per line.
+        Keys and values are escaped, or in hex with --hex, which applies to the
+        arguments too.
+    photondb-tools compare <path> <other> [--start <key>] [--end <key>] [--lsn <lsn>] [--hex]
+        Compares the entries in [start, end) of two tables, such as a replica or a
+        backup and its source, and prints the keys that differ and the checksums
+        of each range of keys. Fails if any key differs.
+    photondb-tools stats <path>
+        Prints the shape of the tree and the space usage of each page file.
+    photondb-tools manifest <path>
+        Prints the manifest of the last checkpoint.
+    photondb-tools verify <path>
+        Checks the integrity of the last checkpoint and prints all the problems.
+    photondb-tools repair <path>
+        Rebuilds the table from the readable leaves and prints the keys that are
+        lost. The table must not be opened by any other process.";
+
+/// A command of `photondb-tools`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Command {
+    Dump {
+        path: PathBuf,
+        start: Vec<u8>,
+        /// An empty end means that the range is unbounded.
+        end: Vec<u8>,
+        lsn: u64,
+        hex: bool,
+    },
+    Compare {
+        path: PathBuf,
+        other: PathBuf,
+        start: Vec<u8>,
+        /// An empty end means that the range is unbounded.
+        end: Vec<u8>,
+        lsn: u64,
+        hex: bool,
+    },
+    Stats {
+        path: PathBuf,
+    },
+    Manifest {
+        path: PathBuf,
+    },
+    Verify {
+        path: PathBuf,
+    },
+    Repair {
+        path: PathBuf,
+    },
+}
+
+impl Command {
+    /// Parses a command from the arguments after the program name.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut args = args.into_iter();
+        let name = args.next().ok_or("missing command")?;
+        let path = PathBuf::from(args.next().ok_or("missing table path")?);
+        match name.as_str() {
+            "dump" => {
+                let (start, end, lsn, hex) = parse_range(args)?;
+                Ok(Self::Dump {
+                    path,
+                    start,
+                    end,
+                    lsn,
+                    hex,
+                })
+            }
+            "compare" => {
+                let other = PathBuf::from(args.next().ok_or("missing other table path")?);
+                let (start, end, lsn, hex) = parse_range(args)?;
+                Ok(Self::Compare {
+                    path,
+                    other,
+                    start,
+                    end,
+                    lsn,
+                    hex,
+                })
+            }
+            "stats" | "manifest" | "verify" | "repair" => {
+                if let Some(arg) = args.next() {
+                    return Err(format!("unknown argument {}", arg));
+                }
+                match name.as_str() {
+                    "stats" => Ok(Self::Stats { path }),
+                    "manifest" => Ok(Self::Manifest { path }),
+                    "verify" => Ok(Self::Verify { path }),
+                    _ => Ok(Self::Repair { path }),
+                }
+            }
+            _ => Err(format!("unknown command {}", name)),
+        }
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        match self {
+            Self::Dump { path, .. }
+            | Self::Compare { path, .. }
+            | Self::Stats { path }
+            | Self::Manifest { path }
+            | Self::Verify { path }
+            | Self::Repair { path } => path,
+        }
+    }
+
+    /// Opens the table read-only and writes the output of the command to `out`.
+    ///
+    /// `verify` returns an error if it finds any problems. `repair` doesn't open the table, which
+    /// it rewrites instead.
+    pub fn run(&self, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+        if let Self::Repair { path } = self {
+            return repair(path, out);
+        }
+        let opts = Options {
+            read_only: true,
+            ..Default::default()
+        };
+        let table = Table::open(self.path(), opts.clone())?;
+        match self {
+            Self::Dump {
+                start,
+                end,
+                lsn,
+                hex,
+                ..
+            } => dump(&table, start, end, *lsn, *hex, out),
+            Self::Compare {
+                other,
+                start,
+                end,
+                lsn,
+                hex,
+                ..
+            } => {
+                let other = Table::open(other, opts)?;
+                compare(&table, &other, start, end, *lsn, *hex, out)
+            }
+            Self::Stats { .. } => stats(&table, out),
+            Self::Manifest { .. } => manifest(&table, out),
+            Self::Verify { .. } => verify(&table, out),
+            Self::Repair { .. } => unreachable!(),
+        }
+    }
+}
+
+/// Parses the options of the range of keys to read.
+fn parse_range(
+    mut args: impl Iterator<Item = String>,
+) -> Result<(Vec<u8>, Vec<u8>, u64, bool), String> {
+    let (mut start, mut end, mut lsn, mut hex) = (None, None, u64::MAX, false);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("missing value of {}", arg));
+        match arg.as_str() {
+            "--start" => start = Some(value()?),
+            "--end" => end = Some(value()?),
+            "--lsn" => {
+                let value = value()?;
+                lsn = value
+                    .parse()
+                    .map_err(|_| format!("invalid lsn {}", value))?;
+            }
+            "--hex" => hex = true,
+            _ => return Err(format!("unknown argument {}", arg)),
+        }
+    }
+    let parse_key = |key: Option<String>| match key {
+        Some(key) if hex => decode_hex(&key),
+        Some(key) => Ok(key.into_bytes()),
+        None => Ok(Vec::new()),
+    };
+    Ok((parse_key(start)?, parse_key(end)?, lsn, hex))
+}
+
+fn dump(
+    table: &Table,
+    start: &[u8],
+    end: &[u8],
+    lsn: u64,
+    hex: bool,
+    out: &mut dyn Write,
+) -> Result<(), Box<dyn Error>> {
+    let format = |bytes: &[u8]| format_bytes(bytes, hex);
+    // Stops at the first failed write, like a closed pipe. The cursor doesn't pin the table
+    // while the output blocks.
+    for entry in table.cursor(start, end, lsn) {
+        let (key, value) = entry?;
+        writeln!(out, "{} => {}", format(&key), format(&value))?;
+    }
+    Ok(())
+}
+
+fn compare(
+    table: &Table,
+    other: &Table,
+    start: &[u8],
+    end: &[u8],
+    lsn: u64,
+    hex: bool,
+    out: &mut dyn Write,
+) -> Result<(), Box<dyn Error>> {
+    let format = |bytes: &[u8]| format_bytes(bytes, hex);
+    let format_value = |value: &Option<Vec<u8>>| match value {
+        Some(value) => format(value),
+        None => "(none)".to_owned(),
+    };
+    let opts = CompareOptions {
+        lsn,
+        ..Default::default()
+    };
+    let report = compare_tables(table, other, start, end, &opts)?;
+    writeln!(
+        out,
+        "compared {} keys, {} divergent",
+        report.num_keys, report.num_divergent_keys
+    )?;
+    for range in &report.ranges {
+        writeln!(
+            out,
+            "range [{}, {}): entries {}/{}, checksums {:016x}/{:016x}{}",
+            format(&range.start),
+            format(&range.end),
+            range.num_entries[0],
+            range.num_entries[1],
+            range.checksums[0],
+            range.checksums[1],
+            if range.matches() { "" } else { " diverged" }
+        )?;
+    }
+    for key in &report.divergent_keys {
+        writeln!(
+            out,
+            "{} => {} != {}",
+            format(&key.key),
+            format_value(&key.first),
+            format_value(&key.second)
+        )?;
+    }
+    if report.is_ok() {
+        Ok(())
+    } else {
+        Err(format!("found {} divergent keys", report.num_divergent_keys).into())
+    }
+}
+
+fn stats(table: &Table, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    let info = table.inspect()?;
+    writeln!(out, "height: {}", info.height)?;
+    writeln!(out, "index nodes: {}", info.num_index_nodes)?;
+    writeln!(
+        out,
+        "leaf nodes: {} ({} on disk)",
+        info.num_leaf_nodes, info.num_disk_leaves
+    )?;
+    writeln!(
+        out,
+        "pages in memory: {} ({} bytes, largest chain {} bytes)",
+        info.num_mem_pages, info.mem_bytes, info.max_chain_size
+    )?;
+    let total_size: u64 = info.files.iter().map(|file| file.file_size).sum();
+    let live_bytes: u64 = info.files.iter().map(|file| file.live_bytes).sum();
+    writeln!(
+        out,
+        "page files: {} ({} bytes, {} live)",
+        info.files.len(),
+        total_size,
+        live_bytes
+    )?;
+    for file in &info.files {
+        writeln!(
+            out,
+            "  file {}: tier {}, size {}, pages {}, live pages {}, live bytes {} ({:.1}%)",
+            file.file_id,
+            if file.cold { "cold" } else { "hot" },
+            file.file_size,
+            file.num_pages,
+            file.live_pages,
+            file.live_bytes,
+            file.live_bytes as f64 * 100.0 / file.file_size.max(1) as f64
+        )?;
+    }
+    Ok(())
+}
+
+fn manifest(table: &Table, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    let manifest = table.manifest();
+    writeln!(out, "run id: {}", manifest.run_id)?;
+    writeln!(out, "root id: {}", manifest.root_id)?;
+    writeln!(out, "next page id: {}", manifest.next_page_id)?;
+    writeln!(out, "page files: {}", manifest.files.len())?;
+    for (file_id, max_lsn) in &manifest.files {
+        let tier = if manifest.cold_files.contains(file_id) {
+            "cold"
+        } else {
+            "hot"
+        };
+        writeln!(
+            out,
+            "  file {}: tier {}, max lsn {}",
+            file_id, tier, max_lsn
+        )?;
+    }
+    writeln!(out, "page table: {}", manifest.page_table.len())?;
+    for (page_id, file_id, offset) in &manifest.page_table {
+        writeln!(
+            out,
+            "  page {}: file {}, offset {}",
+            page_id, file_id, offset
+        )?;
+    }
+    writeln!(out, "counters: {}", manifest.counters.len())?;
+    for (name, value) in &manifest.counters {
+        writeln!(out, "  {}: {}", name, value)?;
+    }
+    Ok(())
+}
+
+fn verify(table: &Table, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    let report = table.verify()?;
+    writeln!(
+        out,
+        "verified {} nodes with {} entries",
+        report.num_nodes, report.num_entries
+    )?;
+    for problem in &report.problems {
+        writeln!(out, "{}", problem)?;
+    }
+    if report.is_ok() {
+        Ok(())
+    } else {
+        Err(format!("found {} problems", report.problems.len()).into())
+    }
+}
+
+fn repair(path: &Path, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    let report = Table::repair(path, Options::default())?;
+    writeln!(
+        out,
+        "salvaged {} leaves with {} entries",
+        report.num_leaves, report.num_entries
+    )?;
+    for lost in &report.lost {
+        writeln!(out, "{}", lost)?;
+    }
+    Ok(())
+}
+
+fn format_bytes(bytes: &[u8], hex: bool) -> String {
+    if hex {
+        encode_hex(bytes)
+    } else {
+        bytes.escape_ascii().to_string()
+    }
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_hex(s: &str) -> Result<Vec<u8>, String> {
+    s.as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .filter(|pair| pair.len() == 2)
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(|| format!("invalid hex {}", s))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(args: &str) -> Result<Command, String> {
+        Command::parse(args.split_whitespace().map(String::from))
+    }
+
+    fn run(args: &str) -> String {
+        let mut out = Vec::new();
+        parse(args).unwrap().run(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn parse_args() {
+        assert_eq!(
+            parse("dump db --start 0a --lsn 7 --hex").unwrap(),
+            Command::Dump {
+                path: "db".into(),
+                start: vec![10],
+                end: vec![],
+                lsn: 7,
+                hex: true,
+            }
+        );
+        assert_eq!(
+            parse("compare db backup --end z").unwrap(),
+            Command::Compare {
+                path: "db".into(),
+                other: "backup".into(),
+                start: vec![],
+                end: b"z".to_vec(),
+                lsn: u64::MAX,
+                hex: false,
+            }
+        );
+        assert_eq!(
+            parse("stats db").unwrap(),
+            Command::Stats { path: "db".into() }
+        );
+        assert!(parse("").is_err());
+        assert!(parse("dump").is_err());
+        assert!(parse("dump db --end").is_err());
+        assert!(parse("compare db").is_err());
+        assert!(parse("dump db --start 0 --hex").is_err());
+        assert!(parse("stats db --hex").is_err());
+        assert!(parse("compact db").is_err());
+    }
+
+    #[test]
+    fn commands() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        let table = Table::open(path, Options::default()).unwrap();
+        for i in 0..4u8 {
+            table.put(&[b'k', i], i as u64, &[b'v', i]).unwrap();
+        }
+        table.checkpoint().unwrap();
+        table.put(b"new", 4, b"value").unwrap();
+
+        // The tools see the last checkpoint while the table is open.
+        let out = run(&format!("dump {} --start 6b01 --end 6b03 --hex", path));
+        assert_eq!(out, "6b01 => 7601\n6b02 => 7602\n");
+        let out = run(&format!("dump {} --lsn 1", path));
+        assert_eq!(out, "k\\x00 => v\\x00\nk\\x01 => v\\x01\n");
+
+        let out = run(&format!("stats {}", path));
+        assert!(out.starts_with("height: 2\nindex nodes: 1\nleaf nodes: 1 (1 on disk)\n"));
+        assert!(out.contains("page files: 1 "));
+
+        let out = run(&format!("manifest {}", path));
+        assert!(out.contains("page files: 1\n"));
+        assert!(out.contains("page table: 2\n"));
+
+        let out = run(&format!("verify {}", path));
+        assert_eq!(out, "verified 2 nodes with 5 entries\n");
+
+        drop(table);
+        let out = run(&format!("repair {}", path));
+        assert_eq!(out, "salvaged 1 leaves with 4 entries\n");
+        let out = run(&format!("verify {}", path));
+        assert_eq!(out, "verified 2 nodes with 5 entries\n");
+    }
+
+    #[test]
+    fn compare() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let backup = dir.path().join("backup");
+        for path in [&path, &backup] {
+            let table = Table::open(path, Options::default()).unwrap();
+            for i in 0..4u8 {
+                table.put(&[b'k', i], 1, &[b'v', i]).unwrap();
+            }
+            table.close().unwrap();
+        }
+        let args = format!("compare {} {}", path.display(), backup.display());
+        let out = run(&args);
+        assert!(out.starts_with("compared 4 keys, 0 divergent\nrange [, ): entries 4/4"));
+
+        let table = Table::open(&path, Options::default()).unwrap();
+        table.put(b"k\x01", 2, b"new").unwrap();
+        table.close().unwrap();
+        let mut out = Vec::new();
+        let err = parse(&args).unwrap().run(&mut out).unwrap_err();
+        assert_eq!(err.to_string(), "found 1 divergent keys");
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains(" diverged\n"));
+        assert!(out.ends_with("k\\x01 => new != v\\x01\n"));
+    }
+}
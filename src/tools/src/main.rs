@@ -0,0 +1,18 @@
+use std::{io, process};
+
+use photondb_tools::{Command, USAGE};
+
+fn main() {
+    let cmd = match Command::parse(std::env::args().skip(1)) {
+        Ok(cmd) => cmd,
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, USAGE);
+            process::exit(2);
+        }
+    };
+    let stdout = io::stdout();
+    if let Err(err) = cmd.run(&mut stdout.lock()) {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}